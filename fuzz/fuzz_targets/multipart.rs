@@ -0,0 +1,40 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simpleserve::multipart::{MultipartOptions, MultipartParser};
+
+const BOUNDARY: &str = "XyZ";
+
+type Parsed = Vec<(Option<String>, Option<String>, Vec<u8>)>;
+
+/// Feeds the body in chunks of the sizes given, stopping at the first error like the server does
+fn parse(body: &[u8], mut sizes: impl Iterator<Item = usize>) -> Result<Parsed, ()> {
+    let mut parser = MultipartParser::new(BOUNDARY, MultipartOptions::new());
+    let mut rest = body;
+    while !rest.is_empty() {
+        let (chunk, next) = rest.split_at(sizes.next().unwrap_or(1).min(rest.len()));
+        parser.feed(chunk).map_err(|_| ())?;
+        rest = next;
+    }
+    parser.finish().map_err(|_| ())?;
+    Ok(parser.parts()
+        .map(|part| (part.name().map(String::from), part.filename().map(String::from), part.bytes().unwrap()))
+        .collect())
+}
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte is the number of chunk sizes that follow, the rest is the body
+    let (count, rest) = match data.split_first() {
+        Some((count, rest)) => (usize::from(*count % 8).min(rest.len()), rest),
+        None => return,
+    };
+    let (sizes, body) = rest.split_at(count);
+    let whole = parse(body, std::iter::once(body.len()));
+    // Without sizes the body is fed a byte at a time
+    let sizes: Vec<usize> = sizes.iter().map(|size| usize::from(*size) + 1).collect();
+    let chunked = parse(body, sizes.iter().copied().cycle());
+    // Where reads split the body must not change what it parses to
+    if let (Ok(whole), Ok(chunked)) = (whole, chunked) {
+        assert_eq!(whole, chunked);
+    }
+});
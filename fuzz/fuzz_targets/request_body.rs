@@ -0,0 +1,55 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simpleserve::{
+    multipart::{self, Multipart},
+    request::{parse_head, parse_query}
+};
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Requests on a kept-alive connection, each body framed by its Content-Length
+    let mut rest = data;
+    while let Some(end) = find(rest, b"\r\n\r\n") {
+        let head = String::from_utf8_lossy(&rest[..end + 2]);
+        let headers = match parse_head(&head) {
+            Ok((_, headers)) => headers,
+            Err(_) => return,
+        };
+        let length = match headers.content_length() {
+            Ok(length) => length,
+            Err(_) => return,
+        };
+        // A length is only taken from a single header of digits
+        if let Some(length) = length {
+            let values: Vec<&str> = headers.get_all("content-length").collect();
+            assert_eq!(values.len(), 1);
+            assert!(values[0].bytes().all(|byte| byte.is_ascii_digit()));
+            assert_eq!(values[0].parse::<usize>().ok(), Some(length));
+        }
+        let start = end + 4;
+        let length = length.unwrap_or(0);
+        if rest.len() - start < length {
+            return;
+        }
+        let body = &rest[start..start + length];
+        match headers.get("content-type").and_then(multipart::boundary) {
+            Some(boundary) => {
+                for part in Multipart::new(body, boundary) {
+                    if part.is_err() {
+                        break;
+                    }
+                }
+            },
+            None => {
+                if let Ok(body) = std::str::from_utf8(body) {
+                    parse_query(body);
+                }
+            }
+        }
+        rest = &rest[start + length..];
+    }
+});
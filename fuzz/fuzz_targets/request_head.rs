@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simpleserve::request::parse_head;
+
+fuzz_target!(|data: &[u8]| {
+    let head = String::from_utf8_lossy(data);
+    if let Ok((_, headers)) = parse_head(&head) {
+        for (name, _) in headers.iter() {
+            assert!(!name.is_empty());
+            assert!(headers.contains(name));
+        }
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simpleserve::utils::parse_route;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request_line) = std::str::from_utf8(data) {
+        let _ = parse_route(request_line);
+    }
+});
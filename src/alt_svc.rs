@@ -0,0 +1,207 @@
+//! Advertising other protocols
+//!
+//! A server behind a load balancer or CDN that speaks HTTP/3 or HTTP/2 can still tell clients
+//! about it, even though this server only speaks HTTP/1.1. [`AltSvc`] adds an `Alt-Svc` header
+//! (RFC 7838) to every response, listing the [`AltService`]s clients can switch to, and can
+//! advertise an upgrade to cleartext HTTP/2 (`h2c`) with `Upgrade: h2c` on HTTP connections.
+//!
+//! It is configured with [`Webserver::with_alt_svc`](crate::Webserver::with_alt_svc) and runs as
+//! a [`Middleware`] in [`Phase::PreSend`], so every response that passes through middleware gets
+//! the headers. Streamed responses, which cannot be changed, are sent without them.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     alt_svc::{AltService, AltSvc}
+//! };
+//!
+//! let server = Webserver::new(10, vec![]).with_alt_svc(AltSvc::new()
+//!     .with_service(AltService::new("h3", ":443").with_max_age(Duration::from_secs(86400)))
+//!     .with_service(AltService::new("h2", "edge.example.com:443"))
+//!     .advertise_h2c());
+//! ```
+
+use std::{
+    fmt,
+    time::Duration
+};
+
+use crate::{
+    middleware::{Middleware, Phase},
+    server::{
+        ConnectionType,
+        RequestInfo,
+        Sendable
+    }
+};
+
+/// A protocol clients can switch to, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AltService {
+    protocol: String,
+    authority: String,
+    max_age: Option<Duration>,
+    persist: bool,
+}
+
+impl AltService {
+    /// Creates a service
+    ///
+    /// # Arguments
+    /// * `protocol` - The ALPN protocol id, like `h3` or `h2`
+    /// * `authority` - Where it is served, `host:port`, or `:port` for the same host
+    ///
+    /// # Panics
+    /// If the protocol or authority contain a `"`, a space or a control character.
+    pub fn new(protocol: &str, authority: &str) -> AltService {
+        let valid = |value: &str| !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_graphic() && byte != b'"');
+        assert!(valid(protocol) && valid(authority), "Invalid alternative service {}={}", protocol, authority);
+        AltService {
+            protocol: String::from(protocol),
+            authority: String::from(authority),
+            max_age: None,
+            persist: false,
+        }
+    }
+
+    /// Sets how long clients remember the service, 24 hours when not set
+    pub fn with_max_age(mut self, max_age: Duration) -> AltService {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Asks clients to remember the service when their network changes
+    pub fn persist(mut self) -> AltService {
+        self.persist = true;
+        self
+    }
+
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.persist
+    }
+}
+
+impl fmt::Display for AltService {
+    /// Formats the service as an entry of an `Alt-Svc` header
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=\"{}\"", self.protocol, self.authority)?;
+        if let Some(max_age) = self.max_age {
+            write!(f, "; ma={}", max_age.as_secs())?;
+        }
+        if self.persist {
+            write!(f, "; persist=1")?;
+        }
+        Ok(())
+    }
+}
+
+/// Middleware that advertises other protocols on every response
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AltSvc {
+    services: Vec<AltService>,
+    clear: bool,
+    h2c: bool,
+}
+
+impl AltSvc {
+    /// Advertises nothing until services are added
+    pub fn new() -> AltSvc {
+        AltSvc::default()
+    }
+
+    /// Adds a service, listed in the order it was added, the preferred one first
+    pub fn with_service(mut self, service: AltService) -> AltSvc {
+        self.services.push(service);
+        self
+    }
+
+    /// Tells clients to forget the services they were told about before, with `Alt-Svc: clear`
+    ///
+    /// Replaces any services that were added.
+    pub fn clear(mut self) -> AltSvc {
+        self.clear = true;
+        self
+    }
+
+    /// Offers an upgrade to cleartext HTTP/2 on HTTP connections, with `Upgrade: h2c`
+    ///
+    /// For servers behind a terminator that accepts the upgrade. Connections over TLS negotiate
+    /// HTTP/2 with ALPN instead, so they are not offered it.
+    pub fn advertise_h2c(mut self) -> AltSvc {
+        self.h2c = true;
+        self
+    }
+
+    pub fn services(&self) -> &[AltService] {
+        &self.services
+    }
+
+    pub fn advertises_h2c(&self) -> bool {
+        self.h2c
+    }
+
+    /// The value of the `Alt-Svc` header, or `None` if there is nothing to advertise
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::alt_svc::{AltService, AltSvc};
+    ///
+    /// let alt_svc = AltSvc::new()
+    ///     .with_service(AltService::new("h3", ":443").with_max_age(Duration::from_secs(3600)).persist())
+    ///     .with_service(AltService::new("h2", "alt.example.com:8443"));
+    /// assert_eq!(alt_svc.header_value().as_deref(), Some("h3=\":443\"; ma=3600; persist=1, h2=\"alt.example.com:8443\""));
+    /// assert_eq!(alt_svc.clear().header_value().as_deref(), Some("clear"));
+    /// assert_eq!(AltSvc::new().header_value(), None);
+    /// ```
+    pub fn header_value(&self) -> Option<String> {
+        if self.clear {
+            return Some(String::from("clear"));
+        }
+        if self.services.is_empty() {
+            return None;
+        }
+        let services: Vec<String> = self.services.iter().map(AltService::to_string).collect();
+        Some(services.join(", "))
+    }
+}
+
+impl Middleware for AltSvc {
+    fn after_phase(&self) -> Phase {
+        Phase::PreSend
+    }
+
+    fn after(&self, request: &RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+        let mut advertised = match response.to_response() {
+            Some(original) => original,
+            None => return response,
+        };
+        // A switching response already speaks another protocol
+        if advertised.status() < 200 {
+            return response;
+        }
+        if let Some(value) = self.header_value().filter(|_| !advertised.headers().contains("alt-svc")) {
+            advertised = advertised.header("Alt-Svc", &value);
+        }
+        let cleartext = matches!(request.conn.connection_type(), ConnectionType::Http);
+        if self.h2c && cleartext && !advertised.headers().contains("upgrade") {
+            // A server sending Upgrade has to list it in Connection as well
+            advertised = advertised.header("Upgrade", "h2c").header("Connection", "Upgrade");
+        }
+        Box::new(advertised)
+    }
+}
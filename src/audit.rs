@@ -0,0 +1,173 @@
+//! A tamper-evident audit log
+//!
+//! An [`AuditLog`] appends one line per action to a file, with who asked for it. Every entry
+//! carries the SHA-256 hash of itself and of the entry before it, so editing, removing or
+//! reordering entries breaks the chain from that point on, which [`AuditLog::verify`] reports.
+//! Appending forged entries to the end still needs the hash of the last entry, so keep a copy of
+//! it, or of the whole log, where the server cannot write.
+//!
+//! Given to [`Webserver::with_audit_log`](crate::Webserver::with_audit_log), the server records
+//! its shutdowns, and who asked for them: the [`ShutdownHandle`](crate::ShutdownHandle)
+//! (or the actor given to [`ShutdownHandle::shutdown_by`](crate::ShutdownHandle::shutdown_by)),
+//! a signal, or the task channel. Handlers of admin routes record their own actions with
+//! [`AuditLog::record`], shared with them through the application state.
+//!
+//! Each line holds the sequence number, the time, the actor, the action, the hash of the
+//! previous entry and the hash of this one, separated by tabs. Tabs, line breaks and backslashes
+//! in actors and actions are escaped.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     audit::AuditLog
+//! };
+//!
+//! # fn main() -> std::io::Result<()> {
+//! # let path = std::env::temp_dir().join(format!("simpleserve-audit-doc-{}", std::process::id()));
+//! let log = AuditLog::open(&path)?;
+//! log.record("alice", "enable maintenance mode")?;
+//! assert_eq!(AuditLog::verify(&path)?, 1);
+//!
+//! let server = Webserver::new(10, vec![]).with_audit_log(log);
+//! # std::fs::remove_file(&path)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex}
+};
+
+use openssl::sha::sha256;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    utils
+};
+
+/// The previous hash of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An append-only, hash-chained log of actions and who took them
+pub struct AuditLog {
+    path: PathBuf,
+    clock: Arc<dyn Clock>,
+    chain: Mutex<Chain>,
+}
+
+/// Where the next entry goes
+struct Chain {
+    file: File,
+    sequence: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Opens the log at a path, creating it if it does not exist
+    ///
+    /// New entries continue the chain of the existing ones. Fails if the existing entries have
+    /// been tampered with.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<AuditLog> {
+        let path = path.as_ref().to_path_buf();
+        let (sequence, last_hash) = match fs::read_to_string(&path) {
+            Ok(contents) => check(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, String::from(GENESIS)),
+            Err(e) => return Err(e),
+        };
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path)?;
+        Ok(AuditLog {
+            path,
+            clock: Arc::new(SystemClock),
+            chain: Mutex::new(Chain { file, sequence, last_hash }),
+        })
+    }
+
+    /// Sets the clock entries are timestamped with
+    ///
+    /// Useful in tests, together with [`MockClock`](crate::clock::MockClock).
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> AuditLog {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends an entry, and waits until it is on disk
+    ///
+    /// # Arguments
+    /// * `actor` - Who took the action, such as a user name or `SIGTERM`
+    /// * `action` - What was done
+    pub fn record(&self, actor: &str, action: &str) -> io::Result<()> {
+        let mut chain = self.chain.lock().unwrap();
+        let sequence = chain.sequence + 1;
+        let entry = format!(
+            "{}\t{}\t{}\t{}\t{}",
+            sequence,
+            utils::format_http_date(self.clock.system_time()),
+            escape(actor),
+            escape(action),
+            chain.last_hash
+        );
+        let hash = hash(&entry);
+        chain.file.write_all(format!("{}\t{}\n", entry, hash).as_bytes())?;
+        chain.file.sync_data()?;
+        chain.sequence = sequence;
+        chain.last_hash = hash;
+        Ok(())
+    }
+
+    /// Checks the chain of the log at a path, and returns the number of entries in it
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] naming the first entry that does not match the
+    /// ones before it.
+    pub fn verify<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+        check(&fs::read_to_string(path)?).map(|(sequence, _)| sequence)
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").field("path", &self.path).finish()
+    }
+}
+
+/// The last sequence number and hash of a log, if its chain is intact
+fn check(contents: &str) -> io::Result<(u64, String)> {
+    let mut sequence = 0;
+    let mut last_hash = String::from(GENESIS);
+    for line in contents.lines() {
+        sequence += 1;
+        let tampered = || io::Error::new(io::ErrorKind::InvalidData, format!("Audit log entry {} has been tampered with", sequence));
+        let (entry, entry_hash) = line.rsplit_once('\t').ok_or_else(tampered)?;
+        let fields: Vec<&str> = entry.split('\t').collect();
+        if fields.len() != 5 || fields[0] != sequence.to_string() || fields[4] != last_hash || hash(entry) != entry_hash {
+            return Err(tampered());
+        }
+        last_hash = String::from(entry_hash);
+    }
+    Ok((sequence, last_hash))
+}
+
+fn hash(entry: &str) -> String {
+    sha256(entry.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Keeps a field on its line and out of the other fields
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
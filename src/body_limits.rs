@@ -0,0 +1,152 @@
+//! Request body limits by content type
+//!
+//! Some bodies are fine to be large and others are not: a file upload may need 100 MB, while a
+//! JSON body that large is almost certainly an attack on the parser. [`BodyLimits`] sets the
+//! largest body of each type the server reads. Bodies over the limit of their type are answered
+//! with 413 Payload Too Large, and with [`BodyLimits::only_listed`], bodies of types without a
+//! limit are answered with 415 Unsupported Media Type. Both are decided from the headers, before
+//! the body is read.
+//!
+//! Limits are set for every route with
+//! [`Webserver::with_body_limits`](crate::Webserver::with_body_limits), and replaced for a route
+//! with [`Webserver::set_body_limits`](crate::Webserver::set_body_limits). Types without a limit
+//! fall back to [`Webserver::with_max_body_size`](crate::Webserver::with_max_body_size), or to
+//! [`MultipartOptions::max_size`](crate::multipart::MultipartOptions::max_size) for multipart bodies.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     RequestInfo,
+//!     Page,
+//!     body_limits::BodyLimits
+//! };
+//!
+//! fn upload(_: &RequestInfo) -> Box<dyn Sendable> {
+//!     Box::new(Page::new(200, String::from("Uploaded")))
+//! }
+//!
+//! const MB: usize = 1024 * 1024;
+//! let mut server = Webserver::new(10, vec![])
+//!     .with_body_limits(BodyLimits::new()
+//!         .with_type("application/json", MB)
+//!         .with_type("text/", 64 * 1024));
+//! server.post("/upload", upload);
+//! server.set_body_limits("/upload", BodyLimits::new()
+//!     .with_type("multipart/form-data", 100 * MB)
+//!     .only_listed());
+//! ```
+
+use crate::errors::UnsupportedMediaTypeError;
+
+/// The largest body the server reads for each content type
+///
+/// An entry ending in `/` applies to every subtype, and an exact entry wins over it.
+///
+/// # Examples
+/// ```
+/// use simpleserve::body_limits::BodyLimits;
+///
+/// let limits = BodyLimits::new()
+///     .with_type("application/json", 1024)
+///     .with_type("image/", 4096)
+///     .with_type("image/svg+xml", 512);
+/// assert_eq!(limits.limit(Some("application/json; charset=utf-8")), Ok(Some(1024)));
+/// assert_eq!(limits.limit(Some("image/png")), Ok(Some(4096)));
+/// assert_eq!(limits.limit(Some("image/svg+xml")), Ok(Some(512)));
+/// assert_eq!(limits.limit(Some("text/plain")), Ok(None));
+///
+/// let strict = limits.only_listed();
+/// assert!(strict.limit(Some("text/plain")).is_err());
+/// assert!(strict.limit(None).is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BodyLimits {
+    types: Vec<(String, usize)>,
+    default: Option<usize>,
+    only_listed: bool,
+}
+
+impl BodyLimits {
+    /// Creates limits without any types, which leave every body to the server's own limits
+    pub fn new() -> BodyLimits {
+        BodyLimits::default()
+    }
+
+    /// Limits bodies of a type, or of every subtype if it ends in `/`, in bytes
+    ///
+    /// Replaces any earlier limit for the type.
+    pub fn with_type(mut self, mime_type: &str, max_size: usize) -> BodyLimits {
+        let mime_type = mime_type.to_ascii_lowercase();
+        self.types.retain(|(existing, _)| *existing != mime_type);
+        self.types.push((mime_type, max_size));
+        self
+    }
+
+    /// Limits bodies of the types without a limit of their own, in bytes
+    pub fn with_default(mut self, max_size: usize) -> BodyLimits {
+        self.default = Some(max_size);
+        self
+    }
+
+    /// Answers bodies of the types without a limit with 415 Unsupported Media Type
+    ///
+    /// This includes bodies without a `Content-Type`. A limit set with
+    /// [`BodyLimits::with_default`] is ignored.
+    pub fn only_listed(mut self) -> BodyLimits {
+        self.only_listed = true;
+        self
+    }
+
+    pub fn is_only_listed(&self) -> bool {
+        self.only_listed
+    }
+
+    /// The types with a limit, in the order they were added
+    pub fn types(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.types.iter().map(|(mime_type, max_size)| (mime_type.as_str(), *max_size))
+    }
+
+    /// The limit for a body of a type, or `None` if the server's own limit applies
+    ///
+    /// # Errors
+    /// If the type has no limit and only the listed types are accepted.
+    pub fn limit(&self, content_type: Option<&str>) -> Result<Option<usize>, UnsupportedMediaTypeError> {
+        let essence = content_type.map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+        let listed = essence.as_deref().and_then(|essence| {
+            let exact = self.types.iter().find(|(mime_type, _)| mime_type == essence);
+            let prefix = || self.types.iter()
+                .filter(|(mime_type, _)| mime_type.ends_with('/') && essence.starts_with(mime_type.as_str()))
+                .max_by_key(|(mime_type, _)| mime_type.len());
+            exact.or_else(prefix).map(|(_, max_size)| *max_size)
+        });
+        match listed {
+            Some(max_size) => Ok(Some(max_size)),
+            None if self.only_listed => Err(UnsupportedMediaTypeError::new(content_type)),
+            None => Ok(self.default),
+        }
+    }
+
+    /// The largest body of a type the server reads
+    ///
+    /// # Arguments
+    /// * `content_type` - The `Content-Type` of the request, if it has one
+    /// * `fallback` - The limit for types without one
+    ///
+    /// # Errors
+    /// If the type has no limit and only the listed types are accepted.
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::body_limits::BodyLimits;
+    ///
+    /// let limits = BodyLimits::new().with_type("application/json", 100);
+    /// assert_eq!(limits.max_size(Some("application/json"), 1000), Ok(100));
+    /// assert_eq!(limits.max_size(Some("text/plain"), 1000), Ok(1000));
+    /// assert!(limits.only_listed().max_size(Some("text/plain"), 1000).is_err());
+    /// ```
+    pub fn max_size(&self, content_type: Option<&str>, fallback: usize) -> Result<usize, UnsupportedMediaTypeError> {
+        Ok(self.limit(content_type)?.unwrap_or(fallback))
+    }
+}
@@ -0,0 +1,390 @@
+//! Stricter rate limits for crawlers
+//!
+//! Aggressive scrapers can take a small server down long before its users do. A [`BotThrottle`]
+//! gives automated clients rate limits of their own, so they can be slowed down without touching
+//! the limits of people using the site:
+//! - Clients whose `User-Agent` identifies them as a bot, see [`UserAgent::is_bot`], get the bot
+//!   limit. The header is trivially faked, but a scraper that hides behind a browser's is no worse
+//!   off than without the throttle.
+//! - With [`BotThrottle::with_crawler_limit`], clients are also checked against known
+//!   [`Crawler`]s by reverse DNS: the address has to resolve to a host name in one of the
+//!   crawler's domains, and that name has to resolve back to the address. Verified crawlers get
+//!   the crawler limit, whatever their `User-Agent` says, and clients that only claim to be one
+//!   keep the bot limit.
+//!
+//! DNS lookups block, so they run one at a time on a thread of the throttle's own, and a client
+//! is treated by its `User-Agent` until its address is verified. Connections never wait for a
+//! lookup, however slow the resolver is. Results are kept for [`DEFAULT_MAX_VERIFIED`]
+//! addresses, and all of them are forgotten at once when there are more.
+//!
+//! The limits are [`RateLimiter`]s, so each client IP has its own bucket, and limited requests
+//! are answered with 429 Too Many Requests like those of a rate limiter.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     bot_throttle::BotThrottle,
+//!     rate_limit::RateLimiter
+//! };
+//!
+//! let server = Webserver::new(10, vec![])
+//!     .with_rate_limit(RateLimiter::new(600, Duration::from_secs(60)))
+//!     .with_bot_throttle(BotThrottle::new(RateLimiter::new(10, Duration::from_secs(60)))
+//!         .with_crawler_limit(RateLimiter::new(60, Duration::from_secs(60))));
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    net::{IpAddr, ToSocketAddrs},
+    sync::{
+        Arc,
+        Mutex,
+        OnceLock,
+        mpsc::{self, SyncSender}
+    }
+};
+
+use crate::{
+    middleware::Middleware,
+    rate_limit::{self, RateLimiter},
+    request::Request,
+    server::Sendable,
+    user_agent::UserAgent
+};
+
+/// The number of addresses verification results are kept for by default
+pub const DEFAULT_MAX_VERIFIED: usize = 10_000;
+
+/// How many addresses may wait for the verification thread
+const MAX_QUEUED: usize = 1024;
+
+/// A crawler, and the domains its addresses resolve to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crawler {
+    name: String,
+    domains: Vec<String>,
+}
+
+impl Crawler {
+    /// Creates a crawler
+    ///
+    /// # Arguments
+    /// * `name` - The name of the crawler, like `Googlebot`
+    /// * `domains` - The domains the host names of its addresses are in, like `googlebot.com`
+    pub fn new(name: &str, domains: &[&str]) -> Crawler {
+        Crawler {
+            name: String::from(name),
+            domains: domains.iter().map(|domain| domain.trim_start_matches('.').to_ascii_lowercase()).collect(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    /// Whether a host name is in one of the crawler's domains
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::bot_throttle::Crawler;
+    ///
+    /// let crawler = Crawler::new("Googlebot", &["googlebot.com"]);
+    /// assert!(crawler.owns("crawl-66-249-66-1.googlebot.com"));
+    /// assert!(crawler.owns("crawl-66-249-66-1.googlebot.com."));
+    /// assert!(!crawler.owns("googlebot.com.example.net"));
+    /// assert!(!crawler.owns("notgooglebot.com"));
+    /// ```
+    pub fn owns(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.iter().any(|domain| host.strip_suffix(domain.as_str()).is_some_and(|rest| rest.ends_with('.')))
+    }
+}
+
+/// The crawlers of the major search engines, with the domains they publish for verification
+pub fn known_crawlers() -> Vec<Crawler> {
+    vec![
+        Crawler::new("Googlebot", &["googlebot.com", "google.com", "googleusercontent.com"]),
+        Crawler::new("Bingbot", &["search.msn.com"]),
+        Crawler::new("Applebot", &["applebot.apple.com"]),
+        Crawler::new("DuckDuckBot", &["duckduckgo.com"]),
+        Crawler::new("YandexBot", &["yandex.ru", "yandex.net", "yandex.com"]),
+        Crawler::new("Baiduspider", &["baidu.com", "baidu.jp"]),
+    ]
+}
+
+/// Looks up host names and addresses
+///
+/// [`SystemResolver`] asks the system. Tests can use a resolver with fixed answers.
+pub trait Resolver: Debug + Send + Sync {
+    /// The host name of an address, from its PTR record
+    fn reverse(&self, ip: IpAddr) -> Option<String>;
+
+    /// The addresses of a host name
+    fn forward(&self, host: &str) -> Vec<IpAddr>;
+}
+
+/// The resolver of the system, through `getnameinfo` and `getaddrinfo`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    #[cfg(unix)]
+    fn reverse(&self, ip: IpAddr) -> Option<String> {
+        // SAFETY: zeroed storage is a valid socket address of no family, which is set below
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let length = match ip {
+            IpAddr::V4(ip) => {
+                // SAFETY: the storage is large enough and aligned for every socket address
+                let address = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+                address.sin_family = libc::AF_INET as libc::sa_family_t;
+                address.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(ip.octets()) };
+                std::mem::size_of::<libc::sockaddr_in>()
+            },
+            IpAddr::V6(ip) => {
+                // SAFETY: as above
+                let address = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+                address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                address.sin6_addr = libc::in6_addr { s6_addr: ip.octets() };
+                std::mem::size_of::<libc::sockaddr_in6>()
+            },
+        };
+        // NI_MAXHOST, the longest name getnameinfo returns
+        let mut host = [0 as libc::c_char; 1025];
+        // SAFETY: the address and the buffer are valid for the lengths given, and NI_NAMEREQD
+        // makes it fail instead of writing the address as text when there is no name
+        let result = unsafe {
+            libc::getnameinfo(
+                (&storage as *const libc::sockaddr_storage).cast(),
+                length as libc::socklen_t,
+                host.as_mut_ptr(),
+                host.len() as libc::socklen_t,
+                std::ptr::null_mut(),
+                0,
+                libc::NI_NAMEREQD,
+            )
+        };
+        if result != 0 {
+            return None;
+        }
+        // SAFETY: getnameinfo wrote a terminated string into the buffer
+        let host = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+        host.to_str().ok().map(String::from)
+    }
+
+    /// Reverse lookups are not supported on this platform, so no address is verified
+    #[cfg(not(unix))]
+    fn reverse(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+
+    fn forward(&self, host: &str) -> Vec<IpAddr> {
+        match (host, 0).to_socket_addrs() {
+            Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// How a client is throttled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Client {
+    /// Not throttled
+    Human,
+    /// Identified as a bot by its `User-Agent`, and given the bot limit
+    Bot,
+    /// Verified as the named crawler, and given the crawler limit
+    Crawler(String),
+}
+
+#[derive(Debug, Clone)]
+enum Verification {
+    Pending,
+    Verified(String),
+    Failed,
+}
+
+/// What verifies addresses, shared with the lookups running in the background
+#[derive(Debug)]
+struct Verifier {
+    crawlers: Vec<Crawler>,
+    resolver: Box<dyn Resolver>,
+    max_verified: usize,
+    verified: Mutex<HashMap<IpAddr, Verification>>,
+    lookups: OnceLock<SyncSender<IpAddr>>,
+}
+
+impl Verifier {
+    /// Looks the address up, and remembers the result
+    fn verify(&self, ip: IpAddr) -> Option<String> {
+        let crawler = self.resolver.reverse(ip)
+            .and_then(|host| {
+                let crawler = self.crawlers.iter().find(|crawler| crawler.owns(&host))?;
+                // Anyone can set the PTR record of their own address, but not the addresses of the crawler's domain
+                self.resolver.forward(&host).contains(&ip).then(|| String::from(crawler.name()))
+            });
+        let result = match &crawler {
+            Some(name) => Verification::Verified(name.clone()),
+            None => Verification::Failed,
+        };
+        self.remember(ip, result);
+        crawler
+    }
+
+    /// Queues an address for the verification thread, which is started the first time
+    fn verify_later(self: &Arc<Self>, ip: IpAddr) {
+        let lookups = self.lookups.get_or_init(|| {
+            let (lookups, queued) = mpsc::sync_channel(MAX_QUEUED);
+            // The thread does not keep the verifier alive, and stops with it when the sender is dropped
+            let verifier = Arc::downgrade(self);
+            std::thread::spawn(move || {
+                while let Ok(ip) = queued.recv() {
+                    match verifier.upgrade() {
+                        Some(verifier) => { verifier.verify(ip); },
+                        None => break,
+                    }
+                }
+            });
+            lookups
+        });
+        if lookups.try_send(ip).is_err() {
+            // Queued again by a later request, once the thread has caught up
+            self.verified.lock().unwrap().remove(&ip);
+        }
+    }
+
+    /// Keeps the result for an address
+    fn remember(&self, ip: IpAddr, result: Verification) {
+        let mut verified = self.verified.lock().unwrap();
+        if !verified.contains_key(&ip) && verified.len() >= self.max_verified {
+            verified.clear();
+        }
+        verified.insert(ip, result);
+    }
+}
+
+/// Middleware that gives bots and crawlers rate limits of their own
+#[derive(Debug)]
+pub struct BotThrottle {
+    bots: RateLimiter,
+    crawlers: Option<RateLimiter>,
+    verifier: Arc<Verifier>,
+}
+
+impl BotThrottle {
+    /// Limits the clients whose `User-Agent` identifies them as a bot
+    pub fn new(bots: RateLimiter) -> BotThrottle {
+        BotThrottle {
+            bots,
+            crawlers: None,
+            verifier: Arc::new(Verifier {
+                crawlers: known_crawlers(),
+                resolver: Box::new(SystemResolver),
+                max_verified: DEFAULT_MAX_VERIFIED,
+                verified: Mutex::new(HashMap::new()),
+                lookups: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Verifies every client by reverse DNS, and limits verified crawlers with their own limit
+    pub fn with_crawler_limit(mut self, crawlers: RateLimiter) -> BotThrottle {
+        self.crawlers = Some(crawlers);
+        self
+    }
+
+    /// Sets the crawlers clients are verified as, [`known_crawlers`] by default
+    pub fn with_crawlers(mut self, crawlers: Vec<Crawler>) -> BotThrottle {
+        self.verifier_mut().crawlers = crawlers;
+        self
+    }
+
+    /// Sets the resolver addresses are verified with, [`SystemResolver`] by default
+    pub fn with_resolver<R: Resolver + 'static>(mut self, resolver: R) -> BotThrottle {
+        self.verifier_mut().resolver = Box::new(resolver);
+        self
+    }
+
+    /// Sets how many addresses verification results are kept for
+    ///
+    /// Defaults to [`DEFAULT_MAX_VERIFIED`].
+    ///
+    /// # Panics
+    /// If the number of addresses is 0.
+    pub fn with_max_verified(mut self, max_verified: usize) -> BotThrottle {
+        assert!(max_verified > 0, "A bot throttle must keep at least one verification");
+        self.verifier_mut().max_verified = max_verified;
+        self
+    }
+
+    /// The verifier is only shared once lookups run, after the throttle is built
+    fn verifier_mut(&mut self) -> &mut Verifier {
+        Arc::get_mut(&mut self.verifier).expect("The verifier is not shared yet")
+    }
+
+    pub fn bot_limit(&self) -> &RateLimiter {
+        &self.bots
+    }
+
+    pub fn crawler_limit(&self) -> Option<&RateLimiter> {
+        self.crawlers.as_ref()
+    }
+
+    pub fn crawlers(&self) -> &[Crawler] {
+        &self.verifier.crawlers
+    }
+
+    /// Verifies an address now, blocking on the lookups
+    ///
+    /// Returns the name of the crawler the address belongs to, and remembers the result for
+    /// [`BotThrottle::classify`].
+    pub fn verify(&self, ip: IpAddr) -> Option<String> {
+        self.verifier.verify(ip)
+    }
+
+    /// Decides how a client is throttled
+    ///
+    /// Without a crawler limit, only the `User-Agent` is used. With one, an address that was not
+    /// verified yet is queued for verification in the background, and goes by its `User-Agent`
+    /// until then.
+    pub fn classify(&self, ip: IpAddr, user_agent: Option<&str>) -> Client {
+        if self.crawlers.is_some() {
+            let known = self.verifier.verified.lock().unwrap().get(&ip).cloned();
+            match known {
+                Some(Verification::Verified(name)) => return Client::Crawler(name),
+                Some(Verification::Pending) | Some(Verification::Failed) => {},
+                None => {
+                    // Two requests racing here both look the address up, which is only wasted work
+                    self.verifier.remember(ip, Verification::Pending);
+                    self.verifier.verify_later(ip);
+                },
+            }
+        }
+        match user_agent.map(UserAgent::parse).is_some_and(|user_agent| user_agent.is_bot()) {
+            true => Client::Bot,
+            false => Client::Human,
+        }
+    }
+}
+
+impl Middleware for BotThrottle {
+    /// Answers 429 Too Many Requests to bots and crawlers over their limit
+    ///
+    /// Requests without a peer address, like ones handled without a connection, are not limited.
+    fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+        let client = request.peer_addr?.ip();
+        let limiter = match self.classify(client, request.headers.get("user-agent")) {
+            Client::Human => return None,
+            Client::Bot => &self.bots,
+            Client::Crawler(_) => self.crawlers.as_ref()?,
+        };
+        let wait = limiter.check(client).err()?;
+        Some(rate_limit::too_many_requests(wait))
+    }
+}
@@ -0,0 +1,164 @@
+//! Caching headers for static files
+//!
+//! A [`CachePolicy`] decides how long browsers and proxies may keep the files the server sends,
+//! by route or by file extension. The file handlers add `Cache-Control` and `Expires` headers
+//! from it to every file response, next to the `Last-Modified` and `ETag` headers they always send,
+//! so static routes do not each need a handler of their own to set them. The policy also decides
+//! how those ETags are made, see [`ETagMode`].
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     cache_policy::{CacheControl, CachePolicy}
+//! };
+//!
+//! let policy = CachePolicy::new()
+//!     .with_default(CacheControl::NoCache)
+//!     .with_extension("css", CacheControl::MaxAge(Duration::from_secs(3600)))
+//!     .with_route("/assets/", CacheControl::Immutable(Duration::from_secs(365 * 24 * 3600)))
+//!     .with_route("/private/", CacheControl::NoStore);
+//! let server = Webserver::new(10, vec![]).with_cache_policy(policy);
+//! ```
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use crate::{
+    etag::ETagMode,
+    utils
+};
+
+/// How a response may be cached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheControl {
+    /// Never stored, for responses with private data
+    NoStore,
+    /// Stored, but checked with the server before every use
+    NoCache,
+    /// Used without checking for a while
+    MaxAge(Duration),
+    /// Used without checking for a while, and not even checked when the page is reloaded
+    ///
+    /// For files whose name changes with their content, like `app.3f2a9c.js`.
+    Immutable(Duration),
+}
+
+impl CacheControl {
+    /// The value of the `Cache-Control` header
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::cache_policy::CacheControl;
+    ///
+    /// assert_eq!(CacheControl::MaxAge(Duration::from_secs(60)).header_value(), "public, max-age=60");
+    /// assert_eq!(CacheControl::NoStore.header_value(), "no-store");
+    /// ```
+    pub fn header_value(&self) -> String {
+        match self {
+            CacheControl::NoStore => String::from("no-store"),
+            CacheControl::NoCache => String::from("no-cache"),
+            CacheControl::MaxAge(max_age) => format!("public, max-age={}", max_age.as_secs()),
+            CacheControl::Immutable(max_age) => format!("public, max-age={}, immutable", max_age.as_secs()),
+        }
+    }
+
+    /// The value of the `Expires` header, for clients that do not understand `Cache-Control`
+    ///
+    /// # Arguments
+    /// * `now` - The time the response is sent
+    pub fn expires(&self, now: SystemTime) -> String {
+        match self {
+            // A date in the past means the response is already stale
+            CacheControl::NoStore | CacheControl::NoCache => utils::format_http_date(UNIX_EPOCH),
+            CacheControl::MaxAge(max_age) | CacheControl::Immutable(max_age) => utils::format_http_date(now + *max_age),
+        }
+    }
+}
+
+/// How long files may be cached, by route and by extension
+///
+/// A route rule applies to every route starting with it, and the longest one wins. Route rules
+/// come before extension rules, which come before the default. Without a matching rule, no
+/// caching headers are added and clients use their own heuristics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CachePolicy {
+    routes: Vec<(String, CacheControl)>,
+    extensions: HashMap<String, CacheControl>,
+    default: Option<CacheControl>,
+    etag_mode: ETagMode,
+}
+
+impl CachePolicy {
+    /// Creates a policy without any rules
+    pub fn new() -> CachePolicy {
+        CachePolicy::default()
+    }
+
+    /// Caches files with an extension, given without the dot
+    pub fn with_extension(mut self, extension: &str, control: CacheControl) -> CachePolicy {
+        self.extensions.insert(extension.trim_start_matches('.').to_ascii_lowercase(), control);
+        self
+    }
+
+    /// Caches files whose route starts with a prefix
+    pub fn with_route(mut self, prefix: &str, control: CacheControl) -> CachePolicy {
+        self.routes.push((String::from(prefix), control));
+        self
+    }
+
+    /// Caches files that no other rule matches
+    pub fn with_default(mut self, control: CacheControl) -> CachePolicy {
+        self.default = Some(control);
+        self
+    }
+
+    /// Sets how the ETags of files are made
+    ///
+    /// Defaults to [`ETagMode::Weak`].
+    pub fn with_etag_mode(mut self, mode: ETagMode) -> CachePolicy {
+        self.etag_mode = mode;
+        self
+    }
+
+    pub fn etag_mode(&self) -> ETagMode {
+        self.etag_mode
+    }
+
+    /// How a file sent for a route may be cached
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::cache_policy::{CacheControl, CachePolicy};
+    ///
+    /// let hour = CacheControl::MaxAge(Duration::from_secs(3600));
+    /// let policy = CachePolicy::new()
+    ///     .with_extension("png", hour)
+    ///     .with_route("/private/", CacheControl::NoStore);
+    /// assert_eq!(policy.lookup("/logo.PNG"), Some(hour));
+    /// assert_eq!(policy.lookup("/private/avatar.png"), Some(CacheControl::NoStore));
+    /// assert_eq!(policy.lookup("/index.html"), None);
+    /// ```
+    pub fn lookup(&self, route: &str) -> Option<CacheControl> {
+        let by_route = self.routes.iter()
+            .filter(|(prefix, _)| route.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, control)| *control);
+        let by_extension = || {
+            let extension = Path::new(route).extension()?.to_str()?.to_ascii_lowercase();
+            self.extensions.get(&extension).copied()
+        };
+        by_route.or_else(by_extension).or(self.default)
+    }
+
+    /// Whether the policy has no rules
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty() && self.extensions.is_empty() && self.default.is_none()
+    }
+}
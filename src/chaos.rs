@@ -0,0 +1,193 @@
+//! Fault injection
+//!
+//! The [`Chaos`] middleware makes the server misbehave on purpose: it delays some requests,
+//! fails some with 500 Internal Server Error, and drops the connection of some without any
+//! response. This shows how clients, retries and dashboards cope before a real outage does.
+//! The rates can be changed while the server runs through a [`ChaosController`].
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     chaos::{Chaos, ChaosSettings}
+//! };
+//!
+//! let chaos = Chaos::new(ChaosSettings::new()
+//!     .with_delay(0.2, Duration::from_millis(500))
+//!     .with_failures(0.05));
+//! let controller = chaos.controller();
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_middleware(chaos);
+//!
+//! // Later, from anywhere
+//! controller.update(|settings| settings.with_drops(0.01));
+//! controller.disable();
+//! ```
+
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    middleware::Middleware,
+    request::Request,
+    server::{
+        ConnectionInfo,
+        Page,
+        Sendable
+    }
+};
+
+/// How often each fault is injected, as fractions of requests between 0 and 1
+///
+/// The default injects nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ChaosSettings {
+    delay_rate: f64,
+    delay: Duration,
+    failure_rate: f64,
+    drop_rate: f64,
+}
+
+impl ChaosSettings {
+    pub fn new() -> ChaosSettings {
+        ChaosSettings::default()
+    }
+
+    /// Delays a fraction of requests before they are handled
+    pub fn with_delay(mut self, rate: f64, delay: Duration) -> ChaosSettings {
+        self.delay_rate = rate.clamp(0.0, 1.0);
+        self.delay = delay;
+        self
+    }
+
+    /// Answers a fraction of requests with 500 Internal Server Error
+    pub fn with_failures(mut self, rate: f64) -> ChaosSettings {
+        self.failure_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Closes the connection of a fraction of requests without a response
+    pub fn with_drops(mut self, rate: f64) -> ChaosSettings {
+        self.drop_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn delay_rate(&self) -> f64 {
+        self.delay_rate
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn failure_rate(&self) -> f64 {
+        self.failure_rate
+    }
+
+    pub fn drop_rate(&self) -> f64 {
+        self.drop_rate
+    }
+}
+
+/// Middleware that injects faults into requests
+pub struct Chaos {
+    settings: Arc<RwLock<ChaosSettings>>,
+}
+
+impl Chaos {
+    pub fn new(settings: ChaosSettings) -> Chaos {
+        Chaos {
+            settings: Arc::new(RwLock::new(settings)),
+        }
+    }
+
+    /// A handle to change the settings while the server runs
+    pub fn controller(&self) -> ChaosController {
+        ChaosController {
+            settings: Arc::clone(&self.settings),
+        }
+    }
+}
+
+impl Middleware for Chaos {
+    fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+        let settings = *self.settings.read().unwrap();
+        if chance(settings.drop_rate) {
+            println!("Chaos: dropping the connection for {}", request.route);
+            return Some(Box::new(Dropped));
+        }
+        if chance(settings.failure_rate) {
+            println!("Chaos: failing {}", request.route);
+            return Some(Box::new(Page::new(500, String::from("Injected failure"))));
+        }
+        if chance(settings.delay_rate) {
+            println!("Chaos: delaying {} by {:?}", request.route, settings.delay);
+            // Middleware runs on the connection's own thread, so this only holds up this request
+            std::thread::sleep(settings.delay);
+        }
+        None
+    }
+}
+
+/// Changes the settings of a [`Chaos`] middleware while the server runs
+#[derive(Clone)]
+pub struct ChaosController {
+    settings: Arc<RwLock<ChaosSettings>>,
+}
+
+impl ChaosController {
+    pub fn settings(&self) -> ChaosSettings {
+        *self.settings.read().unwrap()
+    }
+
+    pub fn set(&self, settings: ChaosSettings) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    /// Changes the settings based on the current ones
+    pub fn update<F: FnOnce(ChaosSettings) -> ChaosSettings>(&self, update: F) {
+        let mut settings = self.settings.write().unwrap();
+        *settings = update(*settings);
+    }
+
+    /// Stops injecting faults
+    pub fn disable(&self) {
+        self.set(ChaosSettings::new());
+    }
+}
+
+/// A response that sends nothing, so the connection is closed without an answer
+struct Dropped;
+
+#[async_trait]
+impl Sendable for Dropped {
+    fn render(&self) -> String {
+        String::new()
+    }
+
+    fn is_delimited(&self) -> bool {
+        false
+    }
+
+    async fn send(&self, _conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// Whether an event with the probability happens
+fn chance(rate: f64) -> bool {
+    if rate <= 0.0 {
+        return false;
+    }
+    let mut bytes = [0; 4];
+    if openssl::rand::rand_bytes(&mut bytes).is_err() {
+        return false;
+    }
+    (u32::from_le_bytes(bytes) as f64) < rate * (u32::MAX as f64 + 1.0)
+}
@@ -0,0 +1,91 @@
+//! Time sources for the server
+//!
+//! Everything in the server that depends on the current time reads it from a [`Clock`],
+//! so tests can swap in a [`MockClock`] and control time without real sleeps.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::clock::{Clock, MockClock};
+//!
+//! let clock = MockClock::new();
+//! let start = clock.now();
+//! clock.advance(Duration::from_secs(30));
+//! assert_eq!(clock.now() - start, Duration::from_secs(30));
+//! ```
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime}
+};
+
+/// A source of the current time
+pub trait Clock: Debug + Send + Sync {
+    /// The current monotonic time, used for timeouts and durations
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time, used for dates sent to clients
+    fn system_time(&self) -> SystemTime;
+}
+
+/// The real time of the system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when it is told to
+///
+/// Clones share the same time, so a test can keep a handle to a clock given to the server.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: Instant,
+    start_system_time: SystemTime,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    /// Creates a clock stopped at the current time
+    pub fn new() -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            start_system_time: SystemTime::now(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    /// Moves the clock forward
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// The time the clock has been moved forward in total
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system_time + self.elapsed()
+    }
+}
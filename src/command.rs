@@ -0,0 +1,399 @@
+//! Routes answered by external programs
+//!
+//! A [`CommandHandler`] runs a program for every request to its route, with the request body on
+//! its standard input, and answers with what it writes to its standard output. It is meant for
+//! quick internal tooling endpoints, like a route that runs a report script, and has to be added
+//! explicitly with [`Webserver::add_command_route`](crate::Webserver::add_command_route).
+//!
+//! The program and its arguments are fixed when the handler is made, so nothing in a request can
+//! change what runs. The program gets only the environment set with [`CommandHandler::with_env`],
+//! plus `REQUEST_METHOD`, `REQUEST_PATH` and `CONTENT_TYPE` for the request. Every run is limited:
+//! - It is killed after a timeout, [`DEFAULT_TIMEOUT`] by default, and the request is answered
+//!   with 504 Gateway Timeout.
+//! - It is killed once it writes more than [`DEFAULT_MAX_OUTPUT`] bytes by default, and the
+//!   request is answered with 502 Bad Gateway, as it is when the program exits with an error.
+//! - At most [`DEFAULT_MAX_CONCURRENT`] runs of a handler happen at once by default. Requests over
+//!   the limit are answered with 503 Service Unavailable instead of waiting.
+//!
+//! Its standard error goes to the server's. Multipart bodies are parsed as they arrive rather
+//! than kept, so the program gets an empty standard input for them.
+//!
+//! ## CGI
+//!
+//! With [`CommandHandler::with_cgi`], the program is run as a CGI/1.1 script, so legacy scripts
+//! and interpreters like `php-cgi` can be served. It gets the CGI variables instead of the three
+//! above: `GATEWAY_INTERFACE`, `SERVER_PROTOCOL`, `SERVER_SOFTWARE`, `SERVER_NAME`,
+//! `SERVER_PORT`, `REQUEST_METHOD`, `SCRIPT_NAME`, `QUERY_STRING`, `CONTENT_TYPE`,
+//! `CONTENT_LENGTH`, `REMOTE_ADDR`, `REMOTE_PORT`, `HTTPS` on HTTPS connections, and an `HTTP_`
+//! variable for every request header. `QUERY_STRING` is rebuilt from the parsed parameters, in
+//! the order of their names. The `Proxy` header is left out, so a request cannot set the
+//! `HTTP_PROXY` many HTTP clients read their proxy from.
+//!
+//! The output of a script starts with headers, ended by an empty line. `Status` sets the status
+//! of the response, which is 200 OK by default, or 302 Found with a `Location`. The other headers
+//! are sent as they are, except the ones the server frames the response with, and the
+//! `Content-Type` is the handler's if the script sets none. Output without a valid header block
+//! is answered with 502 Bad Gateway. The output is read in full before it is sent, within the
+//! output limit. FastCGI is not supported.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     command::CommandHandler
+//! };
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_command_route("/tools/word-count", CommandHandler::new("wc")
+//!     .with_arg("-w")
+//!     .with_timeout(Duration::from_secs(2))
+//!     .with_max_concurrent(2));
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::{Child, Command},
+    sync::Semaphore
+};
+
+use crate::{
+    request,
+    response::Response,
+    server::{
+        AsyncHandlerFunction,
+        ConnectionType,
+        Page,
+        RequestInfo,
+        Sendable
+    }
+};
+
+/// How long a program may run by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most a program may write to its standard output by default, 1 MiB
+pub const DEFAULT_MAX_OUTPUT: usize = 1024 * 1024;
+
+/// How many runs of a handler may happen at once by default
+pub const DEFAULT_MAX_CONCURRENT: usize = 4;
+
+/// A handler that answers requests with the output of a program
+#[derive(Debug)]
+pub struct CommandHandler {
+    program: PathBuf,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    current_dir: Option<PathBuf>,
+    content_type: String,
+    timeout: Duration,
+    max_output: usize,
+    max_concurrent: usize,
+    running: Arc<Semaphore>,
+    cgi: bool,
+}
+
+impl CommandHandler {
+    /// Creates a handler that runs a program, found on the server's `PATH` unless it is a path
+    pub fn new<P: AsRef<Path>>(program: P) -> CommandHandler {
+        CommandHandler {
+            program: program.as_ref().to_path_buf(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            current_dir: None,
+            content_type: String::from("text/plain; charset=utf-8"),
+            timeout: DEFAULT_TIMEOUT,
+            max_output: DEFAULT_MAX_OUTPUT,
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            running: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT)),
+            cgi: false,
+        }
+    }
+
+    /// Adds an argument to the program
+    pub fn with_arg(mut self, arg: &str) -> CommandHandler {
+        self.args.push(String::from(arg));
+        self
+    }
+
+    /// Adds a variable to the environment of the program, which is otherwise empty
+    pub fn with_env(mut self, name: &str, value: &str) -> CommandHandler {
+        self.envs.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Sets the directory the program runs in, the server's own by default
+    pub fn with_current_dir<P: AsRef<Path>>(mut self, dir: P) -> CommandHandler {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the `Content-Type` of the output, `text/plain; charset=utf-8` by default
+    pub fn with_content_type(mut self, content_type: &str) -> CommandHandler {
+        self.content_type = String::from(content_type);
+        self
+    }
+
+    /// Sets how long the program may run before it is killed
+    pub fn with_timeout(mut self, timeout: Duration) -> CommandHandler {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many bytes the program may write before it is killed
+    pub fn with_max_output(mut self, max_output: usize) -> CommandHandler {
+        self.max_output = max_output;
+        self
+    }
+
+    /// Sets how many runs may happen at once
+    ///
+    /// # Panics
+    /// If `max_concurrent` is 0.
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> CommandHandler {
+        assert!(max_concurrent > 0, "A command needs to be able to run at least once at a time");
+        self.max_concurrent = max_concurrent;
+        self.running = Arc::new(Semaphore::new(max_concurrent));
+        self
+    }
+
+    /// Runs the program as a CGI/1.1 script, see [CGI](self#cgi)
+    pub fn with_cgi(mut self) -> CommandHandler {
+        self.cgi = true;
+        self
+    }
+
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn max_output(&self) -> usize {
+        self.max_output
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    pub fn is_cgi(&self) -> bool {
+        self.cgi
+    }
+
+    /// How many runs are happening now
+    pub fn running(&self) -> usize {
+        self.max_concurrent - self.running.available_permits()
+    }
+
+    /// Runs the program for a request and turns its output into a response
+    async fn respond(&self, request: &RequestInfo<'_>) -> Box<dyn Sendable> {
+        let _permit = match self.running.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                return Box::new(Response::new(503)
+                    .header("Retry-After", "1")
+                    .text("Too many requests are running this command."));
+            },
+        };
+        let mut command = Command::new(&self.program);
+        command.args(&self.args)
+            .env_clear()
+            .envs(self.envs.iter().map(|(name, value)| (name, value)));
+        if self.cgi {
+            command.envs(cgi_environment(request));
+        } else {
+            command.env("REQUEST_METHOD", request.method().to_string())
+                .env("REQUEST_PATH", request.route)
+                .env("CONTENT_TYPE", request.header("content-type").unwrap_or_default());
+        }
+        command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true);
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                println!("Could not run {}: {}", self.program.display(), e);
+                return Box::new(Page::new(500, String::from("Internal Server Error")));
+            },
+        };
+        let run = tokio::time::timeout(self.timeout, run(&mut child, request.body(), self.max_output)).await;
+        let outcome = match run {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(e)) => {
+                println!("Could not run {}: {}", self.program.display(), e);
+                return Box::new(Page::new(500, String::from("Internal Server Error")));
+            },
+            Err(_) => {
+                println!("{} timed out after {:?} and was killed", self.program.display(), self.timeout);
+                let _ = child.kill().await;
+                return Box::new(Page::new(504, String::from("Gateway Timeout")));
+            },
+        };
+        match outcome {
+            Outcome::Exited(status, output) if status.success() && self.cgi => {
+                match cgi_response(&output, &self.content_type) {
+                    Some(response) => Box::new(response),
+                    None => {
+                        println!("{} did not start its output with CGI headers", self.program.display());
+                        Box::new(Page::new(502, String::from("Bad Gateway")))
+                    },
+                }
+            },
+            Outcome::Exited(status, output) if status.success() => {
+                Box::new(Response::new(200).header("Content-Type", &self.content_type).bytes(output))
+            },
+            Outcome::Exited(status, _) => {
+                println!("{} failed: {}", self.program.display(), status);
+                Box::new(Page::new(502, String::from("Bad Gateway")))
+            },
+            Outcome::TooLarge => {
+                println!("{} wrote more than {} bytes and was killed", self.program.display(), self.max_output);
+                let _ = child.kill().await;
+                Box::new(Page::new(502, String::from("Bad Gateway")))
+            },
+        }
+    }
+}
+
+/// How a run ended
+enum Outcome {
+    Exited(ExitStatus, Vec<u8>),
+    TooLarge,
+}
+
+/// Feeds the body to a program and reads its output, up to one byte over the limit
+async fn run(child: &mut Child, body: &[u8], max_output: usize) -> std::io::Result<Outcome> {
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take().expect("The output of the command is piped");
+    let write = async {
+        if let Some(mut stdin) = stdin {
+            // A program that exits without reading its input closes the pipe, which is fine
+            let _ = stdin.write_all(body).await;
+        }
+    };
+    let mut output = Vec::new();
+    let mut limited = stdout.take(max_output as u64 + 1);
+    let read = limited.read_to_end(&mut output);
+    let (_, read) = tokio::join!(write, read);
+    read?;
+    if output.len() > max_output {
+        return Ok(Outcome::TooLarge);
+    }
+    Ok(Outcome::Exited(child.wait().await?, output))
+}
+
+/// The CGI/1.1 variables for a request
+fn cgi_environment(request: &RequestInfo<'_>) -> Vec<(String, String)> {
+    let https = matches!(request.conn.connection_type(), ConnectionType::Https);
+    let host = request.header("host").unwrap_or_default();
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        // Not the colons of an IPv6 address without a port
+        Some((name, port)) if !port.contains(']') => (name, port),
+        _ => (host, if https { "443" } else { "80" }),
+    };
+    let mut parameters: Vec<_> = request.query().iter().collect();
+    parameters.sort();
+    let query = parameters.iter()
+        .map(|(name, value)| format!("{}={}", urlencoding::encode(name), urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let mut environment = vec![
+        (String::from("GATEWAY_INTERFACE"), String::from("CGI/1.1")),
+        (String::from("SERVER_PROTOCOL"), String::from("HTTP/1.1")),
+        (String::from("SERVER_SOFTWARE"), format!("simpleserve/{}", env!("CARGO_PKG_VERSION"))),
+        (String::from("SERVER_NAME"), String::from(server_name)),
+        (String::from("SERVER_PORT"), String::from(server_port)),
+        (String::from("REQUEST_METHOD"), request.method().to_string()),
+        (String::from("SCRIPT_NAME"), String::from(request.route)),
+        (String::from("QUERY_STRING"), query),
+    ];
+    if let Some(content_type) = request.header("content-type") {
+        environment.push((String::from("CONTENT_TYPE"), String::from(content_type)));
+    }
+    if !request.body().is_empty() {
+        environment.push((String::from("CONTENT_LENGTH"), request.body().len().to_string()));
+    }
+    if let Some(peer_addr) = request.conn.peer_addr() {
+        environment.push((String::from("REMOTE_ADDR"), peer_addr.ip().to_string()));
+        environment.push((String::from("REMOTE_PORT"), peer_addr.port().to_string()));
+    }
+    if https {
+        environment.push((String::from("HTTPS"), String::from("on")));
+    }
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for (name, value) in request.headers().iter() {
+        // Content-Type and Content-Length have variables of their own, and Proxy would set HTTP_PROXY
+        if ["content-type", "content-length", "proxy"].iter().any(|skipped| name.eq_ignore_ascii_case(skipped)) {
+            continue;
+        }
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match headers.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            },
+            None => headers.push((name, String::from(value))),
+        }
+    }
+    environment.extend(headers);
+    environment
+}
+
+/// Turns the output of a CGI script into a response, or `None` if it has no valid header block
+fn cgi_response(output: &[u8], content_type: &str) -> Option<Response> {
+    // The header block ends with an empty line, with or without carriage returns
+    let end = (0..output.len()).find(|&i| {
+        output[i] == b'\n' && (output[i + 1..].starts_with(b"\n") || output[i + 1..].starts_with(b"\r\n"))
+    })?;
+    let body = &output[end + if output[end + 1] == b'\n' { 2 } else { 3 }..];
+    let mut status = None;
+    let mut headers = Vec::new();
+    for line in std::str::from_utf8(&output[..end]).ok()?.lines() {
+        let (name, value) = line.split_once(':')?;
+        let (name, value) = (name.trim(), value.trim());
+        if !request::is_token(name) {
+            return None;
+        }
+        if name.eq_ignore_ascii_case("status") {
+            let code = value.split_whitespace().next()?.parse::<u16>().ok().filter(|code| (200..600).contains(code))?;
+            status = Some(code);
+        } else if !["content-length", "transfer-encoding", "connection"].iter().any(|framing| name.eq_ignore_ascii_case(framing)) {
+            headers.push((name, value));
+        }
+    }
+    let redirect = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("location"));
+    let mut response = Response::new(status.unwrap_or(if redirect { 302 } else { 200 }));
+    if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type")) {
+        response = response.header("Content-Type", content_type);
+    }
+    for (name, value) in headers {
+        response = response.header(name, value);
+    }
+    Some(response.bytes(body.to_vec()))
+}
+
+/// The handler for a command route
+pub(crate) fn handler(command: Arc<CommandHandler>) -> AsyncHandlerFunction {
+    Arc::new(move |request| {
+        let command = command.clone();
+        Box::pin(async move { command.respond(request).await })
+    })
+}
@@ -0,0 +1,581 @@
+//! Response compression
+//!
+//! The [`Compression`] middleware compresses response bodies with gzip or deflate when the client
+//! accepts it in `Accept-Encoding`, and sets `Content-Encoding` so the client knows to decompress.
+//! Brotli, which compresses text better than gzip, is available with the `brotli` feature.
+//!
+//! A [`CompressionConfig`] decides which responses are worth compressing: small bodies are sent as
+//! they are, since compressing them saves little and can even grow them, and so are types like
+//! images that are already compressed. It applies the same way to [`Page`](crate::Page),
+//! [`Bytes`](crate::Bytes) and [`Response`](crate::response::Response) bodies. Responses that are streamed, or that already
+//! have a `Content-Encoding`, are left alone. A strong `ETag` is made weak on a compressed
+//! response, since its bytes are no longer those the tag was made for.
+//!
+//! The middleware runs its `after` hook in [`Phase::PreSend`], after every middleware that changes
+//! response bodies in [`Phase::PostHandler`], so it compresses the body that is really sent.
+//!
+//! Each type can have its own minimum size and compression level with [`TypeSettings`], to spend
+//! more time on types that are sent often and compress well.
+//!
+//! Compressing costs CPU time that a busy server may not have. With [`LoadThresholds`], the
+//! middleware compresses at a lower level, or not at all, while connections are queued for the
+//! thread pool or the process uses much of the CPU, trading bandwidth for latency. The responses
+//! it compressed less or skipped are counted in
+//! [`ServerStats::compressions_reduced`](crate::ServerStats::compressions_reduced) and
+//! [`ServerStats::compressions_skipped`](crate::ServerStats::compressions_skipped).
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     compression::{Compression, CompressionConfig, LoadThresholds, TypeSettings}
+//! };
+//!
+//! let config = CompressionConfig::new()
+//!     .with_min_size(512)
+//!     .with_mime_type("application/wasm")
+//!     .with_type_settings("application/json", TypeSettings::new().with_level(6).with_min_size(256));
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_middleware(Compression::with_config(config)
+//!     .with_load_thresholds(LoadThresholds::new().with_queue_depth(4, 16).with_cpu_usage(0.7, 0.9)));
+//! ```
+
+use std::{
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant}
+};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::{
+    middleware::{Middleware, Phase},
+    server::{
+        RequestInfo,
+        Sendable
+    },
+    utils
+};
+
+/// Bodies smaller than this are not compressed by default, 1 KiB
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// The types compressed by default, an entry ending in `/` matches every subtype
+pub const DEFAULT_MIME_TYPES: [&str; 5] = [
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// The highest compression level, which compresses the most and the slowest
+pub const MAX_LEVEL: u32 = 9;
+
+/// Brotli quality for responses compressed on the fly without a level, out of 11
+///
+/// The highest qualities are far too slow to run on every request.
+#[cfg(feature = "brotli")]
+const BROTLI_QUALITY: u32 = 5;
+
+/// Brotli window size, as a power of two
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW: u32 = 22;
+
+/// A content coding a response body can be compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    /// Enabled with the `brotli` feature
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Encoding {
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// Compresses the bytes with the encoding, at its default level
+    pub fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        self.encode_with_level(bytes, None)
+    }
+
+    /// Compresses the bytes with the encoding, at a level from 0 to [`MAX_LEVEL`]
+    ///
+    /// Brotli uses the level as its quality, so its slowest qualities above 9 are never used.
+    pub fn encode_with_level(&self, bytes: &[u8], level: Option<u32>) -> Result<Vec<u8>, std::io::Error> {
+        let level = level.map(|level| level.min(MAX_LEVEL));
+        let flate_level = level.map_or_else(flate2::Compression::default, flate2::Compression::new);
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate_level);
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            },
+            // The deflate content coding is the zlib format, not a raw deflate stream
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate_level);
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            },
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => {
+                let quality = level.unwrap_or(BROTLI_QUALITY);
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, quality, BROTLI_WINDOW);
+                encoder.write_all(bytes)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+}
+
+/// The encoding the client prefers out of the ones in `supported`, based on `Accept-Encoding`
+///
+/// The highest quality wins, and ties go to the encoding listed first in `supported`.
+/// An encoding with a quality of 0 is never chosen, and `*` stands for every encoding not named.
+///
+/// # Examples
+/// ```
+/// use simpleserve::compression::{negotiate, Encoding};
+///
+/// let supported = [Encoding::Gzip, Encoding::Deflate];
+/// assert_eq!(negotiate("deflate, gzip;q=0.5", &supported), Some(Encoding::Deflate));
+/// assert_eq!(negotiate("*, gzip;q=0", &supported), Some(Encoding::Deflate));
+/// assert_eq!(negotiate("identity", &supported), None);
+/// ```
+pub fn negotiate(accept_encoding: &str, supported: &[Encoding]) -> Option<Encoding> {
+    let preferences = utils::parse_quality_list(accept_encoding);
+    let quality_of = |encoding: &Encoding| {
+        preferences.iter()
+            .find(|(name, _)| name == encoding.as_str())
+            .or_else(|| preferences.iter().find(|(name, _)| name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in supported {
+        let quality = quality_of(encoding);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((*encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// How bodies of a type are compressed
+///
+/// Anything not set falls back to the [`CompressionConfig`] and the encoding's own defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TypeSettings {
+    min_size: Option<usize>,
+    level: Option<u32>,
+}
+
+impl TypeSettings {
+    pub fn new() -> TypeSettings {
+        TypeSettings::default()
+    }
+
+    /// Sets the smallest body of the type that is compressed
+    pub fn with_min_size(mut self, min_size: usize) -> TypeSettings {
+        self.min_size = Some(min_size);
+        self
+    }
+
+    /// Sets the compression level, from 0 to [`MAX_LEVEL`]
+    pub fn with_level(mut self, level: u32) -> TypeSettings {
+        self.level = Some(level.min(MAX_LEVEL));
+        self
+    }
+
+    pub fn min_size(&self) -> Option<usize> {
+        self.min_size
+    }
+
+    pub fn level(&self) -> Option<u32> {
+        self.level
+    }
+}
+
+/// Which responses are compressed, and how
+///
+/// Only the listed types are compressed, so types that are already compressed, like images,
+/// are skipped. When several entries match a type, an exact one wins over one ending in `/`.
+///
+/// # Examples
+/// ```
+/// use simpleserve::compression::{CompressionConfig, TypeSettings};
+///
+/// let config = CompressionConfig::new()
+///     .with_mime_type("application/wasm")
+///     .with_type_settings("application/json", TypeSettings::new().with_level(6).with_min_size(256));
+/// assert!(config.compresses(Some("text/css; charset=utf-8"), 2048));
+/// assert!(config.compresses(Some("application/wasm"), 2048));
+/// assert!(config.compresses(Some("application/json"), 300));
+/// assert_eq!(config.settings(Some("application/json")).unwrap().level(), Some(6));
+/// assert!(!config.compresses(Some("image/png"), 2048));
+/// assert!(!config.compresses(Some("text/css"), 100));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    min_size: usize,
+    mime_types: Vec<(String, TypeSettings)>,
+}
+
+impl CompressionConfig {
+    /// Compresses the [`DEFAULT_MIME_TYPES`] from [`DEFAULT_MIN_SIZE`] bytes
+    pub fn new() -> CompressionConfig {
+        CompressionConfig {
+            min_size: DEFAULT_MIN_SIZE,
+            mime_types: DEFAULT_MIME_TYPES.iter()
+                .map(|mime_type| (String::from(*mime_type), TypeSettings::new()))
+                .collect(),
+        }
+    }
+
+    /// Sets the smallest body that is compressed, for types without a size of their own
+    pub fn with_min_size(mut self, min_size: usize) -> CompressionConfig {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Compresses another type, or every subtype if it ends in `/`
+    pub fn with_mime_type(self, mime_type: &str) -> CompressionConfig {
+        self.with_type_settings(mime_type, TypeSettings::new())
+    }
+
+    /// Compresses a type with settings of its own, replacing any earlier settings for it
+    pub fn with_type_settings(mut self, mime_type: &str, settings: TypeSettings) -> CompressionConfig {
+        let mime_type = mime_type.to_ascii_lowercase();
+        self.mime_types.retain(|(existing, _)| *existing != mime_type);
+        self.mime_types.push((mime_type, settings));
+        self
+    }
+
+    /// Stops compressing a type
+    pub fn without_mime_type(mut self, mime_type: &str) -> CompressionConfig {
+        self.mime_types.retain(|(existing, _)| !existing.eq_ignore_ascii_case(mime_type));
+        self
+    }
+
+    /// Replaces the types that are compressed
+    pub fn with_mime_types(mut self, mime_types: &[&str]) -> CompressionConfig {
+        self.mime_types = mime_types.iter()
+            .map(|mime_type| (mime_type.to_ascii_lowercase(), TypeSettings::new()))
+            .collect();
+        self
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    /// The types that are compressed
+    pub fn mime_types(&self) -> impl Iterator<Item = &str> {
+        self.mime_types.iter().map(|(mime_type, _)| mime_type.as_str())
+    }
+
+    /// The settings for a type, or `None` if it is not compressed
+    ///
+    /// A body without a `Content-Type`, like a [`Page`](crate::Page), is taken to be `text/html`.
+    pub fn settings(&self, content_type: Option<&str>) -> Option<TypeSettings> {
+        let content_type = content_type
+            .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+            .unwrap_or_else(|| String::from("text/html"));
+        let exact = self.mime_types.iter().find(|(mime_type, _)| *mime_type == content_type);
+        let prefix = || self.mime_types.iter()
+            .filter(|(mime_type, _)| mime_type.ends_with('/') && content_type.starts_with(mime_type.as_str()))
+            .max_by_key(|(mime_type, _)| mime_type.len());
+        exact.or_else(prefix).map(|(_, settings)| *settings)
+    }
+
+    /// Whether a body of the type and size is compressed
+    pub fn compresses(&self, content_type: Option<&str>, size: usize) -> bool {
+        self.settings(content_type)
+            .is_some_and(|settings| size >= settings.min_size.unwrap_or(self.min_size))
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig::new()
+    }
+}
+
+/// The compression level used under load by default, the fastest that still compresses
+pub const DEFAULT_REDUCED_LEVEL: u32 = 1;
+
+/// How often the CPU usage of the process is measured, at most
+const CPU_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How a response is compressed, given the load of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadDecision {
+    /// At the configured level
+    Full,
+    /// At the reduced level of the [`LoadThresholds`]
+    Reduced,
+    /// Not at all
+    Skipped,
+}
+
+/// When compression backs off because the server is busy
+///
+/// The queue depth is the number of accepted connections waiting for a worker of the thread
+/// pool, and the CPU usage is the share of every core's time the process used recently, from 0
+/// to 1. Reaching either threshold is enough. Thresholds that are not set are never reached.
+///
+/// # Examples
+/// ```
+/// use simpleserve::compression::{LoadDecision, LoadThresholds};
+///
+/// let thresholds = LoadThresholds::new().with_queue_depth(4, 16).with_cpu_usage(0.7, 0.9);
+/// assert_eq!(thresholds.decide(0, 0.2), LoadDecision::Full);
+/// assert_eq!(thresholds.decide(4, 0.2), LoadDecision::Reduced);
+/// assert_eq!(thresholds.decide(0, 0.95), LoadDecision::Skipped);
+/// assert_eq!(thresholds.decide(20, 0.0), LoadDecision::Skipped);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadThresholds {
+    queue_depth: Option<(usize, usize)>,
+    cpu_usage: Option<(f64, f64)>,
+    reduced_level: u32,
+}
+
+impl LoadThresholds {
+    /// Creates thresholds that are never reached, with a reduced level of [`DEFAULT_REDUCED_LEVEL`]
+    pub fn new() -> LoadThresholds {
+        LoadThresholds {
+            queue_depth: None,
+            cpu_usage: None,
+            reduced_level: DEFAULT_REDUCED_LEVEL,
+        }
+    }
+
+    /// Reduces the level from `reduce_at` queued connections, and skips compression from `skip_at`
+    pub fn with_queue_depth(mut self, reduce_at: usize, skip_at: usize) -> LoadThresholds {
+        self.queue_depth = Some((reduce_at, skip_at));
+        self
+    }
+
+    /// Reduces the level from a CPU usage of `reduce_at`, and skips compression from `skip_at`
+    ///
+    /// The usage is measured every 250 milliseconds at most, and only when this is set.
+    pub fn with_cpu_usage(mut self, reduce_at: f64, skip_at: f64) -> LoadThresholds {
+        self.cpu_usage = Some((reduce_at, skip_at));
+        self
+    }
+
+    /// Sets the level used under load, from 0 to [`MAX_LEVEL`]
+    ///
+    /// A type with a lower level of its own keeps it.
+    pub fn with_reduced_level(mut self, level: u32) -> LoadThresholds {
+        self.reduced_level = level.min(MAX_LEVEL);
+        self
+    }
+
+    pub fn queue_depth(&self) -> Option<(usize, usize)> {
+        self.queue_depth
+    }
+
+    pub fn cpu_usage(&self) -> Option<(f64, f64)> {
+        self.cpu_usage
+    }
+
+    pub fn reduced_level(&self) -> u32 {
+        self.reduced_level
+    }
+
+    /// How to compress at a queue depth and CPU usage
+    pub fn decide(&self, queue_depth: usize, cpu_usage: f64) -> LoadDecision {
+        let skip = self.queue_depth.is_some_and(|(_, skip_at)| queue_depth >= skip_at)
+            || self.cpu_usage.is_some_and(|(_, skip_at)| cpu_usage >= skip_at);
+        let reduce = self.queue_depth.is_some_and(|(reduce_at, _)| queue_depth >= reduce_at)
+            || self.cpu_usage.is_some_and(|(reduce_at, _)| cpu_usage >= reduce_at);
+        match (skip, reduce) {
+            (true, _) => LoadDecision::Skipped,
+            (false, true) => LoadDecision::Reduced,
+            (false, false) => LoadDecision::Full,
+        }
+    }
+}
+
+impl Default for LoadThresholds {
+    fn default() -> LoadThresholds {
+        LoadThresholds::new()
+    }
+}
+
+/// The CPU usage of the process, measured at most every [`CPU_SAMPLE_INTERVAL`]
+#[derive(Debug)]
+struct CpuUsage {
+    // When it was last measured, the CPU time used by then, and the usage since the time before
+    sample: Mutex<(Instant, Duration, f64)>,
+}
+
+impl CpuUsage {
+    fn new() -> CpuUsage {
+        CpuUsage {
+            sample: Mutex::new((Instant::now(), process_cpu_time(), 0.0)),
+        }
+    }
+
+    fn current(&self) -> f64 {
+        let mut sample = self.sample.lock().unwrap();
+        let (at, cpu_time, usage) = *sample;
+        let elapsed = at.elapsed();
+        if elapsed < CPU_SAMPLE_INTERVAL {
+            return usage;
+        }
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let now = process_cpu_time();
+        let usage = now.saturating_sub(cpu_time).as_secs_f64() / (elapsed.as_secs_f64() * cores as f64);
+        *sample = (Instant::now(), now, usage);
+        usage
+    }
+}
+
+/// The user and system CPU time the process has used
+fn process_cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return Duration::ZERO;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let time = |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Middleware that compresses response bodies the client accepts compressed
+pub struct Compression {
+    config: CompressionConfig,
+    encodings: Vec<Encoding>,
+    load_thresholds: Option<LoadThresholds>,
+    cpu_usage: CpuUsage,
+}
+
+impl Compression {
+    /// Compresses with the default [`CompressionConfig`]
+    ///
+    /// Brotli is preferred when the `brotli` feature is enabled, then gzip, then deflate.
+    pub fn new() -> Compression {
+        Compression::with_config(CompressionConfig::new())
+    }
+
+    pub fn with_config(config: CompressionConfig) -> Compression {
+        Compression {
+            config,
+            encodings: vec![
+                #[cfg(feature = "brotli")]
+                Encoding::Brotli,
+                Encoding::Gzip,
+                Encoding::Deflate,
+            ],
+            load_thresholds: None,
+            cpu_usage: CpuUsage::new(),
+        }
+    }
+
+    /// Sets the smallest body that is compressed
+    ///
+    /// Defaults to [`DEFAULT_MIN_SIZE`].
+    pub fn with_min_size(mut self, min_size: usize) -> Compression {
+        self.config = self.config.with_min_size(min_size);
+        self
+    }
+
+    /// Sets the encodings to use, in order of preference
+    pub fn with_encodings(mut self, encodings: &[Encoding]) -> Compression {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Compresses less, or not at all, while the server is busy
+    ///
+    /// See [`LoadThresholds`].
+    pub fn with_load_thresholds(mut self, thresholds: LoadThresholds) -> Compression {
+        self.load_thresholds = Some(thresholds);
+        self
+    }
+
+    pub fn config(&self) -> &CompressionConfig {
+        &self.config
+    }
+
+    pub fn load_thresholds(&self) -> Option<&LoadThresholds> {
+        self.load_thresholds.as_ref()
+    }
+
+    pub fn encodings(&self) -> &[Encoding] {
+        &self.encodings
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn after_phase(&self) -> Phase {
+        Phase::PreSend
+    }
+
+    fn after(&self, request: &RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+        let accept_encoding = request.header("accept-encoding").unwrap_or_default();
+        let encoding = match negotiate(accept_encoding, &self.encodings) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+        let original = match response.to_response() {
+            Some(original) => original,
+            None => return response,
+        };
+        let status = original.status();
+        let content_type = original.headers().get("content-type");
+        if !self.config.compresses(content_type, original.body().len())
+            || original.headers().contains("content-encoding")
+            // A compressed part of a body could not be put back together with the other parts
+            || original.headers().contains("content-range")
+            || status == 204
+            || status == 304
+        {
+            return response;
+        }
+        let mut level = self.config.settings(content_type).and_then(|settings| settings.level());
+        if let Some(thresholds) = &self.load_thresholds {
+            let cpu_usage = if thresholds.cpu_usage.is_some() { self.cpu_usage.current() } else { 0.0 };
+            match thresholds.decide(request.stats.connections_queued(), cpu_usage) {
+                LoadDecision::Full => {},
+                LoadDecision::Reduced => {
+                    request.stats.compression_reduced();
+                    level = Some(level.map_or(thresholds.reduced_level, |level| level.min(thresholds.reduced_level)));
+                },
+                LoadDecision::Skipped => {
+                    request.stats.compression_skipped();
+                    return response;
+                },
+            }
+        }
+        let body = match encoding.encode_with_level(original.body(), level) {
+            Ok(body) => body,
+            Err(e) => {
+                println!("Could not compress the response to {}: {}", request.route, e);
+                return response;
+            }
+        };
+        Box::new(original.with_new_body(body)
+            .header("Content-Encoding", encoding.as_str())
+            .header("Vary", "Accept-Encoding"))
+    }
+}
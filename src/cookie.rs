@@ -0,0 +1,222 @@
+//! Cookies
+//!
+//! Handlers read the cookies of a request with [`RequestInfo::cookies`](crate::RequestInfo::cookies)
+//! and set cookies with [`Response::cookie`](crate::Response::cookie).
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Response,
+//!     cookie::{Cookie, SameSite}
+//! };
+//!
+//! let session = Cookie::new("session", "abc123")
+//!     .with_path("/")
+//!     .with_max_age(Duration::from_secs(3600))
+//!     .secure()
+//!     .http_only()
+//!     .with_same_site(SameSite::Lax);
+//! assert_eq!(session.to_string(), "session=abc123; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Lax");
+//!
+//! let response = Response::new(200).cookie(&session);
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime}
+};
+
+use crate::utils::format_http_date;
+
+/// Whether a cookie is sent with cross-site requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let same_site = match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+        write!(f, "{}", same_site)
+    }
+}
+
+/// A cookie, either received from a client or to be set on one
+///
+/// Cookies parsed from a request only have a name and a value, since clients do not send the attributes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    expires: Option<SystemTime>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: String::from(name),
+            value: String::from(value),
+            path: None,
+            domain: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the client remove the cookie with this name
+    pub fn removal(name: &str) -> Cookie {
+        Cookie::new(name, "").with_max_age(Duration::ZERO)
+    }
+
+    pub fn with_path(mut self, path: &str) -> Cookie {
+        self.path = Some(String::from(path));
+        self
+    }
+
+    pub fn with_domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(String::from(domain));
+        self
+    }
+
+    /// Sets when the client removes the cookie, for clients that do not support `Max-Age`
+    pub fn with_expires(mut self, expires: SystemTime) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Sets how long the client keeps the cookie, in whole seconds
+    pub fn with_max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Only sends the cookie over HTTPS
+    pub fn secure(mut self) -> Cookie {
+        self.secure = true;
+        self
+    }
+
+    /// Hides the cookie from scripts in the browser
+    pub fn http_only(mut self) -> Cookie {
+        self.http_only = true;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn is_http_only(&self) -> bool {
+        self.http_only
+    }
+
+    pub fn same_site(&self) -> Option<SameSite> {
+        self.same_site
+    }
+}
+
+impl fmt::Display for Cookie {
+    /// Formats the cookie as the value of a `Set-Cookie` header
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", format_http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the value of a `Cookie` header
+///
+/// Pairs without a `=` or with an empty name are skipped. Quotes around a value are removed.
+///
+/// # Examples
+/// ```
+/// use simpleserve::cookie::parse_cookies;
+///
+/// let cookies = parse_cookies("session=abc123; theme=\"dark\"; broken");
+/// assert_eq!(cookies.len(), 2);
+/// assert_eq!(cookies[0].name(), "session");
+/// assert_eq!(cookies[1].value(), "dark");
+/// ```
+pub fn parse_cookies(header: &str) -> Vec<Cookie> {
+    header
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            Some(Cookie::new(name, value))
+        })
+        .collect()
+}
@@ -26,3 +26,212 @@ impl Display for OptionUnwrapError {
 }
 impl Error for OptionUnwrapError {}
 
+
+/// An error that occurs when a request cannot be parsed
+/// 
+/// The server responds to these with 400 Bad Request.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::request::parse_head;
+/// 
+/// let error = parse_head("GET / HTTP/1.1\r\nNot a header").unwrap_err();
+/// assert_eq!(error.reason(), "Invalid header line: Not a header");
+/// ```
+#[derive(Debug, Clone)]
+pub struct MalformedRequestError {
+    reason: String,
+}
+
+impl MalformedRequestError {
+    pub fn new(reason: &str) -> MalformedRequestError {
+        MalformedRequestError {
+            reason: String::from(reason),
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl Display for MalformedRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Malformed request: {}", self.reason)
+    }
+}
+impl Error for MalformedRequestError {}
+
+/// An error for a part of a request that is larger than the server allows
+/// 
+/// The server responds with the status of [`RequestTooLargeError::status`] and closes the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestTooLargeError {
+    /// The request line is longer than the limit, answered with 414 URI Too Long
+    RequestLine,
+    /// The headers are larger than the limit, or there are too many of them,
+    /// answered with 431 Request Header Fields Too Large
+    Headers,
+    /// The body is larger than the limit, answered with 413 Payload Too Large
+    Body,
+}
+
+impl RequestTooLargeError {
+    /// The status code the server responds with
+    pub fn status(&self) -> u16 {
+        match self {
+            RequestTooLargeError::RequestLine => 414,
+            RequestTooLargeError::Headers => 431,
+            RequestTooLargeError::Body => 413,
+        }
+    }
+}
+
+impl Display for RequestTooLargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestTooLargeError::RequestLine => write!(f, "Request line is too long"),
+            RequestTooLargeError::Headers => write!(f, "Request headers are too large"),
+            RequestTooLargeError::Body => write!(f, "Request body is too large"),
+        }
+    }
+}
+impl Error for RequestTooLargeError {}
+
+/// An error for a request body of a type the route does not accept
+/// 
+/// The server responds with 415 Unsupported Media Type.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::errors::UnsupportedMediaTypeError;
+/// 
+/// let error = UnsupportedMediaTypeError::new(Some("text/plain"));
+/// assert_eq!(error.to_string(), "Unsupported media type: text/plain");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedMediaTypeError {
+    content_type: Option<String>,
+}
+
+impl UnsupportedMediaTypeError {
+    pub fn new(content_type: Option<&str>) -> UnsupportedMediaTypeError {
+        UnsupportedMediaTypeError {
+            content_type: content_type.map(String::from),
+        }
+    }
+
+    /// The `Content-Type` of the request, if it had one
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+impl Display for UnsupportedMediaTypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported media type: {}", self.content_type.as_deref().unwrap_or("none"))
+    }
+}
+impl Error for UnsupportedMediaTypeError {}
+
+/// Why an upload was refused by an [`UploadGuard`](crate::upload_guard::UploadGuard)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadRejection {
+    /// The content does not match the declared `Content-Type`
+    Mismatch,
+    /// The content could run scripts when it is served back, like HTML or SVG
+    Dangerous,
+    /// The type is not one of the allowed types
+    NotAllowed,
+}
+
+/// An error for an upload that was refused
+#[derive(Debug, Clone)]
+pub struct UploadRejectedError {
+    reason: UploadRejection,
+    declared: Option<String>,
+    detected: Option<&'static str>,
+    quarantined: Option<std::path::PathBuf>,
+}
+
+impl UploadRejectedError {
+    pub fn new(reason: UploadRejection, declared: Option<&str>, detected: Option<&'static str>) -> UploadRejectedError {
+        UploadRejectedError {
+            reason,
+            declared: declared.map(String::from),
+            detected,
+            quarantined: None,
+        }
+    }
+
+    /// Records where the upload was kept for inspection
+    pub fn with_quarantined(mut self, path: std::path::PathBuf) -> UploadRejectedError {
+        self.quarantined = Some(path);
+        self
+    }
+
+    pub fn reason(&self) -> UploadRejection {
+        self.reason
+    }
+
+    /// The type the client declared
+    pub fn declared(&self) -> Option<&str> {
+        self.declared.as_deref()
+    }
+
+    /// The type found from the content
+    pub fn detected(&self) -> Option<&'static str> {
+        self.detected
+    }
+
+    /// Where the upload was quarantined, if it was
+    pub fn quarantined(&self) -> Option<&std::path::Path> {
+        self.quarantined.as_deref()
+    }
+}
+
+impl Display for UploadRejectedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let declared = self.declared.as_deref().unwrap_or("none");
+        let detected = self.detected.unwrap_or("unknown");
+        match self.reason {
+            UploadRejection::Mismatch => write!(f, "Upload declared as {} but looks like {}", declared, detected),
+            UploadRejection::Dangerous => write!(f, "Upload of a dangerous type, declared as {} and detected as {}", declared, detected),
+            UploadRejection::NotAllowed => write!(f, "Upload of a type that is not allowed, declared as {} and detected as {}", declared, detected),
+        }
+    }
+}
+impl Error for UploadRejectedError {}
+
+/// An error from a connection pool, such as an unreachable database or a failed transaction
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::errors::PoolError;
+/// 
+/// let error = PoolError::new("connection refused");
+/// assert_eq!(error.to_string(), "Pool error: connection refused");
+/// ```
+#[derive(Debug, Clone)]
+pub struct PoolError {
+    message: String,
+}
+
+impl PoolError {
+    pub fn new(message: &str) -> PoolError {
+        PoolError {
+            message: String::from(message),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pool error: {}", self.message)
+    }
+}
+impl Error for PoolError {}
@@ -0,0 +1,207 @@
+//! Entity tags
+//!
+//! An [`ETag`] names one version of a response, so a client can ask for a response only if it
+//! changed (`If-None-Match`), or change a resource only if nobody else changed it first
+//! (`If-Match`). A strong tag promises the bytes are identical, a weak tag only that the
+//! responses are equivalent. The file handlers make weak tags from the size and modification
+//! time of a file by default, or strong tags from a hash of its content with [`ETagMode::Strong`],
+//! set through [`CachePolicy::with_etag_mode`](crate::cache_policy::CachePolicy::with_etag_mode).
+//!
+//! ## Example
+//! ```
+//! use simpleserve::etag::{if_match, if_none_match, ETag};
+//!
+//! let etag = ETag::for_content(b"Hello World!");
+//! let header = etag.to_string();
+//! assert!(if_none_match(&header, &etag));
+//! assert!(if_match(&header, Some(&etag)));
+//!
+//! // A weak tag is good enough to skip a download, but not to overwrite a file
+//! let weak = ETag::weak(etag.tag());
+//! assert!(if_none_match(&weak.to_string(), &etag));
+//! assert!(!if_match(&weak.to_string(), Some(&etag)));
+//! ```
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+/// How the file handlers make ETags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ETagMode {
+    /// Weak tags from the size and modification time, which are cheap but miss a change that
+    /// keeps both, like a file rewritten within the same instant
+    #[default]
+    Weak,
+    /// Strong tags from a hash of the content, which costs a hash of every file sent
+    Strong,
+    /// No ETags at all
+    Disabled,
+}
+
+/// An entity tag, as sent in `ETag`, `If-None-Match` and `If-Match` headers
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ETag {
+    tag: String,
+    weak: bool,
+}
+
+impl ETag {
+    /// Creates a strong tag
+    ///
+    /// # Panics
+    /// If the tag contains a `"`, a space or a control character.
+    pub fn strong(tag: &str) -> ETag {
+        assert!(is_valid_tag(tag), "Invalid ETag {:?}", tag);
+        ETag {
+            tag: String::from(tag),
+            weak: false,
+        }
+    }
+
+    /// Creates a weak tag
+    ///
+    /// # Panics
+    /// If the tag contains a `"`, a space or a control character.
+    pub fn weak(tag: &str) -> ETag {
+        ETag {
+            weak: true,
+            ..ETag::strong(tag)
+        }
+    }
+
+    /// A strong tag from a SHA-256 hash of the content
+    pub fn for_content(content: &[u8]) -> ETag {
+        let hash = openssl::sha::sha256(content);
+        ETag::strong(&hash[..16].iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+    }
+
+    /// A weak tag from the size and modification time of a file
+    pub fn for_metadata(size: usize, modified: SystemTime) -> ETag {
+        let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        ETag::weak(&format!("{:x}-{:x}", size, modified.as_nanos()))
+    }
+
+    /// Parses a tag, with its quotes and an optional `W/` prefix
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::etag::ETag;
+    ///
+    /// assert_eq!(ETag::parse("W/\"v1\""), Some(ETag::weak("v1")));
+    /// assert_eq!(ETag::parse("\"v1\""), Some(ETag::strong("v1")));
+    /// assert_eq!(ETag::parse("v1"), None);
+    /// ```
+    pub fn parse(value: &str) -> Option<ETag> {
+        let value = value.trim();
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, value),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        if !is_valid_tag(tag) {
+            return None;
+        }
+        Some(ETag {
+            tag: String::from(tag),
+            weak,
+        })
+    }
+
+    /// The tag, without quotes or a `W/` prefix
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// Strong comparison: both tags are strong and the same
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison: the tags are the same, whether weak or not
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for ETag {
+    /// Formats the tag as it is sent in a header
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Whether an `If-None-Match` header matches a tag, so a `GET` can be answered with 304
+///
+/// Uses weak comparison, so a weak tag the client has cached still matches.
+pub fn if_none_match(header: &str, etag: &ETag) -> bool {
+    match parse_list(header) {
+        List::Any => true,
+        List::Tags(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+    }
+}
+
+/// Whether an `If-Match` header allows a change to a resource with a tag
+///
+/// Uses strong comparison, since a weak tag cannot tell whether the exact bytes the client saw
+/// are the ones it is about to overwrite. `*` matches any resource that exists, so a missing tag
+/// passes only when nothing is required of it.
+///
+/// # Examples
+/// ```
+/// use simpleserve::etag::{if_match, ETag};
+///
+/// let etag = ETag::strong("v2");
+/// assert!(if_match("\"v1\", \"v2\"", Some(&etag)));
+/// assert!(!if_match("\"v1\"", Some(&etag)));
+/// assert!(if_match("*", Some(&etag)));
+/// assert!(!if_match("*", None));
+/// ```
+pub fn if_match(header: &str, etag: Option<&ETag>) -> bool {
+    match (parse_list(header), etag) {
+        (List::Any, etag) => etag.is_some(),
+        (List::Tags(tags), Some(etag)) => tags.iter().any(|tag| tag.strong_eq(etag)),
+        (List::Tags(_), None) => false,
+    }
+}
+
+/// The tags of an `If-None-Match` or `If-Match` header
+enum List {
+    Any,
+    Tags(Vec<ETag>),
+}
+
+/// Parses a list of tags, skipping any that are malformed
+fn parse_list(header: &str) -> List {
+    if header.trim() == "*" {
+        return List::Any;
+    }
+    // Tags may contain commas, so the list is split on commas outside of quotes only
+    let mut tags = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in header.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                tags.extend(ETag::parse(&header[start..i]));
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    tags.extend(ETag::parse(&header[start..]));
+    List::Tags(tags)
+}
+
+fn is_valid_tag(tag: &str) -> bool {
+    tag.bytes().all(|byte| byte == 0x21 || (0x23..=0x7E).contains(&byte) || byte >= 0x80)
+}
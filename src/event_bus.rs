@@ -0,0 +1,197 @@
+//! An event bus inside the server
+//!
+//! The [`EventBus`] of a server, from [`Webserver::events`](crate::Webserver::events), carries
+//! events of any type between handlers, middleware, WebSocket handlers and tasks running next to
+//! the server. Events are routed by their type: every [`Subscription`] to a type gets each event
+//! of that type published after it was made, so components only have to agree on a type, not
+//! pass channels to each other.
+//!
+//! Handlers reach the bus with [`RequestInfo::events`](crate::RequestInfo::events). Events can
+//! be forwarded to a [Server-Sent Events channel](crate::sse::Channel) with [`EventBus::bridge`].
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Page,
+//!     Sendable,
+//!     RequestInfo
+//! };
+//!
+//! #[derive(Debug, Clone)]
+//! struct UserSignedUp {
+//!     name: String,
+//! }
+//!
+//! fn sign_up(request: &RequestInfo) -> Box<dyn Sendable> {
+//!     let name = request.query_param("name").unwrap_or("stranger");
+//!     if let Some(events) = request.events() {
+//!         events.publish(UserSignedUp { name: String::from(name) });
+//!     }
+//!     Box::new(Page::new(200, format!("Welcome {}", name)))
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! let mut signups = server.events().subscribe::<UserSignedUp>();
+//! server.add_route("/signup", sign_up);
+//! std::thread::spawn(move || {
+//!     while let Some(signup) = signups.blocking_recv() {
+//!         println!("Sending a welcome mail to {}", signup.name);
+//!     }
+//! });
+//! ```
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, Mutex}
+};
+
+use tokio::{
+    sync::broadcast,
+    task::JoinHandle
+};
+
+use crate::sse;
+
+/// The number of events queued for each subscriber by default
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// Events by type, shared by every part of a server
+///
+/// Cloning is cheap, every clone publishes to the same subscribers. Events are queued for each
+/// subscriber; one that falls more than its capacity behind misses the oldest events.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    capacity: usize,
+    topics: Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>,
+}
+
+impl EventBus {
+    /// Queues up to [`DEFAULT_CAPACITY`] events for each subscriber
+    pub fn new() -> EventBus {
+        EventBus {
+            capacity: DEFAULT_CAPACITY,
+            topics: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Sets how many events are queued for each subscriber
+    ///
+    /// # Panics
+    /// If the capacity is 0.
+    pub fn with_capacity(mut self, capacity: usize) -> EventBus {
+        assert!(capacity > 0, "The capacity of an event bus must be at least 1");
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sends an event to every subscriber of its type, returning how many it was queued for
+    pub fn publish<T: Clone + Send + Sync + 'static>(&self, event: T) -> usize {
+        self.sender::<T>().send(event).unwrap_or(0)
+    }
+
+    /// The events of a type published from now on
+    pub fn subscribe<T: Clone + Send + Sync + 'static>(&self) -> Subscription<T> {
+        Subscription {
+            receiver: self.sender::<T>().subscribe(),
+        }
+    }
+
+    /// The number of subscribers to a type
+    pub fn subscribers<T: Clone + Send + Sync + 'static>(&self) -> usize {
+        self.sender::<T>().receiver_count()
+    }
+
+    /// Forwards the events of a type to a Server-Sent Events channel
+    ///
+    /// Events that `to_event` returns `None` for are not forwarded. The forwarding runs as a task
+    /// until it is aborted through the returned handle.
+    ///
+    /// # Panics
+    /// If called outside of a Tokio runtime.
+    pub fn bridge<T, F>(&self, channel: sse::Channel, to_event: F) -> JoinHandle<()>
+    where
+        T: Clone + Send + Sync + 'static,
+        F: Fn(&T) -> Option<sse::Event> + Send + 'static,
+    {
+        let mut subscription = self.subscribe::<T>();
+        tokio::spawn(async move {
+            while let Some(event) = subscription.recv().await {
+                if let Some(event) = to_event(&event) {
+                    channel.publish(event);
+                }
+            }
+        })
+    }
+
+    /// The sender for a type, created with its first subscriber or event
+    fn sender<T: Clone + Send + Sync + 'static>(&self) -> broadcast::Sender<T> {
+        self.topics
+            .lock()
+            .unwrap()
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(broadcast::channel::<T>(self.capacity).0))
+            .downcast_ref::<broadcast::Sender<T>>()
+            .expect("Topics are keyed by the type of their sender")
+            .clone()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
+
+/// The events of one type, from [`EventBus::subscribe`]
+#[derive(Debug)]
+pub struct Subscription<T> {
+    receiver: broadcast::Receiver<T>,
+}
+
+impl<T: Clone> Subscription<T> {
+    /// Waits for the next event
+    ///
+    /// Returns `None` once the bus is gone. Events missed by falling behind are skipped.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => println!("Subscriber fell behind, missed {} events", missed),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next event, if one is already queued
+    pub fn try_recv(&mut self) -> Option<T> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::TryRecvError::Lagged(missed)) => println!("Subscriber fell behind, missed {} events", missed),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Waits for the next event, blocking the thread
+    ///
+    /// For threads of their own, outside of the runtime the server runs on.
+    ///
+    /// # Panics
+    /// If called from within a Tokio runtime.
+    pub fn blocking_recv(&mut self) -> Option<T> {
+        loop {
+            match self.receiver.blocking_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => println!("Subscriber fell behind, missed {} events", missed),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
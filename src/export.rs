@@ -0,0 +1,108 @@
+//! Export of a server as a static site
+//!
+//! [`Webserver::export`](crate::Webserver::export) requests every route through the normal request
+//! pipeline, over a loopback connection, and writes the bodies of the successful responses to disk.
+//! Routes without a file extension are written as `index.html` in a directory of the same name, so
+//! the exported site keeps its URLs when served by any static file server.
+//!
+//! ## Example
+//! ```no_run
+//! use simpleserve::Webserver;
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let server = Webserver::new(10, vec![]);
+//! let written = server.export("./out").await?;
+//! println!("Exported {} pages", written.len());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf}
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream}
+};
+
+use crate::{
+    request::Method,
+    server::{ConnectionInfo, Handler, ServerContext},
+    utils
+};
+
+/// Whether a route is exported by default
+///
+/// Routes that handle GET and are not patterns or health checks are exported.
+pub(crate) fn is_exportable(handler: &Handler) -> bool {
+    let route = handler.route();
+    route.starts_with('/')
+        && !handler.is_health_check()
+        && handler.method().is_none_or(|method| *method == Method::Get)
+        && !route.split('/').any(|segment| segment.starts_with(':') || segment.starts_with('*'))
+}
+
+/// The file a route is exported to, relative to the export directory
+///
+/// # Examples
+/// ```
+/// use std::path::PathBuf;
+/// use simpleserve::export::file_for_route;
+///
+/// assert_eq!(file_for_route("/"), PathBuf::from("index.html"));
+/// assert_eq!(file_for_route("/blog/first-post"), PathBuf::from("blog/first-post/index.html"));
+/// assert_eq!(file_for_route("/docs/"), PathBuf::from("docs/index.html"));
+/// assert_eq!(file_for_route("/style.css"), PathBuf::from("style.css"));
+/// ```
+pub fn file_for_route(route: &str) -> PathBuf {
+    let relative = route.trim_start_matches('/');
+    let has_extension = relative.rsplit('/').next().is_some_and(|name| name.contains('.'));
+    if relative.is_empty() || relative.ends_with('/') || !has_extension {
+        Path::new(relative).join("index.html")
+    } else {
+        PathBuf::from(relative)
+    }
+}
+
+/// Requests a route and returns the status and body of the response
+pub(crate) async fn fetch(context: &ServerContext, route: &str) -> Result<(u16, Vec<u8>), Box<dyn Error>> {
+    fetch_with_headers(context, route, &[]).await
+}
+
+/// Requests a route with headers, and returns the status and body of the response
+///
+/// The route and headers must not contain line breaks.
+pub(crate) async fn fetch_with_headers(context: &ServerContext, route: &str, headers: &[(&str, &str)]) -> Result<(u16, Vec<u8>), Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let mut client = TcpStream::connect(listener.local_addr()?).await?;
+    let (server, _) = listener.accept().await?;
+
+    let mut head = format!("GET {} HTTP/1.1\r\nConnection: close\r\n", route);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    client.write_all(head.as_bytes()).await?;
+    context.stats.connection_opened();
+    let mut response = Vec::new();
+    let (handled, read) = tokio::join!(
+        utils::handle_connection(ConnectionInfo::new(server), context.clone()),
+        client.read_to_end(&mut response)
+    );
+    handled?;
+    read?;
+
+    let end = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Err(format!("Malformed response for {}", route).into()),
+    };
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("Malformed status line for {}", route))?;
+    Ok((status, response[end + 4..].to_vec()))
+}
@@ -0,0 +1,103 @@
+//! Geographic lookups of client addresses
+//!
+//! A [`GeoResolver`] set with [`Webserver::with_geo_resolver`](crate::Webserver::with_geo_resolver)
+//! is asked about the address of every request, and handlers see the result through
+//! [`RequestInfo::geo`](crate::RequestInfo::geo). With the `maxminddb` feature, [`MaxMindResolver`]
+//! reads a MaxMind GeoIP2 or GeoLite2 database.
+//!
+//! ## Example
+//! ```
+//! use std::net::IpAddr;
+//! use simpleserve::{
+//!     Webserver,
+//!     geo::{GeoInfo, GeoResolver}
+//! };
+//!
+//! struct Loopback;
+//!
+//! impl GeoResolver for Loopback {
+//!     fn resolve(&self, addr: IpAddr) -> Option<GeoInfo> {
+//!         if addr.is_loopback() {
+//!             Some(GeoInfo::new().with_country_code("NL"))
+//!         } else {
+//!             None
+//!         }
+//!     }
+//! }
+//!
+//! let server = Webserver::new(10, vec![]).with_geo_resolver(Loopback);
+//! ```
+
+use std::net::IpAddr;
+
+/// Where a client is
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    country_code: Option<String>,
+    continent_code: Option<String>,
+}
+
+impl GeoInfo {
+    pub fn new() -> GeoInfo {
+        GeoInfo::default()
+    }
+
+    /// Sets the ISO 3166-1 alpha-2 code of the country, such as `NL`
+    pub fn with_country_code(mut self, country_code: &str) -> GeoInfo {
+        self.country_code = Some(String::from(country_code));
+        self
+    }
+
+    /// Sets the two letter code of the continent, such as `EU`
+    pub fn with_continent_code(mut self, continent_code: &str) -> GeoInfo {
+        self.continent_code = Some(String::from(continent_code));
+        self
+    }
+
+    pub fn country_code(&self) -> Option<&str> {
+        self.country_code.as_deref()
+    }
+
+    pub fn continent_code(&self) -> Option<&str> {
+        self.continent_code.as_deref()
+    }
+}
+
+/// Looks up where an address is
+pub trait GeoResolver: Send + Sync {
+    /// Returns `None` if nothing is known about the address
+    fn resolve(&self, addr: IpAddr) -> Option<GeoInfo>;
+}
+
+/// Resolves addresses with a MaxMind database
+///
+/// Enabled with the `maxminddb` feature.
+#[cfg(feature = "maxminddb")]
+pub struct MaxMindResolver {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "maxminddb")]
+impl MaxMindResolver {
+    /// Opens a `.mmdb` database, such as `GeoLite2-Country.mmdb`
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<MaxMindResolver, maxminddb::MaxMindDBError> {
+        Ok(MaxMindResolver {
+            reader: maxminddb::Reader::open_readfile(path)?,
+        })
+    }
+}
+
+#[cfg(feature = "maxminddb")]
+impl GeoResolver for MaxMindResolver {
+    fn resolve(&self, addr: IpAddr) -> Option<GeoInfo> {
+        let record: maxminddb::geoip2::Country = self.reader.lookup(addr).ok()?;
+        let mut info = GeoInfo::new();
+        if let Some(code) = record.country.and_then(|country| country.iso_code) {
+            info = info.with_country_code(code);
+        }
+        if let Some(code) = record.continent.and_then(|continent| continent.code) {
+            info = info.with_continent_code(code);
+        }
+        Some(info)
+    }
+}
@@ -0,0 +1,137 @@
+//! GraphQL endpoints
+//!
+//! An [`Executor`] runs GraphQL requests. Mounting one with
+//! [`Webserver::mount_graphql`](crate::Webserver::mount_graphql) answers POST requests to the
+//! route with a JSON body, and serves a [GraphiQL](https://github.com/graphql/graphiql)
+//! playground on GET requests to the same route to try queries in the browser.
+//!
+//! The trait is small enough to wrap any GraphQL library. For async-graphql, `execute` would
+//! parse the body into an `async_graphql::Request` with serde_json, run it with `Schema::execute`,
+//! and serialize the response back to JSON. The body is handed over as it was sent, so the
+//! library decides how to read variables and operation names.
+//!
+//! Only bodies of type `application/json` are accepted, and others are answered with
+//! 415 Unsupported Media Type. The playground loads GraphiQL from a CDN, so turn it off with
+//! [`GraphQL::without_playground`] where the browser cannot reach it, or in production.
+//!
+//! ## Example
+//! ```
+//! use async_trait::async_trait;
+//! use simpleserve::{
+//!     Webserver,
+//!     RequestInfo,
+//!     graphql::{Executor, GraphQL}
+//! };
+//!
+//! // Answers every query with the same data, standing in for a schema
+//! struct Hello;
+//!
+//! #[async_trait]
+//! impl Executor for Hello {
+//!     async fn execute(&self, _request: &RequestInfo<'_>, _body: &str) -> String {
+//!         String::from(r#"{"data": {"hello": "world"}}"#)
+//!     }
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.mount_graphql("/graphql", GraphQL::new(Hello));
+//! ```
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    response::Respond,
+    server::{
+        AsyncHandlerFunction,
+        RequestInfo,
+        Sendable
+    }
+};
+
+/// Runs GraphQL requests
+#[async_trait]
+pub trait Executor: Send + Sync + 'static {
+    /// Runs a request and returns the JSON response
+    ///
+    /// # Arguments
+    /// * `request` - The HTTP request, for headers, sessions and shared state
+    /// * `body` - The JSON body, with the query, variables and operation name
+    async fn execute(&self, request: &RequestInfo<'_>, body: &str) -> String;
+}
+
+/// A GraphQL endpoint, mounted with [`Webserver::mount_graphql`](crate::Webserver::mount_graphql)
+pub struct GraphQL<E> {
+    executor: E,
+    playground: bool,
+}
+
+impl<E: Executor> GraphQL<E> {
+    /// Creates an endpoint with a playground
+    pub fn new(executor: E) -> GraphQL<E> {
+        GraphQL {
+            executor,
+            playground: true,
+        }
+    }
+
+    /// Does not serve the playground, so GET requests are answered with 405 Method Not Allowed
+    pub fn without_playground(mut self) -> GraphQL<E> {
+        self.playground = false;
+        self
+    }
+
+    pub fn executor(&self) -> &E {
+        &self.executor
+    }
+
+    pub fn has_playground(&self) -> bool {
+        self.playground
+    }
+}
+
+/// The handler for POST requests to an endpoint
+pub(crate) fn handler<E: Executor>(graphql: Arc<GraphQL<E>>) -> AsyncHandlerFunction {
+    Arc::new(move |request| {
+        let graphql = graphql.clone();
+        Box::pin(async move {
+            let body = match request.body_string() {
+                Ok(body) if !body.trim().is_empty() => body,
+                Ok(_) => return error(400, "The request has no query"),
+                Err(_) => return error(400, "The request body is not valid UTF-8"),
+            };
+            let response = graphql.executor.execute(request, body).await;
+            Box::new(Respond::ok().json(&response)) as Box<dyn Sendable>
+        })
+    })
+}
+
+/// A GraphQL error response, for requests that never reached the executor
+fn error(status: u16, message: &str) -> Box<dyn Sendable> {
+    Box::new(Respond::status(status).json(&format!("{{\"errors\": [{{\"message\": \"{}\"}}]}}", message)))
+}
+
+/// The playground page for an endpoint
+pub(crate) fn playground(route: &str) -> String {
+    // The route ends up in a script, where it must not end the string or the element
+    let route = route.replace('\\', "\\\\").replace('"', "\\\"").replace('<', "\\u003c");
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>GraphiQL</title>
+<link rel="stylesheet" href="https://unpkg.com/graphiql@3/graphiql.min.css">
+</head>
+<body style="margin: 0">
+<div id="graphiql" style="height: 100vh"></div>
+<script crossorigin src="https://unpkg.com/react@18/umd/react.production.min.js"></script>
+<script crossorigin src="https://unpkg.com/react-dom@18/umd/react-dom.production.min.js"></script>
+<script crossorigin src="https://unpkg.com/graphiql@3/graphiql.min.js"></script>
+<script>
+const fetcher = GraphiQL.createFetcher({{ url: "{}" }});
+ReactDOM.createRoot(document.getElementById("graphiql")).render(React.createElement(GraphiQL, {{ fetcher }}));
+</script>
+</body>
+</html>"#, route)
+}
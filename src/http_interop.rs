@@ -0,0 +1,99 @@
+//! Interoperability with the [`http`] crate
+//!
+//! Enabled with the `http` feature. Handlers can return an `http::Response` directly,
+//! and the built-in response types convert into `http::Response`.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     RequestInfo
+//! };
+//!
+//! fn api_route(_: &RequestInfo) -> Box<dyn Sendable> {
+//!     let response = http::Response::builder()
+//!         .status(201)
+//!         .header("Content-Type", "application/json")
+//!         .body(String::from("{\"created\":true}"))
+//!         .unwrap();
+//!     Box::new(response)
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_route("/api", api_route);
+//! ```
+
+use async_trait::async_trait;
+
+use crate::response::Response;
+use crate::server::{
+    Bytes,
+    ConnectionInfo,
+    Page,
+    Sendable
+};
+
+#[async_trait]
+impl<B> Sendable for http::Response<B>
+where
+    B: AsRef<[u8]> + Send + Sync,
+{
+    /// Renders the status line and headers
+    ///
+    /// A `Content-Length` header is added if the response does not have one.
+    fn render(&self) -> String {
+        let status = self.status();
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("")
+        );
+        for (name, value) in self.headers() {
+            head.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value.as_bytes())));
+        }
+        if !self.headers().contains_key(http::header::CONTENT_LENGTH) {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body().as_ref().len()));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    fn to_response(&self) -> Option<Response> {
+        let mut response = Response::new(self.status().as_u16()).bytes(self.body().as_ref().to_vec());
+        for (name, value) in self.headers() {
+            response = response.header(name.as_str(), &String::from_utf8_lossy(value.as_bytes()));
+        }
+        Some(response)
+    }
+
+    async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
+        conn.write_all(self.render().as_bytes()).await?;
+        conn.write_all(self.body().as_ref()).await
+    }
+}
+
+impl From<Page> for http::Response<String> {
+    fn from(page: Page) -> http::Response<String> {
+        let mut response = http::Response::new(String::from(page.content()));
+        *response.status_mut() = http::StatusCode::from_u16(page.status())
+            .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+        response
+    }
+}
+
+impl From<Bytes> for http::Response<Vec<u8>> {
+    fn from(bytes: Bytes) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::new(bytes.body().to_vec());
+        *response.status_mut() = http::StatusCode::from_u16(bytes.status())
+            .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static(bytes.mime_type())
+        );
+        if let Some(range) = bytes.content_range().and_then(|range| http::HeaderValue::from_str(&range).ok()) {
+            response.headers_mut().insert(http::header::CONTENT_RANGE, range);
+        }
+        response
+    }
+}
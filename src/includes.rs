@@ -0,0 +1,265 @@
+//! Server-side and edge-side includes
+//!
+//! [`Includes`] composes HTML pages from other routes of the server, without a template engine.
+//! Added with [`Webserver::with_includes`](crate::Webserver::with_includes), it replaces these
+//! directives in HTML responses with the body of the route they name:
+//! - `<!--#include virtual="/header" -->`, a server-side include of a route.
+//! - `<!--#include file="footer.html" -->`, a server-side include relative to the directory of
+//!   the page. It cannot leave that directory.
+//! - `<esi:include src="/header"/>`, an edge-side include, which tries its `alt` route when the
+//!   first one fails.
+//!
+//! Included routes are requested through the normal request pipeline, with the `Host`, `Cookie`,
+//! `Authorization`, `Accept-Language` and `User-Agent` of the page's request, so they see the same
+//! session and language. Only routes of the server are included, never other sites. Included
+//! pages are processed too, up to [`DEFAULT_MAX_DEPTH`] levels deep, and at most
+//! [`DEFAULT_MAX_INCLUDES`] directives of a page are resolved.
+//!
+//! A failed server-side include, one that is not answered with a 2xx status or is over a limit,
+//! is replaced with `[an error occurred while processing this directive]`, like in other servers.
+//! A failed edge-side include is removed. Includes run as a
+//! [`Transform`](crate::transform::Transform), so only buffered pages up to its size cap are
+//! processed, and before they are compressed.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     includes::Includes
+//! };
+//!
+//! let server = Webserver::new(10, vec![])
+//!     .with_includes(Includes::new().with_max_depth(2));
+//! ```
+
+use std::ops::Range;
+
+use crate::{
+    export,
+    response::Response,
+    server::RequestInfo,
+    transform::BodyTransform
+};
+
+/// How many levels of included pages are processed by default
+pub const DEFAULT_MAX_DEPTH: usize = 3;
+
+/// How many directives of a page are resolved by default
+pub const DEFAULT_MAX_INCLUDES: usize = 32;
+
+/// The header that tells an included request how deep it is
+const DEPTH_HEADER: &str = "X-Include-Depth";
+
+/// The headers of a page's request that its included requests get
+const FORWARDED_HEADERS: [&str; 5] = ["Host", "Cookie", "Authorization", "Accept-Language", "User-Agent"];
+
+/// What a failed server-side include is replaced with
+const SSI_ERROR: &str = "[an error occurred while processing this directive]";
+
+/// Resolves server-side and edge-side includes in HTML responses
+#[derive(Debug, Clone)]
+pub struct Includes {
+    max_depth: usize,
+    max_includes: usize,
+}
+
+impl Includes {
+    pub fn new() -> Includes {
+        Includes {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_includes: DEFAULT_MAX_INCLUDES,
+        }
+    }
+
+    /// Sets how many levels of included pages are processed
+    ///
+    /// The directives of a page included at the last level are treated as failed, so a page
+    /// that includes itself stops there. With 0, every directive fails.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Includes {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets how many directives of a page are resolved, the rest are treated as failed
+    pub fn with_max_includes(mut self, max_includes: usize) -> Includes {
+        self.max_includes = max_includes;
+        self
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn max_includes(&self) -> usize {
+        self.max_includes
+    }
+}
+
+impl Default for Includes {
+    fn default() -> Includes {
+        Includes::new()
+    }
+}
+
+impl BodyTransform for Includes {
+    fn applies(&self, _request: &RequestInfo, response: &Response) -> bool {
+        // Pages are HTML unless they say otherwise
+        response.headers().get("content-type").is_none_or(|content_type| content_type.starts_with("text/html"))
+    }
+
+    fn transform(&self, request: &RequestInfo, body: &[u8]) -> Option<Vec<u8>> {
+        let page = std::str::from_utf8(body).ok()?;
+        let directives = directives(page, request.route);
+        if directives.is_empty() {
+            return None;
+        }
+        let depth = request.header(DEPTH_HEADER).and_then(|depth| depth.parse().ok()).unwrap_or(0usize);
+        let depth_header = (depth + 1).to_string();
+        let mut headers: Vec<(&str, &str)> = FORWARDED_HEADERS.iter()
+            .filter_map(|name| Some((*name, request.header(name)?)))
+            .collect();
+        headers.push((DEPTH_HEADER, &depth_header));
+
+        // The included routes are requested on a runtime of their own, since this runs inside the
+        // runtime of the connection, which waits for them like it waits for a handler
+        let (context, route) = (request.context, request.route);
+        let included: Vec<Option<String>> = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().ok()?;
+                Some(rt.block_on(async {
+                    let mut included = Vec::new();
+                    for (index, directive) in directives.iter().enumerate() {
+                        if depth >= self.max_depth || index >= self.max_includes {
+                            included.push(None);
+                            continue;
+                        }
+                        let mut body = None;
+                        for included_route in &directive.routes {
+                            body = match export::fetch_with_headers(context, included_route, &headers).await {
+                                Ok((200..=299, body)) => String::from_utf8(body).ok(),
+                                Ok((status, _)) => {
+                                    println!("Include of {} in {} was answered with {}", included_route, route, status);
+                                    None
+                                },
+                                Err(e) => {
+                                    println!("Error including {} in {}: {}", included_route, route, e);
+                                    None
+                                },
+                            };
+                            if body.is_some() {
+                                break;
+                            }
+                        }
+                        included.push(body);
+                    }
+                    included
+                }))
+            }).join().ok().flatten()
+        })?;
+
+        let mut composed = String::with_capacity(page.len());
+        let mut end = 0;
+        for (directive, body) in directives.iter().zip(included) {
+            composed.push_str(&page[end..directive.span.start]);
+            match (body, directive.kind) {
+                (Some(body), _) => composed.push_str(&body),
+                (None, Kind::Ssi) => composed.push_str(SSI_ERROR),
+                (None, Kind::Esi) => {},
+            }
+            end = directive.span.end;
+        }
+        composed.push_str(&page[end..]);
+        Some(composed.into_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Ssi,
+    Esi,
+}
+
+/// A directive, where it is in the page, and the routes to try in order
+#[derive(Debug)]
+struct Directive {
+    kind: Kind,
+    span: Range<usize>,
+    routes: Vec<String>,
+}
+
+/// The include directives of a page, in order
+fn directives(page: &str, route: &str) -> Vec<Directive> {
+    let mut directives = Vec::new();
+    let mut from = 0;
+    loop {
+        let rest = &page[from..];
+        let (start, kind) = match (rest.find("<!--#include"), rest.find("<esi:include")) {
+            (Some(ssi), Some(esi)) if esi < ssi => (from + esi, Kind::Esi),
+            (Some(ssi), _) => (from + ssi, Kind::Ssi),
+            (None, Some(esi)) => (from + esi, Kind::Esi),
+            (None, None) => return directives,
+        };
+        let (tag, end) = match kind {
+            Kind::Ssi => match page[start..].find("-->") {
+                Some(close) => (&page[start..start + close], start + close + "-->".len()),
+                None => return directives,
+            },
+            Kind::Esi => match page[start..].find('>') {
+                Some(close) => {
+                    let tag = &page[start..start + close];
+                    let end = start + close + 1;
+                    // An element that is not self-closing ends with its end tag
+                    let end = match tag.ends_with('/') {
+                        true => end,
+                        false => page[end..].find("</esi:include>").map_or(end, |close| end + close + "</esi:include>".len()),
+                    };
+                    (tag, end)
+                },
+                None => return directives,
+            },
+        };
+        let routes = match kind {
+            Kind::Ssi => match (attribute(tag, "virtual"), attribute(tag, "file")) {
+                (Some(virtual_route), _) => vec![String::from(virtual_route)],
+                (None, Some(file)) if !file.starts_with('/') && !file.split('/').any(|segment| segment == "..") => {
+                    vec![format!("{}{}", &route[..route.rfind('/').map_or(0, |slash| slash + 1)], file)]
+                },
+                _ => Vec::new(),
+            },
+            Kind::Esi => ["src", "alt"].iter().filter_map(|name| attribute(tag, name)).map(String::from).collect(),
+        };
+        directives.push(Directive {
+            kind,
+            span: start..end,
+            routes: routes.into_iter().filter(|route| is_local(route)).collect(),
+        });
+        from = end;
+    }
+}
+
+/// The value of an attribute of a tag, in double or single quotes
+fn attribute<'t>(tag: &'t str, name: &str) -> Option<&'t str> {
+    let mut from = 0;
+    while let Some(found) = tag[from..].find(name) {
+        let start = from + found;
+        from = start + name.len();
+        if !tag[..start].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let value = match tag[from..].trim_start().strip_prefix('=') {
+            Some(value) => value.trim_start(),
+            None => continue,
+        };
+        let quote = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => continue,
+        };
+        return value[1..].find(quote).map(|end| &value[1..1 + end]);
+    }
+    None
+}
+
+/// Whether a route is a path on this server, that fits in a request line
+fn is_local(route: &str) -> bool {
+    route.starts_with('/') && !route.starts_with("//") && !route.chars().any(|c| c.is_whitespace() || c.is_control())
+}
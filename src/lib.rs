@@ -9,13 +9,13 @@
 //!    Webserver,
 //!    Page,
 //!    Sendable,
-//!    HandlerFunction,
 //!    RequestInfo
 //! };
 //! 
 //! fn main() {
-//!     let main_route: HandlerFunction = |_: &RequestInfo| -> Box<dyn Sendable + 'static> {
-//!        Box::new(Page::new(200, String::from("Hello World!")))
+//!     let greeting = String::from("Hello World!");
+//!     let main_route = move |_: &RequestInfo| -> Box<dyn Sendable> {
+//!        Box::new(Page::new(200, greeting.clone()))
 //!     };
 //!     let mut server = Webserver::new(10, vec![]);
 //!     server.add_route("/", main_route);
@@ -24,13 +24,64 @@
 //! ```
 
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::HashMap,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 pub mod server;
 pub mod utils;
 pub mod errors;
+pub mod theme;
+pub mod plugin;
+pub mod clock;
+pub mod request;
+pub mod geo;
+pub mod user_agent;
+pub mod tarpit;
+pub mod sitemap;
+pub mod middleware;
+pub mod export;
+pub mod response;
+pub mod status;
+pub mod cookie;
+pub mod session;
+pub mod self_check;
+pub mod multipart;
+pub mod streaming;
+pub mod privileges;
+pub mod sse;
+pub mod long_poll;
+pub mod event_bus;
+pub mod rate_limit;
+pub mod pool;
+pub mod chaos;
+pub mod websocket;
+pub mod compression;
+pub mod cache_policy;
+pub mod etag;
+pub mod static_files;
+pub mod upload_guard;
+pub mod live_reload;
+pub mod audit;
+pub mod body_limits;
+pub mod alt_svc;
+pub mod transform;
+pub mod graphql;
+pub mod command;
+pub mod bot_throttle;
+pub mod workers;
+pub mod includes;
+#[cfg(feature = "http")]
+pub mod http_interop;
 
 pub use server::prelude::*;
 
@@ -67,6 +118,26 @@ impl ThreadPool {
     ///
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::new_pinned(size, &[])
+    }
+
+    /// Create a new ThreadPool with its workers pinned to CPU cores.
+    ///
+    /// Worker `i` is pinned to `cores[i % cores.len()]`. If `cores` is empty, or pinning is not
+    /// supported on the platform, the workers are left unpinned.
+    ///
+    /// # Panics
+    ///
+    /// The `new_pinned` function will panic if the size is zero.
+    ///
+    /// ## Example
+    /// ```
+    /// use simpleserve::ThreadPool;
+    ///
+    /// // Two workers on core 0, two on core 1
+    /// let pool = ThreadPool::new_pinned(4, &[0, 1]);
+    /// ```
+    pub fn new_pinned(size: usize, cores: &[usize]) -> ThreadPool {
         assert!(size > 0);
 
         let (sender, receiver) = mpsc::channel();
@@ -75,8 +146,9 @@ impl ThreadPool {
 
         let mut workers = Vec::with_capacity(size);
 
-        for _ in 0..size {
-            workers.push(Worker::new(Arc::clone(&receiver)));
+        for i in 0..size {
+            let core = if cores.is_empty() { None } else { Some(cores[i % cores.len()]) };
+            workers.push(Worker::new(Arc::clone(&receiver), core));
         }
 
         ThreadPool {
@@ -96,8 +168,95 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.send(Box::new(f));
+    }
+
+    /// Executes a closure with a job context.
+    ///
+    /// The context is available through [`JobContext::with_current`] while the closure runs.
+    ///
+    /// # Panics
+    ///
+    /// The `execute_with_context` function will fail if the job cannot be sent.
+    ///
+    /// ## Example
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::{ThreadPool, JobContext};
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let context = JobContext::new()
+    ///     .with(String::from("request-42"))
+    ///     .with_timeout(Duration::from_secs(5));
+    ///
+    /// pool.execute_with_context(context, || {
+    ///     JobContext::with_current(|context| {
+    ///         let context = context.unwrap();
+    ///         println!("{:?} has {:?} left", context.get::<String>(), context.remaining());
+    ///     });
+    /// });
+    /// ```
+    pub fn execute_with_context<F>(&self, context: JobContext, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send(Box::new(move || {
+            CURRENT_JOB.with(|current| *current.borrow_mut() = Some(context));
+            f();
+            CURRENT_JOB.with(|current| current.borrow_mut().take());
+        }));
+    }
+
+    /// Runs a closure that can execute jobs borrowing from the caller's stack.
+    ///
+    /// Works like [`std::thread::scope`], but runs the jobs on the pool. All jobs
+    /// executed through the [`Scope`] have finished by the time `scope` returns.
+    ///
+    /// Calling `scope` from a job on the same pool can deadlock if every worker is waiting.
+    ///
+    /// # Panics
+    ///
+    /// The `scope` function will panic if the closure or any of the scoped jobs panicked.
+    ///
+    /// ## Example
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use simpleserve::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let files = vec!["index.html", "style.css", "app.js"];
+    /// let total = AtomicUsize::new(0);
+    ///
+    /// pool.scope(|scope| {
+    ///     for file in &files {
+    ///         scope.execute(|| {
+    ///             total.fetch_add(file.len(), Ordering::SeqCst);
+    ///         });
+    ///     }
+    /// });
+    ///
+    /// assert_eq!(total.into_inner(), 25);
+    /// ```
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'_, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            pending: Arc::new((Mutex::new(0), Condvar::new())),
+            panicked: Arc::new(AtomicBool::new(false)),
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.panicked.load(Ordering::SeqCst) => panic!("A scoped job panicked"),
+            Ok(result) => result,
+        }
+    }
 
+    fn send(&self, job: Job) {
         self.sender
             .as_ref()
             .expect("Failed to get reference to sender")
@@ -105,6 +264,11 @@ impl ThreadPool {
             .expect("Failed to send job");
     }
 
+    /// The number of workers in the pool
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
     pub fn stop(&mut self) {
         drop(self.sender.take());
         println!("Server stopped")
@@ -123,21 +287,150 @@ impl Drop for ThreadPool {
     }
 }
 
+/// A scope for jobs borrowing data, created by [`ThreadPool::scope`]
+pub struct Scope<'pool, 'env> {
+    pool: &'pool ThreadPool,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+    panicked: Arc<AtomicBool>,
+    // Invariant over 'env, like std::thread::Scope
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'pool, 'env> Scope<'pool, 'env> {
+    /// Executes a closure that may borrow anything outliving the scope.
+    ///
+    /// # Panics
+    ///
+    /// The `execute` function will fail if the job cannot be sent.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        *self.pending.0.lock().unwrap() += 1;
+
+        let pending = Arc::clone(&self.pending);
+        let panicked = Arc::clone(&self.panicked);
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+                panicked.store(true, Ordering::SeqCst);
+            }
+            let (count, finished) = &*pending;
+            *count.lock().unwrap() -= 1;
+            finished.notify_all();
+        });
+        // SAFETY: `ThreadPool::scope` waits for every job executed through this scope
+        // before returning, even when unwinding, so nothing borrowed for 'env is used
+        // after it goes out of scope.
+        let job: Job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'env>, Job>(job) };
+        // A job that was never sent never finishes, so it must not be waited for
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| self.pool.send(job))) {
+            let (count, finished) = &*self.pending;
+            *count.lock().unwrap() -= 1;
+            finished.notify_all();
+            panic::resume_unwind(payload);
+        }
+    }
+
+    fn wait(&self) {
+        let (count, finished) = &*self.pending;
+        let mut count = count.lock().unwrap();
+        while *count > 0 {
+            count = finished.wait(count).unwrap();
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_JOB: RefCell<Option<JobContext>> = const { RefCell::new(None) };
+}
+
+/// Context attached to a job on the [`ThreadPool`]
+///
+/// Holds at most one value per type plus an optional deadline, so code running
+/// on the pool can find request ids and the time it has left without extra parameters.
+#[derive(Debug, Default)]
+pub struct JobContext {
+    values: HashMap<TypeId, Box<dyn Any + Send>>,
+    deadline: Option<Instant>,
+}
+
+impl JobContext {
+    pub fn new() -> JobContext {
+        JobContext::default()
+    }
+
+    /// Adds a value, replacing any previous value of the same type
+    pub fn with<T: Any + Send>(mut self, value: T) -> JobContext {
+        self.insert(value);
+        self
+    }
+
+    /// Sets the deadline of the job
+    pub fn with_deadline(mut self, deadline: Instant) -> JobContext {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the deadline of the job relative to now
+    pub fn with_timeout(self, timeout: Duration) -> JobContext {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Adds a value, returning the previous value of the same type
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The time left until the deadline, zero if it has passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Calls `f` with the context of the job running on the current thread
+    ///
+    /// `f` receives `None` when called outside of a job started with [`ThreadPool::execute_with_context`].
+    pub fn with_current<R>(f: impl FnOnce(Option<&JobContext>) -> R) -> R {
+        CURRENT_JOB.with(|current| f(current.borrow().as_ref()))
+    }
+}
+
 struct Worker {
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new( receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+    fn new(receiver: Arc<Mutex<mpsc::Receiver<Job>>>, core: Option<usize>) -> Worker {
+        let thread = thread::spawn(move || {
+            if let Some(core) = core {
+                pin_current_thread(core);
+            }
+            loop {
+                let message = receiver.lock().unwrap().recv();
 
-            match message {
-                Ok(job) => {
-                    job();
-                }
-                Err(_) => {
-                    break;
+                match message {
+                    Ok(job) => {
+                        job();
+                    }
+                    Err(_) => {
+                        break;
+                    }
                 }
             }
         });
@@ -148,12 +441,24 @@ impl Worker {
     }
 }
 
+/// Pins the current thread to a CPU core.
+///
+/// Returns whether pinning succeeded. On platforms without support for CPU affinity this does nothing.
+pub fn pin_current_thread(core: usize) -> bool {
+    let pinned = core_affinity::set_for_current(core_affinity::CoreId { id: core });
+    if !pinned {
+        println!("Could not pin thread to core {}", core);
+    }
+    pinned
+}
+
 #[cfg(test)]
 mod tests {
     use crate::server::Sendable;
 
     use super::*;
     use std::path;
+    use proptest::prelude::*;
 
     #[test]
     fn test_thread_pool() {
@@ -179,17 +484,3550 @@ mod tests {
         drop(pool);
     }
 
+    #[test]
+    fn test_thread_pool_pinned() {
+        let pool = ThreadPool::new_pinned(4, &[0]);
+        let (sender, receiver) = mpsc::channel();
+
+        for i in 0..20 {
+            let sender = sender.clone();
+            pool.execute(move || {
+                sender.send(i).unwrap();
+            });
+        }
+
+        assert_eq!(pool.size(), 4);
+        assert_eq!(receiver.iter().take(20).sum::<i32>(), (0..20).sum());
+    }
+
+    #[test]
+    fn test_job_context() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+
+        let context = JobContext::new()
+            .with(42_u32)
+            .with(String::from("request"))
+            .with_timeout(Duration::from_secs(60));
+        let job_sender = sender.clone();
+        pool.execute_with_context(context, move || {
+            let values = JobContext::with_current(|context| {
+                let context = context.unwrap();
+                (*context.get::<u32>().unwrap(), context.get::<String>().cloned(), context.is_expired())
+            });
+            job_sender.send(Some(values)).unwrap();
+        });
+        let job_sender = sender.clone();
+        pool.execute(move || {
+            job_sender.send(JobContext::with_current(|context| context.map(|_| (0, None, true)))).unwrap();
+        });
+
+        let results: Vec<_> = receiver.iter().take(2).collect();
+        assert!(results.contains(&Some((42, Some(String::from("request")), false))));
+        assert!(results.contains(&None));
+        assert!(JobContext::new().with_timeout(Duration::ZERO).is_expired());
+    }
+
+    #[test]
+    fn test_thread_pool_scope() {
+        let pool = ThreadPool::new(4);
+        let numbers: Vec<u64> = (1..=100).collect();
+        let mut sums = [0; 4];
+
+        pool.scope(|scope| {
+            for (chunk, sum) in numbers.chunks(25).zip(sums.iter_mut()) {
+                scope.execute(move || {
+                    *sum = chunk.iter().sum();
+                });
+            }
+        });
+
+        assert_eq!(sums.iter().sum::<u64>(), 5050);
+    }
+
+    #[test]
+    #[should_panic(expected = "A scoped job panicked")]
+    fn test_thread_pool_scope_panic() {
+        let pool = ThreadPool::new(2);
+        pool.scope(|scope| {
+            scope.execute(|| panic!("Job failed"));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to get reference to sender")]
+    fn test_thread_pool_scope_stopped() {
+        let mut pool = ThreadPool::new(2);
+        pool.stop();
+        // Panics instead of waiting for a job that was never sent
+        pool.scope(|scope| {
+            scope.execute(|| {});
+        });
+    }
+
     #[test]
     fn test_server_routes() {
         let cargo_lock = path::Path::new("Cargo.lock").canonicalize().unwrap();
-        let handlers: server::HandlerFunction = |_| -> Box<dyn Sendable + 'static> {
+        let handlers = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
             Box::new(server::Page::new(200, String::from("Hello World!")))
         };
         let mut server = server::Webserver::new(10, vec![cargo_lock.clone()]);
-        server.add_route("/", handlers.clone());
-        server.add_route("/sleep", handlers.clone());
-        server.add_route("/image.jpg", handlers.clone());
+        server.add_route("/", handlers);
+        server.add_route("/sleep", handlers);
+        server.add_route("/image.jpg", handlers);
         server.add_accessible_files(vec!["src/lib.rs", "src/server.rs"]).unwrap();
         assert_eq!(server.blacklisted_paths()[0], cargo_lock);
     }
+
+    #[test]
+    fn test_theme_render() {
+        let theme = theme::Theme::new(String::from("<title>{{title}}</title>{{status}}:{{content}}"));
+        assert_eq!(
+            theme.render(404, "Not Found", "<p>Missing</p>"),
+            "<title>Not Found</title>404:<p>Missing</p>"
+        );
+        assert!(theme::Theme::default().render(500, "Internal Server Error", "").contains("500 Internal Server Error"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_report() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        server.add_route("/", handler);
+
+        let client = async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let mut stream = tokio::net::TcpStream::connect("127.0.0.1:7979").await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            sender.send(server::Task::Shutdown).await.unwrap();
+            response
+        };
+        let (report, response) = tokio::join!(
+            server.start("127.0.0.1:7979", server::ConnectionType::Http, None, None),
+            client
+        );
+        let report = report.unwrap();
+
+        assert!(response.ends_with("Hello World!"));
+        assert_eq!(report.connections_accepted, 1);
+        assert_eq!(report.requests_served + report.connections_force_closed, 1);
+    }
+
+    async fn send_request(addr: &str, request: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        // Closing our side ends the kept-alive connection once the response is sent
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn get(addr: &str, route: &str) -> String {
+        send_request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", route)).await
+    }
+
+    #[tokio::test]
+    async fn test_readiness_gate() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let (warmed_up, warm_up) = tokio::sync::oneshot::channel::<()>();
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        server.add_route("/", handler);
+        server.add_health_route("/health", handler);
+        server.ready_when(async {
+            warm_up.await.unwrap();
+        });
+        assert!(!server.is_ready());
+
+        // Reads one response with a Content-Length, leaving the connection open
+        async fn read_response(stream: &mut tokio::net::TcpStream) -> String {
+            use tokio::io::AsyncReadExt;
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            loop {
+                let text = String::from_utf8_lossy(&received).into_owned();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head.lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map_or(0, |length| length.parse().unwrap());
+                    if body.len() >= length {
+                        return text;
+                    }
+                }
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&buffer[..n]);
+            }
+        }
+
+        let client = async {
+            use tokio::io::AsyncWriteExt;
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let before = (get("127.0.0.1:7980", "/").await, get("127.0.0.1:7980", "/health").await);
+            let mut kept_alive = tokio::net::TcpStream::connect("127.0.0.1:7980").await.unwrap();
+            kept_alive.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            assert!(read_response(&mut kept_alive).await.starts_with("HTTP/1.1 503"));
+            warmed_up.send(()).unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let after = get("127.0.0.1:7980", "/").await;
+            // A connection opened during warm-up is served once the server is ready
+            kept_alive.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            assert!(read_response(&mut kept_alive).await.starts_with("HTTP/1.1 200"));
+            drop(kept_alive);
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (before, after)
+        };
+        let (report, ((before, health), after)) = tokio::join!(
+            server.start("127.0.0.1:7980", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(before.starts_with("HTTP/1.1 503"));
+        assert!(health.starts_with("HTTP/1.1 200"));
+        assert!(after.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn test_readiness_gate_failed() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        server.add_route("/", handler);
+        server.add_health_route("/health", handler);
+        server.ready_when(async {});
+        server.ready_when(async {
+            panic!("The cache could not be primed");
+        });
+
+        let client = async {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            let responses = (get("127.0.0.1:8039", "/").await, get("127.0.0.1:8039", "/health").await);
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (after, health)) = tokio::join!(
+            server.start("127.0.0.1:8039", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        // The other task completing does not make up for the one that failed
+        assert!(after.starts_with("HTTP/1.1 503"), "{}", after);
+        assert!(health.starts_with("HTTP/1.1 200"));
+        assert!(!server.is_ready());
+    }
+
+    #[test]
+    fn test_readiness_flags() {
+        let readiness = server::Readiness::new();
+        let flag = readiness.flag();
+        readiness.set_warmed_up(false);
+        readiness.set_checks_failing(true);
+        // Checks recovering during warm-up do not make the server ready
+        readiness.set_checks_failing(false);
+        assert!(!readiness.is_ready());
+        // Warm-up completing while checks fail does not either
+        readiness.set_checks_failing(true);
+        readiness.set_warmed_up(true);
+        assert!(!flag.load(Ordering::SeqCst));
+        readiness.set_checks_failing(false);
+        assert!(flag.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_cpu_pool() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("{:?}", thread::current().id())))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_cpu_pool(1).with_receiver(receiver);
+        server.add_route("/", handler);
+        server.add_cpu_route("/cpu", handler);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut cpu = Vec::new();
+            for _ in 0..3 {
+                cpu.push(get("127.0.0.1:7981", "/cpu").await);
+            }
+            let io = get("127.0.0.1:7981", "/").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (cpu, io)
+        };
+        let (report, (cpu, io)) = tokio::join!(
+            server.start("127.0.0.1:7981", server::ConnectionType::Http, None, None),
+            client
+        );
+
+        assert_eq!(report.unwrap().requests_served, 4);
+        let cpu_thread = cpu[0].split("\r\n\r\n").nth(1).unwrap();
+        assert!(cpu.iter().all(|response| response.ends_with(cpu_thread)));
+        assert!(!io.ends_with(cpu_thread));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_interop() {
+        let response = http::Response::builder()
+            .status(404)
+            .header("X-Test", "yes")
+            .body(String::from("Gone"))
+            .unwrap();
+        assert_eq!(response.render(), "HTTP/1.1 404 Not Found\r\nx-test: yes\r\nContent-Length: 4\r\n\r\n");
+
+        let page: http::Response<String> = server::Page::new(201, String::from("Created")).into();
+        assert_eq!(page.status(), http::StatusCode::CREATED);
+        assert_eq!(page.body(), "Created");
+
+        let bytes: http::Response<Vec<u8>> = server::Bytes::new(200, "Cargo.toml").unwrap().into();
+        assert_eq!(bytes.headers()["content-type"], "application/octet-stream");
+    }
+
+    #[tokio::test]
+    async fn test_plugin_hooks() {
+        use std::sync::atomic::AtomicUsize;
+
+        static STARTED: AtomicUsize = AtomicUsize::new(0);
+        static SHUT_DOWN: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counter;
+
+        impl plugin::Plugin for Counter {
+            fn name(&self) -> &str {
+                "counter"
+            }
+
+            fn register(&self, server: &mut server::Webserver) {
+                server.add_route("/counter", |_| -> Box<dyn Sendable> {
+                    Box::new(server::Page::new(200, String::from("counted")))
+                });
+            }
+
+            fn on_start(&self) {
+                STARTED.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_shutdown(&self, _report: &server::ShutdownReport) {
+                SHUT_DOWN.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.register_plugin(Counter);
+        assert!(server.routes().iter().any(|handler| handler.route() == "/counter"));
+
+        sender.send(server::Task::Shutdown).await.unwrap();
+        server.start("127.0.0.1:7982", server::ConnectionType::Http, None, None).await.unwrap();
+        assert_eq!(STARTED.load(Ordering::SeqCst), 1);
+        assert_eq!(SHUT_DOWN.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_uptime() {
+        let clock = clock::MockClock::new();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_clock(clock.clone())
+            .with_receiver(receiver);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            clock.advance(Duration::from_secs(3600));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (report, _) = tokio::join!(
+            server.start("127.0.0.1:7983", server::ConnectionType::Http, None, None),
+            client
+        );
+        assert_eq!(report.unwrap().uptime, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_parse_head() {
+        let (request_line, headers) = request::parse_head(
+            "GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept:text/html\r\nX-Forwarded-For: 10.0.0.1\r\nx-forwarded-for: 10.0.0.2"
+        ).unwrap();
+        assert_eq!(request_line, "GET /index.html HTTP/1.1");
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers.get("HOST"), Some("example.com"));
+        assert_eq!(headers.get("accept"), Some("text/html"));
+        assert_eq!(headers.get_all("X-Forwarded-For").collect::<Vec<_>>(), vec!["10.0.0.1", "10.0.0.2"]);
+        assert!(request::parse_head("").is_err());
+        assert!(request::parse_head("GET / HTTP/1.1\r\nBad Header: x").is_err());
+        assert!(request::parse_head("GET / HTTP/1.1\r\nHost: a\nAccept: */*").is_err());
+        assert!(request::parse_head("GET / HTTP/1.1\r\nHost: a\rAccept: */*").is_err());
+        assert!(request::parse_head("GET / HTTP/1.1\r\nX-Bad\u{0}: x").is_err());
+        assert!(request::parse_head("GET / HTTP/1.1\r\n[Host]: a").is_err());
+        assert!(request::parse_head("GET / HTTP/1.1\r\nX-Tab: a\tb").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_headers() {
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("{:?}", request.header("user-agent"))))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/", handler);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let with_agent = send_request("127.0.0.1:7984", "GET / HTTP/1.1\r\nUser-Agent: test-client\r\n\r\n").await;
+            let malformed = send_request("127.0.0.1:7984", "GET / HTTP/1.1\r\nnot a header\r\n\r\n").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (with_agent, malformed)
+        };
+        let (report, (with_agent, malformed)) = tokio::join!(
+            server.start("127.0.0.1:7984", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(with_agent.ends_with("Some(\"test-client\")"));
+        assert!(malformed.starts_with("HTTP/1.1 400"));
+    }
+
+    #[tokio::test]
+    async fn test_method_routes() {
+        let list = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("list {}", request.method())))
+        };
+        let create = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(201, format!("create {}", request.method())))
+        };
+        let show = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("user {} {:?}", request.param("id").unwrap(), request.query_param("fields"))))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.get("/users", list);
+        server.post("/users", create);
+        server.get("/users/:id", show);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let listed = get("127.0.0.1:7985", "/users").await;
+            let post = send_request("127.0.0.1:7985", "POST /users HTTP/1.1\r\n\r\n").await;
+            let delete = send_request("127.0.0.1:7985", "DELETE /users HTTP/1.1\r\n\r\n").await;
+            let unknown = send_request("127.0.0.1:7985", "BREW /users HTTP/1.1\r\n\r\n").await;
+            let user = get("127.0.0.1:7985", "/users/42?fields=name%2Cemail").await;
+            let head = send_request("127.0.0.1:7985", "HEAD /users HTTP/1.1\r\n\r\n").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (listed, post, delete, unknown, user, head)
+        };
+        let (report, (listed, post, delete, unknown, user, head)) = tokio::join!(
+            server.start("127.0.0.1:7985", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(listed.ends_with("list GET"));
+        assert!(post.ends_with("create POST"));
+        assert!(delete.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(delete.contains("\r\nAllow: GET, HEAD, POST\r\n"));
+        assert!(delete.contains("Allowed methods: GET, HEAD, POST"));
+        assert!(unknown.starts_with("HTTP/1.1 501"));
+        assert!(user.ends_with("user 42 Some(\"name,email\")"));
+        // GET routes answer HEAD, without the body
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(head.contains("Content-Length: 9\r\n") && head.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Route already exists")]
+    fn test_method_route_conflicts_with_any_method_route() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let mut server = server::Webserver::new(1, vec![]);
+        server.add_route("/users", handler);
+        server.get("/users", handler);
+    }
+
+    #[tokio::test]
+    async fn test_request_body() {
+        let echo = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("{}:{}", request.body().len(), request.body_string().unwrap())))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_receiver(receiver)
+            .with_max_body_size(16);
+        server.post("/echo", echo);
+        server.post("/form", |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let form = request.form().unwrap_or_default();
+            Box::new(server::Page::new(200, format!("{:?} {:?}", form.get("name"), form.get("note"))))
+        });
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let posted = send_request("127.0.0.1:7986", "POST /echo HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello=world").await;
+            let empty = send_request("127.0.0.1:7986", "POST /echo HTTP/1.1\r\n\r\n").await;
+            let too_large = send_request("127.0.0.1:7986", "POST /echo HTTP/1.1\r\nContent-Length: 17\r\n\r\n").await;
+            let invalid = send_request("127.0.0.1:7986", "POST /echo HTTP/1.1\r\nContent-Length: ten\r\n\r\n").await;
+            let form = send_request(
+                "127.0.0.1:7986",
+                "POST /form HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded; charset=UTF-8\r\nContent-Length: 14\r\n\r\nname=J%C3%B6+D"
+            ).await;
+            let not_form = send_request("127.0.0.1:7986", "POST /form HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\nname=x").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (posted, empty, too_large, invalid, form, not_form)
+        };
+        let (report, (posted, empty, too_large, invalid, form, not_form)) = tokio::join!(
+            server.start("127.0.0.1:7986", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(posted.ends_with("11:hello=world"));
+        assert!(empty.ends_with("0:"));
+        assert!(too_large.starts_with("HTTP/1.1 413"));
+        assert!(invalid.starts_with("HTTP/1.1 400"));
+        assert!(form.ends_with("Some(\"Jö D\") None"));
+        assert!(not_form.ends_with("None None"));
+    }
+
+    #[tokio::test]
+    async fn test_body_limits() {
+        let echo = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("{} bytes", request.body().len())))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_receiver(receiver)
+            .with_max_body_size(32)
+            .with_body_limits(body_limits::BodyLimits::new().with_type("application/json", 8));
+        server.post("/echo", echo);
+        server.add_route("/upload", echo);
+        server.set_body_limits("/upload", body_limits::BodyLimits::new().with_type("text/", 64).only_listed());
+
+        let addr = "127.0.0.1:8028";
+        let post = |route: &'static str, content_type: &'static str, body: &'static str| async move {
+            let content_type = if content_type.is_empty() { String::new() } else { format!("Content-Type: {}\r\n", content_type) };
+            let request = format!("POST {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", route, content_type, body.len(), body);
+            send_request(addr, &request).await
+        };
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let responses = [
+                post("/echo", "application/json", "[1,2,3]").await,
+                post("/echo", "application/json; charset=utf-8", "[1,2,3,4]").await,
+                // Types without a limit of their own fall back to the maximum body size
+                post("/echo", "text/plain", "forty bytes of plain text, over the JSON").await,
+                post("/echo", "text/plain", "twenty bytes of text").await,
+                // The route limits replace the global ones
+                post("/upload", "text/csv", "a,b\n1,2\n3,4\n5,6\n7,8\n9,10\n11,12\n13,14\n").await,
+                post("/upload", "application/json", "[]").await,
+                post("/upload", "", "untyped").await,
+                get(addr, "/upload").await,
+            ];
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, responses) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let statuses: Vec<&str> = responses.iter().map(|response| &response[9..12]).collect();
+        assert_eq!(statuses, ["200", "413", "413", "200", "200", "415", "415", "200"]);
+        assert!(responses[4].ends_with("37 bytes"));
+        assert!(responses[5].contains("Unsupported Media Type"));
+        assert!(responses[5].contains("Accept-Post: text/*\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_accepted_types() {
+        let echo = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("{} bytes", request.body().len())))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/api/users", echo);
+        server.add_route("/anything", echo);
+        server.set_accepted_types("/api/users", &["application/json", "text/"]);
+
+        let addr = "127.0.0.1:8029";
+        let send = |method: &'static str, route: &'static str, content_type: &'static str, body: &'static str| async move {
+            let content_type = if content_type.is_empty() { String::new() } else { format!("Content-Type: {}\r\n", content_type) };
+            let request = format!("{} {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", method, route, content_type, body.len(), body);
+            send_request(addr, &request).await
+        };
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let responses = [
+                send("POST", "/api/users", "Application/JSON; charset=utf-8", "{}").await,
+                send("POST", "/api/users", "text/csv", "a,b").await,
+                send("POST", "/api/users", "application/xml", "<a/>").await,
+                send("PATCH", "/api/users", "application/xml", "<a/>").await,
+                send("POST", "/api/users", "", "untyped").await,
+                send("POST", "/api/users", "", "").await,
+                send("POST", "/anything", "application/xml", "<a/>").await,
+            ];
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, responses) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let statuses: Vec<&str> = responses.iter().map(|response| &response[9..12]).collect();
+        assert_eq!(statuses, ["200", "200", "415", "415", "415", "200", "200"]);
+        assert!(responses[2].contains("Accept-Post: application/json, text/*\r\n"));
+        assert!(responses[3].contains("Accept-Patch: application/json, text/*\r\n"));
+        assert!(!responses[3].contains("Accept-Post"));
+    }
+
+    #[test]
+    fn test_route_patterns() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let mut server = server::Webserver::new(1, vec![]);
+        server.add_route("/users/:id", handler);
+        server.add_route("/users/me", handler);
+        server.add_route("/users/*rest", handler);
+        server.post("/posts/:id", handler);
+
+        let find = |route: &str, method: request::Method| utils::find_handler(server.routes(), route, &method).unwrap().route();
+        assert_eq!(find("/users/me", request::Method::Get), "/users/me");
+        assert_eq!(find("/users/42", request::Method::Get), "/users/:id");
+        assert_eq!(find("/users/42/posts", request::Method::Get), "/users/*rest");
+        assert_eq!(find("/users", request::Method::Get), "404");
+        assert_eq!(find("/posts/1", request::Method::Get), "404");
+        assert_eq!(utils::allowed_methods(server.routes(), "/posts/1"), vec![request::Method::Post]);
+
+        let params = utils::match_route("/users/:id/posts/:post", "/users/42/posts/7").unwrap();
+        assert_eq!(params["id"], "42");
+        assert_eq!(params["post"], "7");
+        assert!(utils::match_route("/users/:id", "/users/").is_none());
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let firefox = user_agent::UserAgent::parse("Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0");
+        assert_eq!(firefox.product(), Some("Firefox"));
+        assert_eq!(firefox.version(), Some("121.0"));
+        assert!(firefox.is_desktop());
+
+        let chrome = user_agent::UserAgent::parse("Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36");
+        assert_eq!(chrome.product(), Some("Chrome"));
+        assert!(chrome.is_mobile());
+
+        let edge = user_agent::UserAgent::parse("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.2210.91");
+        assert_eq!(edge.product(), Some("Edge"));
+        assert_eq!(edge.version(), Some("120.0.2210.91"));
+
+        let bing = user_agent::UserAgent::parse("Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)");
+        assert!(bing.is_bot());
+        assert!(!bing.is_desktop());
+        assert_eq!(bing.product(), None);
+    }
+
+    #[tokio::test]
+    async fn test_closure_handler_state() {
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = Arc::clone(&hits);
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/", move |_: &server::RequestInfo| -> Box<dyn Sendable> {
+            let count = counted.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::new(server::Page::new(200, format!("hit {}", count)))
+        });
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            get("127.0.0.1:7988", "/").await;
+            let second = get("127.0.0.1:7988", "/").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            second
+        };
+        let (report, second) = tokio::join!(
+            server.start("127.0.0.1:7988", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(second.ends_with("hit 2"));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tarpit() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver).with_max_tarpitted(1);
+        server.add_tarpit_with("/wp-login.php", tarpit::Tarpit::new()
+            .with_interval(Duration::from_millis(10))
+            .with_max_bytes(20));
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let start = Instant::now();
+            let first = tokio::spawn(get("127.0.0.1:7989", "/wp-login.php"));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            // The only slot is taken, so the second scanner is not held
+            assert!(get("127.0.0.1:7989", "/wp-login.php").await.starts_with("HTTP/1.1 404"));
+            let response = first.await.unwrap();
+            let elapsed = start.elapsed();
+            let head = send_request("127.0.0.1:7989", "HEAD /wp-login.php HTTP/1.1\r\n\r\n").await;
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(head.ends_with("\r\n\r\n"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (response, elapsed)
+        };
+        let (report, (response, elapsed)) = tokio::join!(
+            server.start("127.0.0.1:7989", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", " ".repeat(20))));
+        assert!(elapsed >= Duration::from_millis(200));
+        assert_eq!(server.stats().tarpit_hits(), 3);
+    }
+
+    #[test]
+    fn test_app_state() {
+        struct Config {
+            name: &'static str,
+        }
+        let server = server::Webserver::new(1, vec![])
+            .with_state(Config { name: "first" })
+            .with_state(Config { name: "second" })
+            .with_state(42usize);
+        assert_eq!(server.state::<Config>().unwrap().name, "second");
+        assert_eq!(*server.state::<usize>().unwrap(), 42);
+        assert!(server.state::<String>().is_none());
+    }
+
+    fn async_greeting<'a>(request: &'a server::RequestInfo<'a>) -> server::HandlerFuture<'a> {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Box::new(server::Page::new(200, format!("Hello {}", request.query_param("name").unwrap_or("stranger")))) as Box<dyn Sendable>
+        })
+    }
+
+    #[tokio::test]
+    async fn test_async_route() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_async_route("/greet", async_greeting);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = get("127.0.0.1:7990", "/greet?name=Ferris").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            response
+        };
+        let (report, response) = tokio::join!(
+            server.start("127.0.0.1:7990", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(response.ends_with("Hello Ferris"));
+        assert!(server.routes()[1].async_handler().is_some());
+        assert!(server.routes()[1].handler().is_none());
+    }
+
+    #[test]
+    fn test_sitemap() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let mut server = server::Webserver::new(1, vec![]);
+        server.add_route("/", handler);
+        server.add_route("/about", handler);
+        server.add_route("/admin/users", handler);
+        server.add_route("/users/:id", handler);
+        server.post("/contact", handler);
+        server.add_health_route("/health", handler);
+        let server = server.with_sitemap(sitemap::Sitemap::new("https://example.com/")
+            .with_change_frequency(sitemap::ChangeFrequency::Daily)
+            .with_priority("/", 1.0)
+            .exclude("/admin"));
+
+        let xml = server.sitemap_xml();
+        assert!(xml.contains("<loc>https://example.com/</loc>\n    <changefreq>daily</changefreq>\n    <priority>1.0</priority>"));
+        assert!(xml.contains("<loc>https://example.com/about</loc>"));
+        for excluded in ["/admin/users", "/users/:id", "/contact", "/health", "/sitemap.xml", "404"] {
+            assert!(!xml.contains(&format!("{}</loc>", excluded)), "{} should not be listed", excluded);
+        }
+    }
+
+    struct Counter(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl middleware::Middleware for Counter {
+        fn after(&self, _: &server::RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            response
+        }
+    }
+
+    struct Guard;
+
+    impl middleware::Middleware for Guard {
+        fn before(&self, request: &mut request::Request) -> Option<Box<dyn Sendable>> {
+            if request.route == "/old" {
+                request.route = String::from("/new");
+            }
+            if request.route == "/private" && !request.headers.contains("authorization") {
+                return Some(Box::new(server::Page::new(401, String::from("Unauthorized"))));
+            }
+            None
+        }
+    }
+
+    struct Shout(Box<dyn Sendable>);
+
+    impl Sendable for Shout {
+        fn render(&self) -> String {
+            self.0.render().to_uppercase()
+        }
+    }
+
+    struct Shouting;
+
+    impl middleware::Middleware for Shouting {
+        fn after(&self, _: &server::RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+            Box::new(Shout(response))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("hello from {}", request.route)))
+        };
+        let responses = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/new", handler);
+        server.add_route("/private", handler);
+        server.add_middleware(Counter(Arc::clone(&responses)));
+        server.add_middleware(Guard);
+        server.add_middleware(Shouting);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let rewritten = get("127.0.0.1:7991", "/old").await;
+            let denied = get("127.0.0.1:7991", "/private").await;
+            let allowed = send_request("127.0.0.1:7991", "GET /private HTTP/1.1\r\nAuthorization: Bearer x\r\n\r\n").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (rewritten, denied, allowed)
+        };
+        let (report, (rewritten, denied, allowed)) = tokio::join!(
+            server.start("127.0.0.1:7991", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(rewritten.ends_with("HELLO FROM /NEW"));
+        // Shouting never ran, since Guard responded first
+        assert!(denied.ends_with("Unauthorized"));
+        assert!(allowed.ends_with("HELLO FROM /PRIVATE"));
+        assert_eq!(responses.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_middleware() {
+        use middleware::{when, host_is, path_starts_with, method_is};
+
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("hello from {}", request.route)))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/api", handler);
+        server.add_route("/other", handler);
+        server.add_route("/new", handler);
+        server.add_middleware(when(host_is("loud.example")).and(path_starts_with("/api")).then(Shouting));
+        server.add_middleware(when(method_is(request::Method::Get).not()).then(Guard));
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let loud = send_request("127.0.0.1:7994", "GET /api HTTP/1.1\r\nHost: LOUD.example:8080\r\n\r\n").await;
+            let quiet = send_request("127.0.0.1:7994", "GET /api HTTP/1.1\r\nHost: quiet.example\r\n\r\n").await;
+            let other = send_request("127.0.0.1:7994", "GET /other HTTP/1.1\r\nHost: loud.example\r\n\r\n").await;
+            let rewritten = send_request("127.0.0.1:7994", "POST /old HTTP/1.1\r\n\r\n").await;
+            let not_rewritten = get("127.0.0.1:7994", "/old").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (loud, quiet, other, rewritten, not_rewritten)
+        };
+        let (report, (loud, quiet, other, rewritten, not_rewritten)) = tokio::join!(
+            server.start("127.0.0.1:7994", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(loud.ends_with("HELLO FROM /API"));
+        assert!(quiet.ends_with("hello from /api"));
+        assert!(other.ends_with("hello from /other"));
+        assert!(rewritten.ends_with("hello from /new"));
+        assert!(not_rewritten.starts_with("HTTP/1.1 404"));
+    }
+
+    struct Recorder(&'static str, Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl middleware::Middleware for Recorder {
+        fn before(&self, request: &mut request::Request) -> Option<Box<dyn Sendable>> {
+            let params = request.params.get("id").map(|id| format!(" id={}", id)).unwrap_or_default();
+            self.1.lock().unwrap().push(format!("before {}{}", self.0, params));
+            None
+        }
+
+        fn after(&self, _: &server::RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+            self.1.lock().unwrap().push(format!("after {}", self.0));
+            response
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_phases() {
+        use middleware::{Ordered, Phase};
+
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("user {}", request.params["id"])))
+        };
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/users/:id", handler);
+        server.add_middleware(Ordered::new(Recorder("send", Arc::clone(&log))).with_after_phase(Phase::PreSend));
+        server.add_middleware(Recorder("plain", Arc::clone(&log)));
+        server.add_middleware(Ordered::new(Recorder("handler", Arc::clone(&log))).with_before_phase(Phase::PreHandler));
+        server.add_middleware(Ordered::new(Recorder("first", Arc::clone(&log))).with_priority(5));
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = get("127.0.0.1:8027", "/users/7").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            response
+        };
+        let (report, response) = tokio::join!(
+            server.start("127.0.0.1:8027", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(response.ends_with("user 7"));
+        assert_eq!(*log.lock().unwrap(), [
+            "before first",
+            "before send",
+            "before plain",
+            // Runs after routing, so it sees the route parameters
+            "before handler id=7",
+            "after handler",
+            "after plain",
+            "after first",
+            "after send",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("page {}", request.route)))
+        };
+        let mut server = server::Webserver::new(1, vec![]);
+        server.add_route("/", handler);
+        server.add_route("/about", handler);
+        server.add_route("/users/:id", handler);
+        let dir = std::env::temp_dir().join(format!("simpleserve-export-{}", std::process::id()));
+
+        let written = server.export(&dir).await.unwrap();
+        assert_eq!(written, vec![dir.join("index.html"), dir.join("about/index.html")]);
+        assert_eq!(std::fs::read_to_string(dir.join("about/index.html")).unwrap(), "page /about");
+
+        let written = server.export_routes(&dir, &["/users/42", "/missing"]).await.unwrap();
+        assert_eq!(written, vec![dir.join("users/42/index.html")]);
+        assert_eq!(server.stats().requests_served(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_response_builder() {
+        let response = response::Response::new(404)
+            .header("Cache-Control", "no-store")
+            .header("Set-Cookie", "a=1")
+            .header("Set-Cookie", "b=2")
+            .header("X-Injected", "oops\r\nSet-Cookie: evil=1")
+            .text("gone");
+        assert_eq!(
+            response.render(),
+            "HTTP/1.1 404 Not Found\r\nCache-Control: no-store\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 4\r\n\r\n"
+        );
+        assert_eq!(response.body(), b"gone");
+        assert_eq!(response::Response::new(799).render(), "HTTP/1.1 799 \r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_respond() {
+        let respond = response::Respond::ok()
+            .html("<p>hi</p>")
+            .cookie(&cookie::Cookie::new("a", "1").http_only())
+            .cache_for(60)
+            .cache_for(120)
+            .header("X-Frame-Options", "DENY");
+        let head = respond.render();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\nSet-Cookie: a=1; HttpOnly\r\nX-Frame-Options: DENY\r\nContent-Type: text/html; charset=utf-8\r\n"));
+        // Only the last caching setting is sent
+        assert!(head.contains("Cache-Control: public, max-age=120\r\nExpires: "));
+        assert!(!head.contains("max-age=60"));
+        assert_eq!(respond.to_response().unwrap().body(), b"<p>hi</p>");
+
+        // A Content-Type header wins over the one of the body
+        let response = response::Respond::created()
+            .header("Content-Type", "application/problem+json")
+            .json("{}")
+            .into_response();
+        assert_eq!(response.headers().get_all("content-type").collect::<Vec<_>>(), ["application/problem+json"]);
+
+        let response: Response = response::Respond::see_other("/login").into();
+        assert_eq!(response.status(), 303);
+        assert_eq!(response.headers().get("location"), Some("/login"));
+        assert!(!response.headers().contains("cache-control"));
+    }
+
+    #[test]
+    fn test_status_lines() {
+        assert!(server::Page::new(404, String::new()).render().starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(server::Page::new(500, String::new()).render().starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(server::Page::new(200, String::new()).render().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(StatusCode::from(418).status_line(), "HTTP/1.1 418 ");
+        assert!(StatusCode::SERVICE_UNAVAILABLE.is_server_error());
+        assert!(!StatusCode::SEE_OTHER.is_success());
+    }
+
+    #[tokio::test]
+    async fn test_cookies() {
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let visits = request.cookie("visits").and_then(|visits| visits.parse::<u32>().ok()).unwrap_or(0);
+            Box::new(response::Response::new(200)
+                .cookie(&cookie::Cookie::new("visits", &(visits + 1).to_string()).with_path("/").http_only())
+                .text(&format!("{} cookies", request.cookies().len())))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/", handler);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = send_request("127.0.0.1:7992", "GET / HTTP/1.1\r\nCookie: visits=2; theme=dark\r\nCookie: lang=en\r\n\r\n").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            response
+        };
+        let (report, response) = tokio::join!(
+            server.start("127.0.0.1:7992", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(response.contains("\r\nSet-Cookie: visits=3; Path=/; HttpOnly\r\n"));
+        assert!(response.ends_with("3 cookies"));
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        use clock::Clock;
+
+        let visit = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            request.session().unwrap().set("theme", "dark");
+            Box::new(server::Page::new(200, String::from("Welcome")))
+        };
+        let login = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let session = request.session().unwrap();
+            session.regenerate();
+            session.set("user", "ferris");
+            Box::new(server::Page::new(200, String::from("Logged in")))
+        };
+        let profile = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let user = request.session().and_then(|session| session.get("user"));
+            Box::new(server::Page::new(200, format!("{:?}", user)))
+        };
+        let logout = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            request.session().unwrap().destroy();
+            Box::new(server::Page::new(200, String::from("Logged out")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        let clock = clock::MockClock::new();
+        server.add_middleware(session::Sessions::new(session::MemoryStore::new().with_clock(clock.clone()))
+            .with_expiry(Duration::from_secs(60))
+            .with_clock(clock.clone()));
+        server.add_route("/visit", visit);
+        server.add_route("/login", login);
+        server.add_route("/profile", profile);
+        server.add_route("/logout", logout);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let session_id = |response: &str| response
+                .split("Set-Cookie: session=")
+                .nth(1)
+                .and_then(|rest| rest.split(';').next())
+                .unwrap()
+                .to_string();
+            let with_cookie = |route: &str, id: &str| format!("GET {} HTTP/1.1\r\nCookie: session={}\r\n\r\n", route, id);
+            let anonymous = get("127.0.0.1:7993", "/profile").await;
+            let visitor = session_id(&get("127.0.0.1:7993", "/visit").await);
+            let logged_in = send_request("127.0.0.1:7993", &with_cookie("/login", &visitor)).await;
+            let id = session_id(&logged_in);
+            let expires = utils::format_http_date(clock.system_time() + Duration::from_secs(60));
+            assert!(logged_in.contains(&format!("Expires={}; Max-Age=60", expires)), "{}", logged_in);
+            // Logging in moves the session to a new id, so the one from before the login is useless
+            assert_ne!(id, visitor);
+            let fixated = send_request("127.0.0.1:7993", &with_cookie("/profile", &visitor)).await;
+            assert!(fixated.ends_with("None"));
+            let profile = send_request("127.0.0.1:7993", &with_cookie("/profile", &id)).await;
+            let logged_out = send_request("127.0.0.1:7993", &with_cookie("/logout", &id)).await;
+            let after_logout = send_request("127.0.0.1:7993", &with_cookie("/profile", &id)).await;
+
+            let id = session_id(&get("127.0.0.1:7993", "/login").await);
+            clock.advance(Duration::from_secs(59));
+            assert!(send_request("127.0.0.1:7993", &with_cookie("/profile", &id)).await.ends_with("Some(\"ferris\")"));
+            clock.advance(Duration::from_secs(1));
+            let expired = send_request("127.0.0.1:7993", &with_cookie("/profile", &id)).await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (anonymous, id, profile, logged_out, after_logout, expired)
+        };
+        let (report, (anonymous, id, profile, logged_out, after_logout, expired)) = tokio::join!(
+            server.start("127.0.0.1:7993", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(!anonymous.contains("Set-Cookie"));
+        assert!(anonymous.ends_with("None"));
+        assert_eq!(id.len(), 32);
+        assert!(profile.ends_with("Some(\"ferris\")"));
+        assert!(logged_out.contains("Set-Cookie: session=; Path=/; Max-Age=0"));
+        assert!(after_logout.ends_with("None"));
+        assert!(expired.ends_with("None"));
+    }
+
+    #[tokio::test]
+    async fn test_self_check() {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let flaky = {
+            let healthy = Arc::clone(&healthy);
+            move |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+                let status = if healthy.load(Ordering::SeqCst) { 200 } else { 500 };
+                Box::new(server::Page::new(status, String::new()))
+            }
+        };
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_receiver(receiver)
+            .with_self_check(self_check::SelfCheck::new(&["/flaky"])
+                .with_interval(Duration::from_millis(20))
+                .with_failure_threshold(2));
+        server.add_route("/flaky", flaky);
+        server.add_route("/", handler);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let failing = get("127.0.0.1:7995", "/").await;
+            healthy.store(true, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let recovered = get("127.0.0.1:7995", "/").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (failing, recovered)
+        };
+        let (report, (failing, recovered)) = tokio::join!(
+            server.start("127.0.0.1:7995", server::ConnectionType::Http, None, None),
+            client
+        );
+        let report = report.unwrap();
+
+        assert!(failing.starts_with("HTTP/1.1 503"));
+        assert!(recovered.ends_with("Hello World!"));
+        // Only the two client requests count as traffic
+        assert_eq!(report.requests_served, 2);
+        let results = server.self_check().unwrap().results();
+        assert_eq!(results[0].route(), "/flaky");
+        assert_eq!(results[0].status(), Some(200));
+        assert_eq!(results[0].consecutive_failures(), 0);
+    }
+
+    #[test]
+    fn test_header_parsing() {
+        use utils::{BareItem, ListMember};
+
+        assert_eq!(utils::split_list("a, \"b, \\\"c\", d"), vec!["a", "\"b, \\\"c\"", "d"]);
+        assert_eq!(utils::parse_quality_list("en;q=2, fr;q=x")[0].1, 1.0);
+        assert_eq!(utils::parse_quality_list("en;q=2, fr;q=x")[1].1, 0.0);
+        let hops = utils::parse_forwarded("for=\"_hidden;x\";by=10.0.0.1");
+        assert_eq!(hops[0]["for"], "_hidden;x");
+        assert_eq!(hops[0]["by"], "10.0.0.1");
+
+        assert_eq!(utils::parse_sf_item("-12.345").unwrap().value, BareItem::Decimal(-12.345));
+        assert_eq!(utils::parse_sf_item("\"a\\\"b\"").unwrap().value, BareItem::String(String::from("a\"b")));
+        assert_eq!(utils::parse_sf_item("foo/bar:baz").unwrap().value, BareItem::Token(String::from("foo/bar:baz")));
+        assert!(utils::parse_sf_item("1234567890123456").is_none());
+        assert!(utils::parse_sf_item("1.2345").is_none());
+        assert!(utils::parse_sf_item("\"a\\b\"").is_none());
+        assert!(utils::parse_sf_item("?2").is_none());
+        assert!(utils::parse_sf_list("1, 2,").is_none());
+        assert!(utils::parse_sf_list("(1 2)3").is_none());
+        assert_eq!(utils::parse_sf_list("").unwrap(), vec![]);
+        let list = utils::parse_sf_list("( ), (1;a 2)").unwrap();
+        assert_eq!(list[0], ListMember::InnerList(vec![], vec![]));
+        assert!(matches!(&list[1], ListMember::InnerList(items, _) if items[0].params.len() == 1));
+        let dictionary = utils::parse_sf_dictionary("a=1, b=2;x, a=3").unwrap();
+        assert_eq!(dictionary.len(), 2);
+        assert_eq!(dictionary[0].0, "a");
+        assert!(matches!(&dictionary[0].1, ListMember::Item(item) if item.value == BareItem::Integer(3)));
+        assert!(utils::parse_sf_dictionary("A=1").is_none());
+    }
+
+    #[test]
+    fn test_multipart() {
+        let body = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"doc\"; filename=\"a;b.txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\nline two\r\n\
+--XyZ--\r\n";
+        let boundary = multipart::boundary("multipart/form-data; boundary=XyZ").unwrap();
+
+        let parts: Vec<_> = multipart::Multipart::new(body, boundary).collect::<Result<_, _>>().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name(), Some("title"));
+        assert_eq!(parts[0].text(), Some("Hello"));
+        assert_eq!(parts[1].filename(), Some("a;b.txt"));
+        assert_eq!(parts[1].content_type(), Some("text/plain"));
+        assert_eq!(parts[1].text(), Some("line one\r\nline two"));
+
+        let mut spilled = multipart::Multipart::new(body, boundary).with_memory_threshold(8);
+        assert!(spilled.next().unwrap().unwrap().file().is_none());
+        let file = spilled.next().unwrap().unwrap().into_file().unwrap();
+        let path = file.path().to_path_buf();
+        assert_eq!(std::fs::read(&path).unwrap(), b"line one\r\nline two");
+        drop(file);
+        assert!(!path.exists());
+
+        let mut limited = multipart::Multipart::new(body, boundary).with_max_parts(1);
+        assert!(limited.next().unwrap().is_ok());
+        assert_eq!(limited.next().unwrap().unwrap_err().reason(), "Too many multipart parts");
+        assert!(limited.next().is_none());
+        let truncated = multipart::Multipart::new(&body[..60], boundary).last().unwrap();
+        assert!(truncated.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload() {
+        let upload = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let mut summary = Vec::new();
+            for part in request.multipart().unwrap() {
+                let part = match part {
+                    Ok(part) => part,
+                    Err(e) => return Box::new(server::Page::new(400, e.reason().to_string())),
+                };
+                match part.file() {
+                    Some(file) => {
+                        let content = std::fs::read(file.path()).unwrap();
+                        assert!(content.iter().all(|byte| *byte == b'x'));
+                        #[cfg(unix)]
+                        {
+                            use std::os::unix::fs::PermissionsExt;
+                            assert_eq!(std::fs::metadata(file.path()).unwrap().permissions().mode() & 0o777, 0o600);
+                        }
+                        summary.push(format!("{}={} bytes on disk", part.name().unwrap(), content.len()));
+                    },
+                    None => summary.push(format!("{}={}", part.name().unwrap(), part.text().unwrap())),
+                }
+            }
+            // The body was parsed while it was read, so it was never buffered
+            assert!(request.body.is_empty());
+            assert!(request.multipart().is_none());
+            Box::new(server::Page::new(200, summary.join(", ")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![])
+            .with_receiver(receiver)
+            .with_multipart(multipart::MultipartOptions::new().with_max_size(4 * 1024 * 1024).with_max_part_size(3 * 1024 * 1024));
+        server.post("/upload", upload);
+        let addr = "127.0.0.1:8022";
+        let form = |file_size: usize| {
+            let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"doc\"; filename=\"big.txt\"\r\n\r\n".to_vec();
+            body.resize(body.len() + file_size, b'x');
+            body.extend_from_slice(b"\r\n--XyZ--\r\n");
+            let mut request = format!(
+                "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            ).into_bytes();
+            request.extend(body);
+            request
+        };
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // Larger than the maximum body size, which only applies to other bodies
+            let size = 2 * server::DEFAULT_MAX_BODY_SIZE;
+            let response = send_request(addr, &String::from_utf8(form(size)).unwrap()).await;
+            assert!(response.ends_with(&format!("title=Hello, doc={} bytes on disk", size)), "{}", response);
+            let response = send_request(addr, &String::from_utf8(form(3 * 1024 * 1024 + 1)).unwrap()).await;
+            assert!(response.starts_with("HTTP/1.1 400"));
+            assert!(response.ends_with("Multipart part too large"));
+            let too_large = format!(
+                "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n",
+                4 * 1024 * 1024 + 1
+            );
+            assert!(send_request(addr, &too_large).await.starts_with("HTTP/1.1 413"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[test]
+    fn test_upload_guard() {
+        use errors::UploadRejection;
+
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"; filename=\"logo.png\"\r\nContent-Type: image/png\r\n\r\n\x89PNG\r\n\x1a\n\0\0\0\rIHDR\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"b\"; filename=\"cat.png\"\r\nContent-Type: image/png\r\n\r\n <html><script>alert(1)</script>\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"c\"; filename=\"notes.txt\"\r\n\r\n<svg onload=alert(1)>\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"d\"; filename=\"page.html\"\r\nContent-Type: text/plain\r\n\r\nHello\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"e\"; filename=\"data\"\r\n\r\n<?xml version=\"1.0\"?><x:script xmlns:x=\"http://www.w3.org/1999/xhtml\">alert(1)</x:script>\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"feed.xsl\"\r\nContent-Type: text/plain\r\n\r\nHello\r\n\
+--XyZ--\r\n";
+        let parts: Vec<_> = multipart::Multipart::new(body, "XyZ").collect::<Result<_, _>>().unwrap();
+        let guard = upload_guard::UploadGuard::new();
+        assert!(guard.check(&parts[0]).is_ok());
+        let mismatch = guard.check(&parts[1]).unwrap_err();
+        assert_eq!(mismatch.reason(), UploadRejection::Mismatch);
+        assert_eq!(mismatch.detected(), Some("text/html"));
+        assert_eq!(guard.check(&parts[2]).unwrap_err().reason(), UploadRejection::Dangerous);
+        assert_eq!(guard.check(&parts[3]).unwrap_err().reason(), UploadRejection::Dangerous);
+        assert!(guard.clone().allow_dangerous(true).check(&parts[3]).is_ok());
+        // Served back as application/xml, where browsers run XHTML script elements
+        let xml = guard.check(&parts[4]).unwrap_err();
+        assert_eq!(xml.reason(), UploadRejection::Dangerous);
+        assert_eq!(xml.detected(), Some("application/xml"));
+        assert_eq!(guard.check(&parts[5]).unwrap_err().reason(), UploadRejection::Dangerous);
+        let images = guard.clone().with_allowed_types(&["image/"]);
+        assert!(images.check(&parts[0]).is_ok());
+        assert_eq!(images.clone().allow_dangerous(true).check(&parts[3]).unwrap_err().reason(), UploadRejection::NotAllowed);
+
+        let dir = std::env::temp_dir().join(format!("simpleserve-quarantine-{}", std::process::id()));
+        let guard = guard.with_quarantine(&dir);
+        let mut parts = parts.into_iter();
+        assert!(guard.screen(parts.next().unwrap()).is_ok());
+        let rejected = guard.screen(parts.next().unwrap()).unwrap_err();
+        let quarantined = rejected.quarantined().unwrap();
+        assert!(quarantined.starts_with(&dir));
+        assert_eq!(std::fs::read(quarantined).unwrap(), b" <html><script>alert(1)</script>");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("[{}]", request.route)))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_receiver(receiver)
+            .with_keep_alive_timeout(Duration::from_millis(100))
+            .with_max_requests_per_connection(2);
+        server.add_route("/:page", handler);
+
+        // Writes the requests without closing our side, so only the server can end the connection
+        async fn exchange(requests: &str) -> String {
+            let mut stream = tokio::net::TcpStream::connect("127.0.0.1:7996").await.unwrap();
+            stream.write_all(requests.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let closed = exchange("GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\nGET /c HTTP/1.1\r\n\r\n").await;
+            let limited = exchange("GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n").await;
+            let idle = exchange("GET /a HTTP/1.1\r\n\r\n").await;
+            let old = exchange("GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n").await;
+            let head = exchange("HEAD /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (closed, limited, idle, old, head)
+        };
+        let (report, (closed, limited, idle, old, head)) = tokio::join!(
+            server.start("127.0.0.1:7996", server::ConnectionType::Http, None, None),
+            client
+        );
+        let report = report.unwrap();
+
+        assert!(closed.contains("[/a]") && closed.ends_with("[/b]"));
+        assert!(limited.contains("[/a]") && limited.ends_with("[/b]"));
+        assert!(idle.ends_with("[/a]"));
+        assert!(old.ends_with("[/a]"));
+        // The HEAD response announces the length of the body without sending it
+        assert_eq!(head.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(head.contains("Content-Length: 4\r\n") && !head.contains("[/a]"));
+        assert!(head.ends_with("[/b]"));
+        assert_eq!(report.connections_accepted, 5);
+        assert_eq!(report.requests_served, 8);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_response() {
+        let numbers = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let chunks = ["one ", "", "two ", "three"].map(|chunk| chunk.as_bytes().to_vec());
+            Box::new(streaming::StreamingResponse::new(200, chunks).header("Content-Length", "3"))
+        };
+        let channel = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            std::thread::spawn(move || {
+                for i in 0..20 {
+                    sender.blocking_send(vec![b'a' + i]).unwrap();
+                }
+            });
+            Box::new(streaming::StreamingResponse::from_receiver(201, receiver))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/numbers", numbers);
+        server.add_route("/channel", channel);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let numbers = get("127.0.0.1:7997", "/numbers").await;
+            let channel = get("127.0.0.1:7997", "/channel").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (numbers, channel)
+        };
+        let (report, (numbers, channel)) = tokio::join!(
+            server.start("127.0.0.1:7997", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert_eq!(
+            numbers,
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\none \r\n4\r\ntwo \r\n5\r\nthree\r\n0\r\n\r\n"
+        );
+        assert!(channel.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert_eq!(channel.matches("1\r\n").count(), 20);
+        assert!(channel.ends_with("1\r\nt\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_run_as_unknown_user() {
+        let error = privileges::Privileges::new("simpleserve-no-such-user", "root").drop_privileges().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        // The server refuses to serve rather than keep running with its original privileges
+        let mut server = server::Webserver::new(1, vec![]).run_as("simpleserve-no-such-user", "root");
+        assert!(server.start("127.0.0.1:7998", server::ConnectionType::Http, None, None).await.is_err());
+        let mut server = server::Webserver::new(1, vec![]).with_chroot("/tmp");
+        assert!(server.start("127.0.0.1:7998", server::ConnectionType::Http, None, None).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chroot() {
+        // A chroot cannot be undone, so the server runs in a process of its own
+        if std::env::var_os("SIMPLESERVE_CHROOT_TEST").is_none() {
+            if unsafe { libc::geteuid() } != 0 {
+                println!("Skipping the chroot test, it needs root");
+                return;
+            }
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::test_chroot", "--nocapture"])
+                .env("SIMPLESERVE_CHROOT_TEST", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(format!("simpleserve-chroot-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("public")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "Secret").unwrap();
+        std::fs::write(dir.join("public/secret.txt"), "Secret").unwrap();
+        std::fs::write(dir.join("public/index.html"), "Home").unwrap();
+        let blacklist = vec![dir.join("secret.txt"), dir.join("public/secret.txt")];
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, blacklist)
+            .with_receiver(receiver)
+            .run_as("root", "root")
+            .with_chroot(&dir);
+        server.serve_directory("/static", dir.join("public")).unwrap();
+        let addr = "127.0.0.1:8021";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(get(addr, "/secret.txt").await.starts_with("HTTP/1.1 403"));
+            assert!(get(addr, "/static/secret.txt").await.starts_with("HTTP/1.1 403"));
+            assert!(get(addr, "/static/").await.ends_with("Home"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (served, _) = runtime.block_on(async { tokio::join!(server.start(addr, ConnectionType::Http, None, None), client) });
+        served.unwrap();
+        assert_eq!(server.blacklisted_paths(), &[path::PathBuf::from("/secret.txt"), path::PathBuf::from("/public/secret.txt")]);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let events = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            std::thread::spawn(move || {
+                sender.blocking_send(sse::Event::new("first").with_id("1")).unwrap();
+                std::thread::sleep(Duration::from_millis(60));
+                sender.blocking_send(sse::Event::new("a\nb").with_event("update\ndata: injected")).unwrap();
+            });
+            Box::new(sse::EventStream::new(receiver)
+                .with_keep_alive(Duration::from_millis(20))
+                .with_retry(Duration::from_secs(3)))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/events", events);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let stream = get("127.0.0.1:7999", "/events").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            stream
+        };
+        let (report, stream) = tokio::join!(
+            server.start("127.0.0.1:7999", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let (head, body) = stream.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Content-Type: text/event-stream"));
+        assert!(body.starts_with("retry: 3000\n\nid: 1\ndata: first\n\n: keep-alive\n\n"));
+        assert!(body.ends_with("event: update data: injected\ndata: a\ndata: b\n\n"));
+    }
+
+    #[tokio::test]
+    async fn test_chaos() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let chaos = chaos::Chaos::new(chaos::ChaosSettings::new().with_failures(1.0));
+        let controller = chaos.controller();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/", handler);
+        server.add_middleware(chaos);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let failed = get("127.0.0.1:8001", "/").await;
+            controller.update(|settings| settings.with_failures(0.0).with_drops(1.0));
+            let dropped = get("127.0.0.1:8001", "/").await;
+            controller.set(chaos::ChaosSettings::new().with_delay(1.0, Duration::from_millis(100)));
+            let started = Instant::now();
+            let delayed = get("127.0.0.1:8001", "/").await;
+            let delay = started.elapsed();
+            controller.disable();
+            let healthy = get("127.0.0.1:8001", "/").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (failed, dropped, delayed, delay, healthy)
+        };
+        let (report, (failed, dropped, delayed, delay, healthy)) = tokio::join!(
+            server.start("127.0.0.1:8001", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(failed.starts_with("HTTP/1.1 500"));
+        assert!(dropped.is_empty());
+        assert!(delayed.ends_with("Hello World!"));
+        assert!(delay >= Duration::from_millis(100));
+        assert!(healthy.ends_with("Hello World!"));
+        assert_eq!(controller.settings(), chaos::ChaosSettings::new());
+    }
+
+    fn echo_socket<'a>(mut socket: websocket::WebSocket<'a>) -> websocket::WebSocketFuture<'a> {
+        Box::pin(async move {
+            while let Ok(Some(message)) = socket.receive().await {
+                let reply = match message {
+                    websocket::Message::Text(text) => format!("{} says {}", socket.param("name").unwrap(), text),
+                    websocket::Message::Binary(data) => format!("{} bytes", data.len()),
+                };
+                socket.send_text(&reply).await.unwrap();
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Client frames are masked, as the protocol requires
+        fn frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+            let mask = [1, 2, 3, 4];
+            let mut frame = vec![if fin { 0x80 | opcode } else { opcode }, 0x80 | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+            frame
+        }
+
+        async fn read_frame(stream: &mut tokio::net::TcpStream) -> (u8, Vec<u8>) {
+            let mut head = [0; 2];
+            stream.read_exact(&mut head).await.unwrap();
+            let mut payload = vec![0; (head[1] & 0x7F) as usize];
+            stream.read_exact(&mut payload).await.unwrap();
+            (head[0], payload)
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.on_websocket("/chat/:name", echo_socket);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let plain = get("127.0.0.1:8002", "/chat/ferris").await;
+
+            let mut stream = tokio::net::TcpStream::connect("127.0.0.1:8002").await.unwrap();
+            stream.write_all(b"GET /chat/ferris HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+
+            let mut frames = Vec::new();
+            stream.write_all(&frame(0x1, true, b"hello")).await.unwrap();
+            frames.push(read_frame(&mut stream).await);
+            // A fragmented message, with a ping in between
+            stream.write_all(&frame(0x2, false, &[1, 2])).await.unwrap();
+            stream.write_all(&frame(0x9, true, b"ping")).await.unwrap();
+            stream.write_all(&frame(0x0, true, &[3])).await.unwrap();
+            frames.push(read_frame(&mut stream).await);
+            frames.push(read_frame(&mut stream).await);
+            stream.write_all(&frame(0x8, true, &1000u16.to_be_bytes())).await.unwrap();
+            frames.push(read_frame(&mut stream).await);
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (plain, String::from_utf8(head).unwrap(), frames)
+        };
+        let (report, (plain, head, frames)) = tokio::join!(
+            server.start("127.0.0.1:8002", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(plain.starts_with("HTTP/1.1 426 "));
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert_eq!(frames, vec![
+            (0x81, b"ferris says hello".to_vec()),
+            (0x8A, b"ping".to_vec()),
+            (0x81, b"3 bytes".to_vec()),
+            (0x88, 1000u16.to_be_bytes().to_vec()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_compression() {
+        let large = "Hello World! ".repeat(200);
+        let handler = move |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            // Keeps the only worker busy, so the other connections wait in the queue
+            if request.route == "/slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            Box::new(server::Page::new(200, large.clone()))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/slow", handler.clone());
+        server.add_route("/fast", handler);
+        server.add_middleware(compression::Compression::new()
+            .with_load_thresholds(compression::LoadThresholds::new().with_queue_depth(1, 2)));
+
+        let addr = "127.0.0.1:8030";
+        let fetch = |route: &'static str, delay: u64| async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", route);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let client = async {
+            // Two connections are queued when the slow one is answered, then one, then none
+            let responses = tokio::join!(fetch("/slow", 50), fetch("/fast", 100), fetch("/fast", 150));
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (skipped, reduced, full)) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(skipped.ends_with("Hello World! "));
+        assert!(!skipped.contains("Content-Encoding"));
+        assert!(reduced.contains("Content-Encoding: gzip\r\n"));
+        assert!(full.contains("Content-Encoding: gzip\r\n"));
+        assert_eq!(server.stats().compressions_skipped(), 1);
+        assert_eq!(server.stats().compressions_reduced(), 1);
+        assert_eq!(server.stats().connections_queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use std::io::Read;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn fetch(accept_encoding: &str, route: &str) -> (String, Vec<u8>) {
+            let mut stream = tokio::net::TcpStream::connect("127.0.0.1:8003").await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", route, accept_encoding);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            let split = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+            let body = response.split_off(split);
+            (String::from_utf8(response).unwrap(), body)
+        }
+
+        let large = "Hello World! ".repeat(200);
+        let handler = move |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            match request.route {
+                "/small" => Box::new(server::Page::new(200, String::from("Hello World!"))),
+                "/image" => Box::new(response::Response::new(200).header("Content-Type", "image/png").text(&large)),
+                "/tagged" => Box::new(response::Response::new(200).header("ETag", "\"v1\"").text(&large)),
+                _ => Box::new(server::Page::new(200, large.clone())),
+            }
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/large", handler.clone());
+        server.add_route("/image", handler.clone());
+        server.add_route("/tagged", handler.clone());
+        server.add_route("/small", handler);
+        server.add_middleware(compression::Compression::new());
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let gzip = fetch("deflate;q=0.5, gzip", "/large").await;
+            let deflate = fetch("gzip;q=0, br;q=0, *", "/large").await;
+            let identity = fetch("identity", "/large").await;
+            let small = fetch("gzip", "/small").await;
+            let image = fetch("gzip", "/image").await;
+            let brotli = fetch("gzip, br", "/large").await;
+            // The compressed bytes are not the ones the strong tag was made for
+            assert!(fetch("gzip", "/tagged").await.0.contains("ETag: W/\"v1\"\r\n"));
+            assert!(fetch("identity", "/tagged").await.0.contains("ETag: \"v1\"\r\n"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (gzip, deflate, identity, small, image, brotli)
+        };
+        let (report, (gzip, deflate, identity, small, image, brotli)) = tokio::join!(
+            server.start("127.0.0.1:8003", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let expected = "Hello World! ".repeat(200);
+        assert!(gzip.0.contains("Content-Encoding: gzip\r\n"));
+        assert!(gzip.0.contains("Vary: Accept-Encoding\r\n"));
+        assert!(gzip.0.contains(&format!("Content-Length: {}\r\n", gzip.1.len())));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&gzip.1[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+
+        assert!(deflate.0.contains("Content-Encoding: deflate\r\n"));
+        let mut decoded = String::new();
+        flate2::read::ZlibDecoder::new(&deflate.1[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+
+        assert!(!identity.0.contains("Content-Encoding"));
+        assert_eq!(identity.1, expected.as_bytes());
+        assert!(!small.0.contains("Content-Encoding"));
+        assert_eq!(small.1, b"Hello World!");
+        assert!(!image.0.contains("Content-Encoding"));
+
+        #[cfg(feature = "brotli")]
+        {
+            assert!(brotli.0.contains("Content-Encoding: br\r\n"));
+            let mut decoded = String::new();
+            brotli::Decompressor::new(&brotli.1[..], 4096).read_to_string(&mut decoded).unwrap();
+            assert_eq!(decoded, expected);
+        }
+        #[cfg(not(feature = "brotli"))]
+        assert!(brotli.0.contains("Content-Encoding: gzip\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Bytes::new(200, "Cargo.toml").unwrap().with_range(request.header("range")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/file", handler);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let range = |range: &str| format!("GET /file HTTP/1.1\r\nRange: {}\r\n\r\n", range);
+            let first = send_request("127.0.0.1:8004", &range("bytes=0-8")).await;
+            let last = send_request("127.0.0.1:8004", &range("bytes=-3")).await;
+            let past_end = send_request("127.0.0.1:8004", &range("bytes=100000-")).await;
+            let full = get("127.0.0.1:8004", "/file").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (first, last, past_end, full)
+        };
+        let (report, (first, last, past_end, full)) = tokio::join!(
+            server.start("127.0.0.1:8004", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let file = std::fs::read_to_string("Cargo.toml").unwrap();
+        assert!(first.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(first.contains(&format!("Content-Range: bytes 0-8/{}\r\n", file.len())));
+        assert!(first.contains("Content-Length: 9\r\n"));
+        assert!(first.ends_with("\r\n\r\n[package]"));
+        assert!(last.contains(&format!("Content-Range: bytes {}-{}/{}\r\n", file.len() - 3, file.len() - 1, file.len())));
+        assert!(last.ends_with(&file[file.len() - 3..]));
+        assert!(past_end.starts_with("HTTP/1.1 416 "));
+        assert!(past_end.contains(&format!("Content-Range: bytes */{}\r\n", file.len())));
+        assert!(past_end.ends_with("\r\n\r\n"));
+        assert!(full.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(full.contains("Accept-Ranges: bytes\r\n"));
+        assert!(full.ends_with(&file));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let conditional = |header: &str| format!("GET /Cargo.toml HTTP/1.1\r\n{}\r\n\r\n", header);
+            let full = get("127.0.0.1:8005", "/Cargo.toml").await;
+            let header = |name: &str| {
+                let line = full.lines().find(|line| line.starts_with(name)).unwrap();
+                String::from(&line[name.len() + 2..])
+            };
+            let (etag, modified) = (header("ETag"), header("Last-Modified"));
+            let matching = send_request("127.0.0.1:8005", &conditional(&format!("If-None-Match: \"x\", {}", etag))).await;
+            let changed = send_request("127.0.0.1:8005", &conditional("If-None-Match: \"x\"")).await;
+            let unmodified = send_request("127.0.0.1:8005", &conditional(&format!("If-Modified-Since: {}", modified))).await;
+            let modified_since = send_request("127.0.0.1:8005", &conditional("If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT")).await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (full, etag, matching, changed, unmodified, modified_since)
+        };
+        let (report, (full, etag, matching, changed, unmodified, modified_since)) = tokio::join!(
+            server.start("127.0.0.1:8005", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let file = std::fs::read_to_string("Cargo.toml").unwrap();
+        assert!(full.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(etag.starts_with("W/\"") && etag.ends_with('"'));
+        assert!(matching.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(matching.contains(&format!("ETag: {}\r\n", etag)));
+        assert!(matching.ends_with("\r\n\r\n"));
+        assert!(!matching.contains("Content-Length"));
+        assert!(changed.ends_with(&file));
+        assert!(unmodified.starts_with("HTTP/1.1 304 "));
+        assert!(modified_since.ends_with(&file));
+    }
+
+    #[tokio::test]
+    async fn test_cache_policy() {
+        use cache_policy::{CacheControl, CachePolicy};
+        use clock::Clock;
+
+        let clock = clock::MockClock::new();
+        let policy = CachePolicy::new()
+            .with_extension("toml", CacheControl::MaxAge(Duration::from_secs(60)))
+            .with_route("/src/", CacheControl::NoStore)
+            .with_etag_mode(etag::ETagMode::Strong);
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_receiver(receiver)
+            .with_clock(clock.clone())
+            .with_cache_policy(policy);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let toml = get("127.0.0.1:8006", "/Cargo.toml").await;
+            let source = get("127.0.0.1:8006", "/src/lib.rs").await;
+            let other = get("127.0.0.1:8006", "/Cargo.lock").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (toml, source, other)
+        };
+        let (report, (toml, source, other)) = tokio::join!(
+            server.start("127.0.0.1:8006", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let expires = utils::format_http_date(clock.system_time() + Duration::from_secs(60));
+        assert!(toml.contains("Cache-Control: public, max-age=60\r\n"));
+        assert!(toml.contains(&format!("Expires: {}\r\n", expires)));
+        assert!(toml.contains("Last-Modified: "));
+        let strong = etag::ETag::for_content(&std::fs::read("Cargo.toml").unwrap());
+        assert!(toml.contains(&format!("ETag: {}\r\n", strong)));
+        assert!(source.contains("Cache-Control: no-store\r\n"));
+        assert!(source.contains("Expires: Thu, 01 Jan 1970 00:00:00 GMT\r\n"));
+        assert!(other.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!other.contains("Cache-Control"));
+    }
+
+    #[tokio::test]
+    async fn test_serve_assets() {
+        let dir = std::env::temp_dir().join(format!("simpleserve-assets-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("js")).unwrap();
+        std::fs::write(dir.join("js/app.js"), "console.log(1)").unwrap();
+        std::fs::write(dir.join(".env"), "SECRET=1").unwrap();
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        let manifest = server.serve_assets("/assets/", &dir).unwrap();
+        server.add_route("/", |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("<script src=\"{}\"></script>", request.asset("js/app.js").unwrap())))
+        });
+        assert_eq!(manifest.len(), 1);
+        let hashed = manifest.lookup("js/app.js").unwrap();
+        assert_eq!(hashed, format!("/assets/{}", static_files::hashed_name("js/app.js", b"console.log(1)")));
+
+        let addr = "127.0.0.1:8031";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut responses = Vec::new();
+            for route in ["/", hashed.as_str(), "/assets/js/app.js", "/assets/js/app.00000000.js"] {
+                responses.push(get(addr, route).await);
+            }
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, responses) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(responses[0].ends_with(&format!("<script src=\"{}\"></script>", hashed)));
+        assert!(responses[1].contains("Cache-Control: public, max-age=31536000, immutable\r\n"));
+        assert!(responses[1].contains("Content-Type: application/javascript\r\n"));
+        assert!(responses[1].ends_with("console.log(1)"));
+        // The file is still served under its own name, without the promise that it never changes
+        assert!(responses[2].ends_with("console.log(1)"));
+        assert!(!responses[2].contains("immutable"));
+        assert!(responses[3].starts_with("HTTP/1.1 404 "));
+    }
+
+    #[tokio::test]
+    async fn test_alt_svc() {
+        use alt_svc::{AltService, AltSvc};
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver).with_alt_svc(AltSvc::new()
+            .with_service(AltService::new("h3", ":443").with_max_age(Duration::from_secs(86400)))
+            .advertise_h2c());
+        server.add_route("/", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        });
+        server.add_route("/own", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(response::Response::new(200).header("Alt-Svc", "clear").text("Cleared"))
+        });
+
+        let addr = "127.0.0.1:8032";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let responses = (get(addr, "/").await, get(addr, "/own").await);
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (advertised, own)) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(advertised.contains("Alt-Svc: h3=\":443\"; ma=86400\r\n"));
+        assert!(advertised.contains("Upgrade: h2c\r\n"));
+        assert!(advertised.ends_with("Hello World!"));
+        // A header set by the handler is kept
+        assert!(own.contains("Alt-Svc: clear\r\n"));
+        assert!(!own.contains("h3="));
+    }
+
+    #[tokio::test]
+    async fn test_serve_directory() {
+        let dir = std::env::temp_dir().join(format!("simpleserve-static-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("css")).unwrap();
+        std::fs::write(dir.join("index.html"), "Home").unwrap();
+        std::fs::write(dir.join("css/app.css"), "body {}").unwrap();
+        std::fs::write(dir.join("secret.txt"), "Secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(std::fs::canonicalize("Cargo.toml").unwrap(), dir.join("escape")).unwrap();
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![dir.join("secret.txt")]).with_receiver(receiver);
+        server.serve_directory("/static", &dir).unwrap();
+        server.serve_directory_with_options("/files", &dir, static_files::MountOptions::new().with_listing(true)).unwrap();
+        assert!(server.serve_directory("/missing", dir.join("missing")).is_err());
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut responses = Vec::new();
+            for route in [
+                "/static", "/static/", "/static/css/app.css", "/static/secret.txt", "/static/nothing", "/static/escape",
+                "/static/css/", "/files/css/",
+            ] {
+                responses.push(get("127.0.0.1:8007", route).await);
+            }
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, responses) = tokio::join!(
+            server.start("127.0.0.1:8007", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(responses[0].starts_with("HTTP/1.1 301 "));
+        assert!(responses[0].contains("Location: /static/\r\n"));
+        assert!(responses[1].ends_with("\r\n\r\nHome"));
+        assert!(responses[2].contains("Content-Type: text/css\r\n"));
+        assert!(responses[2].ends_with("body {}"));
+        assert!(responses[3].starts_with("HTTP/1.1 403 "));
+        assert!(responses[4].starts_with("HTTP/1.1 404 "));
+        #[cfg(unix)]
+        assert!(responses[5].starts_with("HTTP/1.1 403 "));
+        assert!(responses[6].starts_with("HTTP/1.1 404 "));
+        assert!(responses[7].starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(responses[7].contains("Index of /files/css/"));
+        assert!(responses[7].contains("<a href=\"../\">../</a>"));
+        assert!(responses[7].contains("<a href=\"app.css\">app.css</a></td><td>7</td>"));
+    }
+
+    #[tokio::test]
+    async fn test_mount_preload() {
+        let dir = std::env::temp_dir().join(format!("simpleserve-preload-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("css")).unwrap();
+        std::fs::write(dir.join("index.html"), "Home").unwrap();
+        std::fs::write(dir.join("css/app.css"), "body {}").unwrap();
+        std::fs::write(dir.join("large.txt"), "Too large to fit").unwrap();
+        std::fs::write(dir.join(".hidden"), "Hidden").unwrap();
+
+        // Files that do not fit in the budget are skipped, smaller ones after them still fit
+        let cache = static_files::FileCache::new();
+        assert_eq!(cache.preload(&ThreadPool::new(2), &dir.canonicalize().unwrap(), 12).unwrap(), (2, 11));
+
+        let policy = cache_policy::CachePolicy::new().with_etag_mode(etag::ETagMode::Strong);
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver).with_cache_policy(policy);
+        server.serve_directory_with_options("/files", &dir, static_files::MountOptions::new().with_preload(12)).unwrap();
+
+        let addr = "127.0.0.1:8042";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let cached = get(addr, "/files/css/app.css").await;
+            std::fs::write(dir.join("css/app.css"), "body { margin: 0 }").unwrap();
+            let changed = get(addr, "/files/css/app.css").await;
+            let large = get(addr, "/files/large.txt").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (cached, changed, large)
+        };
+        let (report, (cached, changed, large)) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(cached.contains("Content-Type: text/css\r\n"));
+        assert!(cached.contains(&format!("ETag: {}\r\n", etag::ETag::for_content(b"body {}"))));
+        assert!(cached.ends_with("\r\n\r\nbody {}"));
+        // A file changed since it was preloaded is read again
+        assert!(changed.contains(&format!("ETag: {}\r\n", etag::ETag::for_content(b"body { margin: 0 }"))));
+        assert!(changed.ends_with("\r\n\r\nbody { margin: 0 }"));
+        assert!(large.ends_with("\r\n\r\nToo large to fit"));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let slow = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            std::thread::sleep(Duration::from_millis(200));
+            Box::new(server::Page::new(200, String::from("Done")))
+        };
+        let mut server = server::Webserver::new(4, vec![]);
+        server.add_route("/slow", slow);
+        let shutdown = server.shutdown_handle();
+        let addr = "127.0.0.1:8009";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = tokio::spawn(get(addr, "/slow"));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            shutdown.shutdown();
+            assert!(shutdown.is_shutting_down());
+            let response = request.await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.ends_with("Done"));
+            drop(idle);
+        };
+        let (report, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        let report = report.unwrap();
+        assert_eq!(report.connections_drained, 2);
+        assert_eq!(report.requests_completed_during_drain, 1);
+        assert_eq!(report.connections_force_closed, 0);
+
+        let mut server = server::Webserver::new(4, vec![]).with_shutdown_timeout(Duration::from_millis(20));
+        server.add_async_route("/slow", |_: &server::RequestInfo| -> server::HandlerFuture {
+            Box::pin(async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                Box::new(server::Page::new(200, String::from("Done"))) as Box<dyn Sendable>
+            })
+        });
+        let shutdown = server.shutdown_handle();
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let request = tokio::spawn(get(addr, "/slow"));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            shutdown.shutdown();
+            request.await.unwrap()
+        };
+        let (report, response) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        let report = report.unwrap();
+        assert_eq!(report.connections_drained, 0);
+        assert_eq!(report.connections_force_closed, 1);
+        // The connection was closed without an answer
+        assert_eq!(response, "");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_streams() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut server = server::Webserver::new(2, vec![]).with_shutdown_timeout(Duration::from_millis(100));
+        let news = server.sse_channel("news");
+        server.add_route("/news", |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(request.sse_channel("news").unwrap().subscribe())
+        });
+        let shutdown = server.shutdown_handle();
+        let addr = "127.0.0.1:8026";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // Never disconnects, and the stream only writes when there is an event
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /news HTTP/1.1\r\n\r\n").await.unwrap();
+            while news.subscribers() < 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            shutdown.shutdown();
+            stream
+        };
+        let started = std::time::Instant::now();
+        let (report, mut stream) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        let report = report.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        assert_eq!(report.connections_drained, 0);
+        assert_eq!(report.connections_force_closed, 1);
+        assert_eq!(server.stats().connections_active(), 0);
+        // The server closed the stream
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut received)).await;
+        assert!(read.is_ok());
+        assert!(String::from_utf8_lossy(&received).starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_handle_signals() {
+        let mut server = server::Webserver::new(1, vec![]).handle_signals();
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+        };
+        let (report, _) = tokio::join!(server.start("127.0.0.1:8010", ConnectionType::Http, None, None), client);
+        assert_eq!(report.unwrap().connections_accepted, 0);
+    }
+
+    #[tokio::test]
+    async fn test_localized_pages() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let dir = std::env::temp_dir().join(format!("simpleserve-localized-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "Notes").unwrap();
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(201, String::from("Created")))
+        };
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.set_theme(theme::Theme::default()
+            .with_translation("fr", 404, "Introuvable", "La page est introuvable.")
+            .with_translation("pt-BR", 404, "Não encontrado", "A página não foi encontrada.")
+            .with_translation("fr", 405, "Méthode non autorisée", "Cette méthode n'est pas autorisée ici.")
+            .with_translation("fr", 200, "Contenu de", "Les fichiers de ce dossier."));
+        server.post("/notes", handler);
+        server.serve_directory_with_options("/files", &dir, static_files::MountOptions::new().with_listing(true)).unwrap();
+        let addr = "127.0.0.1:8011";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let french = send_request(addr, "GET /missing HTTP/1.1\r\nAccept-Language: fr-CA, en;q=0.8\r\n\r\n").await;
+            assert!(french.starts_with("HTTP/1.1 404"));
+            assert!(french.contains("<h1>404 Introuvable</h1>"));
+            // Caches keep one copy per language
+            assert!(french.contains("\r\nContent-Language: fr\r\n"));
+            assert!(french.contains("\r\nVary: Accept-Language\r\n"));
+            assert!(french.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+            let portuguese = send_request(addr, "GET /missing HTTP/1.1\r\nAccept-Language: de, pt;q=0.5\r\n\r\n").await;
+            assert!(portuguese.contains("A página não foi encontrada."));
+            assert!(portuguese.contains("\r\nContent-Language: pt-br\r\n"));
+            let default = send_request(addr, "GET /missing HTTP/1.1\r\nAccept-Language: de, fr;q=0\r\n\r\n").await;
+            assert!(default.contains("<h1>404 Not Found</h1>"));
+            assert!(default.contains("\r\nContent-Language: en\r\n"));
+            assert!(default.contains("\r\nVary: Accept-Language\r\n"));
+
+            let not_allowed = send_request(addr, "GET /notes HTTP/1.1\r\nAccept-Language: fr\r\n\r\n").await;
+            assert!(not_allowed.starts_with("HTTP/1.1 405"));
+            assert!(not_allowed.contains("\r\nAllow: POST\r\n"));
+            assert!(not_allowed.contains("Cette méthode n'est pas autorisée ici."));
+            assert_eq!(not_allowed.matches("Content-Type:").count(), 1);
+            let listing = send_request(addr, "GET /files/ HTTP/1.1\r\nAccept-Language: fr\r\n\r\n").await;
+            assert!(listing.contains("Contenu de /files/"), "{}", listing);
+            assert!(listing.contains("<p>Les fichiers de ce dossier.</p>"));
+            assert!(listing.contains("notes.txt"));
+            assert!(listing.contains("\r\nContent-Language: fr\r\n"));
+            let listing = get(addr, "/files/").await;
+            assert!(listing.contains("Index of /files/"));
+            assert!(listing.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+            // Pages without translations are the same for everyone
+            let created = send_request(addr, "POST /notes HTTP/1.1\r\nAccept-Language: fr\r\n\r\n").await;
+            assert!(!created.contains("Vary"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_smuggling() {
+        use std::sync::atomic::AtomicUsize;
+
+        let smuggled = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        });
+        let hits = Arc::clone(&smuggled);
+        server.add_route("/smuggled", move |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            hits.fetch_add(1, Ordering::SeqCst);
+            Box::new(server::Page::new(200, String::from("Smuggled")))
+        });
+        let addr = "127.0.0.1:8023";
+        // Each attack follows a request on the same connection, and hides a request in what a
+        // server reading the body differently would take for the next one
+        let vectors = [
+            ("Content-Length: 43\r\nTransfer-Encoding: chunked", "501"),
+            ("Transfer-Encoding: chunked\r\nContent-Length: 43", "501"),
+            ("Transfer-Encoding : chunked", "400"),
+            ("Content-Length : 0", "400"),
+            ("Content-Length: 0\r\nContent-Length: 43", "400"),
+            ("Content-Length: 43, 43", "400"),
+            ("Content-Length: +0", "400"),
+            ("X-Padding: a\r\n Content-Length: 0", "400"),
+            ("X-Padding: a\r\n\tTransfer-Encoding: chunked", "400"),
+            ("X-Padding: a\nContent-Length: 43", "400"),
+            ("X-Padding: a\rContent-Length: 43", "400"),
+            ("Content-Length: 43\n", "400"),
+            ("Transfer-Encoding\x00: chunked", "400"),
+            ("[Content-Length]: 43", "400"),
+            ("X-Padding: a\x00b\r\nContent-Length: 43", "400"),
+        ];
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            for (headers, status) in vectors {
+                let request = format!(
+                    "GET / HTTP/1.1\r\n\r\nPOST / HTTP/1.1\r\n{}\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+                    headers
+                );
+                let response = send_request(addr, &request).await;
+                assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", headers, response);
+                let rejected = format!("HTTP/1.1 {} ", status);
+                assert!(response.contains(&rejected), "{:?}: {}", headers, response);
+                assert!(!response.contains("Smuggled"), "{:?}: {}", headers, response);
+            }
+            // A head ending in bare line feeds is refused as soon as it arrives
+            let response = send_request(addr, "GET /smuggled HTTP/1.1\n\n").await;
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+            // Even the same Content-Length twice is rejected, proxies differ in which one they use
+            let response = send_request(addr, "POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nokGET /smuggled HTTP/1.1\r\n\r\n").await;
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+            assert!(!response.contains("Smuggled"), "{}", response);
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+        assert_eq!(smuggled.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeouts() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![])
+            .with_receiver(receiver)
+            .with_header_timeout(Duration::from_millis(100))
+            .with_read_timeout(Duration::from_millis(100));
+        server.add_route("/", handler);
+        server.add_route("/busy", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            std::thread::sleep(Duration::from_millis(400));
+            Box::new(server::Page::new(200, String::from("Done")))
+        });
+        let addr = "127.0.0.1:8012";
+        let stall = |request: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            // Without closing our side, only the timeout ends the connection
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let started = std::time::Instant::now();
+            let slow_head = stall("GET / HTTP/1.1\r\nHost: exa").await;
+            assert!(slow_head.starts_with("HTTP/1.1 408 Request Timeout"));
+            assert!(started.elapsed() < Duration::from_secs(2));
+            let slow_body = stall("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").await;
+            assert!(slow_body.starts_with("HTTP/1.1 408 Request Timeout"));
+            assert!(get(addr, "/").await.ends_with("Hello World!"));
+
+            // The header timeout counts from the accept, not from when a worker picks the connection up
+            let busy = async {
+                tokio::join!(get(addr, "/busy"), get(addr, "/busy"))
+            };
+            let queued = async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+                // Finishes the head after a worker is free, but later than the deadline
+                tokio::time::sleep(Duration::from_millis(430)).await;
+                let _ = stream.write_all(b"\r\n").await;
+                let mut response = String::new();
+                let _ = stream.read_to_string(&mut response).await;
+                response
+            };
+            let (_, queued) = tokio::join!(busy, queued);
+            assert!(queued.starts_with("HTTP/1.1 408 Request Timeout"), "{}", queued);
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sse_channel() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let subscribe = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(request.sse_channel("news").unwrap().subscribe())
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        let news = server.sse_channel("news");
+        assert_eq!(server.sse_channel("news").name(), "news");
+        // A stream ends with the connection, so the connection cannot be kept alive after it
+        assert!(!news.subscribe().is_delimited());
+        assert!(server::Page::new(200, String::new()).is_delimited());
+        server.add_route("/news", subscribe);
+        let addr = "127.0.0.1:8013";
+        let connect = || async {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /news HTTP/1.1\r\n\r\n").await.unwrap();
+            stream
+        };
+        let read_until = |mut stream: tokio::net::TcpStream, needle: &'static str| async move {
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !String::from_utf8_lossy(&received).contains(needle) {
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&buffer[..n]);
+            }
+            stream
+        };
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let (first, second) = (connect().await, connect().await);
+            while news.subscribers() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(news.publish(sse::Event::new("hello")), 2);
+            let first = read_until(first, "data: hello\n\n").await;
+            let second = read_until(second, "data: hello\n\n").await;
+
+            // Streams notice a client is gone when they write to it
+            drop(first);
+            let mut published = 0;
+            while news.subscribers() > 1 {
+                published += 1;
+                assert!(published < 100);
+                news.publish(sse::Event::new("again"));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            drop(second);
+            while news.subscribers() > 0 {
+                news.publish(sse::Event::new("again"));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(news.publish(sse::Event::new("nobody")), 0);
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (report, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        assert_eq!(report.unwrap().connections_force_closed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_body_transform() {
+        use std::io::Read;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn fetch(accept_encoding: &str, route: &str) -> (String, Vec<u8>) {
+            let mut stream = tokio::net::TcpStream::connect("127.0.0.1:8033").await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", route, accept_encoding);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            let split = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+            let body = response.split_off(split);
+            (String::from_utf8(response).unwrap(), body)
+        }
+
+        let page = format!("<html><body>{}</body></html>", "Hello World! ".repeat(100));
+        let handler = move |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            match request.route {
+                "/json" => Box::new(response::Response::new(200).header("Content-Type", "application/json").text("{\"body\": \"</body>\"}")),
+                "/large" => Box::new(server::Page::new(200, format!("{}</body>", "a".repeat(4096)))),
+                _ => Box::new(response::Response::new(200).header("Content-Type", "text/html").header("ETag", "\"v1\"").text(&page)),
+            }
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.add_route("/", handler.clone());
+        server.add_route("/json", handler.clone());
+        server.add_route("/large", handler);
+        // Added after compression, and still run before it
+        server.add_middleware(compression::Compression::new());
+        server.add_middleware(transform::Transform::new(|_: &server::RequestInfo, body: &[u8]| {
+            let page = std::str::from_utf8(body).ok()?;
+            let end = page.rfind("</body>")?;
+            Some(format!("{}<script></script>{}", &page[..end], &page[end..]).into_bytes())
+        }).for_types(&["text/html"]).with_max_size(2048));
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let responses = (fetch("identity", "/").await, fetch("gzip", "/").await, fetch("identity", "/json").await, fetch("identity", "/large").await);
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (plain, gzip, json, large)) = tokio::join!(
+            server.start("127.0.0.1:8033", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        let plain_body = String::from_utf8(plain.1).unwrap();
+        assert!(plain_body.ends_with("Hello World! <script></script></body></html>"));
+        assert!(plain.0.contains(&format!("Content-Length: {}\r\n", plain_body.len())));
+        assert!(plain.0.contains("ETag: W/\"v1\"\r\n"));
+        assert!(gzip.0.contains("Content-Encoding: gzip\r\n"));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&gzip.1[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, plain_body);
+        assert_eq!(json.1, b"{\"body\": \"</body>\"}");
+        assert!(!String::from_utf8(large.1).unwrap().contains("<script>"));
+    }
+
+    #[tokio::test]
+    async fn test_graphql() {
+        use graphql::{Executor, GraphQL};
+
+        struct Echo;
+
+        #[async_trait::async_trait]
+        impl Executor for Echo {
+            async fn execute(&self, request: &server::RequestInfo<'_>, body: &str) -> String {
+                format!("{{\"data\": {{\"route\": \"{}\", \"request\": {}}}}}", request.route, body)
+            }
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.mount_graphql("/graphql", GraphQL::new(Echo));
+        server.mount_graphql("/private", GraphQL::new(Echo).without_playground());
+
+        async fn post(route: &str, content_type: &str, body: &str) -> String {
+            let request = format!("POST {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}", route, content_type, body.len(), body);
+            send_request("127.0.0.1:8034", &request).await
+        }
+
+        let addr = "127.0.0.1:8034";
+        let query = "{\"query\": \"{ hello }\"}";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let responses = (
+                post("/graphql", "application/json", query).await,
+                post("/graphql", "text/plain", query).await,
+                post("/graphql", "application/json", "").await,
+                get(addr, "/graphql").await,
+                get(addr, "/private").await,
+            );
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (executed, plain, empty, playground, private)) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(executed.starts_with("HTTP/1.1 200 "));
+        assert!(executed.contains("Content-Type: application/json\r\n"));
+        assert!(executed.ends_with("{\"data\": {\"route\": \"/graphql\", \"request\": {\"query\": \"{ hello }\"}}}"));
+        assert!(plain.starts_with("HTTP/1.1 415 "));
+        assert!(plain.contains("Accept-Post: application/json\r\n"));
+        assert!(empty.starts_with("HTTP/1.1 400 "));
+        assert!(playground.contains("GraphiQL.createFetcher({ url: \"/graphql\" })"));
+        assert!(private.starts_with("HTTP/1.1 405 "));
+    }
+
+    #[tokio::test]
+    async fn test_command_route() {
+        use command::CommandHandler;
+
+        async fn post(route: &str, body: &str) -> String {
+            let request = format!("POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", route, body.len(), body);
+            send_request("127.0.0.1:8035", &request).await
+        }
+
+        let shell = |script: &str| CommandHandler::new("sh").with_arg("-c").with_arg(script);
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(4, vec![]).with_receiver(receiver);
+        server.add_command_route("/echo", CommandHandler::new("cat"));
+        server.add_command_route("/env", shell("printf '%s %s %s' \"$REQUEST_METHOD\" \"$GREETING\" \"$HOME\"").with_env("GREETING", "hello"));
+        server.add_command_route("/slow", shell("sleep 5").with_timeout(Duration::from_millis(100)));
+        server.add_command_route("/loud", shell("yes").with_max_output(1024));
+        server.add_command_route("/fail", shell("exit 3"));
+        server.add_command_route("/busy", shell("sleep 0.5; printf done").with_max_concurrent(1));
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let responses = (
+                post("/echo", "Hello World!").await,
+                get("127.0.0.1:8035", "/env").await,
+                get("127.0.0.1:8035", "/slow").await,
+                get("127.0.0.1:8035", "/loud").await,
+                get("127.0.0.1:8035", "/fail").await,
+                tokio::join!(get("127.0.0.1:8035", "/busy"), async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    get("127.0.0.1:8035", "/busy").await
+                }),
+            );
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (echo, env, slow, loud, fail, (busy, turned_away))) = tokio::join!(
+            server.start("127.0.0.1:8035", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(echo.starts_with("HTTP/1.1 200 "));
+        assert!(echo.ends_with("\r\n\r\nHello World!"));
+        // Only the configured variables reach the program
+        assert!(env.ends_with("\r\n\r\nGET hello "), "{}", env);
+        assert!(slow.starts_with("HTTP/1.1 504 "));
+        assert!(loud.starts_with("HTTP/1.1 502 "));
+        assert!(fail.starts_with("HTTP/1.1 502 "));
+        assert!(busy.ends_with("done"));
+        assert!(turned_away.starts_with("HTTP/1.1 503 "));
+        assert!(turned_away.contains("Retry-After: 1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_cgi_route() {
+        use command::CommandHandler;
+
+        let script = |script: &str| CommandHandler::new("sh").with_arg("-c").with_arg(script).with_cgi();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        server.add_command_route("/env", script(concat!(
+            r#"printf 'Content-Type: text/plain\r\nX-Script: yes\r\n\r\n%s|%s|%s|%s|%s|%s|%s' "#,
+            r#""$GATEWAY_INTERFACE" "$REQUEST_METHOD" "$QUERY_STRING" "$CONTENT_LENGTH" "$HTTP_X_TOKEN" "${HTTP_PROXY-unset}" "$(cat)""#
+        )));
+        server.add_command_route("/missing", script(r"printf 'Status: 404 Not Found\n\nGone'"));
+        server.add_command_route("/moved", script(r"printf 'Location: /elsewhere\n\n'"));
+        server.add_command_route("/broken", script("printf 'no headers here'"));
+
+        let addr = "127.0.0.1:8040";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let request = "POST /env?b=2&a=1 HTTP/1.1\r\nX-Token: abc\r\nProxy: http://attacker\r\nContent-Length: 5\r\n\r\nhello";
+            let responses = (
+                send_request(addr, request).await,
+                get(addr, "/missing").await,
+                get(addr, "/moved").await,
+                get(addr, "/broken").await,
+            );
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (env, missing, moved, broken)) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(env.starts_with("HTTP/1.1 200 "), "{}", env);
+        assert!(env.contains("\r\nX-Script: yes\r\n"));
+        assert_eq!(env.matches("Content-Type:").count(), 1);
+        // The script's headers are not part of the body, and a request cannot set its proxy
+        assert!(env.ends_with("\r\n\r\nCGI/1.1|POST|a=1&b=2|5|abc|unset|hello"), "{}", env);
+        assert!(missing.starts_with("HTTP/1.1 404 "));
+        assert!(missing.contains("\r\nContent-Type: text/plain; charset=utf-8\r\n"));
+        assert!(missing.ends_with("\r\n\r\nGone"));
+        assert!(moved.starts_with("HTTP/1.1 302 "));
+        assert!(moved.contains("\r\nLocation: /elsewhere\r\n"));
+        assert!(broken.starts_with("HTTP/1.1 502 "));
+    }
+
+    #[tokio::test]
+    async fn test_includes() {
+        fn page(body: &'static str) -> impl Fn(&server::RequestInfo) -> Box<dyn Sendable> {
+            move |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+                Box::new(server::Page::new(200, String::from(body)))
+            }
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![])
+            .with_receiver(receiver)
+            .with_includes(includes::Includes::new());
+        server.add_route("/", page(concat!(
+            r#"<body><!--#include virtual="/header" -->"#,
+            r#"<esi:include src="/missing" alt="/footer"/>"#,
+            r#"<!--#include virtual="/missing" -->"#,
+            r#"<esi:include src="/missing"></esi:include>"#,
+            r#"<!--#include virtual="http://example.com/" --></body>"#
+        )));
+        server.add_route("/header", page(r#"<h1>Hello <!--#include file="name" --></h1>"#));
+        server.add_route("/name", |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let name = request.cookie("name").unwrap_or_default();
+            Box::new(response::Response::new(200).header("Content-Type", "text/plain").text(&name))
+        });
+        server.add_route("/footer", page("<footer>Bye</footer>"));
+        server.add_route("/loop", page(r#"<p><!--#include virtual="/loop" --></p>"#));
+        server.add_route("/data", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(response::Response::new(200).header("Content-Type", "application/json").text(r#"{"page": "<!--#include virtual=\"/footer\" -->"}"#))
+        });
+
+        let addr = "127.0.0.1:8041";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let responses = (
+                send_request(addr, "GET / HTTP/1.1\r\nCookie: name=World\r\n\r\n").await,
+                get(addr, "/loop").await,
+                get(addr, "/data").await,
+            );
+            sender.send(server::Task::Shutdown).await.unwrap();
+            responses
+        };
+        let (report, (composed, looped, data)) = tokio::join!(
+            server.start(addr, server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        // Included pages are processed too, and see the cookies of the page's request. Failed
+        // server-side includes, like the one of another site, leave an error, edge-side ones nothing
+        assert!(composed.ends_with(concat!(
+            "\r\n\r\n<body><h1>Hello World</h1><footer>Bye</footer>",
+            "[an error occurred while processing this directive]",
+            "[an error occurred while processing this directive]</body>"
+        )), "{}", composed);
+        // A page including itself stops at the depth limit
+        assert!(looped.ends_with("<p><p><p><p>[an error occurred while processing this directive]</p></p></p></p>"), "{}", looped);
+        assert!(data.contains("<!--#include"));
+    }
+
+    #[tokio::test]
+    async fn test_live_reload() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = std::env::temp_dir().join(format!("simpleserve-live-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.css"), "body {}").unwrap();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        server.register_plugin(live_reload::LiveReload::new().watch(&dir).with_interval(Duration::from_millis(20)));
+        server.add_route("/", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("<html><body><h1>Hello</h1></BODY></html>")))
+        });
+        server.add_route("/data", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(response::Response::new(200).header("Content-Type", "application/json").text("{\"body\": \"</body>\"}"))
+        });
+        let reloads = server.sse_channel(live_reload::CHANNEL);
+        let addr = "127.0.0.1:8024";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let page = get(addr, "/").await;
+            let body = page.split("\r\n\r\n").nth(1).unwrap();
+            assert!(body.starts_with("<html><body><h1>Hello</h1><script>new EventSource(\"/__live_reload\")"), "{}", body);
+            assert!(body.ends_with("</script></BODY></html>"));
+            assert!(page.contains(&format!("Content-Length: {}\r\n", body.len())));
+            assert!(!get(addr, "/data").await.contains("<script>"));
+
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /__live_reload HTTP/1.1\r\n\r\n").await.unwrap();
+            while reloads.subscribers() < 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            std::fs::write(dir.join("app.css"), "body { color: red; }").unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !String::from_utf8_lossy(&received).contains("event: reload\n") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&buffer[..n]);
+            }
+
+            // The stream notices the page is gone when it next writes
+            drop(stream);
+            while reloads.subscribers() > 0 {
+                reloads.publish(sse::Event::new("again"));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (report, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        assert_eq!(report.unwrap().connections_force_closed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        use clock::Clock;
+
+        let path = std::env::temp_dir().join(format!("simpleserve-audit-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = clock::MockClock::new();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let log = audit::AuditLog::open(&path).unwrap().with_clock(clock.clone());
+        log.record("alice", "enable\tmaintenance\nmode").unwrap();
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver).with_audit_log(log);
+        let addr = "127.0.0.1:8025";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (report, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        report.unwrap();
+
+        // Reopening continues the chain
+        let mut server = server::Webserver::new(1, vec![]).with_audit_log(audit::AuditLog::open(&path).unwrap());
+        let shutdown = server.shutdown_handle();
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            shutdown.shutdown_by("operator");
+            shutdown.shutdown();
+        };
+        let (report, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        report.unwrap();
+        assert_eq!(audit::AuditLog::verify(&path).unwrap(), 3);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Vec<&str>> = contents.lines().map(|line| line.split('\t').collect()).collect();
+        let date = utils::format_http_date(clock.system_time());
+        assert_eq!(lines[0][..4], ["1", date.as_str(), "alice", "enable\\tmaintenance\\nmode"]);
+        assert_eq!(lines[1][..4], ["2", date.as_str(), "task channel", "shutdown"]);
+        assert_eq!(lines[2][0], "3");
+        assert_eq!(lines[2][2..4], ["operator", "shutdown"]);
+        assert_eq!(lines[1][4], lines[0][5]);
+        assert_eq!(lines[2][4], lines[1][5]);
+
+        // Editing an entry breaks the chain from there on
+        std::fs::write(&path, contents.replacen("alice", "mallory", 1)).unwrap();
+        let error = audit::AuditLog::verify(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Audit log entry 1 has been tampered with");
+        assert!(audit::AuditLog::open(&path).is_err());
+
+        // So does removing one
+        let removed: Vec<&str> = contents.lines().filter(|line| !line.starts_with("2\t")).collect();
+        std::fs::write(&path, removed.join("\n")).unwrap();
+        assert_eq!(audit::AuditLog::verify(&path).unwrap_err().to_string(), "Audit log entry 2 has been tampered with");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_limits() {
+        let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![])
+            .with_receiver(receiver)
+            .with_max_request_line(64)
+            .with_max_header_size(256)
+            .with_max_headers(3);
+        server.add_route("/", handler);
+        let addr = "127.0.0.1:8014";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let long_line = get(addr, &format!("/{}", "a".repeat(100))).await;
+            assert!(long_line.starts_with("HTTP/1.1 414 URI Too Long"));
+            let large_head = send_request(addr, &format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", "b".repeat(300))).await;
+            assert!(large_head.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+            let many_headers = send_request(addr, "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n").await;
+            assert!(many_headers.starts_with("HTTP/1.1 431"));
+            let within = send_request(addr, "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n").await;
+            assert!(within.ends_with("Hello World!"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let slow = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            std::thread::sleep(Duration::from_millis(200));
+            Box::new(server::Page::new(200, String::from("Done")))
+        };
+        let addr = "127.0.0.1:8015";
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(4, vec![])
+            .with_receiver(receiver)
+            .with_max_connections(1)
+            .reject_when_saturated(Duration::from_secs(2));
+        server.set_theme(theme::Theme::default()
+            .with_translation("fr", 503, "Service indisponible", "Le serveur est occupé."));
+        server.add_route("/", slow);
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let first = tokio::spawn(get(addr, "/"));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let second = send_request(addr, "GET / HTTP/1.1\r\nAccept-Language: fr\r\n\r\n").await;
+            assert!(second.starts_with("HTTP/1.1 503"));
+            assert!(second.contains("Retry-After: 2\r\n"));
+            assert!(second.contains("Le serveur est occupé."));
+            assert!(second.contains("\r\nContent-Language: fr\r\n"));
+            assert!(first.await.unwrap().ends_with("Done"));
+            // The slot is free again once the first connection closed
+            assert!(get(addr, "/").await.ends_with("Done"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+        assert_eq!(server.stats().connections_rejected(), 1);
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(4, vec![]).with_receiver(receiver).with_max_connections(1);
+        server.add_route("/", slow);
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let started = std::time::Instant::now();
+            let (first, second) = tokio::join!(get(addr, "/"), get(addr, "/"));
+            assert!(first.ends_with("Done") && second.ends_with("Done"));
+            // The second connection waited for the first instead of running next to it
+            assert!(started.elapsed() >= Duration::from_millis(400));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+        assert_eq!(server.stats().connections_rejected(), 0);
+    }
+
+    /// Writes a self-signed key and certificate for localhost, returning their paths
+    fn tls_files(name: &str) -> (path::PathBuf, path::PathBuf) {
+        use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::{X509, X509NameBuilder}};
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name_builder = X509NameBuilder::new().unwrap();
+        name_builder.append_entry_by_text("CN", "localhost").unwrap();
+        let subject = name_builder.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("simpleserve-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(dir.join("cert.pem"), builder.build().to_pem().unwrap()).unwrap();
+        (dir.join("key.pem"), dir.join("cert.pem"))
+    }
+
+    async fn get_tls(addr: &str, route: &str) -> String {
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let ssl = connector.build().configure().unwrap().into_ssl("localhost").unwrap();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+        std::pin::Pin::new(&mut stream).connect().await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", route).as_bytes()).await.unwrap();
+        // The server closes the connection without a TLS close, so reading stops at the first error
+        let mut response = Vec::new();
+        let mut buffer = [0; 1024];
+        while let Ok(read @ 1..) = stream.read(&mut buffer).await {
+            response.extend_from_slice(&buffer[..read]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_https() {
+        let slow = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            std::thread::sleep(Duration::from_millis(200));
+            Box::new(server::Page::new(200, String::from("Done")))
+        };
+        let (key, cert) = tls_files("https");
+        let mut server = server::Webserver::new(4, vec![]);
+        server.add_route("/", slow);
+        let shutdown = server.shutdown_handle();
+        let addr = "127.0.0.1:8020";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(get_tls(addr, "/").await.ends_with("Done"));
+            // The server keeps accepting after the first connection, and drains on shutdown
+            let request = tokio::spawn(get_tls(addr, "/"));
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            shutdown.shutdown();
+            let response = request.await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert!(response.ends_with("Done"));
+        };
+        let (report, _) = tokio::join!(server.start(addr, ConnectionType::Https, Some(key), Some(cert)), client);
+        let report = report.unwrap();
+        assert_eq!(report.connections_accepted, 2);
+        assert_eq!(report.requests_completed_during_drain, 1);
+        assert_eq!(report.connections_force_closed, 0);
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(4, vec![])
+            .with_receiver(receiver)
+            .with_max_connections(1)
+            .reject_when_saturated(Duration::from_secs(2));
+        server.add_route("/", slow);
+        server.add_accessible_files(vec!["Cargo.toml"]).unwrap();
+        let (key, cert) = tls_files("https");
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // Accessible files are found relative to the working directory, as over HTTP
+            assert!(get_tls(addr, "/Cargo.toml").await.contains("[package]"));
+            let first = tokio::spawn(get_tls(addr, "/"));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let second = get_tls(addr, "/").await;
+            assert!(second.starts_with("HTTP/1.1 503"), "{}", second);
+            assert!(second.contains("Retry-After: 2\r\n"));
+            assert!(first.await.unwrap().ends_with("Done"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Https, Some(key), Some(cert)), client);
+        served.unwrap();
+        assert_eq!(server.stats().connections_rejected(), 1);
+
+        // Missing or unreadable TLS files are errors, not panics
+        let (key, cert) = tls_files("https");
+        let mut server = server::Webserver::new(1, vec![]);
+        assert!(server.start(addr, ConnectionType::Https, None, Some(cert.clone())).await.is_err());
+        assert!(server.start(addr, ConnectionType::Https, Some(key.clone()), None).await.is_err());
+        assert!(server.start(addr, ConnectionType::Https, Some(key.with_extension("missing")), Some(cert.clone())).await.is_err());
+        assert!(server.start(addr, ConnectionType::Https, Some(cert), Some(key)).await.is_err());
+    }
+
+    fn poll_news<'a>(request: &'a server::RequestInfo<'a>) -> server::HandlerFuture<'a> {
+        Box::pin(async move {
+            request.long_poll().unwrap().respond(request, "news", Duration::from_millis(200)).await
+        })
+    }
+
+    #[tokio::test]
+    async fn test_long_poll() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        let long_poll = server.long_poll();
+        server.add_async_route("/news", poll_news);
+        let addr = "127.0.0.1:8016";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = get(addr, "/news").await;
+            assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+            assert!(!response.contains("Content-Length"));
+            // Nothing was published for the key, so it is forgotten once nobody waits on it
+            assert_eq!(long_poll.keys(), 0);
+
+            // Queued events are returned right away to a client that saw an older one
+            assert_eq!(long_poll.publish("news", sse::Event::new("first")), 0);
+            assert_eq!(long_poll.publish("news", sse::Event::new("second").with_event("update")), 1);
+            let response = send_request(addr, "GET /news HTTP/1.1\r\nLast-Event-ID: 0\r\n\r\n").await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.contains("Event-ID: 1\r\n"));
+            assert!(response.contains("Event-Type: update\r\n"));
+            assert!(response.ends_with("second"));
+            assert!(get(addr, "/news?after=0").await.ends_with("second"));
+            assert!(get(addr, "/news?after=1").await.starts_with("HTTP/1.1 204"));
+
+            // A waiting client gets the next event as soon as it is published
+            let waiting = tokio::spawn(get(addr, "/news"));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            long_poll.publish("news", sse::Event::new("third"));
+            let response = waiting.await.unwrap();
+            assert!(response.contains("Event-ID: 2\r\n"));
+            assert!(response.ends_with("third"));
+
+            // Only the last events are kept
+            let small = long_poll::LongPoll::new().with_capacity(1).with_max_keys(2);
+            small.publish("key", sse::Event::new("dropped"));
+            small.publish("key", sse::Event::new("kept"));
+            let next = small.next("key", Some(0), Duration::ZERO).await.unwrap();
+            assert_eq!(next.data(), "kept");
+            assert!(small.next("other", Some(0), Duration::from_millis(10)).await.is_none());
+            assert_eq!(small.keys(), 1);
+            // And only for the keys published to last
+            small.publish("second", sse::Event::new("first"));
+            small.publish("third", sse::Event::new("first"));
+            assert_eq!(small.keys(), 2);
+            // The queue of the first key was dropped, so its ids start over
+            assert_eq!(small.publish("key", sse::Event::new("again")), 0);
+            assert_eq!(small.publish("third", sse::Event::new("second")), 1);
+            assert_eq!(small.keys(), 2);
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_event_bus() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Visited(String);
+
+        let visit = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let queued = request.events().unwrap().publish(Visited(String::from(request.route)));
+            Box::new(server::Page::new(200, queued.to_string()))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![]).with_receiver(receiver);
+        let events = server.events();
+        let mut visits = events.subscribe::<Visited>();
+        let mut numbers = events.subscribe::<u32>();
+        let news = server.sse_channel("news");
+        let mut news_events = news.receiver();
+        let bridge = events.bridge(news.clone(), |visit: &Visited| Some(sse::Event::new(&visit.0)));
+        server.add_route("/visit", visit);
+        let addr = "127.0.0.1:8017";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(events.subscribers::<Visited>(), 2);
+            // Both the subscription and the bridge get the event
+            assert!(get(addr, "/visit").await.ends_with("2"));
+            assert_eq!(visits.recv().await, Some(Visited(String::from("/visit"))));
+            assert_eq!(news_events.recv().await.unwrap().data(), "/visit");
+            // Events only reach subscribers of their type
+            assert_eq!(numbers.try_recv(), None);
+            assert_eq!(events.publish(7u32), 1);
+            assert_eq!(numbers.try_recv(), Some(7));
+            assert_eq!(visits.try_recv(), None);
+            assert_eq!(events.publish(String::from("nobody listens")), 0);
+
+            bridge.abort();
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+
+        // Subscribers that fell behind skip to the oldest event still queued
+        let small = event_bus::EventBus::new().with_capacity(2);
+        let mut subscription = small.subscribe::<u32>();
+        for number in 0..4u32 {
+            small.publish(number);
+        }
+        assert_eq!(subscription.try_recv(), Some(2));
+        assert_eq!(subscription.try_recv(), Some(3));
+        assert_eq!(subscription.try_recv(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let clock = clock::MockClock::new();
+        let limiter = rate_limit::RateLimiter::new(1, Duration::from_secs(60))
+            .with_burst(2)
+            .with_max_clients(2)
+            .with_clock(clock.clone());
+        let (first, second, third) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap());
+        assert!(limiter.check(first).is_ok());
+        assert!(limiter.check(first).is_ok());
+        assert_eq!(limiter.check(first), Err(Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(limiter.check(first), Err(Duration::from_secs(45)));
+        // The least recently seen client is forgotten, and starts over with a full bucket
+        assert!(limiter.check(second).is_ok());
+        assert!(limiter.check(third).is_ok());
+        assert_eq!(limiter.clients(), 2);
+        assert!(limiter.check(first).is_ok());
+        assert!(limiter.check(third).is_ok());
+        assert!(limiter.check(third).is_err());
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![])
+            .with_receiver(receiver)
+            .with_rate_limit(rate_limit::RateLimiter::new(1, Duration::from_secs(30)));
+        server.add_route("/", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        });
+        let addr = "127.0.0.1:8018";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(get(addr, "/").await.starts_with("HTTP/1.1 200"));
+            let limited = get(addr, "/").await;
+            assert!(limited.starts_with("HTTP/1.1 429"), "{}", limited);
+            assert!(limited.contains("Retry-After: 30\r\n"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct FixedResolver {
+        names: HashMap<std::net::IpAddr, String>,
+        addresses: HashMap<String, Vec<std::net::IpAddr>>,
+    }
+
+    impl bot_throttle::Resolver for FixedResolver {
+        fn reverse(&self, ip: std::net::IpAddr) -> Option<String> {
+            self.names.get(&ip).cloned()
+        }
+
+        fn forward(&self, host: &str) -> Vec<std::net::IpAddr> {
+            self.addresses.get(host).cloned().unwrap_or_default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bot_throttle() {
+        use bot_throttle::{BotThrottle, Client};
+
+        let (crawler, impostor, human, local) = ("66.249.66.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        let mut resolver = FixedResolver::default();
+        resolver.names.insert(crawler, String::from("crawl-66-249-66-1.googlebot.com"));
+        // Anyone can point the PTR record of their own address at the crawler's domain
+        resolver.names.insert(impostor, String::from("crawl-66-249-66-1.googlebot.com"));
+        resolver.names.insert(human, String::from("host.example.net"));
+        resolver.names.insert(local, String::from("crawl.search.msn.com"));
+        resolver.addresses.insert(String::from("crawl-66-249-66-1.googlebot.com"), vec![crawler]);
+        resolver.addresses.insert(String::from("crawl.search.msn.com"), vec![local]);
+        let throttle = BotThrottle::new(rate_limit::RateLimiter::new(1, Duration::from_secs(60)))
+            .with_crawler_limit(rate_limit::RateLimiter::new(2, Duration::from_secs(60)))
+            .with_resolver(resolver);
+
+        assert_eq!(throttle.verify(crawler).as_deref(), Some("Googlebot"));
+        assert_eq!(throttle.verify(impostor), None);
+        assert_eq!(throttle.verify(human), None);
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(throttle.classify(crawler, Some(firefox)), Client::Crawler(String::from("Googlebot")));
+        assert_eq!(throttle.classify(impostor, Some(googlebot)), Client::Bot);
+        assert_eq!(throttle.classify(human, Some(firefox)), Client::Human);
+        // Unknown addresses go by their User-Agent until they are verified in the background
+        assert_eq!(throttle.classify(local, Some(firefox)), Client::Human);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(throttle.classify(local, Some(firefox)), Client::Crawler(String::from("Bingbot")));
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![])
+            .with_receiver(receiver)
+            .with_bot_throttle(BotThrottle::new(rate_limit::RateLimiter::new(1, Duration::from_secs(30))));
+        server.add_route("/", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        });
+        async fn fetch(user_agent: &str) -> String {
+            send_request("127.0.0.1:8036", &format!("GET / HTTP/1.1\r\nUser-Agent: {}\r\n\r\n", user_agent)).await
+        }
+
+        let addr = "127.0.0.1:8036";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(fetch("SomeBot/1.0").await.starts_with("HTTP/1.1 200"));
+            let limited = fetch("SomeBot/1.0").await;
+            assert!(limited.starts_with("HTTP/1.1 429"), "{}", limited);
+            assert!(limited.contains("Retry-After: 30\r\n"));
+            // People on the same address are not limited with the bots
+            assert!(fetch(firefox).await.starts_with("HTTP/1.1 200"));
+            assert!(fetch(firefox).await.starts_with("HTTP/1.1 200"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    /// Takes its time to answer, like a resolver that cannot reach its DNS server
+    #[derive(Debug)]
+    struct SlowResolver(Duration);
+
+    impl bot_throttle::Resolver for SlowResolver {
+        fn reverse(&self, _ip: std::net::IpAddr) -> Option<String> {
+            std::thread::sleep(self.0);
+            None
+        }
+
+        fn forward(&self, _host: &str) -> Vec<std::net::IpAddr> {
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bot_throttle_slow_resolver() {
+        use bot_throttle::BotThrottle;
+
+        // With a single worker, the second request is only answered once the first connection let go of it
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_receiver(receiver)
+            .with_bot_throttle(BotThrottle::new(rate_limit::RateLimiter::new(10, Duration::from_secs(60)))
+                .with_crawler_limit(rate_limit::RateLimiter::new(10, Duration::from_secs(60)))
+                .with_resolver(SlowResolver(Duration::from_secs(3))));
+        server.add_route("/", |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, String::from("Hello World!")))
+        });
+
+        let addr = "127.0.0.1:8038";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let started = std::time::Instant::now();
+            assert!(send_request(addr, "GET / HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 200"));
+            assert!(send_request(addr, "GET / HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 200"));
+            assert!(started.elapsed() < Duration::from_secs(2), "Waited {:?} for the lookup", started.elapsed());
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_workers() {
+        // Workers share the address instead of failing to bind it
+        let first = workers::bind_shared("127.0.0.1:8037").await.unwrap();
+        let second = workers::bind_shared("127.0.0.1:8037").await.unwrap();
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+
+        // The first worker runs until it is stopped, the second crashes and is restarted
+        let script = "if [ \"$SIMPLESERVE_WORKER\" = 0 ]; then exec sleep 30; else exit 1; fi";
+        let supervisor = workers::Supervisor::new("sh".into(), vec!["-c".into(), script.into()], 2, Duration::from_secs(5));
+        let server = server::Webserver::new(1, vec![]);
+        let shutdown = server.shutdown_handle();
+        let stopper = shutdown.clone();
+        let started = std::time::Instant::now();
+        let (supervised, _) = tokio::join!(supervisor.run(&shutdown), async {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            stopper.shutdown();
+        });
+        let supervised = supervised.unwrap();
+        assert_eq!(supervised.started, 2);
+        assert!(supervised.restarted >= 1);
+        // The sleeping worker stops on SIGTERM rather than being killed after the grace period
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[derive(Default)]
+    struct TestPool {
+        reachable: AtomicBool,
+        committed: Mutex<Vec<String>>,
+        rolled_back: Mutex<Vec<String>>,
+    }
+
+    /// A connection to a stand-in database, whose sockets are bound to the runtime it was opened on
+    struct TestConnection {
+        pending: Vec<String>,
+        client: tokio::net::TcpStream,
+        database: tokio::net::TcpStream,
+    }
+
+    impl TestConnection {
+        async fn round_trip(&mut self, statement: &[u8]) -> Result<(), errors::PoolError> {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let failed = |e: std::io::Error| errors::PoolError::new(&e.to_string());
+            let mut received = vec![0; statement.len()];
+            self.client.write_all(statement).await.map_err(failed)?;
+            self.database.read_exact(&mut received).await.map_err(failed)?;
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl pool::Pool for TestPool {
+        type Connection = TestConnection;
+
+        fn name(&self) -> &str {
+            "notes"
+        }
+
+        async fn acquire(&self) -> Result<TestConnection, errors::PoolError> {
+            if !self.reachable.load(Ordering::SeqCst) {
+                return Err(errors::PoolError::new("connection refused"));
+            }
+            let refused = |e: std::io::Error| errors::PoolError::new(&e.to_string());
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.map_err(refused)?;
+            let addr = listener.local_addr().map_err(refused)?;
+            let (client, accepted) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+            Ok(TestConnection {
+                pending: Vec::new(),
+                client: client.map_err(refused)?,
+                database: accepted.map_err(refused)?.0,
+            })
+        }
+
+        async fn begin(&self, connection: &mut TestConnection) -> Result<(), errors::PoolError> {
+            connection.round_trip(b"BEGIN").await
+        }
+
+        async fn commit(&self, connection: &mut TestConnection) -> Result<(), errors::PoolError> {
+            connection.round_trip(b"COMMIT").await?;
+            self.committed.lock().unwrap().append(&mut connection.pending);
+            Ok(())
+        }
+
+        async fn rollback(&self, connection: &mut TestConnection) -> Result<(), errors::PoolError> {
+            // Slow enough to still be running if the response did not wait for it
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            connection.round_trip(b"ROLLBACK").await?;
+            self.rolled_back.lock().unwrap().append(&mut connection.pending);
+            Ok(())
+        }
+    }
+
+    fn add_note<'a>(request: &'a server::RequestInfo<'a>) -> server::HandlerFuture<'a> {
+        Box::pin(async move {
+            let mut transaction = match pool::transaction::<TestPool>(request).await {
+                Ok(transaction) => transaction,
+                Err(e) => return Box::new(server::Page::new(503, e.to_string())) as Box<dyn Sendable>,
+            };
+            transaction.pending.push(String::from(request.query_param("text").unwrap_or_default()));
+            // Returning without committing rolls the transaction back
+            if request.query_param("fail").is_some() {
+                return Box::new(server::Page::new(500, String::from("Failed"))) as Box<dyn Sendable>;
+            }
+            match transaction.commit().await {
+                Ok(()) => Box::new(server::Page::new(201, String::from("Saved"))) as Box<dyn Sendable>,
+                Err(e) => Box::new(server::Page::new(500, e.to_string())),
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_pool() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(2, vec![])
+            .with_receiver(receiver)
+            .with_pool(TestPool { reachable: AtomicBool::new(true), ..TestPool::default() });
+        assert!(!server.is_ready());
+        let notes = server.state::<TestPool>().unwrap();
+        server.add_async_route("/notes", add_note);
+        server.add_async_route("/ready", pool::readiness);
+        let addr = "127.0.0.1:8019";
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(get(addr, "/ready").await.ends_with("Ready"));
+            assert!(get(addr, "/notes?text=first").await.starts_with("HTTP/1.1 201"));
+            let failed = send_request(addr, "GET /notes?text=second&fail HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+            assert!(failed.starts_with("HTTP/1.1 500"));
+            // Rolled back on the runtime of the request, before the response was sent
+            assert_eq!(*notes.committed.lock().unwrap(), ["first"]);
+            assert_eq!(*notes.rolled_back.lock().unwrap(), ["second"]);
+
+            notes.reachable.store(false, Ordering::SeqCst);
+            let response = get(addr, "/ready").await;
+            assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+            assert!(response.ends_with("notes: connection refused"));
+            assert!(get(addr, "/notes?text=third").await.starts_with("HTTP/1.1 503"));
+            sender.send(server::Task::Shutdown).await.unwrap();
+        };
+        let (served, _) = tokio::join!(server.start(addr, ConnectionType::Http, None, None), client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_preconditions() {
+        let document = Arc::new(Mutex::new(String::from("first")));
+        let saved = Arc::clone(&document);
+        let save = move |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            let mut document = saved.lock().unwrap();
+            let etag = etag::ETag::for_content(document.as_bytes());
+            if let Some(failed) = request.check_preconditions(Some(&etag), None) {
+                return failed;
+            }
+            *document = String::from(request.body_string().unwrap());
+            Box::new(response::Response::new(200).header("ETag", &etag::ETag::for_content(document.as_bytes()).to_string()))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![]).with_receiver(receiver);
+        server.put("/document", save);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let put = |if_match: &str, body: &str| format!(
+                "PUT /document HTTP/1.1\r\nIf-Match: {}\r\nContent-Length: {}\r\n\r\n{}", if_match, body.len(), body
+            );
+            let first = etag::ETag::for_content(b"first").to_string();
+            let updated = send_request("127.0.0.1:8008", &put(&first, "second")).await;
+            let stale = send_request("127.0.0.1:8008", &put(&first, "third")).await;
+            let weak = send_request("127.0.0.1:8008", &put(&format!("W/{}", etag::ETag::for_content(b"second")), "third")).await;
+            let any = send_request("127.0.0.1:8008", &put("*", "fourth")).await;
+            let file = send_request("127.0.0.1:8008", "GET /Cargo.toml HTTP/1.1\r\nIf-Match: \"nope\"\r\n\r\n").await;
+            let unmodified = send_request(
+                "127.0.0.1:8008",
+                "GET /Cargo.toml HTTP/1.1\r\nIf-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n"
+            ).await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            (updated, stale, weak, any, file, unmodified)
+        };
+        let (report, (updated, stale, weak, any, file, unmodified)) = tokio::join!(
+            server.start("127.0.0.1:8008", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(updated.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(updated.contains(&format!("ETag: {}\r\n", etag::ETag::for_content(b"second"))));
+        assert!(stale.starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+        assert!(weak.starts_with("HTTP/1.1 412 "));
+        assert!(any.starts_with("HTTP/1.1 200 "));
+        assert_eq!(*document.lock().unwrap(), "fourth");
+        assert!(file.starts_with("HTTP/1.1 412 "));
+        assert!(unmodified.starts_with("HTTP/1.1 412 "));
+    }
+
+    struct LoopbackResolver;
+
+    impl geo::GeoResolver for LoopbackResolver {
+        fn resolve(&self, addr: std::net::IpAddr) -> Option<geo::GeoInfo> {
+            addr.is_loopback().then(|| geo::GeoInfo::new().with_country_code("NL"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_resolver() {
+        let handler = |request: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+            Box::new(server::Page::new(200, format!("{:?}", request.geo().and_then(|geo| geo.country_code()))))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut server = server::Webserver::new(1, vec![])
+            .with_receiver(receiver)
+            .with_geo_resolver(LoopbackResolver);
+        server.add_route("/", handler);
+
+        let client = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = get("127.0.0.1:7987", "/").await;
+            sender.send(server::Task::Shutdown).await.unwrap();
+            response
+        };
+        let (report, response) = tokio::join!(
+            server.start("127.0.0.1:7987", server::ConnectionType::Http, None, None),
+            client
+        );
+        report.unwrap();
+
+        assert!(response.ends_with("Some(\"NL\")"));
+    }
+
+    fn segment() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just(String::from("..")),
+            Just(String::from(".")),
+            Just(String::from("")),
+            Just(String::from("%2e%2e")),
+            Just(String::from("%2E%2e")),
+            Just(String::from(".%2e")),
+            Just(String::from("..%2f")),
+            Just(String::from("%2f..")),
+            Just(String::from("%252e%252e")),
+            "[a-zA-Z0-9._-]{1,8}",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_route_never_escapes_root(segments in prop::collection::vec(segment(), 0..12)) {
+            let route = utils::parse_route(&format!("GET /{} HTTP/1.1", segments.join("/"))).unwrap();
+            prop_assert!(route.starts_with('/'));
+            prop_assert!(!route.contains("//"));
+            for segment in route.split('/') {
+                prop_assert!(segment != ".." && segment != ".");
+            }
+            prop_assert!(!path::Path::new(&route[1..]).components().any(|c| matches!(c, path::Component::ParentDir | path::Component::RootDir)));
+        }
+
+        #[test]
+        fn test_normalize_path_is_idempotent(path in "[a-z./%]{0,32}") {
+            let normalized = utils::normalize_path(&path);
+            prop_assert_eq!(utils::normalize_path(&normalized), normalized);
+        }
+
+        #[test]
+        fn test_route_matching_is_total_and_deterministic(route in "/[a-z/]{0,16}") {
+            let handler = |_: &server::RequestInfo| -> Box<dyn Sendable + 'static> {
+                Box::new(server::Page::new(200, String::from("Hello World!")))
+            };
+            let mut server = server::Webserver::new(1, vec![]);
+            server.add_route("/", handler);
+            server.add_route("/about", handler);
+            server.add_route("/about/team", handler);
+
+            let found = utils::find_handler(server.routes(), &route, &request::Method::Get).unwrap().route();
+            prop_assert_eq!(found, utils::find_handler(server.routes(), &route, &request::Method::Get).unwrap().route());
+            if ["/", "/about", "/about/team"].contains(&route.as_str()) {
+                prop_assert_eq!(found, route.as_str());
+            } else {
+                prop_assert_eq!(found, "404");
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,210 @@
+//! Live reload for development
+//!
+//! The [`LiveReload`] plugin watches files, and makes every open page reload itself when one of
+//! them changes. It adds a small script to HTML responses, which listens on a
+//! [Server-Sent Events](crate::sse) route for a `reload` event, and publishes that event when
+//! the modification time or size of a watched file changes.
+//!
+//! Files are polled rather than watched with the operating system, so it works the same
+//! everywhere at the cost of a short delay. Hidden files, such as the swap files of editors, are
+//! skipped. Only register the plugin in development: every open page keeps a worker of the
+//! thread pool busy, and the script is added to every HTML response.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     live_reload::LiveReload
+//! };
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! if cfg!(debug_assertions) {
+//!     server.register_plugin(LiveReload::new()
+//!         .watch("static")
+//!         .watch("templates")
+//!         .with_interval(Duration::from_millis(250)));
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime}
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    plugin::Plugin,
+    response::Response,
+    server::{
+        RequestInfo,
+        Sendable,
+        ShutdownReport,
+        Webserver
+    },
+    sse::{Channel, Event},
+    transform::{BodyTransform, Transform}
+};
+
+/// The route the pages listen on for reloads by default
+pub const DEFAULT_ROUTE: &str = "/__live_reload";
+
+/// The name of the [`sse`](crate::sse) channel reload events are published on
+pub const CHANNEL: &str = "live-reload";
+
+/// Reloads open pages when watched files change
+///
+/// Registered with [`Webserver::register_plugin`]. Files are checked every 500 milliseconds by
+/// default.
+#[derive(Debug)]
+pub struct LiveReload {
+    paths: Vec<PathBuf>,
+    interval: Duration,
+    route: String,
+    channel: Mutex<Option<Channel>>,
+    watcher: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl LiveReload {
+    pub fn new() -> LiveReload {
+        LiveReload {
+            paths: Vec::new(),
+            interval: Duration::from_millis(500),
+            route: String::from(DEFAULT_ROUTE),
+            channel: Mutex::new(None),
+            watcher: Mutex::new(None),
+        }
+    }
+
+    /// Watches a file, or every file in a directory and its subdirectories
+    pub fn watch<P: AsRef<Path>>(mut self, path: P) -> LiveReload {
+        self.paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets how often the watched files are checked
+    pub fn with_interval(mut self, interval: Duration) -> LiveReload {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the route the pages listen on, [`DEFAULT_ROUTE`] by default
+    pub fn with_route(mut self, route: &str) -> LiveReload {
+        self.route = String::from(route);
+        self
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+impl Default for LiveReload {
+    fn default() -> LiveReload {
+        LiveReload::new()
+    }
+}
+
+impl Plugin for LiveReload {
+    fn name(&self) -> &str {
+        "live-reload"
+    }
+
+    fn register(&self, server: &mut Webserver) {
+        let channel = server.sse_channel(CHANNEL);
+        *self.channel.lock().unwrap() = Some(channel.clone());
+        server.add_route(&self.route, move |_: &RequestInfo| -> Box<dyn Sendable> {
+            Box::new(channel.subscribe())
+        });
+        server.add_middleware(Transform::new(Injector { route: self.route.clone() }));
+    }
+
+    fn on_start(&self) {
+        let channel = match self.channel.lock().unwrap().clone() {
+            Some(channel) => channel,
+            None => return,
+        };
+        let paths = self.paths.clone();
+        let interval = self.interval;
+        let watcher = tokio::spawn(async move {
+            let mut known = snapshot(&paths);
+            loop {
+                tokio::time::sleep(interval).await;
+                let current = snapshot(&paths);
+                if current != known {
+                    println!("Watched files changed, reloading {} page(s)", channel.publish(Event::new("reload").with_event("reload")));
+                    known = current;
+                }
+            }
+        });
+        if let Some(previous) = self.watcher.lock().unwrap().replace(watcher) {
+            previous.abort();
+        }
+    }
+
+    fn on_shutdown(&self, _report: &ShutdownReport) {
+        if let Some(watcher) = self.watcher.lock().unwrap().take() {
+            watcher.abort();
+        }
+    }
+}
+
+/// Adds the reload script to HTML responses
+struct Injector {
+    route: String,
+}
+
+impl BodyTransform for Injector {
+    fn applies(&self, _request: &RequestInfo, response: &Response) -> bool {
+        // Pages are HTML unless they say otherwise
+        response.headers().get("content-type").is_none_or(|content_type| content_type.starts_with("text/html"))
+    }
+
+    fn transform(&self, _request: &RequestInfo, body: &[u8]) -> Option<Vec<u8>> {
+        let body = std::str::from_utf8(body).ok()?;
+        let end = body.to_ascii_lowercase().rfind("</body>")?;
+        let script = format!(
+            "<script>new EventSource(\"{}\").addEventListener(\"reload\", () => location.reload());</script>",
+            self.route
+        );
+        Some(format!("{}{}{}", &body[..end], script, &body[end..]).into_bytes())
+    }
+}
+
+/// The modification time and size of every watched file
+fn snapshot(paths: &[PathBuf]) -> HashMap<PathBuf, (Option<SystemTime>, u64)> {
+    let mut files = HashMap::new();
+    let mut pending = paths.to_vec();
+    while let Some(path) = pending.pop() {
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if !metadata.is_dir() {
+            files.insert(path, (metadata.modified().ok(), metadata.len()));
+            continue;
+        }
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                pending.push(entry.path());
+            }
+        }
+    }
+    files
+}
@@ -0,0 +1,229 @@
+//! Long polling
+//!
+//! Some clients cannot keep a stream open, like old browsers or proxies that buffer
+//! `text/event-stream` responses. A [`LongPoll`] lets them ask for the next event instead: the
+//! request waits until an event is published for its key, and is answered with it, or with
+//! 204 No Content once the timeout passes so the client asks again.
+//!
+//! Events go through the same channels as [Server-Sent Events](crate::sse), and each key also
+//! keeps a queue of its latest events. Every event gets its position in the queue as its id, so
+//! a client that sends back the id of the last event it saw gets the events it missed between
+//! two requests, instead of only the ones published while it waits.
+//!
+//! Every waiting request keeps a worker of the thread pool busy, like an open stream does.
+//! Keys are forgotten once nothing is queued for them and no request waits on them, and at most
+//! [`DEFAULT_MAX_KEYS`] keys keep a queue, so clients asking for made up keys cannot fill the memory.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     Page,
+//!     Sendable,
+//!     RequestInfo,
+//!     HandlerFuture,
+//!     sse::Event
+//! };
+//!
+//! fn poll<'a>(request: &'a RequestInfo<'a>) -> HandlerFuture<'a> {
+//!     Box::pin(async move {
+//!         match request.long_poll() {
+//!             Some(long_poll) => long_poll.respond(request, "news", Duration::from_secs(30)).await,
+//!             None => Box::new(Page::new(404, String::from("No long polling"))) as Box<dyn Sendable>,
+//!         }
+//!     })
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! let long_poll = server.long_poll();
+//! server.add_async_route("/news", poll);
+//! // From anywhere, for as long as the server runs
+//! long_poll.publish("news", Event::new("Hello World!"));
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex
+    },
+    time::Duration
+};
+
+use crate::{
+    response::Response,
+    server::{
+        RequestInfo,
+        Sendable
+    },
+    sse::{Channels, Event}
+};
+
+/// The number of events kept for each key by default
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+/// The number of keys that keep a queue by default
+pub const DEFAULT_MAX_KEYS: usize = 1024;
+
+/// Waits for events by key, for clients that cannot use Server-Sent Events
+///
+/// Created with [`Webserver::long_poll`](crate::Webserver::long_poll), or added as state with
+/// [`Webserver::with_state`](crate::Webserver::with_state) to set its capacity. Cloning is cheap,
+/// every clone shares the same queues.
+#[derive(Debug, Clone)]
+pub struct LongPoll {
+    capacity: usize,
+    max_keys: usize,
+    inner: Arc<LongPollInner>,
+}
+
+#[derive(Debug, Default)]
+struct LongPollInner {
+    channels: Channels,
+    queues: Mutex<HashMap<String, Queue>>,
+    published: AtomicU64,
+}
+
+/// The latest events of a key, with the id of the next one
+#[derive(Debug, Default)]
+struct Queue {
+    next_id: u64,
+    events: VecDeque<(u64, Event)>,
+    // The number of events published to any key when this one was last published to
+    last_published: u64,
+}
+
+impl LongPoll {
+    /// Keeps the last [`DEFAULT_QUEUE_CAPACITY`] events of each key
+    pub fn new() -> LongPoll {
+        LongPoll {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            max_keys: DEFAULT_MAX_KEYS,
+            inner: Arc::new(LongPollInner::default()),
+        }
+    }
+
+    /// Sets how many events are kept for each key
+    ///
+    /// A client that falls further behind than this misses the oldest events.
+    pub fn with_capacity(mut self, capacity: usize) -> LongPoll {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sets how many keys keep a queue
+    ///
+    /// Publishing to a new key past this drops the queue of the key published to longest ago.
+    /// Clients polling that key get the events published from then on.
+    pub fn with_max_keys(mut self, max_keys: usize) -> LongPoll {
+        self.max_keys = max_keys;
+        self
+    }
+
+    pub fn max_keys(&self) -> usize {
+        self.max_keys
+    }
+
+    /// The number of keys with queued events or waiting requests
+    pub fn keys(&self) -> usize {
+        self.inner.channels.len()
+    }
+
+    /// Queues an event for a key and wakes the requests waiting for it
+    ///
+    /// The id of the event is replaced by its position in the queue, which is returned.
+    pub fn publish(&self, key: &str, event: Event) -> u64 {
+        let mut queues = self.inner.queues.lock().unwrap();
+        if !queues.contains_key(key) && queues.len() >= self.max_keys {
+            let oldest = queues.iter()
+                .min_by_key(|(_, queue)| queue.last_published)
+                .map(|(oldest, _)| oldest.clone());
+            if let Some(oldest) = oldest {
+                queues.remove(&oldest);
+                self.inner.channels.remove_unused(&oldest);
+            }
+        }
+        let queue = queues.entry(String::from(key)).or_default();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.last_published = self.inner.published.fetch_add(1, Ordering::SeqCst);
+        let event = event.with_id(&id.to_string());
+        queue.events.push_back((id, event.clone()));
+        while queue.events.len() > self.capacity {
+            queue.events.pop_front();
+        }
+        // Still locked, so a request cannot miss the event between the queue and the channel
+        self.inner.channels.get_or_create(key).publish(event);
+        id
+    }
+
+    /// The next event for a key, or `None` if there was none before the timeout
+    ///
+    /// # Arguments
+    /// * `key` - The key to wait on
+    /// * `after` - The id of the last event the client saw. Queued events after it are returned
+    ///   right away. Without one, only events published from now on are waited for.
+    /// * `timeout` - How long to wait
+    pub async fn next(&self, key: &str, after: Option<u64>, timeout: Duration) -> Option<Event> {
+        let (channel, mut receiver) = {
+            let queues = self.inner.queues.lock().unwrap();
+            let queued = after.and_then(|after| {
+                queues.get(key)?.events.iter().find(|(id, _)| *id > after)
+            });
+            if let Some((_, event)) = queued {
+                return Some(event.clone());
+            }
+            let channel = self.inner.channels.get_or_create(key);
+            let receiver = channel.receiver();
+            (channel, receiver)
+        };
+        let event = tokio::time::timeout(timeout, receiver.recv()).await.ok().flatten();
+        drop(receiver);
+        // Locked like `publish`, so a key is not forgotten while an event is queued for it
+        let queues = self.inner.queues.lock().unwrap();
+        if queues.contains_key(key) {
+            // Counting drops the senders of requests that stopped waiting
+            channel.subscribers();
+        } else {
+            self.inner.channels.remove_unused(key);
+        }
+        event
+    }
+
+    /// Answers a request with the next event for a key
+    ///
+    /// The last id the client saw is read from the `Last-Event-ID` header, or the `after` query
+    /// parameter. The event is sent as `text/plain` with its id in an `Event-ID` header, and its
+    /// type in an `Event-Type` header if it has one. Answers 204 No Content on timeout.
+    pub async fn respond(&self, request: &RequestInfo<'_>, key: &str, timeout: Duration) -> Box<dyn Sendable> {
+        let after = request.header("last-event-id")
+            .or_else(|| request.query_param("after"))
+            .and_then(|id| id.trim().parse().ok());
+        let event = match self.next(key, after, timeout).await {
+            Some(event) => event,
+            None => return Box::new(Response::new(204).header("Cache-Control", "no-cache")),
+        };
+        let mut response = Response::new(200)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .header("Cache-Control", "no-cache");
+        if let Some(id) = event.id() {
+            response = response.header("Event-ID", id);
+        }
+        if let Some(event_type) = event.event() {
+            response = response.header("Event-Type", event_type);
+        }
+        Box::new(response.text(event.data()))
+    }
+}
+
+impl Default for LongPoll {
+    fn default() -> LongPoll {
+        LongPoll::new()
+    }
+}
@@ -0,0 +1,402 @@
+//! Middleware for the webserver
+//!
+//! A [`Middleware`] sees every request before it is routed and every response before it is sent,
+//! so cross-cutting concerns like logging, authentication and headers are written once instead of
+//! in every handler. Middleware runs in the order it was added with
+//! [`Webserver::add_middleware`](crate::Webserver::add_middleware), and the responses pass back
+//! through it in reverse order.
+//!
+//! Layers that have to run at a certain point, whatever order they were added in, say so with a
+//! [`Phase`] and a priority:
+//! 1. [`Phase::PreRouting`] `before` hooks, which can still change the route
+//! 2. [`Phase::PreHandler`] `before` hooks, once the route parameters are known
+//! 3. The handler
+//! 4. [`Phase::PostHandler`] `after` hooks, which see the response as the handler made it, like
+//!    the body rewriting of [`Transform`](crate::transform::Transform)
+//! 5. [`Phase::PreSend`] `after` hooks, for layers like [`Compression`](crate::compression::Compression)
+//!    that have to see the final response
+//!
+//! Within a phase, `before` hooks with a higher [`Middleware::priority`] run first, and ties run in
+//! the order the middleware was added. `after` hooks run in the reverse order of the `before`
+//! hooks within each phase. [`Ordered`] changes the phases and priority of a middleware that
+//! does not choose them itself.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     middleware::Middleware,
+//!     request::Request
+//! };
+//!
+//! struct RequireToken;
+//!
+//! impl Middleware for RequireToken {
+//!     fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+//!         if request.route.starts_with("/api") && !request.headers.contains("authorization") {
+//!             return Some(Box::new(simpleserve::Page::new(401, String::from("Unauthorized"))));
+//!         }
+//!         None
+//!     }
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_middleware(RequireToken);
+//! ```
+//!
+//! Middleware can also be limited to some requests with a [`Predicate`], built with [`when`]:
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     middleware::{when, host_is, path_starts_with, Middleware},
+//!     request::Request
+//! };
+//!
+//! struct ForceHttps;
+//!
+//! impl Middleware for ForceHttps {
+//!     fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+//!         let location = format!("https://admin.example.com{}", request.route);
+//!         Some(Box::new(simpleserve::Response::new(308).header("Location", &location)))
+//!     }
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_middleware(when(host_is("admin.example.com")).and(path_starts_with("/login")).then(ForceHttps));
+//! ```
+//!
+//! A middleware that needs the route parameters runs after routing:
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     middleware::{Middleware, Phase},
+//!     request::Request
+//! };
+//!
+//! struct OwnAccountOnly;
+//!
+//! impl Middleware for OwnAccountOnly {
+//!     fn before_phase(&self) -> Phase {
+//!         Phase::PreHandler
+//!     }
+//!
+//!     fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+//!         let user = request.params.get("user")?;
+//!         if request.headers.get("x-user") != Some(user.as_str()) {
+//!             return Some(Box::new(simpleserve::Page::new(403, String::from("Forbidden"))));
+//!         }
+//!         None
+//!     }
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_middleware(OwnAccountOnly);
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc
+    }
+};
+
+use crate::{
+    request::{Method, Request},
+    server::{
+        RequestInfo,
+        Sendable
+    }
+};
+
+/// When a hook of a middleware runs
+///
+/// `before` hooks run in [`Phase::PreRouting`] or [`Phase::PreHandler`], and `after` hooks in
+/// [`Phase::PostHandler`] or [`Phase::PreSend`]. See the [module](self) for the full order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Before the request is routed, so the route can still be changed
+    PreRouting,
+    /// After the request is routed, when the route parameters are known
+    ///
+    /// Changing the route no longer changes the handler.
+    PreHandler,
+    /// Straight after the handler, before the other `after` hooks
+    PostHandler,
+    /// Last before the response is sent, after every [`Phase::PostHandler`] hook
+    PreSend,
+}
+
+/// Intercepts requests and responses
+pub trait Middleware: Send + Sync {
+    /// Called before the request is routed, or after it if [`Middleware::before_phase`] says so
+    ///
+    /// The request can be changed, including its route. Returning a response skips the handler
+    /// and the middleware after this one. Route parameters are not known yet before routing.
+    fn before(&self, _request: &mut Request) -> Option<Box<dyn Sendable>> {
+        None
+    }
+
+    /// Called with the response before it is sent
+    ///
+    /// Only called if [`Middleware::before`] was called for the request.
+    /// The response can be inspected, wrapped or replaced.
+    fn after(&self, _request: &RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+        response
+    }
+
+    /// When [`Middleware::before`] runs, [`Phase::PreRouting`] by default
+    ///
+    /// Any phase other than [`Phase::PreRouting`] runs the hook after routing.
+    fn before_phase(&self) -> Phase {
+        Phase::PreRouting
+    }
+
+    /// When [`Middleware::after`] runs, [`Phase::PostHandler`] by default
+    ///
+    /// Any phase other than [`Phase::PreSend`] runs the hook straight after the handler.
+    fn after_phase(&self) -> Phase {
+        Phase::PostHandler
+    }
+
+    /// Orders the middleware within its phases, higher runs its `before` hook first, 0 by default
+    fn priority(&self) -> i32 {
+        0
+    }
+}
+
+/// A middleware with its phases and priority set from outside
+///
+/// # Examples
+/// ```
+/// use simpleserve::{
+///     Webserver,
+///     compression::Compression,
+///     middleware::{Middleware, Ordered, Phase}
+/// };
+///
+/// let compression = Ordered::new(Compression::new()).with_priority(10);
+/// assert_eq!(compression.after_phase(), Phase::PreSend);
+/// assert_eq!(compression.priority(), 10);
+/// let mut server = Webserver::new(10, vec![]);
+/// server.add_middleware(compression);
+/// ```
+pub struct Ordered<M> {
+    middleware: M,
+    before_phase: Option<Phase>,
+    after_phase: Option<Phase>,
+    priority: Option<i32>,
+}
+
+impl<M: Middleware> Ordered<M> {
+    /// Keeps the phases and priority of the middleware until they are set
+    pub fn new(middleware: M) -> Ordered<M> {
+        Ordered {
+            middleware,
+            before_phase: None,
+            after_phase: None,
+            priority: None,
+        }
+    }
+
+    pub fn with_before_phase(mut self, phase: Phase) -> Ordered<M> {
+        self.before_phase = Some(phase);
+        self
+    }
+
+    pub fn with_after_phase(mut self, phase: Phase) -> Ordered<M> {
+        self.after_phase = Some(phase);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Ordered<M> {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.middleware
+    }
+}
+
+impl<M: Middleware> Middleware for Ordered<M> {
+    fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+        self.middleware.before(request)
+    }
+
+    fn after(&self, request: &RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+        self.middleware.after(request, response)
+    }
+
+    fn before_phase(&self) -> Phase {
+        self.before_phase.unwrap_or_else(|| self.middleware.before_phase())
+    }
+
+    fn after_phase(&self) -> Phase {
+        self.after_phase.unwrap_or_else(|| self.middleware.after_phase())
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority.unwrap_or_else(|| self.middleware.priority())
+    }
+}
+
+/// Whether the `before` hook of a middleware runs before routing
+pub(crate) fn runs_before_routing(middleware: &dyn Middleware) -> bool {
+    middleware.before_phase() == Phase::PreRouting
+}
+
+/// Sorts middleware into the order their `before` hooks run in
+///
+/// The sort is stable, so middleware with the same phase and priority keep the order they were added in.
+pub(crate) fn before_order(middleware: &[Arc<dyn Middleware>]) -> Vec<Arc<dyn Middleware>> {
+    let mut ordered = middleware.to_vec();
+    ordered.sort_by_key(|middleware| (!runs_before_routing(middleware.as_ref()), Reverse(middleware.priority())));
+    ordered
+}
+
+/// The order the `after` hooks of middleware run in, given the order their `before` hooks ran in
+pub(crate) fn after_order(ran: &[Arc<dyn Middleware>]) -> Vec<&Arc<dyn Middleware>> {
+    let mut ordered: Vec<_> = ran.iter().rev().collect();
+    ordered.sort_by_key(|middleware| middleware.after_phase() == Phase::PreSend);
+    ordered
+}
+
+/// A condition on a request, used to only run middleware for some requests
+///
+/// Predicates are combined with [`Predicate::and`], [`Predicate::or`] and [`Predicate::not`],
+/// and turned into middleware with [`Predicate::then`].
+#[derive(Clone)]
+pub struct Predicate {
+    test: Arc<dyn Fn(&Request) -> bool + Send + Sync>,
+}
+
+impl Predicate {
+    /// Creates a predicate from a function
+    pub fn new<F>(test: F) -> Predicate
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        Predicate { test: Arc::new(test) }
+    }
+
+    pub fn matches(&self, request: &Request) -> bool {
+        (self.test)(request)
+    }
+
+    /// Matches requests that match both predicates
+    pub fn and(self, other: Predicate) -> Predicate {
+        Predicate::new(move |request| self.matches(request) && other.matches(request))
+    }
+
+    /// Matches requests that match either predicate
+    pub fn or(self, other: Predicate) -> Predicate {
+        Predicate::new(move |request| self.matches(request) || other.matches(request))
+    }
+
+    /// Matches requests that do not match the predicate
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Predicate {
+        Predicate::new(move |request| !self.matches(request))
+    }
+
+    /// Runs the middleware only for requests that match the predicate
+    pub fn then<M: Middleware + 'static>(self, middleware: M) -> Conditional {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        Conditional {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            predicate: self,
+            middleware: Box::new(middleware),
+        }
+    }
+}
+
+/// Starts a condition, reads as `when(...).and(...).then(middleware)`
+pub fn when(predicate: Predicate) -> Predicate {
+    predicate
+}
+
+/// Matches requests for a host, ignoring the port and case
+pub fn host_is(host: &str) -> Predicate {
+    let host = host.to_ascii_lowercase();
+    Predicate::new(move |request| {
+        request.headers.get("host").is_some_and(|value| {
+            let name = match value.rsplit_once(':') {
+                Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+                _ => value,
+            };
+            name.eq_ignore_ascii_case(&host)
+        })
+    })
+}
+
+/// Matches requests whose route starts with a prefix
+pub fn path_starts_with(prefix: &str) -> Predicate {
+    let prefix = String::from(prefix);
+    Predicate::new(move |request| request.route.starts_with(&prefix))
+}
+
+pub fn method_is(method: Method) -> Predicate {
+    Predicate::new(move |request| request.method == method)
+}
+
+pub fn has_header(name: &str) -> Predicate {
+    let name = String::from(name);
+    Predicate::new(move |request| request.headers.contains(&name))
+}
+
+/// Matches requests with a header that has the value, ignoring the case of the value
+pub fn header_is(name: &str, value: &str) -> Predicate {
+    let (name, value) = (String::from(name), String::from(value));
+    Predicate::new(move |request| {
+        request.headers.get_all(&name).any(|header| header.eq_ignore_ascii_case(&value))
+    })
+}
+
+/// Middleware that only runs for requests matching a [`Predicate`]
+///
+/// The predicate is checked when the `before` hook of the middleware would run. The response only
+/// passes through the middleware if the request matched, even if a middleware changed the request since.
+pub struct Conditional {
+    id: usize,
+    predicate: Predicate,
+    middleware: Box<dyn Middleware>,
+}
+
+/// The conditional middleware that matched a request
+struct Matched(HashSet<usize>);
+
+impl Middleware for Conditional {
+    fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+        if !self.predicate.matches(request) {
+            return None;
+        }
+        let mut matched = request.extensions.get::<Matched>().map(|matched| matched.0.clone()).unwrap_or_default();
+        matched.insert(self.id);
+        request.extensions.insert(Matched(matched));
+        self.middleware.before(request)
+    }
+
+    fn after(&self, request: &RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+        match request.extension::<Matched>() {
+            Some(matched) if matched.0.contains(&self.id) => self.middleware.after(request, response),
+            _ => response,
+        }
+    }
+
+    fn before_phase(&self) -> Phase {
+        self.middleware.before_phase()
+    }
+
+    fn after_phase(&self) -> Phase {
+        self.middleware.after_phase()
+    }
+
+    fn priority(&self) -> i32 {
+        self.middleware.priority()
+    }
+}
@@ -0,0 +1,649 @@
+//! Multipart form data
+//!
+//! Forms submitted with `enctype="multipart/form-data"`, such as file uploads, are read with
+//! [`RequestInfo::multipart`](crate::RequestInfo::multipart). It returns a [`Multipart`] iterator
+//! over the [`Part`]s of the body. Parts larger than the memory threshold are written to temporary
+//! files, which are removed when the part is dropped unless they are [persisted](UploadedFile::persist).
+//! Temporary files are only readable by the user the server runs as.
+//!
+//! The server parses a multipart body while reading it from the connection, so only small parts
+//! are held in memory, and bodies are limited by the [`MultipartOptions`] of the server instead
+//! of its maximum body size. Set them with [`Webserver::with_multipart`](crate::Webserver::with_multipart).
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Page,
+//!     Sendable,
+//!     RequestInfo,
+//!     multipart::MultipartOptions
+//! };
+//!
+//! fn upload(request: &RequestInfo) -> Box<dyn Sendable> {
+//!     let parts = match request.multipart() {
+//!         Some(multipart) => multipart,
+//!         None => return Box::new(Page::new(415, String::from("Expected a multipart form"))),
+//!     };
+//!     for part in parts {
+//!         let part = match part {
+//!             Ok(part) => part,
+//!             Err(e) => return Box::new(Page::new(400, e.to_string())),
+//!         };
+//!         if part.filename().is_some() {
+//!             match part.into_file() {
+//!                 Some(file) => file.persist("./uploads/latest").unwrap(),
+//!                 None => println!("Small upload, kept in memory"),
+//!             }
+//!         }
+//!     }
+//!     Box::new(Page::new(200, String::from("Uploaded")))
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![])
+//!     .with_multipart(MultipartOptions::new().with_max_size(100 * 1024 * 1024).with_max_part_size(50 * 1024 * 1024));
+//! server.post("/upload", upload);
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex
+    }
+};
+
+use crate::{
+    errors::MalformedRequestError,
+    request::Headers,
+    server::{ConnectionInfo, DEFAULT_MAX_BODY_SIZE}
+};
+
+/// Parts larger than this are written to a temporary file by default, 64 KiB
+pub const DEFAULT_MEMORY_THRESHOLD: usize = 64 * 1024;
+
+/// The largest headers of a part
+const MAX_PART_HEAD_SIZE: usize = 8 * 1024;
+
+/// Limits for multipart bodies
+///
+/// The server reads multipart bodies up to [`MultipartOptions::max_size`], set with
+/// [`Webserver::with_multipart`](crate::Webserver::with_multipart), instead of the maximum body size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultipartOptions {
+    max_size: usize,
+    max_parts: usize,
+    max_part_size: usize,
+    memory_threshold: usize,
+}
+
+impl MultipartOptions {
+    /// Allows bodies of up to [`DEFAULT_MAX_BODY_SIZE`], with 100 parts of up to the same size each
+    pub fn new() -> MultipartOptions {
+        MultipartOptions {
+            max_size: DEFAULT_MAX_BODY_SIZE,
+            max_parts: 100,
+            max_part_size: DEFAULT_MAX_BODY_SIZE,
+            memory_threshold: DEFAULT_MEMORY_THRESHOLD,
+        }
+    }
+
+    /// Sets the largest multipart body the server reads, in bytes
+    pub fn with_max_size(mut self, max_size: usize) -> MultipartOptions {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_max_parts(mut self, max_parts: usize) -> MultipartOptions {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// Sets the largest part content accepted, in bytes
+    pub fn with_max_part_size(mut self, max_part_size: usize) -> MultipartOptions {
+        self.max_part_size = max_part_size;
+        self
+    }
+
+    /// Sets the size above which a part is written to a temporary file, in bytes
+    pub fn with_memory_threshold(mut self, memory_threshold: usize) -> MultipartOptions {
+        self.memory_threshold = memory_threshold;
+        self
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn max_parts(&self) -> usize {
+        self.max_parts
+    }
+
+    pub fn max_part_size(&self) -> usize {
+        self.max_part_size
+    }
+
+    pub fn memory_threshold(&self) -> usize {
+        self.memory_threshold
+    }
+}
+
+impl Default for MultipartOptions {
+    fn default() -> MultipartOptions {
+        MultipartOptions::new()
+    }
+}
+
+/// An iterator over the parts of a multipart body
+///
+/// Iteration stops after the first error. The limits set on it apply when it parses a body
+/// itself. A body the server parsed while reading it was held to the limits of the server.
+#[derive(Debug)]
+pub struct Multipart<'a> {
+    // The body and boundary, until the body is parsed on the first call to next
+    unparsed: Option<(&'a [u8], String)>,
+    options: MultipartOptions,
+    parts: VecDeque<Part>,
+    error: Option<MalformedRequestError>,
+}
+
+impl<'a> Multipart<'a> {
+    /// Creates an iterator over a body with the boundary from the `Content-Type` header
+    ///
+    /// Allows 100 parts of up to [`DEFAULT_MAX_BODY_SIZE`] each.
+    pub fn new(body: &'a [u8], boundary: &str) -> Multipart<'a> {
+        Multipart {
+            unparsed: Some((body, String::from(boundary))),
+            options: MultipartOptions::new(),
+            parts: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// The parts of a body that was parsed as it was read
+    fn parsed(parser: MultipartParser, error: Option<MalformedRequestError>) -> Multipart<'static> {
+        Multipart {
+            unparsed: None,
+            options: parser.options,
+            parts: parser.parts,
+            error,
+        }
+    }
+
+    pub fn with_max_parts(mut self, max_parts: usize) -> Multipart<'a> {
+        self.options.max_parts = max_parts;
+        self
+    }
+
+    /// Sets the largest part content accepted, in bytes
+    pub fn with_max_part_size(mut self, max_part_size: usize) -> Multipart<'a> {
+        self.options.max_part_size = max_part_size;
+        self
+    }
+
+    /// Sets the size above which a part is written to a temporary file, in bytes
+    pub fn with_memory_threshold(mut self, memory_threshold: usize) -> Multipart<'a> {
+        self.options.memory_threshold = memory_threshold;
+        self
+    }
+}
+
+impl Iterator for Multipart<'_> {
+    type Item = Result<Part, MalformedRequestError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((body, boundary)) = self.unparsed.take() {
+            let mut parser = MultipartParser::new(&boundary, self.options);
+            self.error = parser.feed(body).and_then(|_| parser.finish()).err();
+            self.parts = parser.parts;
+        }
+        match self.parts.pop_front() {
+            Some(part) => Some(Ok(part)),
+            None => self.error.take().map(Err),
+        }
+    }
+}
+
+/// A multipart body parsed while it is read from the connection
+///
+/// Kept with the request until [`RequestInfo::multipart`](crate::RequestInfo::multipart) takes it.
+#[derive(Debug)]
+pub(crate) struct Streamed(Mutex<Option<Multipart<'static>>>);
+
+impl Streamed {
+    fn new(parser: MultipartParser, error: Option<MalformedRequestError>) -> Streamed {
+        Streamed(Mutex::new(Some(Multipart::parsed(parser, error))))
+    }
+
+    pub(crate) fn take(&self) -> Option<Multipart<'static>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Reads a multipart body from a connection, parsing it as it arrives
+///
+/// A body that cannot be parsed is still read to its end, so the connection stays usable. The
+/// error is handed to the handler with the parts before it.
+pub(crate) async fn read(conn: &mut ConnectionInfo, length: usize, boundary: &str, options: MultipartOptions) -> io::Result<Streamed> {
+    let mut parser = MultipartParser::new(boundary, options);
+    let mut error = None;
+    let mut chunk = vec![0; 16 * 1024];
+    let mut remaining = length;
+    while remaining > 0 {
+        let read = conn.read(&mut chunk[..remaining.min(16 * 1024)]).await?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed during request body"));
+        }
+        remaining -= read;
+        if error.is_none() {
+            error = parser.feed(&chunk[..read]).err();
+        }
+    }
+    let error = error.or_else(|| parser.finish().err());
+    Ok(Streamed::new(parser, error))
+}
+
+/// Parses a multipart body fed to it in chunks
+///
+/// Only the start of a part that may still hold a boundary is buffered, so the memory used does
+/// not grow with the size of the body. The server uses it to parse bodies while reading them, and
+/// it can be used the same way on a body read some other way.
+///
+/// # Examples
+/// ```
+/// use simpleserve::multipart::{MultipartOptions, MultipartParser};
+///
+/// let mut parser = MultipartParser::new("XyZ", MultipartOptions::new());
+/// parser.feed(b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHel").unwrap();
+/// parser.feed(b"lo\r\n--XyZ--\r\n").unwrap();
+/// parser.finish().unwrap();
+/// let part = parser.parts().next().unwrap();
+/// assert_eq!(part.name(), Some("title"));
+/// assert_eq!(part.text(), Some("Hello"));
+/// ```
+#[derive(Debug)]
+pub struct MultipartParser {
+    delimiter: Vec<u8>,
+    closing: Vec<u8>,
+    options: MultipartOptions,
+    buffer: Vec<u8>,
+    state: State,
+    count: usize,
+    parts: VecDeque<Part>,
+}
+
+#[derive(Debug)]
+enum State {
+    Preamble,
+    Boundary,
+    Head,
+    Content(Part, usize, Option<File>),
+    Done,
+}
+
+impl MultipartParser {
+    /// Creates a parser for a body with the boundary from the `Content-Type` header
+    pub fn new(boundary: &str, options: MultipartOptions) -> MultipartParser {
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let mut closing = b"\r\n".to_vec();
+        closing.extend_from_slice(&delimiter);
+        MultipartParser {
+            delimiter,
+            closing,
+            options,
+            buffer: Vec::new(),
+            state: State::Preamble,
+            count: 0,
+            parts: VecDeque::new(),
+        }
+    }
+
+    /// Parses the next bytes of the body
+    ///
+    /// After an error the parser should not be fed any further.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), MalformedRequestError> {
+        self.buffer.extend_from_slice(chunk);
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Checks that the body ended with its closing boundary
+    pub fn finish(&mut self) -> Result<(), MalformedRequestError> {
+        match self.state {
+            State::Done => Ok(()),
+            State::Preamble => Err(MalformedRequestError::new("Missing multipart boundary")),
+            State::Head => Err(MalformedRequestError::new("Unterminated multipart headers")),
+            State::Boundary | State::Content(..) => Err(MalformedRequestError::new("Unterminated multipart part")),
+        }
+    }
+
+    /// The parts parsed so far
+    pub fn parts(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter()
+    }
+
+    /// Parses as much of the buffer as possible, returning whether it should be called again
+    fn step(&mut self) -> Result<bool, MalformedRequestError> {
+        match &mut self.state {
+            State::Preamble => match find(&self.buffer, &self.delimiter, 0) {
+                Some(start) => {
+                    self.buffer.drain(..start + self.delimiter.len());
+                    self.state = State::Boundary;
+                    Ok(true)
+                },
+                None => {
+                    // The end of the buffer may be the start of the boundary
+                    let keep = self.buffer.len().min(self.delimiter.len() - 1);
+                    self.buffer.drain(..self.buffer.len() - keep);
+                    Ok(false)
+                }
+            },
+            State::Boundary if self.buffer.len() < 2 => Ok(false),
+            State::Boundary if self.buffer.starts_with(b"--") => {
+                self.buffer.clear();
+                self.state = State::Done;
+                Ok(false)
+            },
+            State::Boundary if self.buffer.starts_with(b"\r\n") => {
+                if self.count == self.options.max_parts {
+                    return Err(MalformedRequestError::new("Too many multipart parts"));
+                }
+                self.buffer.drain(..2);
+                self.state = State::Head;
+                Ok(true)
+            },
+            State::Boundary => Err(MalformedRequestError::new("Invalid multipart boundary line")),
+            State::Head => {
+                let (head, content_start) = if self.buffer.starts_with(b"\r\n") {
+                    (String::new(), 2)
+                } else {
+                    match find(&self.buffer, b"\r\n\r\n", 0) {
+                        Some(end) => {
+                            let head = std::str::from_utf8(&self.buffer[..end])
+                                .map_err(|_| MalformedRequestError::new("Multipart headers are not UTF-8"))?;
+                            (String::from(head), end + 4)
+                        },
+                        None if self.buffer.len() > MAX_PART_HEAD_SIZE => {
+                            return Err(MalformedRequestError::new("Multipart headers too large"));
+                        },
+                        None => return Ok(false),
+                    }
+                };
+                self.buffer.drain(..content_start);
+                self.count += 1;
+                self.state = State::Content(Part::new(parse_part_headers(&head)?), 0, None);
+                Ok(true)
+            },
+            State::Content(part, size, file) => match find(&self.buffer, &self.closing, 0) {
+                Some(end) => {
+                    write_content(&self.options, part, size, file, &self.buffer[..end])?;
+                    self.buffer.drain(..end + self.closing.len());
+                    if let State::Content(part, ..) = std::mem::replace(&mut self.state, State::Boundary) {
+                        self.parts.push_back(part);
+                    }
+                    Ok(true)
+                },
+                None => {
+                    // The end of the buffer may be the start of the boundary
+                    let complete = self.buffer.len().saturating_sub(self.closing.len() - 1);
+                    write_content(&self.options, part, size, file, &self.buffer[..complete])?;
+                    self.buffer.drain(..complete);
+                    Ok(false)
+                }
+            },
+            State::Done => {
+                self.buffer.clear();
+                Ok(false)
+            },
+        }
+    }
+}
+
+/// Adds bytes to the content of a part, moving it to a temporary file once it passes the threshold
+fn write_content(
+    options: &MultipartOptions,
+    part: &mut Part,
+    size: &mut usize,
+    file: &mut Option<File>,
+    bytes: &[u8]
+) -> Result<(), MalformedRequestError> {
+    *size += bytes.len();
+    if *size > options.max_part_size {
+        return Err(MalformedRequestError::new("Multipart part too large"));
+    }
+    let stored = |e: io::Error| MalformedRequestError::new(&format!("Could not store upload: {}", e));
+    if let Content::Memory(data) = &mut part.content {
+        if *size <= options.memory_threshold {
+            data.extend_from_slice(bytes);
+            return Ok(());
+        }
+        let (upload, mut created) = UploadedFile::create().map_err(stored)?;
+        created.write_all(data).map_err(stored)?;
+        part.content = Content::File(upload);
+        *file = Some(created);
+    }
+    if let (Content::File(upload), Some(file)) = (&mut part.content, file) {
+        file.write_all(bytes).map_err(stored)?;
+        upload.size = *size;
+    }
+    Ok(())
+}
+
+/// A field or file of a multipart body
+#[derive(Debug)]
+pub struct Part {
+    headers: Headers,
+    name: Option<String>,
+    filename: Option<String>,
+    content: Content,
+}
+
+#[derive(Debug)]
+enum Content {
+    Memory(Vec<u8>),
+    File(UploadedFile),
+}
+
+impl Part {
+    fn new(headers: Headers) -> Part {
+        let disposition = headers.get("content-disposition").map(parse_parameters).unwrap_or_default();
+        let parameter = |key: &str| disposition
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone());
+        Part {
+            name: parameter("name"),
+            filename: parameter("filename"),
+            headers,
+            content: Content::Memory(Vec::new()),
+        }
+    }
+
+    /// The name of the form field
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The name of the uploaded file, as sent by the client
+    ///
+    /// This must not be used as a path without sanitizing it.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("content-type")
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The content, if it was kept in memory
+    pub fn data(&self) -> Option<&[u8]> {
+        match &self.content {
+            Content::Memory(data) => Some(data),
+            Content::File(_) => None,
+        }
+    }
+
+    /// The content as text, if it was kept in memory and is UTF-8
+    pub fn text(&self) -> Option<&str> {
+        self.data().and_then(|data| std::str::from_utf8(data).ok())
+    }
+
+    /// The temporary file holding the content, if it was too large to keep in memory
+    pub fn file(&self) -> Option<&UploadedFile> {
+        match &self.content {
+            Content::File(file) => Some(file),
+            Content::Memory(_) => None,
+        }
+    }
+
+    /// The temporary file holding the content, taking ownership so it can be persisted
+    pub fn into_file(self) -> Option<UploadedFile> {
+        match self.content {
+            Content::File(file) => Some(file),
+            Content::Memory(_) => None,
+        }
+    }
+
+    /// Reads the content, wherever it is kept
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        match &self.content {
+            Content::Memory(data) => Ok(data.clone()),
+            Content::File(file) => fs::read(file.path()),
+        }
+    }
+}
+
+/// A part written to a temporary file
+///
+/// The file is removed when this is dropped, unless it was persisted.
+#[derive(Debug)]
+pub struct UploadedFile {
+    path: PathBuf,
+    size: usize,
+    persisted: bool,
+}
+
+impl UploadedFile {
+    /// Creates an empty temporary file, only readable by the owner of the process
+    fn create() -> io::Result<(UploadedFile, File)> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "simpleserve-upload-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path)?;
+        Ok((UploadedFile { path, size: 0, persisted: false }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Moves the file to a permanent location
+    pub fn persist<P: AsRef<Path>>(mut self, to: P) -> io::Result<()> {
+        if fs::rename(&self.path, &to).is_err() {
+            // Renaming fails across file systems
+            fs::copy(&self.path, &to)?;
+            fs::remove_file(&self.path)?;
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The boundary parameter of a `multipart/form-data` content type
+///
+/// # Examples
+/// ```
+/// use simpleserve::multipart::boundary;
+///
+/// assert_eq!(boundary("multipart/form-data; boundary=----abc"), Some("----abc"));
+/// assert_eq!(boundary("multipart/form-data; boundary=\"a b\""), Some("a b"));
+/// assert_eq!(boundary("text/plain"), None);
+/// ```
+pub fn boundary(content_type: &str) -> Option<&str> {
+    let mut parameters = content_type.split(';');
+    let mime_type = parameters.next()?.trim();
+    if !mime_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    parameters.find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+        (!value.is_empty()).then_some(value)
+    })
+}
+
+fn parse_part_headers(head: &str) -> Result<Headers, MalformedRequestError> {
+    let mut headers = Headers::new();
+    for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() => headers.insert(name.trim(), value.trim()),
+            _ => return Err(MalformedRequestError::new(&format!("Invalid multipart header: {}", line))),
+        }
+    }
+    Ok(headers)
+}
+
+/// Splits `form-data; name="a"; filename="b;c.txt"` into its parameters, keeping quoted semicolons
+fn parse_parameters(value: &str) -> Vec<(String, String)> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            },
+            ';' if !quoted => segments.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    segments.push(current);
+    segments
+        .iter()
+        .filter_map(|segment| {
+            let (name, value) = segment.split_once('=')?;
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+            Some((String::from(name.trim()), value.replace("\\\"", "\"")))
+        })
+        .collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| from + position)
+}
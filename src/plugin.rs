@@ -0,0 +1,55 @@
+//! Plugins for the webserver
+//!
+//! A [`Plugin`] bundles routes and lifecycle hooks so reusable functionality can be
+//! shipped as a separate crate and added to a server with one call.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Page,
+//!     Sendable,
+//!     RequestInfo,
+//!     plugin::Plugin
+//! };
+//!
+//! struct Ping;
+//!
+//! impl Plugin for Ping {
+//!     fn name(&self) -> &str {
+//!         "ping"
+//!     }
+//!
+//!     fn register(&self, server: &mut Webserver) {
+//!         server.add_health_route("/ping", |_: &RequestInfo| -> Box<dyn Sendable> {
+//!             Box::new(Page::new(200, String::from("pong")))
+//!         });
+//!     }
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.register_plugin(Ping);
+//! assert_eq!(server.plugins()[0].name(), "ping");
+//! ```
+
+use crate::server::{
+    Webserver,
+    ShutdownReport
+};
+
+/// Functionality that can be added to a [`Webserver`]
+pub trait Plugin: Send + Sync {
+    /// The name of the plugin, used in log messages
+    fn name(&self) -> &str;
+
+    /// Registers the plugin's routes and settings
+    ///
+    /// Called once, from [`Webserver::register_plugin`].
+    fn register(&self, server: &mut Webserver);
+
+    /// Called when the server starts, before it accepts connections
+    fn on_start(&self) {}
+
+    /// Called after the server has shut down
+    fn on_shutdown(&self, _report: &ShutdownReport) {}
+}
@@ -0,0 +1,310 @@
+//! Connection pools
+//!
+//! A [`Pool`] hands out connections to a database or another backend. Adding one with
+//! [`Webserver::with_pool`](crate::Webserver::with_pool) makes it shared state, so handlers get
+//! it with [`RequestInfo::state`](crate::RequestInfo::state), and keeps the server from being
+//! ready until the pool is reachable. [`readiness`] is a route handler that checks every pool,
+//! for load balancers or for a [`SelfCheck`](crate::self_check::SelfCheck), which then takes the
+//! server out of rotation while a pool is down. [`transaction`] starts a [`Transaction`] for a
+//! request, which is rolled back unless it is committed.
+//!
+//! The trait is small enough to wrap any pool. For sqlx, `acquire` would call `acquire` on the
+//! `PgPool`, `check` would run `SELECT 1`, and the transaction methods would run `BEGIN`,
+//! `COMMIT` and `ROLLBACK` on the connection.
+//!
+//! ## Example
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use async_trait::async_trait;
+//! use simpleserve::{
+//!     Webserver,
+//!     Page,
+//!     Sendable,
+//!     RequestInfo,
+//!     HandlerFuture,
+//!     errors::PoolError,
+//!     pool::{self, Pool}
+//! };
+//!
+//! // Notes kept in memory, standing in for a database
+//! #[derive(Default)]
+//! struct Notes {
+//!     rows: Arc<Mutex<Vec<String>>>,
+//! }
+//!
+//! #[async_trait]
+//! impl Pool for Notes {
+//!     type Connection = Vec<String>;
+//!
+//!     async fn acquire(&self) -> Result<Vec<String>, PoolError> {
+//!         Ok(Vec::new())
+//!     }
+//!
+//!     async fn begin(&self, _: &mut Vec<String>) -> Result<(), PoolError> {
+//!         Ok(())
+//!     }
+//!
+//!     async fn commit(&self, pending: &mut Vec<String>) -> Result<(), PoolError> {
+//!         self.rows.lock().unwrap().append(pending);
+//!         Ok(())
+//!     }
+//!
+//!     async fn rollback(&self, pending: &mut Vec<String>) -> Result<(), PoolError> {
+//!         pending.clear();
+//!         Ok(())
+//!     }
+//! }
+//!
+//! fn add_note<'a>(request: &'a RequestInfo<'a>) -> HandlerFuture<'a> {
+//!     Box::pin(async move {
+//!         let mut transaction = match pool::transaction::<Notes>(request).await {
+//!             Ok(transaction) => transaction,
+//!             Err(e) => return Box::new(Page::new(503, e.to_string())) as Box<dyn Sendable>,
+//!         };
+//!         transaction.push(String::from(request.body_string().unwrap_or_default()));
+//!         match transaction.commit().await {
+//!             Ok(()) => Box::new(Page::new(201, String::from("Saved"))) as Box<dyn Sendable>,
+//!             Err(e) => Box::new(Page::new(500, e.to_string())),
+//!         }
+//!     })
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]).with_pool(Notes::default());
+//! server.add_async_route("/notes", add_note);
+//! server.add_async_route("/ready", pool::readiness);
+//! ```
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration
+};
+
+use async_trait::async_trait;
+use tokio::runtime::Handle;
+
+use crate::{
+    errors::PoolError,
+    response::Response,
+    server::{
+        HandlerFuture,
+        RequestInfo,
+        Sendable
+    }
+};
+
+/// How long a health check may take before the pool counts as down
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait between checks of a pool that is not reachable yet
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A pool of connections
+#[async_trait]
+pub trait Pool: Send + Sync + 'static {
+    type Connection: Send + 'static;
+
+    /// The name of the pool in logs and readiness responses
+    fn name(&self) -> &str {
+        "database"
+    }
+
+    /// Takes a connection from the pool
+    async fn acquire(&self) -> Result<Self::Connection, PoolError>;
+
+    /// Checks that the backend is reachable
+    ///
+    /// Acquires a connection by default. Pools that keep idle connections should run a query
+    /// instead, since an idle connection says little about the backend.
+    async fn check(&self) -> Result<(), PoolError> {
+        self.acquire().await.map(|_| ())
+    }
+
+    /// Starts a transaction on a connection
+    async fn begin(&self, _connection: &mut Self::Connection) -> Result<(), PoolError> {
+        Err(PoolError::new("Transactions are not supported"))
+    }
+
+    async fn commit(&self, _connection: &mut Self::Connection) -> Result<(), PoolError> {
+        Err(PoolError::new("Transactions are not supported"))
+    }
+
+    async fn rollback(&self, _connection: &mut Self::Connection) -> Result<(), PoolError> {
+        Err(PoolError::new("Transactions are not supported"))
+    }
+}
+
+/// A pool without its connection type, so pools of different types can be checked together
+#[async_trait]
+trait HealthCheck: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn check(&self) -> Result<(), PoolError>;
+}
+
+#[async_trait]
+impl<P: Pool> HealthCheck for P {
+    fn name(&self) -> &str {
+        Pool::name(self)
+    }
+
+    async fn check(&self) -> Result<(), PoolError> {
+        match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, Pool::check(self)).await {
+            Ok(result) => result,
+            Err(_) => Err(PoolError::new("Health check timed out")),
+        }
+    }
+}
+
+/// The pools of a server, for [`readiness`]
+#[derive(Default)]
+pub(crate) struct Pools {
+    pools: Mutex<Vec<Arc<dyn HealthCheck>>>,
+}
+
+impl Pools {
+    pub(crate) fn add<P: Pool>(&self, pool: Arc<P>) {
+        self.pools.lock().unwrap().push(pool);
+    }
+}
+
+/// Waits until a pool is reachable, for the warm-up of the server
+pub(crate) async fn wait_until_reachable<P: Pool>(pool: Arc<P>) {
+    while let Err(e) = HealthCheck::check(&*pool).await {
+        println!("Waiting for {}: {}", Pool::name(&*pool), e);
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// A route handler that checks every pool of the server
+///
+/// Answers 200 OK if every pool is reachable, or 503 Service Unavailable with the name and
+/// error of every pool that is not. Add it with
+/// [`Webserver::add_async_route`](crate::Webserver::add_async_route). Like every route, it is
+/// answered with 503 while the server is not ready.
+pub fn readiness<'a>(request: &'a RequestInfo<'a>) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let pools = request.state::<Pools>()
+            .map(|pools| pools.pools.lock().unwrap().clone())
+            .unwrap_or_default();
+        let mut failures = Vec::new();
+        for pool in pools {
+            if let Err(e) = pool.check().await {
+                failures.push(format!("{}: {}", pool.name(), e.message()));
+            }
+        }
+        let (status, body) = match failures.is_empty() {
+            true => (200, String::from("Ready")),
+            false => (503, failures.join("\n")),
+        };
+        Box::new(Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .text(&body)) as Box<dyn Sendable>
+    })
+}
+
+/// Starts a transaction on a connection from the pool of a type
+///
+/// # Errors
+/// If the server has no pool of the type, or the pool could not start a transaction.
+pub async fn transaction<P: Pool>(request: &RequestInfo<'_>) -> Result<Transaction<P>, PoolError> {
+    match request.state::<P>() {
+        Some(pool) => Transaction::begin(pool).await,
+        None => Err(PoolError::new(&format!("No pool of type {}", std::any::type_name::<P>()))),
+    }
+}
+
+/// A transaction on a connection from a pool
+///
+/// Dereferences to the connection. A transaction that is dropped without being committed is
+/// rolled back, so a handler returning early with an error leaves nothing half done. When it is
+/// dropped by a handler, the rollback runs once the handler returns and before its response is
+/// sent, on the runtime the connection was acquired on, which the connections of most drivers
+/// are bound to. Elsewhere it is spawned on the current runtime.
+pub struct Transaction<P: Pool> {
+    pool: Arc<P>,
+    connection: Option<P::Connection>,
+}
+
+impl<P: Pool> Transaction<P> {
+    /// Acquires a connection and starts a transaction on it
+    pub async fn begin(pool: Arc<P>) -> Result<Transaction<P>, PoolError> {
+        let mut connection = pool.acquire().await?;
+        pool.begin(&mut connection).await?;
+        Ok(Transaction {
+            pool,
+            connection: Some(connection),
+        })
+    }
+
+    pub async fn commit(mut self) -> Result<(), PoolError> {
+        let mut connection = self.connection.take().expect("A transaction has its connection until it ends");
+        self.pool.commit(&mut connection).await
+    }
+
+    pub async fn rollback(mut self) -> Result<(), PoolError> {
+        let mut connection = self.connection.take().expect("A transaction has its connection until it ends");
+        self.pool.rollback(&mut connection).await
+    }
+}
+
+impl<P: Pool> Deref for Transaction<P> {
+    type Target = P::Connection;
+
+    fn deref(&self) -> &P::Connection {
+        self.connection.as_ref().expect("A transaction has its connection until it ends")
+    }
+}
+
+impl<P: Pool> DerefMut for Transaction<P> {
+    fn deref_mut(&mut self) -> &mut P::Connection {
+        self.connection.as_mut().expect("A transaction has its connection until it ends")
+    }
+}
+
+impl<P: Pool> Drop for Transaction<P> {
+    fn drop(&mut self) {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let pool = Arc::clone(&self.pool);
+        let mut rollback: Option<Rollback> = Some(Box::pin(async move {
+            if let Err(e) = pool.rollback(&mut connection).await {
+                println!("Could not roll back a transaction on {}: {}", Pool::name(&*pool), e);
+            }
+        }));
+        let _ = ROLLBACKS.try_with(|rollbacks| rollbacks.borrow_mut().extend(rollback.take()));
+        if let Some(rollback) = rollback {
+            match Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn(rollback)),
+                Err(_) => println!("Could not roll back a transaction on {}: no runtime", Pool::name(&*self.pool)),
+            }
+        }
+    }
+}
+
+type Rollback = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+tokio::task_local! {
+    /// The rollbacks of the transactions dropped while handling the current request
+    static ROLLBACKS: RefCell<Vec<Rollback>>;
+}
+
+/// Runs a handler, then the rollbacks of the transactions it dropped
+///
+/// The runtime of a connection is dropped once its response is sent, so a rollback spawned on it
+/// could be cut short.
+pub(crate) async fn rolling_back<F: Future>(handler: F) -> F::Output {
+    ROLLBACKS.scope(RefCell::new(Vec::new()), async {
+        let output = handler.await;
+        for rollback in ROLLBACKS.with(|rollbacks| rollbacks.take()) {
+            rollback.await;
+        }
+        output
+    }).await
+}
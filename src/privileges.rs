@@ -0,0 +1,125 @@
+//! Dropping root privileges
+//!
+//! Binding a port below 1024 needs root, but serving requests as root means any bug in a handler
+//! runs with full access to the machine. With [`Webserver::run_as`](crate::Webserver::run_as) the
+//! server binds its port as root and then switches to an unprivileged user and group before it
+//! accepts a connection, optionally confining itself to a directory with `chroot` first.
+//!
+//! After a chroot, paths are resolved inside the new root and the working directory is its top,
+//! so files served with relative paths should be laid out relative to the chroot directory. The
+//! blacklisted paths and served directories of the server are moved inside the new root for you.
+//! Only supported on Unix.
+//!
+//! ## Example
+//! ```no_run
+//! use simpleserve::{Webserver, ConnectionType};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut server = Webserver::new(10, vec![])
+//!     .run_as("www-data", "www-data")
+//!     .with_chroot("/srv/www");
+//! server.start("0.0.0.0:80", ConnectionType::Http, None, None).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    io,
+    path::{Path, PathBuf}
+};
+
+/// The user and group to switch to, and an optional directory to chroot into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Privileges {
+    user: String,
+    group: String,
+    chroot: Option<PathBuf>,
+}
+
+impl Privileges {
+    pub fn new(user: &str, group: &str) -> Privileges {
+        Privileges {
+            user: String::from(user),
+            group: String::from(group),
+            chroot: None,
+        }
+    }
+
+    pub fn with_chroot<P: AsRef<Path>>(mut self, dir: P) -> Privileges {
+        self.chroot = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    pub fn chroot(&self) -> Option<&Path> {
+        self.chroot.as_deref()
+    }
+
+    /// Switches the whole process to the user and group
+    ///
+    /// The user and group are looked up before anything is changed, so an unknown name leaves
+    /// the process as it was. Fails if the process could regain root afterwards.
+    #[cfg(unix)]
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let user = CString::new(self.user.as_str()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let group = CString::new(self.group.as_str()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: the names are valid C strings, and the returned records are read before any other lookup
+        let uid = unsafe { libc::getpwnam(user.as_ptr()).as_ref() }
+            .map(|passwd| passwd.pw_uid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Unknown user {}", self.user)))?;
+        let gid = unsafe { libc::getgrnam(group.as_ptr()).as_ref() }
+            .map(|group| group.gr_gid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Unknown group {}", self.group)))?;
+
+        if let Some(dir) = &self.chroot {
+            let dir = CString::new(dir.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: the path is a valid C string
+            check(unsafe { libc::chroot(dir.as_ptr()) })?;
+            std::env::set_current_dir("/")?;
+        }
+        // The group has to change first, a process that is no longer root cannot change it.
+        // The libc wrappers apply the change to every thread, including the pool's workers.
+        // SAFETY: plain system calls without pointers, apart from the empty group list
+        unsafe {
+            check(libc::setgroups(0, std::ptr::null()))?;
+            check(libc::setgid(gid))?;
+            check(libc::setuid(uid))?;
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Root privileges could be regained"));
+        }
+        println!("Running as {}:{}", self.user, self.group);
+        Ok(())
+    }
+
+    /// Dropping privileges is not supported on this platform
+    #[cfg(not(unix))]
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Dropping privileges is only supported on Unix"))
+    }
+}
+
+/// The path a file has once the process is chrooted into a directory
+///
+/// Both paths should be canonical. Paths outside the directory cannot be reached after the chroot.
+pub(crate) fn path_in_chroot(root: &Path, path: &Path) -> Option<PathBuf> {
+    path.strip_prefix(root).ok().map(|relative| Path::new("/").join(relative))
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,211 @@
+//! Rate limiting by client IP
+//!
+//! A [`RateLimiter`] gives every client IP a token bucket: each request takes a token, and tokens
+//! come back at a steady rate up to a burst, so a client can send a few requests at once but not
+//! keep sending faster than the rate. Requests without a token are answered with 429 Too Many
+//! Requests, and a `Retry-After` header saying when the next token is back.
+//!
+//! Buckets are kept for a limited number of clients, and the least recently seen client is
+//! forgotten first, so a flood of addresses cannot grow the limiter without bound. A forgotten
+//! client starts over with a full bucket.
+//!
+//! The limiter is a [`Middleware`], added with
+//! [`Webserver::add_middleware`](crate::Webserver::add_middleware) to run in order with other
+//! middleware, or with [`Webserver::with_rate_limit`](crate::Webserver::with_rate_limit) to run
+//! before all of them.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     rate_limit::RateLimiter
+//! };
+//!
+//! // 60 requests a minute, and up to 10 at once
+//! let limiter = RateLimiter::new(60, Duration::from_secs(60)).with_burst(10);
+//! let server = Webserver::new(10, vec![]).with_rate_limit(limiter);
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    middleware::Middleware,
+    request::Request,
+    response::Response,
+    server::Sendable
+};
+
+/// The number of clients buckets are kept for by default
+pub const DEFAULT_MAX_CLIENTS: usize = 10_000;
+
+/// Middleware that limits how fast each client IP can send requests
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: u32,
+    max_clients: usize,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<Buckets>,
+}
+
+/// The buckets of the clients, and the order they were last seen in
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_seen: BTreeMap<u64, IpAddr>,
+    next_seen: u64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    seen: u64,
+}
+
+impl RateLimiter {
+    /// Allows a number of requests per period, with a burst of the same size
+    ///
+    /// # Panics
+    /// If the number of requests or the period is 0.
+    pub fn new(requests: u32, per: Duration) -> RateLimiter {
+        assert!(requests > 0 && !per.is_zero(), "A rate limit must allow some requests per period");
+        RateLimiter {
+            rate: requests as f64 / per.as_secs_f64(),
+            burst: requests,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            clock: Arc::new(SystemClock),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Sets how many requests a client can send at once
+    ///
+    /// # Panics
+    /// If the burst is 0.
+    pub fn with_burst(mut self, burst: u32) -> RateLimiter {
+        assert!(burst > 0, "The burst of a rate limit must be at least 1");
+        self.burst = burst;
+        self
+    }
+
+    /// Sets how many clients buckets are kept for
+    ///
+    /// Defaults to [`DEFAULT_MAX_CLIENTS`].
+    ///
+    /// # Panics
+    /// If the number of clients is 0.
+    pub fn with_max_clients(mut self, max_clients: usize) -> RateLimiter {
+        assert!(max_clients > 0, "A rate limiter must keep at least one client");
+        self.max_clients = max_clients;
+        self
+    }
+
+    /// Sets the clock tokens are refilled by
+    ///
+    /// Useful in tests, together with [`MockClock`](crate::clock::MockClock).
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> RateLimiter {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The number of requests allowed per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    pub fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+
+    /// The number of clients with a bucket
+    pub fn clients(&self) -> usize {
+        self.buckets.lock().unwrap().buckets.len()
+    }
+
+    /// Takes a token for a request from a client
+    ///
+    /// # Errors
+    /// If the client has no token left, with how long until it gets one back.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::{
+    ///     clock::MockClock,
+    ///     rate_limit::RateLimiter
+    /// };
+    ///
+    /// let clock = MockClock::new();
+    /// let limiter = RateLimiter::new(2, Duration::from_secs(1)).with_clock(clock.clone());
+    /// let client = "127.0.0.1".parse().unwrap();
+    /// assert!(limiter.check(client).is_ok());
+    /// assert!(limiter.check(client).is_ok());
+    /// assert_eq!(limiter.check(client), Err(Duration::from_millis(500)));
+    /// clock.advance(Duration::from_millis(500));
+    /// assert!(limiter.check(client).is_ok());
+    /// ```
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { buckets, last_seen, next_seen } = &mut *buckets;
+        let seen = *next_seen;
+        *next_seen += 1;
+
+        if !buckets.contains_key(&client) && buckets.len() >= self.max_clients {
+            if let Some((_, forgotten)) = last_seen.pop_first() {
+                buckets.remove(&forgotten);
+            }
+        }
+        let bucket = buckets.entry(client).or_insert_with(|| Bucket {
+            tokens: self.burst as f64,
+            updated: now,
+            seen,
+        });
+        last_seen.remove(&bucket.seen);
+        last_seen.insert(seen, client);
+        bucket.seen = seen;
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst as f64);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+impl Middleware for RateLimiter {
+    /// Answers 429 Too Many Requests to clients without a token
+    ///
+    /// Requests without a peer address, like ones handled without a connection, are not limited.
+    fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+        let client = request.peer_addr?.ip();
+        let wait = self.check(client).err()?;
+        Some(too_many_requests(wait))
+    }
+}
+
+/// The 429 Too Many Requests response for a client that gets a token back after `wait`
+pub(crate) fn too_many_requests(wait: Duration) -> Box<dyn Sendable> {
+    // Retry-After is in whole seconds, rounded up so the token is back by then
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Box::new(Response::new(429)
+        .header("Retry-After", &retry_after.max(1).to_string())
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .text("Too many requests, try again later."))
+}
@@ -0,0 +1,532 @@
+//! Parsing of incoming requests
+//!
+//! The server reads the head of a request (the request line and the headers) and parses it
+//! with [`parse_head`]. Handlers see the result through [`RequestInfo`](crate::server::RequestInfo).
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::Arc,
+    str::FromStr
+};
+
+use crate::{
+    errors::MalformedRequestError,
+    geo::GeoInfo,
+    utils
+};
+
+/// The method of a request
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::Method;
+///
+/// assert_eq!("POST".parse::<Method>().unwrap(), Method::Post);
+/// assert_eq!(Method::Delete.to_string(), "DELETE");
+/// assert!("post".parse::<Method>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    /// Every method, in the order they are listed in the HTTP specification
+    pub const ALL: [Method; 9] = [
+        Method::Get,
+        Method::Head,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Connect,
+        Method::Options,
+        Method::Trace,
+        Method::Patch,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = MalformedRequestError;
+
+    /// Parses a method name, which is case-sensitive
+    fn from_str(method: &str) -> Result<Method, MalformedRequestError> {
+        match method {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(MalformedRequestError::new(&format!("Unknown method: {}", method))),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Parses the method from a request line
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::{parse_method, Method};
+///
+/// assert_eq!(parse_method("PUT /users/1 HTTP/1.1").unwrap(), Method::Put);
+/// ```
+pub fn parse_method(request_line: &str) -> Result<Method, MalformedRequestError> {
+    match request_line.split_whitespace().next() {
+        Some(method) => method.parse(),
+        None => Err(MalformedRequestError::new("Missing method")),
+    }
+}
+
+/// Parses a query string into its parameters
+///
+/// Keys and values are URL decoded, with `+` decoded as a space. A key without `=` has an empty value,
+/// and if a key is repeated, the last value is kept.
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::parse_query;
+///
+/// let query = parse_query("q=hello+world&page=2&lang=en%2Dgb&debug");
+/// assert_eq!(query["q"], "hello world");
+/// assert_eq!(query["page"], "2");
+/// assert_eq!(query["lang"], "en-gb");
+/// assert_eq!(query["debug"], "");
+/// ```
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_query_component(key), decode_query_component(value))
+        })
+        .collect()
+}
+
+fn decode_query_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    match urlencoding::decode(&component) {
+        Ok(decoded) => decoded.into_owned(),
+        Err(_) => component,
+    }
+}
+
+/// Whether the client wants to keep the connection open for further requests
+///
+/// HTTP/1.1 connections stay open unless the `Connection` header says `close`. Older versions are
+/// closed, since keeping them open would need a `Connection: keep-alive` response header.
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::{wants_keep_alive, Headers};
+///
+/// let mut headers = Headers::new();
+/// assert!(wants_keep_alive("GET / HTTP/1.1", &headers));
+/// assert!(!wants_keep_alive("GET / HTTP/1.0", &headers));
+/// headers.insert("Connection", "Upgrade, Close");
+/// assert!(!wants_keep_alive("GET / HTTP/1.1", &headers));
+/// ```
+pub fn wants_keep_alive(request_line: &str, headers: &Headers) -> bool {
+    let version = request_line.split_whitespace().nth(2);
+    let close = headers
+        .get_all("connection")
+        .flat_map(utils::split_list)
+        .any(|token| token.eq_ignore_ascii_case("close"));
+    version == Some("HTTP/1.1") && !close
+}
+
+/// What a `Range` header asks for out of a body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// The whole body, when there is no range that can be used
+    Full,
+    /// The bytes from the first offset to the last, inclusive
+    Partial(usize, usize),
+    /// No byte of the range is in the body
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a body of a length
+///
+/// Only a single range of bytes is supported. Several ranges, other units and malformed
+/// headers ask for the whole body, since a server is free to ignore a `Range` header.
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::{parse_range, ByteRange};
+///
+/// assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+/// assert_eq!(parse_range("bytes=900-", 1000), ByteRange::Partial(900, 999));
+/// assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+/// assert_eq!(parse_range("bytes=0-5000", 1000), ByteRange::Partial(0, 999));
+/// assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+/// assert_eq!(parse_range("bytes=0-1, 5-6", 1000), ByteRange::Full);
+/// assert_eq!(parse_range("lines=1-2", 1000), ByteRange::Full);
+/// ```
+pub fn parse_range(value: &str, length: usize) -> ByteRange {
+    let spec = match value.trim().split_once('=') {
+        Some((unit, spec)) if unit.trim().eq_ignore_ascii_case("bytes") && !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+    let parse = |bound: &str| match bound.trim() {
+        "" => Ok(None),
+        bound => bound.parse::<usize>().map(Some),
+    };
+    match (parse(first), parse(last)) {
+        // The last bytes of the body
+        (Ok(None), Ok(Some(suffix))) => match suffix.min(length) {
+            0 => ByteRange::Unsatisfiable,
+            suffix => ByteRange::Partial(length - suffix, length - 1),
+        },
+        (Ok(Some(first)), Ok(last)) => {
+            if last.is_some_and(|last| last < first) {
+                return ByteRange::Full;
+            }
+            if first >= length {
+                return ByteRange::Unsatisfiable;
+            }
+            ByteRange::Partial(first, last.map_or(length - 1, |last| last.min(length - 1)))
+        },
+        _ => ByteRange::Full,
+    }
+}
+
+/// The language the client prefers out of the ones in `available`, based on `Accept-Language`
+///
+/// The highest quality wins, and ties go to the language listed first in the header. A range
+/// matches a language with the same tag or with more subtags, so `en` matches `en-GB`, and
+/// otherwise falls back to a shorter tag, so `de-AT` matches `de`. `*` and ranges with a quality
+/// of 0 never pick a language, leaving the choice to the caller.
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::negotiate_language;
+///
+/// let available = ["en", "fr", "pt-BR"];
+/// assert_eq!(negotiate_language("fr-CA, en;q=0.8", &available), Some("fr"));
+/// assert_eq!(negotiate_language("pt", &available), Some("pt-BR"));
+/// assert_eq!(negotiate_language("de, *;q=0.5", &available), None);
+/// ```
+pub fn negotiate_language<'a>(accept_language: &str, available: &[&'a str]) -> Option<&'a str> {
+    let mut ranges: Vec<(String, f32)> = utils::parse_quality_list(accept_language)
+        .into_iter()
+        .filter(|(range, quality)| range != "*" && *quality > 0.0)
+        .collect();
+    // Stable, so ties keep the order of the header
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (range, _) in &ranges {
+        let found = available.iter().find(|language| language.eq_ignore_ascii_case(range))
+            .or_else(|| available.iter().find(|language| {
+                let language = language.to_ascii_lowercase();
+                language.strip_prefix(range.as_str()).is_some_and(|rest| rest.starts_with('-'))
+            }))
+            .or_else(|| {
+                let mut prefix = range.as_str();
+                while let Some((shorter, _)) = prefix.rsplit_once('-') {
+                    prefix = shorter;
+                    if let Some(language) = available.iter().find(|language| language.eq_ignore_ascii_case(prefix)) {
+                        return Some(language);
+                    }
+                }
+                None
+            });
+        if let Some(language) = found {
+            return Some(language);
+        }
+    }
+    None
+}
+
+/// The query string of a request line, without the `?`
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::query_string;
+///
+/// assert_eq!(query_string("GET /search?q=rust HTTP/1.1"), Some("q=rust"));
+/// assert_eq!(query_string("GET /search HTTP/1.1"), None);
+/// ```
+pub fn query_string(request_line: &str) -> Option<&str> {
+    let target = request_line.split_whitespace().nth(1)?;
+    target.split_once('?').map(|(_, query)| query)
+}
+
+/// A map holding one value per type
+///
+/// Used for the shared state of the server, and for values middleware attaches to a request.
+/// Cloning is cheap, the values are shared.
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::Extensions;
+///
+/// struct UserId(u64);
+///
+/// let mut extensions = Extensions::new();
+/// extensions.insert(UserId(7));
+/// assert_eq!(extensions.get::<UserId>().unwrap().0, 7);
+/// assert!(extensions.get::<String>().is_none());
+/// ```
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Adds a value, replacing any value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = Arc::clone(self.values.get(&TypeId::of::<T>())?);
+        value.downcast().ok()
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.values.len()).finish()
+    }
+}
+
+/// A parsed request
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub route: String,
+    pub params: HashMap<String, String>,
+    pub query: HashMap<String, String>,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    pub geo: Option<GeoInfo>,
+    pub peer_addr: Option<SocketAddr>,
+    pub extensions: Extensions,
+}
+
+/// The headers of a request
+///
+/// Keeps the headers in the order they were received. Lookups ignore the case of the name.
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::Headers;
+///
+/// let mut headers = Headers::new();
+/// headers.insert("Content-Type", "text/html");
+/// assert_eq!(headers.get("content-type"), Some("text/html"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    headers: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Adds a header, keeping any existing headers with the same name
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.headers.push((String::from(name), String::from(value)));
+    }
+
+    /// The value of the first header with this name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The values of every header with this name
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Iterates over the headers as `(name, value)` pairs, in the order they were received
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// The type of the body, from the `Content-Type` header without its parameters, in lowercase
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::request::Headers;
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.insert("Content-Type", "Application/JSON; charset=utf-8");
+    /// assert_eq!(headers.mime_type().as_deref(), Some("application/json"));
+    /// ```
+    pub fn mime_type(&self) -> Option<String> {
+        let content_type = self.get("content-type")?;
+        Some(content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+    }
+
+    /// Whether the body is of one of the types, where a type ending in `/` matches every subtype
+    ///
+    /// A request without a `Content-Type` matches none of them.
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::request::Headers;
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.insert("Content-Type", "text/csv");
+    /// assert!(headers.has_mime_type(&["application/json", "text/"]));
+    /// assert!(!headers.has_mime_type(&["text/plain"]));
+    /// ```
+    pub fn has_mime_type<S: AsRef<str>>(&self, mime_types: &[S]) -> bool {
+        let essence = match self.mime_type() {
+            Some(essence) => essence,
+            None => return false,
+        };
+        mime_types.iter().any(|mime_type| {
+            let mime_type = mime_type.as_ref();
+            match mime_type.ends_with('/') {
+                true => essence.starts_with(&mime_type.to_ascii_lowercase()),
+                false => mime_type.eq_ignore_ascii_case(&essence),
+            }
+        })
+    }
+
+    /// The length of the body, from the `Content-Length` header
+    /// 
+    /// Returns `None` if there is no `Content-Length` header, and an error if it is not a number
+    /// or is sent more than once, even with the same value, since proxies differ in which one they use.
+    /// 
+    /// # Examples
+    /// ```
+    /// use simpleserve::request::Headers;
+    /// 
+    /// let mut headers = Headers::new();
+    /// assert_eq!(headers.content_length().unwrap(), None);
+    /// headers.insert("Content-Length", "42");
+    /// assert_eq!(headers.content_length().unwrap(), Some(42));
+    /// headers.insert("Content-Length", "42");
+    /// assert!(headers.content_length().is_err());
+    /// ```
+    pub fn content_length(&self) -> Result<Option<usize>, MalformedRequestError> {
+        let mut values = self.get_all("content-length");
+        let value = match (values.next(), values.next()) {
+            (None, _) => return Ok(None),
+            (Some(value), None) => value,
+            (Some(_), Some(_)) => return Err(MalformedRequestError::new("Repeated Content-Length header")),
+        };
+        // Only digits, since `parse` also takes a sign that other servers may read differently
+        match value.parse::<usize>() {
+            Ok(parsed) if value.bytes().all(|byte| byte.is_ascii_digit()) => Ok(Some(parsed)),
+            _ => Err(MalformedRequestError::new(&format!("Invalid Content-Length: {}", value))),
+        }
+    }
+}
+
+/// Parses the head of a request into its request line and headers
+///
+/// Lines have to end in `\r\n`. A bare `\r` or `\n`, a header name that is not a token and
+/// a control character in a header value are errors, since a proxy in front of the server may read
+/// them differently. Header values are trimmed.
+///
+/// # Examples
+/// ```
+/// use simpleserve::request::parse_head;
+///
+/// let (request_line, headers) = parse_head("GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*").unwrap();
+/// assert_eq!(request_line, "GET / HTTP/1.1");
+/// assert_eq!(headers.get("host"), Some("example.com"));
+/// assert!(parse_head("GET / HTTP/1.1\nHost: example.com").is_err());
+/// assert!(parse_head("GET / HTTP/1.1\r\nHost: example.com\rX: y").is_err());
+/// ```
+pub fn parse_head(head: &str) -> Result<(&str, Headers), MalformedRequestError> {
+    if head.split("\r\n").any(|line| line.contains(['\r', '\n'])) {
+        return Err(MalformedRequestError::new("Bare line break in request head"));
+    }
+    let mut lines = head.split("\r\n");
+    let request_line = match lines.next() {
+        Some(line) if !line.trim().is_empty() => line,
+        _ => return Err(MalformedRequestError::new("Missing request line")),
+    };
+
+    let mut headers = Headers::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) if is_token(name) => (name, value),
+            _ => return Err(MalformedRequestError::new(&format!("Invalid header line: {}", line))),
+        };
+        if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+            return Err(MalformedRequestError::new(&format!("Invalid character in header {}", name)));
+        }
+        headers.insert(name, value.trim());
+    }
+    Ok((request_line, headers))
+}
+
+/// Whether a value is a token, the characters allowed in header names
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
@@ -0,0 +1,321 @@
+//! A general response type
+//!
+//! [`Response`] is built up with a status, any headers and a body of text or bytes,
+//! for when [`Page`](crate::Page) and [`Bytes`](crate::Bytes) are not flexible enough.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     RequestInfo,
+//!     response::Response
+//! };
+//!
+//! fn api_route(_: &RequestInfo) -> Box<dyn Sendable> {
+//!     Box::new(Response::new(201)
+//!         .header("Content-Type", "application/json")
+//!         .header("Cache-Control", "no-store")
+//!         .text("{\"created\":true}"))
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_route("/api", api_route);
+//! ```
+//!
+//! [`Respond`] builds the same responses in the terms handlers usually think in, with the
+//! content type, cookies and caching headers set for them:
+//! ```
+//! use simpleserve::{
+//!     Sendable,
+//!     RequestInfo,
+//!     cookie::Cookie,
+//!     response::Respond
+//! };
+//!
+//! fn home(_: &RequestInfo) -> Box<dyn Sendable> {
+//!     Box::new(Respond::ok()
+//!         .html("<h1>Welcome back</h1>")
+//!         .cookie(&Cookie::new("visited", "1").with_path("/"))
+//!         .cache_for(60)
+//!         .header("X-Frame-Options", "DENY"))
+//! }
+//! ```
+
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+
+use crate::{
+    cache_policy::CacheControl,
+    cookie::Cookie,
+    etag::ETag,
+    request::Headers,
+    status::StatusCode,
+    server::{
+        ConnectionInfo,
+        Sendable
+    }
+};
+
+/// A response with a status, headers and a body
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: u16,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// Creates an empty response with a status
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Adds a header, keeping any existing headers with the same name
+    ///
+    /// Headers whose name or value contain a line break are ignored, so a value taken from
+    /// a request cannot be used to inject headers into the response.
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        if [name, value].iter().any(|part| part.contains(['\r', '\n'])) {
+            println!("Ignoring header {} with a line break", name.escape_debug());
+            return self;
+        }
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds a `Set-Cookie` header
+    pub fn cookie(self, cookie: &Cookie) -> Response {
+        self.header("Set-Cookie", &cookie.to_string())
+    }
+
+    /// Sets a text body
+    pub fn text(mut self, body: &str) -> Response {
+        self.body = body.as_bytes().to_vec();
+        self
+    }
+
+    /// Sets a body of bytes
+    pub fn bytes(mut self, body: Vec<u8>) -> Response {
+        self.body = body;
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The response with another body, for middleware that rewrites bodies
+    ///
+    /// The `Content-Length` is dropped, and added back for the new body when the response is
+    /// rendered. A strong `ETag` is made weak, since it promised the bytes of the old body.
+    pub(crate) fn with_new_body(&self, body: Vec<u8>) -> Response {
+        let mut rewritten = Response::new(self.status);
+        for (name, value) in self.headers.iter() {
+            if name.eq_ignore_ascii_case("content-length") {
+                continue;
+            }
+            let weakened = Some(value)
+                .filter(|_| name.eq_ignore_ascii_case("etag"))
+                .and_then(ETag::parse)
+                .map(|etag| ETag::weak(etag.tag()).to_string());
+            rewritten = rewritten.header(name, weakened.as_deref().unwrap_or(value));
+        }
+        rewritten.bytes(body)
+    }
+}
+
+#[async_trait]
+impl Sendable for Response {
+    /// Renders the status line and headers
+    ///
+    /// A `Content-Length` header is added if the response does not have one, unless its status
+    /// cannot have a body (1xx and 204).
+    fn render(&self) -> String {
+        let mut head = format!("{}\r\n", StatusCode::from(self.status).status_line());
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let bodiless = self.status < 200 || self.status == 204;
+        if !bodiless && !self.headers.contains("content-length") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    fn to_response(&self) -> Option<Response> {
+        Some(self.clone())
+    }
+
+    async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
+        conn.write_all(self.render().as_bytes()).await?;
+        conn.write_all(&self.body).await
+    }
+}
+
+/// A fluent builder for the everyday responses of handlers
+///
+/// The body methods set the `Content-Type`, unless a `Content-Type` header was added, and the
+/// caching methods set `Cache-Control` and `Expires` from a [`CacheControl`], the same way the
+/// file handlers do for a [`CachePolicy`](crate::cache_policy::CachePolicy). It can be returned
+/// from a handler as it is, or turned into a [`Response`].
+///
+/// # Examples
+/// ```
+/// use simpleserve::{
+///     Sendable,
+///     cache_policy::CacheControl,
+///     cookie::Cookie,
+///     response::Respond
+/// };
+///
+/// let response = Respond::ok()
+///     .json("{\"id\":7}")
+///     .cookie(&Cookie::new("seen", "yes"))
+///     .cache_for(300)
+///     .header("X-Request-Id", "abc")
+///     .into_response();
+/// assert_eq!(response.status(), 200);
+/// assert_eq!(response.headers().get("content-type"), Some("application/json"));
+/// assert_eq!(response.headers().get("set-cookie"), Some("seen=yes"));
+/// assert_eq!(response.headers().get("cache-control"), Some("public, max-age=300"));
+/// assert!(response.headers().contains("expires"));
+///
+/// let response = Respond::created().cache(CacheControl::NoStore).into_response();
+/// assert_eq!(response.headers().get("cache-control"), Some("no-store"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Respond {
+    response: Response,
+    content_type: Option<String>,
+    cache: Option<CacheControl>,
+}
+
+impl Respond {
+    /// Starts a response with a status
+    pub fn status(status: u16) -> Respond {
+        Respond {
+            response: Response::new(status),
+            content_type: None,
+            cache: None,
+        }
+    }
+
+    /// Starts a 200 OK response
+    pub fn ok() -> Respond {
+        Respond::status(200)
+    }
+
+    /// Starts a 201 Created response
+    pub fn created() -> Respond {
+        Respond::status(201)
+    }
+
+    /// Starts a 204 No Content response
+    pub fn no_content() -> Respond {
+        Respond::status(204)
+    }
+
+    /// Starts a 303 See Other response, which the client follows with a GET
+    pub fn see_other(location: &str) -> Respond {
+        Respond::status(303).header("Location", location)
+    }
+
+    /// Sets an HTML body
+    pub fn html(self, body: &str) -> Respond {
+        self.body("text/html; charset=utf-8", body.as_bytes().to_vec())
+    }
+
+    /// Sets a plain text body
+    pub fn text(self, body: &str) -> Respond {
+        self.body("text/plain; charset=utf-8", body.as_bytes().to_vec())
+    }
+
+    /// Sets a body of JSON, which is sent as it is
+    pub fn json(self, body: &str) -> Respond {
+        self.body("application/json", body.as_bytes().to_vec())
+    }
+
+    /// Sets a body of any type
+    pub fn body(mut self, content_type: &str, body: Vec<u8>) -> Respond {
+        self.response = self.response.bytes(body);
+        self.content_type = Some(String::from(content_type));
+        self
+    }
+
+    /// Adds a header, keeping any existing headers with the same name
+    ///
+    /// See [`Response::header`].
+    pub fn header(mut self, name: &str, value: &str) -> Respond {
+        self.response = self.response.header(name, value);
+        self
+    }
+
+    /// Adds a `Set-Cookie` header
+    pub fn cookie(mut self, cookie: &Cookie) -> Respond {
+        self.response = self.response.cookie(cookie);
+        self
+    }
+
+    /// Lets clients and proxies use the response without checking for a number of seconds
+    pub fn cache_for(self, seconds: u64) -> Respond {
+        self.cache(CacheControl::MaxAge(Duration::from_secs(seconds)))
+    }
+
+    /// Sets how the response may be cached, replacing any earlier setting
+    pub fn cache(mut self, control: CacheControl) -> Respond {
+        self.cache = Some(control);
+        self
+    }
+
+    /// Builds the response
+    ///
+    /// `Expires` is counted from the time the response is built.
+    pub fn into_response(self) -> Response {
+        let mut response = self.response;
+        if let Some(content_type) = self.content_type.filter(|_| !response.headers().contains("content-type")) {
+            response = response.header("Content-Type", &content_type);
+        }
+        if let Some(control) = self.cache {
+            response = response
+                .header("Cache-Control", &control.header_value())
+                .header("Expires", &control.expires(SystemTime::now()));
+        }
+        response
+    }
+}
+
+impl From<Respond> for Response {
+    fn from(respond: Respond) -> Response {
+        respond.into_response()
+    }
+}
+
+#[async_trait]
+impl Sendable for Respond {
+    fn render(&self) -> String {
+        self.clone().into_response().render()
+    }
+
+    fn to_response(&self) -> Option<Response> {
+        Some(self.clone().into_response())
+    }
+
+    async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
+        self.clone().into_response().send(conn).await
+    }
+}
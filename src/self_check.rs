@@ -0,0 +1,159 @@
+//! Synthetic self-checks
+//!
+//! A [`SelfCheck`] periodically requests some routes through the normal request pipeline,
+//! over a loopback connection like [`Webserver::export`](crate::Webserver::export), and records
+//! the status and latency of every check. If a route fails enough checks in a row the server
+//! is marked as not ready, so it answers 503 until the route recovers. A check fails if the
+//! request errors or the response is not 2xx. Warm-up tasks added with
+//! [`Webserver::ready_when`](crate::Webserver::ready_when) keep the server not ready until they
+//! complete, whatever the checks say.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{Webserver, self_check::SelfCheck};
+//!
+//! let server = Webserver::new(10, vec![]).with_self_check(
+//!     SelfCheck::new(&["/", "/api/status"])
+//!         .with_interval(Duration::from_secs(10))
+//!         .with_failure_threshold(3)
+//! );
+//! ```
+
+use std::{
+    sync::{
+        atomic::AtomicBool,
+        Arc, RwLock
+    },
+    time::Duration
+};
+
+use crate::{
+    export,
+    server::{Readiness, ServerContext, ServerStats}
+};
+
+/// The routes to check and how often
+#[derive(Debug, Clone)]
+pub struct SelfCheck {
+    routes: Vec<String>,
+    interval: Duration,
+    failure_threshold: usize,
+    results: Arc<RwLock<Vec<CheckResult>>>,
+}
+
+/// The outcome of the latest check of a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    route: String,
+    status: Option<u16>,
+    latency: Duration,
+    consecutive_failures: usize,
+}
+
+impl CheckResult {
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// The status of the response, or `None` if the request failed
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status.is_some_and(|status| (200..300).contains(&status))
+    }
+
+    /// The number of checks of the route that failed in a row, 0 after a success
+    pub fn consecutive_failures(&self) -> usize {
+        self.consecutive_failures
+    }
+}
+
+impl SelfCheck {
+    /// Checks the routes every 30 seconds, marking the server as not ready after 3 failures in a row
+    ///
+    /// Routes may include a query string.
+    pub fn new(routes: &[&str]) -> SelfCheck {
+        SelfCheck {
+            routes: routes.iter().map(|route| String::from(*route)).collect(),
+            interval: Duration::from_secs(30),
+            failure_threshold: 3,
+            results: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> SelfCheck {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how many checks of a route must fail in a row before the server is marked as not ready
+    ///
+    /// # Panics
+    /// Panics if the threshold is zero
+    pub fn with_failure_threshold(mut self, failure_threshold: usize) -> SelfCheck {
+        assert!(failure_threshold > 0);
+        self.failure_threshold = failure_threshold;
+        self
+    }
+
+    pub fn routes(&self) -> &[String] {
+        &self.routes
+    }
+
+    /// The latest result for every route that has been checked
+    pub fn results(&self) -> Vec<CheckResult> {
+        self.results.read().unwrap().clone()
+    }
+
+    /// Checks the routes until the future is dropped
+    ///
+    /// The checks failing is tracked apart from the warm-up tasks, so recovering only restores
+    /// readiness once warm-up tasks are done too.
+    pub(crate) async fn run(self, mut context: ServerContext, readiness: Arc<Readiness>) {
+        // Checks are not traffic, and must reach the handlers even while the server is not ready
+        context.stats = Arc::new(ServerStats::default());
+        context.ready = Arc::new(AtomicBool::new(true));
+        let mut failures = vec![0; self.routes.len()];
+        let mut was_failing = false;
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let mut results = Vec::with_capacity(self.routes.len());
+            for (route, failures) in self.routes.iter().zip(failures.iter_mut()) {
+                let started = context.clock.now();
+                let status = match export::fetch(&context, route).await {
+                    Ok((status, _)) => Some(status),
+                    Err(e) => {
+                        println!("Self-check of {} failed: {}", route, e);
+                        None
+                    }
+                };
+                let result = CheckResult {
+                    route: route.clone(),
+                    status,
+                    latency: context.clock.now().saturating_duration_since(started),
+                    consecutive_failures: 0,
+                };
+                *failures = if result.is_success() { 0 } else { *failures + 1 };
+                results.push(CheckResult { consecutive_failures: *failures, ..result });
+            }
+            *self.results.write().unwrap() = results;
+
+            let failing = failures.iter().any(|failures| *failures >= self.failure_threshold);
+            if failing != was_failing {
+                match failing {
+                    true => println!("Self-checks are failing, marking the server as not ready"),
+                    false => println!("Self-checks recovered"),
+                }
+                was_failing = failing;
+                readiness.set_checks_failing(failing);
+            }
+        }
+    }
+}
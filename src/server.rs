@@ -8,13 +8,12 @@
 //!     Webserver,
 //!     Page,
 //!     Sendable,
-//!     HandlerFunction,
 //!     RequestInfo,
 //!     ConnectionType
 //! };
 //! 
 //! fn main() {
-//!     let main_route: HandlerFunction = |_: &RequestInfo| -> Box<dyn Sendable + 'static> {
+//!     let main_route = |_: &RequestInfo| -> Box<dyn Sendable> {
 //!          Box::new(Page::new(200, String::from("Hello World!")))
 //!     };
 //!     let mut server = Webserver::new(10, vec![]);
@@ -31,30 +30,105 @@ use openssl::ssl::{
 use tokio_openssl::SslStream;
 use std::{
     io::prelude::*,
+    collections::HashMap,
     path::{
         self, 
         Path, 
         PathBuf
     },
-    fs::File,
+    fs::{
+        self,
+        File
+    },
     error::Error,
-    thread,
-    time::Duration,
+    fmt,
+    net::SocketAddr,
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering
+        }
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     ThreadPool, 
-    utils
+    JobContext,
+    utils,
+    theme::Theme,
+    cache_policy::{CacheControl, CachePolicy},
+    etag::{self, ETag, ETagMode},
+    static_files::{self, AssetManifest, FileCache, MountOptions},
+    plugin::Plugin,
+    user_agent::UserAgent,
+    session::Session,
+    cookie::{
+        self,
+        Cookie
+    },
+    tarpit::Tarpit,
+    sitemap::Sitemap,
+    self_check::SelfCheck,
+    privileges::{self, Privileges},
+    websocket::{self, WebSocket, WebSocketFuture, WebSocketHandler},
+    errors::{MalformedRequestError, RequestTooLargeError},
+    multipart::{self, Multipart, MultipartOptions},
+    body_limits::BodyLimits,
+    sse,
+    long_poll::LongPoll,
+    event_bus::EventBus,
+    response::Response,
+    status::StatusCode,
+    middleware::{self, Middleware, Ordered},
+    rate_limit::RateLimiter,
+    bot_throttle::BotThrottle,
+    alt_svc::AltSvc,
+    graphql::{self, GraphQL},
+    includes::Includes,
+    transform::Transform,
+    command::{self, CommandHandler},
+    workers::{self, Supervisor},
+    pool::{self, Pool},
+    audit::AuditLog,
+    export,
+    geo::{
+        GeoInfo,
+        GeoResolver
+    },
+    request::{
+        Extensions,
+        Headers,
+        Method,
+        Request,
+        ByteRange,
+        parse_head,
+        parse_query,
+        parse_range
+    },
+    clock::{
+        Clock,
+        SystemClock
+    }
 };
 
 use tokio::{
     self,
-    sync::mpsc,
+    sync::{mpsc, oneshot, Notify, OwnedSemaphorePermit, Semaphore},
     net::{
         TcpListener,
         TcpStream
     },
-    io::AsyncWriteExt,
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt
+    },
     runtime::Runtime,
 };
 
@@ -71,37 +145,75 @@ pub mod prelude {
         ConnectionInfo,
         ConnectionType,
         Task,
-        HandlerFunction
+        HandlerFunction,
+        AsyncHandlerFunction,
+        HandlerFuture,
+        ServerStats,
+        ShutdownReport,
+        ShutdownHandle,
+        AppState,
+        ConnectionId,
+        PoolHint
     };
     pub use crate::utils::{
         get_mime_type,
         base_not_found_handler
     };
+    pub use crate::response::{Respond, Response};
+    pub use crate::status::StatusCode;
 }
 
 #[async_trait]
 pub trait Sendable: Send + Sync {
     fn render(&self) -> String;
+
+    /// The response as a [`Response`], if it can be represented as one
+    /// 
+    /// Middleware uses this to add headers to a response or to change its body.
+    /// Responses that are streamed, and cannot be buffered, return `None`.
+    fn to_response(&self) -> Option<Response> {
+        None
+    }
+
+    /// Whether the client can tell where the response ends before the connection closes
+    /// 
+    /// Only then is the connection kept alive for another request. Responses with a length or
+    /// a chunked body are, event streams, tarpits and upgraded connections are not.
+    fn is_delimited(&self) -> bool {
+        true
+    }
+
     async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
         // Runtime already created in handle_connection, just use that
-        match conn.connection_type() {
-            ConnectionType::Http => {
-                conn.stream().write_all(self.render().as_bytes()).await?;
-                return Ok(());
-            },
-            ConnectionType::Https => {
-                conn.ssl_stream().write_all(self.render().as_bytes()).await?;
-                return Ok(());
-            }
-        }
+        conn.write_all(self.render().as_bytes()).await
     }
 }
 
 /// A handler function
 /// 
+/// Routes take any function or closure with this signature, so handlers can capture
+/// configuration or shared state (behind an `Arc`).
+/// 
+/// # Arguments
+/// * `request` - The request info
+pub type HandlerFunction = Arc<dyn Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync>;
+
+/// The future returned by an async handler
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Box<dyn Sendable>> + Send + 'a>>;
+
+/// An async handler function
+/// 
+/// See [`Webserver::add_async_route`].
+/// 
 /// # Arguments
 /// * `request` - The request info
-pub type HandlerFunction = fn(&RequestInfo) -> Box<dyn Sendable>;
+pub type AsyncHandlerFunction = Arc<dyn for<'a> Fn(&'a RequestInfo<'a>) -> HandlerFuture<'a> + Send + Sync>;
+
+#[derive(Clone)]
+enum Callback {
+    Sync(HandlerFunction),
+    Async(AsyncHandlerFunction),
+}
 
 /// The webserver
 /// 
@@ -123,8 +235,82 @@ pub struct Webserver {
     blacklisted_paths: Vec<path::PathBuf>,
     connection_type: Option<ConnectionType>,
     receiver: Option<mpsc::Receiver<Task>>,
+    theme: Theme,
+    cache_policy: CachePolicy,
+    stats: Arc<ServerStats>,
+    readiness: Arc<Readiness>,
+    readiness_gates: Vec<ReadinessGate>,
+    accept_loop_core: Option<usize>,
+    cpu_pool: Option<Arc<ThreadPool>>,
+    plugins: Vec<Arc<dyn Plugin>>,
+    clock: Arc<dyn Clock>,
+    max_body_size: usize,
+    multipart: MultipartOptions,
+    body_limits: BodyLimits,
+    max_request_line: usize,
+    max_header_size: usize,
+    max_headers: usize,
+    keep_alive_timeout: Duration,
+    header_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    max_requests_per_connection: usize,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    handle_signals: bool,
+    workers: Option<usize>,
+    audit_log: Option<Arc<AuditLog>>,
+    max_connections: Option<usize>,
+    saturated_retry_after: Option<Duration>,
+    geo_resolver: Option<Arc<dyn GeoResolver>>,
+    state: AppState,
+    sitemap: Option<Sitemap>,
+    sitemap_xml: Arc<RwLock<String>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    self_check: Option<SelfCheck>,
+    run_as: Option<Privileges>,
+    chroot: Option<PathBuf>,
+    // The roots of served directories, moved inside the chroot when privileges are dropped
+    mounts: Vec<Arc<RwLock<PathBuf>>>,
+    // The served directories read into memory when the server starts, and how many bytes of each
+    preloads: Vec<(Arc<RwLock<PathBuf>>, Arc<FileCache>, usize)>,
+    max_tarpitted: usize,
+    // Shared by every tarpit route, so they cannot take all the workers together
+    tarpitted: Arc<Semaphore>,
 }
 
+/// The largest request body accepted by default, 1 MiB
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// The longest request line accepted by default, 8 KiB
+pub const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+
+/// The largest request head (request line and headers) accepted by default, 64 KiB
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// The number of request headers accepted by default
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// How long an idle connection is kept open by default, waiting for another request
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has by default to send the head of its first request
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long reading a request body may take by default
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long one write to a client may take by default
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of requests served on one connection by default, before it is closed
+pub const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+
+/// How long active connections are waited for by default when the server shuts down
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+type ReadinessGate = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
 impl Webserver {
     /// Creates a new webserver
     /// 
@@ -133,15 +319,59 @@ impl Webserver {
     /// * `blacklisted_paths` - The paths (file paths) to not allow access to
     /// * `not_found_handler` - The handler for 404 errors
     pub fn new(thread_amount: usize, blacklisted_paths: Vec<path::PathBuf>) -> Webserver {
+        let max_tarpitted = (thread_amount / 2).max(1);
         Webserver {
-            routes: vec![Handler::new("404", utils::base_not_found_handler)],
+            routes: vec![Handler::new("404", Callback::Sync(Arc::new(utils::base_not_found_handler)))],
             thread_pool: ThreadPool::new(thread_amount),
             blacklisted_paths,
             connection_type: None,
             receiver: None,
+            theme: Theme::default(),
+            cache_policy: CachePolicy::new(),
+            stats: Arc::new(ServerStats::default()),
+            readiness: Arc::new(Readiness::new()),
+            readiness_gates: Vec::new(),
+            accept_loop_core: None,
+            cpu_pool: None,
+            plugins: Vec::new(),
+            clock: Arc::new(SystemClock),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            multipart: MultipartOptions::new(),
+            body_limits: BodyLimits::new(),
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_headers: DEFAULT_MAX_HEADERS,
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            handle_signals: false,
+            workers: None,
+            audit_log: None,
+            max_connections: None,
+            saturated_retry_after: None,
+            geo_resolver: None,
+            state: AppState::default(),
+            sitemap: None,
+            sitemap_xml: Arc::new(RwLock::new(String::new())),
+            middleware: Vec::new(),
+            self_check: None,
+            run_as: None,
+            chroot: None,
+            mounts: Vec::new(),
+            preloads: Vec::new(),
+            max_tarpitted,
+            tarpitted: Arc::new(Semaphore::new(max_tarpitted)),
         }
     }
 
+    pub fn routes(&self) -> &Vec<Handler> {
+        &self.routes
+    }
+
     pub fn blacklisted_paths(&self) -> &Vec<path::PathBuf> {
         &self.blacklisted_paths
     }
@@ -155,209 +385,2106 @@ impl Webserver {
         self
     }
 
-    pub fn set_404_callback(&mut self, callback: HandlerFunction) {
-        self.routes[0] = Handler::new("404", callback);
+    /// Pins the worker threads to CPU cores
+    /// 
+    /// Worker `i` is pinned to `cores[i % cores.len()]`. This replaces the thread pool,
+    /// so it should be called before the server is started.
+    /// 
+    /// # Arguments
+    /// * `cores` - The ids of the cores to pin the workers to
+    /// 
+    /// # Examples
+    /// ```
+    /// use simpleserve::Webserver;
+    /// 
+    /// let server = Webserver::new(4, vec![])
+    ///     .with_pinned_workers(vec![1, 2, 3])
+    ///     .with_pinned_accept_loop(0);
+    /// ```
+    pub fn with_pinned_workers(mut self, cores: Vec<usize>) -> Webserver {
+        self.thread_pool = ThreadPool::new_pinned(self.thread_pool.size(), &cores);
+        self
     }
 
-    /// Adds a route to the webserver
+    /// Pins the thread running the accept loop to a CPU core
+    /// 
+    /// The thread that polls [`Webserver::start`] is pinned when the server starts, which
+    /// is only meaningful on a current-thread runtime.
     /// 
     /// # Arguments
-    /// * `route` - The route to add
-    /// * `handler` - The handler for the route
+    /// * `core` - The id of the core to pin the accept loop to
+    pub fn with_pinned_accept_loop(mut self, core: usize) -> Webserver {
+        self.accept_loop_core = Some(core);
+        self
+    }
+
+    /// Adds a separate thread pool for CPU-bound handlers
     /// 
-    /// # Panics
-    /// Panics if the route is empty
+    /// Connections are read and routed on the main (IO) pool. Requests for routes added with
+    /// [`Webserver::add_cpu_route`] are then handed off to this pool, so CPU-heavy work
+    /// cannot starve file serving.
+    /// 
+    /// # Arguments
+    /// * `thread_amount` - The number of threads in the CPU pool
     /// 
     /// # Examples
     /// ```
-    /// use std::{
-    ///     net::TcpStream,
-    ///     fs,
-    ///     path::PathBuf
-    /// };
     /// use simpleserve::{
     ///     Webserver,
     ///     Page,
     ///     Sendable,
-    ///     RequestInfo,
-    ///     ConnectionType
+    ///     RequestInfo
     /// };
-    ///
-    /// fn main() {
-    ///     let mut server = Webserver::new(10, vec![]);
-    ///     server.add_route("/", main_route);
-    ///     server.start("127.0.0.1:7878", ConnectionType::Http, None, None);
-    /// }
-    ///
-    ///
-    /// fn main_route(request: &RequestInfo) -> Box<dyn Sendable> {
-    ///     let contents = fs::read_to_string("index.html").expect("Error reading file");
-    ///     Box::new(Page::new(200, contents))
+    /// 
+    /// fn render_report(_: &RequestInfo) -> Box<dyn Sendable> {
+    ///     Box::new(Page::new(200, String::from("Expensive report")))
     /// }
-    pub fn add_route(&mut self, route: &str, handler: HandlerFunction) {
-        if route.is_empty() {
-            panic!("Route cannot be empty");
-        }
-        for route_handler in &self.routes {
-            if route_handler.route == route {
-                panic!("Route already exists");
-            }
-        }
-        println!("Added route {}", route);
-        self.routes.push(Handler::new(route, handler));
+    /// 
+    /// let mut server = Webserver::new(16, vec![]).with_cpu_pool(4);
+    /// server.add_cpu_route("/report", render_report);
+    /// ```
+    pub fn with_cpu_pool(mut self, thread_amount: usize) -> Webserver {
+        self.cpu_pool = Some(Arc::new(ThreadPool::new(thread_amount)));
+        self
     }
 
-    pub fn add_accessible_files(&mut self, paths: Vec<&str>) -> Result<(), std::io::Error> {
-        for path_str in paths {
-            path::Path::new(path_str).canonicalize()?;
-            let path_str = &*(String::from("/") + path_str);
-            println!("Added route {}", path_str);
-            self.add_route(path_str, utils::base_file_handler);
-        }
-        Ok(())
+    /// The pool for CPU-bound work, if one was configured
+    pub fn cpu_pool(&self) -> Option<&ThreadPool> {
+        self.cpu_pool.as_deref()
     }
 
-    async fn receive(&mut self) -> Option<Task> {
-        match self.receiver.take() {
-            Some(mut receiver) => {
-                match receiver.recv().await {
-                    Some(message) => {
-                        self.receiver = Some(receiver);
-                        return Some(message);
-                    },
-                    None => {
-                        println!("Receiver channel closed");
-                        return None;
-                    }
-                }
-            },
-            None => {
-                return None;
-            }
-        }
+    /// Sets the clock the server reads the time from
+    /// 
+    /// Useful in tests, together with [`MockClock`](crate::clock::MockClock).
+    /// 
+    /// # Arguments
+    /// * `clock` - The clock to use
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Webserver {
+        self.clock = Arc::new(clock);
+        self
     }
 
-    /// Starts the webserver
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Sets the largest request body the server accepts
+    /// 
+    /// Requests with a larger `Content-Length` get 413 Payload Too Large.
+    /// Defaults to [`DEFAULT_MAX_BODY_SIZE`].
     /// 
     /// # Arguments
-    /// * `addr` - The address to start the server on
+    /// * `max_body_size` - The size in bytes
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Webserver {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// Sets the limits for `multipart/form-data` bodies
     /// 
-    /// # Panics
-    /// Panics if the address is invalid
-    pub async fn start(&mut self, addr: &str, connection_type: ConnectionType, pk: Option<PathBuf>, sslc: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
-        if let ConnectionType::Http = connection_type {
-            self.connection_type = Some(connection_type);
-            self.start_http(addr).await?;
-        } else if let ConnectionType::Https = connection_type {
-            self.connection_type = Some(ConnectionType::Https);
-            self.start_https(addr, pk.unwrap(), sslc.unwrap()).await?;
-        }
-        self.thread_pool.stop();
-        Ok(())
+    /// Multipart bodies are parsed while they are read, with large parts written to temporary
+    /// files, so they are limited by [`MultipartOptions::max_size`] instead of the maximum body size.
+    /// See the [`multipart`](crate::multipart) module.
+    /// 
+    /// # Arguments
+    /// * `options` - The limits
+    pub fn with_multipart(mut self, options: MultipartOptions) -> Webserver {
+        self.multipart = options;
+        self
     }
 
-    async fn start_http(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
-        let listener = TcpListener::bind(addr).await?;
-        println!("Server started on {}...", addr);
-        loop {
-            tokio::select! {
-                conn = listener.accept() => match conn {
-                    Ok((stream, _)) => {
-                        let route_clone = self.routes.clone();
-                        let blacklisted_paths_clone = self.blacklisted_paths.clone();
+    pub fn multipart(&self) -> MultipartOptions {
+        self.multipart
+    }
 
-                        let connection_info = ConnectionInfo::new(stream);
+    /// Sets the largest request body the server accepts for each content type
+    /// 
+    /// Routes with limits of their own, set with [`Webserver::set_body_limits`], use those
+    /// instead. See the [`body_limits`](crate::body_limits) module.
+    /// 
+    /// # Arguments
+    /// * `limits` - The limits
+    pub fn with_body_limits(mut self, limits: BodyLimits) -> Webserver {
+        self.body_limits = limits;
+        self
+    }
 
-                        self.thread_pool.execute(|| {
-                            let rt = Runtime::new().unwrap();
-                            rt.block_on(
-                                utils::handle_connection(connection_info, route_clone, blacklisted_paths_clone)
-                            ).unwrap();
-                        });
-                    },
-                    Err(e) => {
-                        println!("Error accepting connection: {}", e);
-                    }
-                },
-                msg = self.receive() => {
-                    match msg {
-                        Some(Task::Shutdown) => {
-                            println!("Shutting down server...");
-                            return Ok(());
-                        },
-                        None => {}
-                        _ => {
-                            println!("Received unknown message");
-                        }
-                    }
-                }
-            }
-            thread::sleep(Duration::from_millis(100));
-        }
+    pub fn body_limits(&self) -> &BodyLimits {
+        &self.body_limits
     }
 
-    async fn start_https(&self, addr: &str, private_key_file: PathBuf, ssl_certificate_file: PathBuf) -> Result<(), Box<dyn Error>> {
-        let listener = TcpListener::bind(addr).await?;
+    /// Sets the longest request line the server accepts
+    /// 
+    /// Requests with a longer request line get 414 URI Too Long, and the connection is closed
+    /// without reading the rest. Defaults to [`DEFAULT_MAX_REQUEST_LINE`].
+    /// 
+    /// # Arguments
+    /// * `max_request_line` - The length in bytes
+    pub fn with_max_request_line(mut self, max_request_line: usize) -> Webserver {
+        self.max_request_line = max_request_line;
+        self
+    }
 
-        let mut acceptor_builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-        acceptor_builder.set_private_key_file(private_key_file, SslFiletype::PEM).unwrap();
-        acceptor_builder.set_certificate_chain_file(ssl_certificate_file).unwrap();
-        let acceptor = acceptor_builder.build();
+    pub fn max_request_line(&self) -> usize {
+        self.max_request_line
+    }
 
-        let ssl = Ssl::new(acceptor.context()).unwrap();
+    /// Sets the largest request head, the request line and headers together, the server accepts
+    /// 
+    /// Requests with a larger head get 431 Request Header Fields Too Large, and the connection
+    /// is closed without reading the rest. Defaults to [`DEFAULT_MAX_HEADER_SIZE`].
+    /// 
+    /// # Arguments
+    /// * `max_header_size` - The size in bytes
+    pub fn with_max_header_size(mut self, max_header_size: usize) -> Webserver {
+        self.max_header_size = max_header_size;
+        self
+    }
 
-        tokio::select! {
-            conn = listener.accept() => match conn {
-                Ok((stream, _)) => {
-                    let stream = SslStream::new(ssl, stream).unwrap();
+    pub fn max_header_size(&self) -> usize {
+        self.max_header_size
+    }
 
-                    let route_clone = self.routes.clone();
-                    let blacklisted_paths_clone = self.blacklisted_paths.clone();
+    /// Sets the number of headers the server accepts in a request
+    /// 
+    /// Requests with more headers get 431 Request Header Fields Too Large.
+    /// Defaults to [`DEFAULT_MAX_HEADERS`].
+    /// 
+    /// # Arguments
+    /// * `max_headers` - The number of headers
+    pub fn with_max_headers(mut self, max_headers: usize) -> Webserver {
+        self.max_headers = max_headers;
+        self
+    }
 
-                    let connection_info = ConnectionInfo::new_ssl(stream);
+    pub fn max_headers(&self) -> usize {
+        self.max_headers
+    }
 
-                    self.thread_pool.execute(|| {
-                        let rt = Runtime::new().unwrap();
-                                    
-                        rt.block_on(
-                            utils::handle_connection(connection_info, route_clone, blacklisted_paths_clone)
-                        ).unwrap()
-                    });
-                },
-                Err(e) => {
-                    println!("Error accepting connection: {}", e);
-                }
-            },
-        }
+    /// Sets how long a connection is kept open after a response, waiting for the next request
+    /// 
+    /// HTTP/1.1 connections are kept open unless the client sends `Connection: close`.
+    /// Defaults to [`DEFAULT_KEEP_ALIVE_TIMEOUT`].
+    /// 
+    /// # Arguments
+    /// * `keep_alive_timeout` - How long to wait for the next request
+    pub fn with_keep_alive_timeout(mut self, keep_alive_timeout: Duration) -> Webserver {
+        self.keep_alive_timeout = keep_alive_timeout;
+        self
+    }
 
-        Ok(())
+    pub fn keep_alive_timeout(&self) -> Duration {
+        self.keep_alive_timeout
     }
-}
 
-/// Internal handler struct
-/// 
-/// Cannot be created outside of the library
-#[derive(Clone)]
-pub struct Handler {
-    route: String,
-    handler: HandlerFunction,
-}
+    /// Sets how long a client has to send the head of its first request
+    /// 
+    /// Counted from when the connection is accepted, and covers the TLS handshake of HTTPS
+    /// connections as well as the time spent waiting for a worker, so a client sending its request
+    /// line and headers a byte at a time cannot hold a worker for longer. Later requests on a kept-alive
+    /// connection have the keep-alive timeout instead. A client that runs out of time is sent
+    /// 408 Request Timeout and the connection is closed. Defaults to [`DEFAULT_HEADER_TIMEOUT`].
+    /// 
+    /// # Arguments
+    /// * `header_timeout` - How long to wait for the head of the request
+    pub fn with_header_timeout(mut self, header_timeout: Duration) -> Webserver {
+        self.header_timeout = header_timeout;
+        self
+    }
 
-impl Handler {
-    fn new(route: &str, handler: HandlerFunction) -> Handler {
-        Handler {
-            route: String::from(route),
-            handler,
-        }
+    pub fn header_timeout(&self) -> Duration {
+        self.header_timeout
     }
-    pub fn route(&self) -> &str {
-        &self.route
+
+    /// Sets how long reading a request body may take
+    /// 
+    /// A client that runs out of time is sent 408 Request Timeout and the connection is closed.
+    /// Defaults to [`DEFAULT_READ_TIMEOUT`].
+    /// 
+    /// # Arguments
+    /// * `read_timeout` - How long to wait for the whole body
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Webserver {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    /// Sets how long one write to a client may take
+    /// 
+    /// Applies to every write on its own, so long-lived responses like event streams are only
+    /// cut off when the client stops reading. The connection is closed when a write times out.
+    /// Defaults to [`DEFAULT_WRITE_TIMEOUT`].
+    /// 
+    /// # Arguments
+    /// * `write_timeout` - How long to wait for a write
+    pub fn with_write_timeout(mut self, write_timeout: Duration) -> Webserver {
+        self.write_timeout = write_timeout;
+        self
     }
-    pub fn handler(&self) -> HandlerFunction {
-        self.handler
+
+    pub fn write_timeout(&self) -> Duration {
+        self.write_timeout
+    }
+
+    /// Sets how many requests are served on one connection before it is closed
+    /// 
+    /// Setting it to 1 turns keep-alive off. Defaults to [`DEFAULT_MAX_REQUESTS_PER_CONNECTION`].
+    /// 
+    /// # Arguments
+    /// * `max_requests` - The number of requests
+    /// 
+    /// # Panics
+    /// Panics if `max_requests` is zero
+    pub fn with_max_requests_per_connection(mut self, max_requests: usize) -> Webserver {
+        assert!(max_requests > 0);
+        self.max_requests_per_connection = max_requests;
+        self
+    }
+
+    pub fn max_requests_per_connection(&self) -> usize {
+        self.max_requests_per_connection
+    }
+
+    /// Sets how many connections are handled at once
+    /// 
+    /// Once the limit is reached, the accept loop waits for a connection to close before it
+    /// accepts the next one, so new clients wait in the listen backlog of the operating system
+    /// instead of in the queue of the thread pool. Without a limit, every connection is accepted
+    /// and queued. See [`Webserver::reject_when_saturated`] to turn clients away instead.
+    /// 
+    /// The limit applies to HTTP and HTTPS alike. An HTTPS connection takes its slot when it is
+    /// accepted, before its TLS handshake.
+    /// 
+    /// # Arguments
+    /// * `max_connections` - The number of connections
+    /// 
+    /// # Panics
+    /// Panics if `max_connections` is zero
+    pub fn with_max_connections(mut self, max_connections: usize) -> Webserver {
+        assert!(max_connections > 0);
+        self.max_connections = Some(max_connections);
+        self
     }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    /// Answers connections beyond the [maximum](Webserver::with_max_connections) with
+    /// 503 Service Unavailable, instead of making them wait
+    /// 
+    /// Turned away connections are counted in [`ServerStats::connections_rejected`].
+    /// 
+    /// # Arguments
+    /// * `retry_after` - How long clients are told to wait before trying again, sent in `Retry-After`
+    /// 
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::Webserver;
+    /// 
+    /// let server = Webserver::new(8, vec![])
+    ///     .with_max_connections(64)
+    ///     .reject_when_saturated(Duration::from_secs(5));
+    /// ```
+    pub fn reject_when_saturated(mut self, retry_after: Duration) -> Webserver {
+        self.saturated_retry_after = Some(retry_after);
+        self
+    }
+
+    /// Sets how long active connections are waited for when the server shuts down
+    /// 
+    /// Once the server stops accepting connections, requests being handled are finished and
+    /// their connections closed. Connections still active after this long, like event streams,
+    /// tarpits and long polls, are closed: whatever they wait for is dropped, and their reads and
+    /// writes fail. They are counted as force-closed in the [`ShutdownReport`]. A handler blocking
+    /// its worker thread cannot be interrupted, but its response is not sent.
+    /// Defaults to [`DEFAULT_SHUTDOWN_TIMEOUT`].
+    /// 
+    /// # Arguments
+    /// * `shutdown_timeout` - How long to wait for active connections
+    pub fn with_shutdown_timeout(mut self, shutdown_timeout: Duration) -> Webserver {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    /// A handle that shuts the server down, like sending [`Task::Shutdown`]
+    /// 
+    /// # Examples
+    /// ```no_run
+    /// use simpleserve::{Webserver, ConnectionType};
+    /// 
+    /// # async fn run() {
+    /// let mut server = Webserver::new(10, vec![]);
+    /// let shutdown = server.shutdown_handle();
+    /// tokio::spawn(async move {
+    ///     tokio::signal::ctrl_c().await.unwrap();
+    ///     shutdown.shutdown();
+    /// });
+    /// server.start("127.0.0.1:7878", ConnectionType::Http, None, None).await.unwrap();
+    /// # }
+    /// ```
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Records shutdowns, and who asked for them, in a tamper-evident log
+    /// 
+    /// See the [`audit`](crate::audit) module.
+    /// 
+    /// # Arguments
+    /// * `audit_log` - The log to append to
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Webserver {
+        self.audit_log = Some(Arc::new(audit_log));
+        self
+    }
+
+    /// The audit log, to record the actions of admin routes in
+    pub fn audit_log(&self) -> Option<Arc<AuditLog>> {
+        self.audit_log.clone()
+    }
+
+    /// Shuts the server down gracefully on Ctrl-C, and on `SIGTERM` on Unix
+    /// 
+    /// Service managers like systemd and Docker stop a process with `SIGTERM`, so the server
+    /// finishes the requests it is handling instead of being killed in the middle of them.
+    /// The signals are only listened for while the server is running.
+    /// 
+    /// # Examples
+    /// ```no_run
+    /// use simpleserve::{Webserver, ConnectionType};
+    /// 
+    /// # async fn run() {
+    /// let mut server = Webserver::new(10, vec![]).handle_signals();
+    /// server.start("127.0.0.1:7878", ConnectionType::Http, None, None).await.unwrap();
+    /// # }
+    /// ```
+    pub fn handle_signals(mut self) -> Webserver {
+        self.handle_signals = true;
+        self
+    }
+
+    /// Runs the server in worker processes, restarting the ones that exit
+    /// 
+    /// [`Webserver::start`] then starts the workers and supervises them, and serves requests in
+    /// the workers, which run the same executable. Both handle signals like with
+    /// [`Webserver::handle_signals`]. See the [`workers`](crate::workers) module.
+    /// 
+    /// # Arguments
+    /// * `workers` - The number of worker processes
+    /// 
+    /// # Panics
+    /// Panics if the number of workers is 0
+    pub fn with_workers(mut self, workers: usize) -> Webserver {
+        assert!(workers > 0, "A server needs at least one worker");
+        self.workers = Some(workers);
+        self
+    }
+
+    pub fn workers(&self) -> Option<usize> {
+        self.workers
+    }
+
+    /// Shuts the server down when a signal is received, until the returned task is aborted
+    fn start_signal_handler(&self) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.handle_signals && self.workers.is_none() {
+            return None;
+        }
+        let shutdown = self.shutdown.clone();
+        Some(tokio::spawn(async move {
+            #[cfg(unix)]
+            let terminate = async {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::terminate()) {
+                    Ok(mut terminate) => { terminate.recv().await; },
+                    Err(e) => {
+                        println!("Error listening for SIGTERM: {}", e);
+                        std::future::pending::<()>().await;
+                    }
+                }
+            };
+            #[cfg(not(unix))]
+            let terminate = std::future::pending::<()>();
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {
+                    println!("Received Ctrl-C");
+                    shutdown.shutdown_by("Ctrl-C");
+                },
+                _ = terminate => {
+                    println!("Received SIGTERM");
+                    shutdown.shutdown_by("SIGTERM");
+                },
+            }
+        }))
+    }
+
+    /// Sets the resolver used to look up where clients are
+    /// 
+    /// See the [`geo`](crate::geo) module.
+    /// 
+    /// # Arguments
+    /// * `resolver` - The resolver to use
+    pub fn with_geo_resolver<R: GeoResolver + 'static>(mut self, resolver: R) -> Webserver {
+        self.geo_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Adds shared application state, such as a database pool or counters
+    /// 
+    /// Handlers get it back with [`RequestInfo::state`]. There is one value per type,
+    /// so adding a second value of the same type replaces the first.
+    /// 
+    /// # Arguments
+    /// * `state` - The state to share
+    /// 
+    /// # Examples
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use simpleserve::{
+    ///     Webserver,
+    ///     Page,
+    ///     Sendable,
+    ///     RequestInfo
+    /// };
+    /// 
+    /// struct Visits(AtomicUsize);
+    /// 
+    /// fn count(request: &RequestInfo) -> Box<dyn Sendable> {
+    ///     let visits = request.state::<Visits>().unwrap();
+    ///     let count = visits.0.fetch_add(1, Ordering::SeqCst) + 1;
+    ///     Box::new(Page::new(200, format!("Visit {}", count)))
+    /// }
+    /// 
+    /// let mut server = Webserver::new(10, vec![]).with_state(Visits(AtomicUsize::new(0)));
+    /// server.add_route("/", count);
+    /// ```
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Webserver {
+        self.state.insert(state);
+        self
+    }
+
+    /// The shared state of a type, if it was added with [`Webserver::with_state`]
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.state.get()
+    }
+
+    /// The Server-Sent Events channel with a name, created the first time it is asked for
+    /// 
+    /// Handlers find it with [`RequestInfo::sse_channel`]. See the [`sse`](crate::sse) module.
+    /// 
+    /// # Arguments
+    /// * `name` - The name of the channel
+    pub fn sse_channel(&mut self, name: &str) -> sse::Channel {
+        if !self.state.contains::<sse::Channels>() {
+            self.state.insert(sse::Channels::default());
+        }
+        self.state.get::<sse::Channels>().unwrap().get_or_create(name)
+    }
+
+    /// The long polling queues of the server, created the first time they are asked for
+    /// 
+    /// Handlers find them with [`RequestInfo::long_poll`]. See the [`long_poll`](crate::long_poll) module.
+    pub fn long_poll(&mut self) -> LongPoll {
+        if !self.state.contains::<LongPoll>() {
+            self.state.insert(LongPoll::new());
+        }
+        LongPoll::clone(&self.state.get::<LongPoll>().unwrap())
+    }
+
+    /// The event bus of the server, created the first time it is asked for
+    /// 
+    /// Handlers and middleware find it with [`RequestInfo::events`]. See the
+    /// [`event_bus`](crate::event_bus) module.
+    pub fn events(&mut self) -> EventBus {
+        if !self.state.contains::<EventBus>() {
+            self.state.insert(EventBus::new());
+        }
+        EventBus::clone(&self.state.get::<EventBus>().unwrap())
+    }
+
+    /// Serves a generated sitemap at `/sitemap.xml`
+    /// 
+    /// See the [`sitemap`](crate::sitemap) module.
+    /// 
+    /// # Arguments
+    /// * `sitemap` - The settings of the sitemap
+    /// 
+    /// # Panics
+    /// Panics if a `/sitemap.xml` route already exists
+    pub fn with_sitemap(mut self, sitemap: Sitemap) -> Webserver {
+        let xml = Arc::clone(&self.sitemap_xml);
+        self.add_route("/sitemap.xml", move |_: &RequestInfo| -> Box<dyn Sendable> {
+            Box::new(Response::new(200)
+                .header("Content-Type", "application/xml")
+                .text(&xml.read().unwrap()))
+        });
+        self.sitemap = Some(sitemap);
+        self.refresh_sitemap();
+        self
+    }
+
+    /// The sitemap as it is currently served
+    pub fn sitemap_xml(&self) -> String {
+        self.sitemap_xml.read().unwrap().clone()
+    }
+
+    fn refresh_sitemap(&self) {
+        if let Some(sitemap) = &self.sitemap {
+            *self.sitemap_xml.write().unwrap() = sitemap.render(&self.routes);
+        }
+    }
+
+    /// Switches to an unprivileged user and group once the port is bound
+    /// 
+    /// See the [`privileges`](crate::privileges) module. [`Webserver::start`] fails if the
+    /// switch fails, rather than serving as root.
+    /// 
+    /// # Arguments
+    /// * `user` - The name of the user to run as
+    /// * `group` - The name of the group to run as
+    pub fn run_as(mut self, user: &str, group: &str) -> Webserver {
+        self.run_as = Some(Privileges::new(user, group));
+        self
+    }
+
+    /// Confines the server to a directory once the port is bound
+    /// 
+    /// Only used together with [`Webserver::run_as`]. The blacklisted paths and the directories
+    /// served with [`Webserver::serve_directory`] are moved inside the chroot, and blacklisted
+    /// paths outside it are dropped. [`Webserver::start`] fails if a served directory is outside it.
+    /// 
+    /// # Arguments
+    /// * `dir` - The directory that becomes the root of the file system
+    pub fn with_chroot<P: AsRef<Path>>(mut self, dir: P) -> Webserver {
+        self.chroot = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    fn drop_privileges(&mut self) -> Result<(), Box<dyn Error>> {
+        let (privileges, root) = match (&self.run_as, &self.chroot) {
+            (Some(privileges), Some(dir)) => (privileges.clone(), dir.canonicalize()?),
+            (Some(privileges), None) => return Ok(privileges.drop_privileges()?),
+            (None, Some(_)) => return Err("A chroot needs a user to run as, set with Webserver::run_as".into()),
+            (None, None) => return Ok(()),
+        };
+        // Paths were canonicalized outside the chroot, where they start with its directory
+        let mut mounts = Vec::new();
+        for mount in &self.mounts {
+            let path = mount.read().unwrap().clone();
+            match privileges::path_in_chroot(&root, &path) {
+                Some(path) => mounts.push(path),
+                None => return Err(format!("{} is served, but is outside the chroot {}", path.display(), root.display()).into()),
+            }
+        }
+        // Outside the chroot nothing can be served, and inside it the same path is another file
+        let blacklisted_paths = self.blacklisted_paths.iter()
+            .filter_map(|path| privileges::path_in_chroot(&root, &path.canonicalize().unwrap_or_else(|_| path.clone())))
+            .collect();
+        privileges.with_chroot(&root).drop_privileges()?;
+        self.blacklisted_paths = blacklisted_paths;
+        for (mount, path) in self.mounts.iter().zip(mounts) {
+            *mount.write().unwrap() = path;
+        }
+        Ok(())
+    }
+
+    /// Periodically requests some routes and marks the server as not ready while they fail
+    ///
+    /// See the [`self_check`](crate::self_check) module.
+    ///
+    /// # Arguments
+    /// * `self_check` - The routes to check and how often
+    pub fn with_self_check(mut self, self_check: SelfCheck) -> Webserver {
+        self.self_check = Some(self_check);
+        self
+    }
+
+    pub fn self_check(&self) -> Option<&SelfCheck> {
+        self.self_check.as_ref()
+    }
+
+    /// Runs the self-checks on their own thread, until the returned sender is dropped
+    fn start_self_check(&self) -> Option<oneshot::Sender<()>> {
+        let self_check = self.self_check.clone()?;
+        let context = self.context();
+        let readiness = Arc::clone(&self.readiness);
+        let (stop, stopped) = oneshot::channel();
+        std::thread::spawn(move || {
+            let rt = Runtime::new().unwrap();
+            rt.block_on(async {
+                tokio::select! {
+                    _ = self_check.run(context, readiness) => {},
+                    _ = stopped => {},
+                }
+            });
+        });
+        Some(stop)
+    }
+
+    pub fn set_404_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.routes[0] = Handler::new("404", Callback::Sync(Arc::new(callback)));
+    }
+
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    pub fn theme(&self) -> &Theme {
+        &self.theme
+    }
+
+    /// Sets the theme used for built-in pages
+    /// 
+    /// # Arguments
+    /// * `theme` - The theme to render built-in pages with
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    /// Sets how long browsers may cache the files sent by the file handlers
+    /// 
+    /// # Arguments
+    /// * `policy` - The caching rules, by route and by file extension
+    pub fn with_cache_policy(mut self, policy: CachePolicy) -> Webserver {
+        self.cache_policy = policy;
+        self
+    }
+
+    pub fn cache_policy(&self) -> &CachePolicy {
+        &self.cache_policy
+    }
+
+    /// Adds a route to the webserver
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add
+    /// * `handler` - The handler for the route
+    /// 
+    /// # Panics
+    /// Panics if the route is empty
+    /// 
+    /// # Examples
+    /// ```
+    /// use std::{
+    ///     net::TcpStream,
+    ///     fs,
+    ///     path::PathBuf
+    /// };
+    /// use simpleserve::{
+    ///     Webserver,
+    ///     Page,
+    ///     Sendable,
+    ///     RequestInfo,
+    ///     ConnectionType
+    /// };
+    ///
+    /// fn main() {
+    ///     let mut server = Webserver::new(10, vec![]);
+    ///     server.add_route("/", main_route);
+    ///     server.start("127.0.0.1:7878", ConnectionType::Http, None, None);
+    /// }
+    ///
+    ///
+    /// fn main_route(request: &RequestInfo) -> Box<dyn Sendable> {
+    ///     let contents = fs::read_to_string("index.html").expect("Error reading file");
+    ///     Box::new(Page::new(200, contents))
+    /// }
+    pub fn add_route<F>(&mut self, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.push_route(route, None, Callback::Sync(Arc::new(handler)));
+    }
+
+    /// Adds a route that only handles one method
+    /// 
+    /// Requests to the route with another method get 405 Method Not Allowed.
+    /// 
+    /// # Arguments
+    /// * `method` - The method to handle
+    /// * `route` - The route to add
+    /// * `handler` - The handler for the route
+    /// 
+    /// # Panics
+    /// Panics if the route is empty, or already has a handler for this method
+    /// 
+    /// # Examples
+    /// ```
+    /// use simpleserve::{
+    ///     Webserver,
+    ///     Page,
+    ///     Sendable,
+    ///     RequestInfo,
+    ///     request::Method
+    /// };
+    /// 
+    /// fn list_users(_: &RequestInfo) -> Box<dyn Sendable> {
+    ///     Box::new(Page::new(200, String::from("[]")))
+    /// }
+    /// 
+    /// fn create_user(_: &RequestInfo) -> Box<dyn Sendable> {
+    ///     Box::new(Page::new(201, String::from("Created")))
+    /// }
+    /// 
+    /// let mut server = Webserver::new(10, vec![]);
+    /// server.add_route_with_method(Method::Get, "/users", list_users);
+    /// server.post("/users", create_user);
+    /// ```
+    pub fn add_route_with_method<F>(&mut self, method: Method, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.push_route(route, Some(method), Callback::Sync(Arc::new(handler)));
+    }
+
+    /// Adds a route that only handles GET requests
+    /// 
+    /// The route answers HEAD requests too, with the same headers and no body.
+    pub fn get<F>(&mut self, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.add_route_with_method(Method::Get, route, handler);
+    }
+
+    /// Adds a route that only handles POST requests
+    pub fn post<F>(&mut self, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.add_route_with_method(Method::Post, route, handler);
+    }
+
+    /// Adds a route that only handles PUT requests
+    pub fn put<F>(&mut self, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.add_route_with_method(Method::Put, route, handler);
+    }
+
+    /// Adds a route that only handles DELETE requests
+    pub fn delete<F>(&mut self, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.add_route_with_method(Method::Delete, route, handler);
+    }
+
+    /// Adds a route with an async handler
+    /// 
+    /// The handler can await database queries or downstream calls without blocking a pool thread
+    /// while it waits.
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add
+    /// * `handler` - The handler for the route
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    /// 
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::{
+    ///     Webserver,
+    ///     Page,
+    ///     RequestInfo,
+    ///     HandlerFuture
+    /// };
+    /// 
+    /// fn slow_lookup<'a>(_: &'a RequestInfo<'a>) -> HandlerFuture<'a> {
+    ///     Box::pin(async {
+    ///         tokio::time::sleep(Duration::from_millis(10)).await;
+    ///         Box::new(Page::new(200, String::from("Found it"))) as _
+    ///     })
+    /// }
+    /// 
+    /// let mut server = Webserver::new(10, vec![]);
+    /// server.add_async_route("/lookup", slow_lookup);
+    /// ```
+    pub fn add_async_route<F>(&mut self, route: &str, handler: F)
+    where
+        F: for<'a> Fn(&'a RequestInfo<'a>) -> HandlerFuture<'a> + Send + Sync + 'static,
+    {
+        self.push_route(route, None, Callback::Async(Arc::new(handler)));
+    }
+
+    /// Adds a route that upgrades GET requests to a WebSocket
+    /// 
+    /// See the [`websocket`](crate::websocket) module. Requests that are not a valid WebSocket
+    /// handshake get 426 Upgrade Required or 400 Bad Request.
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add, which may contain parameters
+    /// * `handler` - Runs with the socket once the handshake is done
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    pub fn on_websocket<F>(&mut self, route: &str, handler: F)
+    where
+        F: for<'a> Fn(WebSocket<'a>) -> WebSocketFuture<'a> + Send + Sync + 'static,
+    {
+        let handler: WebSocketHandler = Arc::new(handler);
+        self.get(route, move |request: &RequestInfo| websocket::upgrade(request, &handler));
+    }
+
+    /// Mounts a GraphQL endpoint at a route
+    /// 
+    /// POST requests with a JSON body are run by the executor, and GET requests get the
+    /// playground, unless it was turned off. See the [`graphql`](crate::graphql) module.
+    /// 
+    /// # Arguments
+    /// * `route` - The route of the endpoint
+    /// * `graphql` - The endpoint
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    pub fn mount_graphql<E: graphql::Executor>(&mut self, route: &str, graphql: GraphQL<E>) {
+        let playground = graphql.has_playground();
+        self.push_route(route, Some(Method::Post), Callback::Async(graphql::handler(Arc::new(graphql))));
+        if playground {
+            let page = graphql::playground(route);
+            self.get(route, move |_: &RequestInfo| -> Box<dyn Sendable> { Box::new(Page::new(200, page.clone())) });
+        }
+        self.set_accepted_types(route, &["application/json"]);
+    }
+
+    /// Adds a route answered by running an external program
+    /// 
+    /// The request body is the program's standard input, and its standard output is the
+    /// response. See the [`command`](crate::command) module for its limits.
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add
+    /// * `command` - The program to run and its limits
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    pub fn add_command_route(&mut self, route: &str, command: CommandHandler) {
+        self.push_route(route, None, Callback::Async(command::handler(Arc::new(command))));
+    }
+
+    fn push_route(&mut self, route: &str, method: Option<Method>, handler: Callback) {
+        if route.is_empty() {
+            panic!("Route cannot be empty");
+        }
+        for route_handler in &self.routes {
+            let same_method = match (&route_handler.method, &method) {
+                (Some(existing), Some(method)) => existing == method,
+                _ => true,
+            };
+            if route_handler.route == route && same_method {
+                panic!("Route already exists");
+            }
+        }
+        match &method {
+            Some(method) => println!("Added route {} {}", method, route),
+            None => println!("Added route {}", route),
+        }
+        let mut route_handler = Handler::new(route, handler);
+        route_handler.method = method;
+        self.routes.push(route_handler);
+    }
+
+    /// Adds a health check route to the webserver
+    /// 
+    /// Health check routes are served even while the server is not ready yet.
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add
+    /// * `handler` - The handler for the route
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    pub fn add_health_route<F>(&mut self, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.add_route(route, handler);
+        if let Some(route_handler) = self.routes.last_mut() {
+            route_handler.health_check = true;
+        }
+    }
+
+    /// Delays readiness until a warm-up task completes
+    /// 
+    /// The listener is bound as usual, but every route except health check routes responds
+    /// with 503 Service Unavailable until all warm-up tasks have completed.
+    /// The tasks are run concurrently once the server starts. If one of them panics, the
+    /// server never becomes ready.
+    /// 
+    /// # Arguments
+    /// * `gate` - The warm-up task to wait for
+    /// 
+    /// # Examples
+    /// ```
+    /// use simpleserve::Webserver;
+    /// 
+    /// let mut server = Webserver::new(10, vec![]);
+    /// server.ready_when(async {
+    ///     // Prime caches, check migrations, ...
+    /// });
+    /// assert!(!server.is_ready());
+    /// ```
+    pub fn ready_when<F>(&mut self, gate: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.readiness.set_warmed_up(false);
+        self.readiness_gates.push(Box::pin(gate));
+    }
+
+    /// Adds a connection pool as shared state
+    /// 
+    /// Handlers get the pool with [`RequestInfo::state`], and the server is not ready until the
+    /// pool is reachable. The pool is checked by [`pool::readiness`]. See the
+    /// [`pool`](crate::pool) module.
+    /// 
+    /// # Arguments
+    /// * `pool` - The pool to add, replacing any earlier pool of the same type
+    pub fn with_pool<P: Pool>(mut self, pool: P) -> Webserver {
+        self.state.insert(pool);
+        let pool = self.state.get::<P>().unwrap();
+        if !self.state.contains::<pool::Pools>() {
+            self.state.insert(pool::Pools::default());
+        }
+        self.state.get::<pool::Pools>().unwrap().add(Arc::clone(&pool));
+        self.ready_when(pool::wait_until_reachable(pool));
+        self
+    }
+
+    /// Whether all warm-up tasks have completed, and no self-check is failing
+    pub fn is_ready(&self) -> bool {
+        self.readiness.is_ready()
+    }
+
+    /// Reads the directories mounted with a preload into memory, on the thread pool
+    fn preload_mounts(&self) {
+        for (root, cache, max_size) in &self.preloads {
+            let root = root.read().unwrap().clone();
+            match cache.preload(&self.thread_pool, &root, *max_size) {
+                Ok((files, size)) => println!("Preloaded {} files, {} bytes, from {}", files, size, root.display()),
+                Err(e) => println!("Error preloading {}: {}", root.display(), e),
+            }
+        }
+    }
+
+    fn start_readiness_gates(&mut self) {
+        if self.readiness_gates.is_empty() {
+            return;
+        }
+        let gates: Vec<_> = self.readiness_gates.drain(..).map(tokio::spawn).collect();
+        let readiness = Arc::clone(&self.readiness);
+        tokio::spawn(async move {
+            let mut failed = false;
+            for gate in gates {
+                if let Err(e) = gate.await {
+                    println!("Warm-up task failed: {}", e);
+                    failed = true;
+                }
+            }
+            // A server that did not warm up is not ready, so it stays out of rotation
+            if failed {
+                println!("Warm-up failed, the server will not become ready");
+                return;
+            }
+            println!("Warm-up completed");
+            readiness.set_warmed_up(true);
+        });
+    }
+
+    /// Adds a route whose handler is CPU-bound
+    /// 
+    /// The handler runs on the CPU pool if one was added with [`Webserver::with_cpu_pool`],
+    /// otherwise on the main pool like any other route.
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add
+    /// * `handler` - The handler for the route
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    pub fn add_cpu_route<F>(&mut self, route: &str, handler: F)
+    where
+        F: Fn(&RequestInfo) -> Box<dyn Sendable> + Send + Sync + 'static,
+    {
+        self.add_route(route, handler);
+        if let Some(route_handler) = self.routes.last_mut() {
+            route_handler.pool_hint = PoolHint::Cpu;
+        }
+    }
+
+    /// Replaces the body limits for a route
+    /// 
+    /// The limits apply to every handler of the route, whatever its method, and replace the ones
+    /// set with [`Webserver::with_body_limits`]. See the [`body_limits`](crate::body_limits) module.
+    /// 
+    /// # Arguments
+    /// * `route` - The route, as it was added
+    /// * `limits` - The limits
+    /// 
+    /// # Panics
+    /// Panics if the route does not exist
+    pub fn set_body_limits(&mut self, route: &str, limits: BodyLimits) {
+        let mut found = false;
+        for route_handler in self.routes.iter_mut().filter(|route_handler| route_handler.route == route) {
+            route_handler.body_limits = Some(limits.clone());
+            found = true;
+        }
+        if !found {
+            panic!("Route does not exist");
+        }
+    }
+
+    /// Sets the types of request body a route accepts
+    /// 
+    /// Requests with a body of another type, or without a `Content-Type`, are answered with
+    /// 415 Unsupported Media Type before the body is read, and the answer lists the accepted types
+    /// in `Accept-Post`, or `Accept-Patch` for PATCH requests. A type ending in `/` accepts every
+    /// subtype. Requests without a body are not checked, and neither are routes without accepted types.
+    /// 
+    /// The types apply to every handler of the route, whatever its method.
+    /// 
+    /// # Arguments
+    /// * `route` - The route, as it was added
+    /// * `mime_types` - The accepted types, such as `application/json`
+    /// 
+    /// # Panics
+    /// Panics if the route does not exist
+    /// 
+    /// # Examples
+    /// ```
+    /// use simpleserve::{Webserver, Sendable, RequestInfo, Page};
+    /// 
+    /// let mut server = Webserver::new(10, vec![]);
+    /// server.post("/api/users", |_: &RequestInfo| -> Box<dyn Sendable> {
+    ///     // Only JSON bodies get this far
+    ///     Box::new(Page::new(201, String::from("Created")))
+    /// });
+    /// server.set_accepted_types("/api/users", &["application/json"]);
+    /// ```
+    pub fn set_accepted_types(&mut self, route: &str, mime_types: &[&str]) {
+        let mime_types: Vec<String> = mime_types.iter().map(|mime_type| mime_type.to_ascii_lowercase()).collect();
+        let mut found = false;
+        for route_handler in self.routes.iter_mut().filter(|route_handler| route_handler.route == route) {
+            route_handler.accepted_types = mime_types.clone();
+            found = true;
+        }
+        if !found {
+            panic!("Route does not exist");
+        }
+    }
+
+    /// Adds a tarpit route with the default caps
+    /// 
+    /// See the [`tarpit`](crate::tarpit) module.
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add, such as `/wp-login.php`
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    pub fn add_tarpit(&mut self, route: &str) {
+        self.add_tarpit_with(route, Tarpit::new());
+    }
+
+    /// Adds a tarpit route
+    /// 
+    /// # Arguments
+    /// * `route` - The route to add
+    /// * `tarpit` - How slowly to respond, and for how long
+    /// 
+    /// # Panics
+    /// Panics if the route is empty or already exists
+    pub fn add_tarpit_with(&mut self, route: &str, tarpit: Tarpit) {
+        let stats = Arc::clone(&self.stats);
+        let tarpitted = Arc::clone(&self.tarpitted);
+        self.add_route(route, move |request: &RequestInfo| -> Box<dyn Sendable> {
+            stats.tarpit_hits.fetch_add(1, Ordering::SeqCst);
+            match request.conn.peer_addr() {
+                Some(addr) => println!("Tarpit hit on {} from {}", request.route, addr),
+                None => println!("Tarpit hit on {}", request.route),
+            }
+            match Arc::clone(&tarpitted).try_acquire_owned() {
+                Ok(permit) => Box::new(tarpit.clone().holding(permit)),
+                // Tarpitting every scanner would leave no worker for real clients
+                Err(_) => Box::new(request.error_page(404, "Not Found", "The requested page could not be found.")),
+            }
+        });
+    }
+
+    /// Sets how many connections can be tarpitted at once
+    /// 
+    /// A tarpitted connection keeps a worker busy, so tarpit routes hit while the limit is reached
+    /// are answered with 404 Not Found right away. Defaults to half of the threads, at least one.
+    /// 
+    /// # Arguments
+    /// * `max_tarpitted` - The number of connections
+    /// 
+    /// # Panics
+    /// Panics if `max_tarpitted` is zero
+    pub fn with_max_tarpitted(mut self, max_tarpitted: usize) -> Webserver {
+        assert!(max_tarpitted > 0);
+        // Tarpit routes added before share the same semaphore, so it is resized in place
+        if max_tarpitted > self.max_tarpitted {
+            self.tarpitted.add_permits(max_tarpitted - self.max_tarpitted);
+        } else if let Ok(permits) = self.tarpitted.try_acquire_many((self.max_tarpitted - max_tarpitted) as u32) {
+            permits.forget();
+        }
+        self.max_tarpitted = max_tarpitted;
+        self
+    }
+
+    pub fn max_tarpitted(&self) -> usize {
+        self.max_tarpitted
+    }
+
+    /// Registers a plugin
+    /// 
+    /// The plugin's [`Plugin::register`] is called right away, and its lifecycle hooks
+    /// are called when the server starts and shuts down.
+    /// 
+    /// # Arguments
+    /// * `plugin` - The plugin to register
+    pub fn register_plugin<P: Plugin + 'static>(&mut self, plugin: P) {
+        println!("Registering plugin {}", plugin.name());
+        plugin.register(self);
+        self.plugins.push(Arc::new(plugin));
+    }
+
+    pub fn plugins(&self) -> &Vec<Arc<dyn Plugin>> {
+        &self.plugins
+    }
+
+    /// Adds a middleware
+    /// 
+    /// Middleware runs in the order it is added, within its [`Phase`](crate::middleware::Phase) and
+    /// priority. See the [`middleware`](crate::middleware) module.
+    /// 
+    /// # Arguments
+    /// * `middleware` - The middleware to add
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    pub fn middleware(&self) -> &Vec<Arc<dyn Middleware>> {
+        &self.middleware
+    }
+
+    /// Limits how fast each client IP can send requests
+    /// 
+    /// The limiter runs before every other middleware, with the highest priority, so limited
+    /// requests cost as little as possible. See the [`rate_limit`](crate::rate_limit) module.
+    /// 
+    /// # Arguments
+    /// * `limiter` - The rate limiter to use
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Webserver {
+        self.middleware.insert(0, Arc::new(Ordered::new(limiter).with_priority(i32::MAX)));
+        self
+    }
+
+    /// Advertises other protocols clients can switch to on every response
+    /// 
+    /// Adds an `Alt-Svc` header, and `Upgrade: h2c` on HTTP connections if the upgrade is
+    /// advertised. See the [`alt_svc`](crate::alt_svc) module.
+    /// 
+    /// # Arguments
+    /// * `alt_svc` - The services to advertise
+    pub fn with_alt_svc(mut self, alt_svc: AltSvc) -> Webserver {
+        self.middleware.push(Arc::new(alt_svc));
+        self
+    }
+
+    /// Resolves server-side and edge-side includes in HTML responses
+    /// 
+    /// See the [`includes`](crate::includes) module.
+    /// 
+    /// # Arguments
+    /// * `includes` - The limits of the includes
+    pub fn with_includes(mut self, includes: Includes) -> Webserver {
+        self.middleware.push(Arc::new(Transform::new(includes)));
+        self
+    }
+
+    /// Gives bots and crawlers stricter rate limits than other clients
+    /// 
+    /// Runs before other middleware, like [`Webserver::with_rate_limit`], and after the rate
+    /// limiter if there is one. See the [`bot_throttle`](crate::bot_throttle) module.
+    /// 
+    /// # Arguments
+    /// * `throttle` - The limits for bots and crawlers
+    pub fn with_bot_throttle(mut self, throttle: BotThrottle) -> Webserver {
+        self.middleware.push(Arc::new(Ordered::new(throttle).with_priority(i32::MAX)));
+        self
+    }
+
+    pub fn add_accessible_files(&mut self, paths: Vec<&str>) -> Result<(), std::io::Error> {
+        for path_str in paths {
+            path::Path::new(path_str).canonicalize()?;
+            let path_str = &*(String::from("/") + path_str);
+            println!("Added route {}", path_str);
+            self.add_route(path_str, utils::base_file_handler);
+        }
+        Ok(())
+    }
+
+    /// Serves the files in a directory under a URL prefix
+    /// 
+    /// A request for `{prefix}/css/app.css` gets `{dir}/css/app.css`, and a request for a
+    /// directory gets its `index.html`. Paths outside the directory and blacklisted paths are
+    /// refused. See the [`static_files`](crate::static_files) module.
+    /// 
+    /// # Arguments
+    /// * `prefix` - The URL prefix, like `/static`, or `/` for the whole site
+    /// * `dir` - The directory to serve, which has to exist
+    pub fn serve_directory<P: AsRef<Path>>(&mut self, prefix: &str, dir: P) -> Result<(), std::io::Error> {
+        self.serve_directory_with_options(prefix, dir, MountOptions::new())
+    }
+
+    /// Serves the files in a directory under a URL prefix, with options for this mount
+    /// 
+    /// Like [`Webserver::serve_directory`], but can also list directories without an index, and
+    /// read the files into memory when the server starts.
+    /// 
+    /// # Arguments
+    /// * `prefix` - The URL prefix, like `/static`, or `/` for the whole site
+    /// * `dir` - The directory to serve, which has to exist
+    /// * `options` - How the directory is served
+    pub fn serve_directory_with_options<P: AsRef<Path>>(&mut self, prefix: &str, dir: P, options: MountOptions) -> Result<(), std::io::Error> {
+        let root = dir.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a directory", root.display())));
+        }
+        let prefix = prefix.trim_end_matches('/');
+        println!("Serving {} at {}/", root.display(), prefix);
+        let root = Arc::new(RwLock::new(root));
+        self.mounts.push(Arc::clone(&root));
+        let cache = Arc::new(FileCache::new());
+        if options.preload() > 0 {
+            self.preloads.push((Arc::clone(&root), Arc::clone(&cache), options.preload()));
+        }
+        if !prefix.is_empty() {
+            let (root, cache) = (Arc::clone(&root), Arc::clone(&cache));
+            self.get(prefix, move |request: &RequestInfo| static_files::serve(request, &root.read().unwrap(), "", options, Some(&cache)));
+        }
+        self.get(&format!("{}/*path", prefix), move |request: &RequestInfo| {
+            static_files::serve(request, &root.read().unwrap(), request.param("path").unwrap_or_default(), options, Some(&cache))
+        });
+        Ok(())
+    }
+
+    /// Serves a directory of assets under hashed names, cached as immutable
+    /// 
+    /// Every file is hashed when it is mounted, and served under a name with the hash, like
+    /// `/assets/app.3fa9c2e1.js` for `app.js`, with `Cache-Control: public, max-age=31536000, immutable`.
+    /// Other paths are served like [`Webserver::serve_directory`] would. The
+    /// [`AssetManifest`](crate::static_files::AssetManifest) is added to the shared state, so
+    /// handlers and templates find the hashed names with [`RequestInfo::asset`].
+    /// See the [`static_files`](crate::static_files) module.
+    /// 
+    /// # Arguments
+    /// * `prefix` - The URL prefix, like `/assets`
+    /// * `dir` - The directory to serve, which has to exist
+    pub fn serve_assets<P: AsRef<Path>>(&mut self, prefix: &str, dir: P) -> Result<Arc<AssetManifest>, std::io::Error> {
+        let root = dir.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a directory", root.display())));
+        }
+        let manifest = AssetManifest::scan(prefix, &root)?;
+        let prefix = manifest.prefix().to_string();
+        println!("Serving {} assets from {} at {}/", manifest.len(), root.display(), prefix);
+        self.state.insert(manifest);
+        let manifest = self.state.get::<AssetManifest>().unwrap();
+        let root = Arc::new(RwLock::new(root));
+        self.mounts.push(Arc::clone(&root));
+        let options = MountOptions::new();
+        let assets = Arc::clone(&manifest);
+        self.get(&format!("{}/*path", prefix), move |request: &RequestInfo| {
+            static_files::serve_asset(request, &root.read().unwrap(), &assets, request.param("path").unwrap_or_default(), options)
+        });
+        Ok(manifest)
+    }
+
+    /// Exports every GET route as a static site
+    /// 
+    /// Routes that are patterns or health checks are skipped. See the [`export`](crate::export) module.
+    /// Returns the files that were written.
+    /// 
+    /// # Arguments
+    /// * `dir` - The directory to write the site to, created if needed
+    pub async fn export<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let routes: Vec<String> = self.routes.iter()
+            .filter(|handler| export::is_exportable(handler))
+            .map(|handler| String::from(handler.route()))
+            .collect();
+        let routes: Vec<&str> = routes.iter().map(String::as_str).collect();
+        self.export_routes(dir, &routes).await
+    }
+
+    /// Exports a list of routes as a static site
+    /// 
+    /// Routes that do not respond with a 2xx status are skipped.
+    /// Returns the files that were written.
+    /// 
+    /// # Arguments
+    /// * `dir` - The directory to write the site to, created if needed
+    /// * `routes` - The routes to request, which may include a query string
+    pub async fn export_routes<P: AsRef<Path>>(&self, dir: P, routes: &[&str]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut context = self.context();
+        // Export requests are not traffic, so they are kept out of the server's stats
+        context.stats = Arc::new(ServerStats::default());
+        let mut written = Vec::new();
+        for route in routes {
+            let (status, body) = export::fetch(&context, route).await?;
+            if !(200..300).contains(&status) {
+                println!("Skipping {}, it responded with {}", route, status);
+                continue;
+            }
+            let route = route.split('?').next().unwrap_or(route);
+            let file = dir.as_ref().join(export::file_for_route(route));
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&file, body)?;
+            println!("Exported {} to {}", route, file.display());
+            written.push(file);
+        }
+        Ok(written)
+    }
+
+    /// Waits for the next task, or forever if there is no receiver
+    /// 
+    /// The receiver stays in place while waiting, so a connection being accepted first does not lose it.
+    async fn receive(&mut self) -> Option<Task> {
+        match &mut self.receiver {
+            Some(receiver) => {
+                match receiver.recv().await {
+                    Some(message) => Some(message),
+                    None => {
+                        println!("Receiver channel closed");
+                        self.receiver = None;
+                        None
+                    }
+                }
+            },
+            None => std::future::pending().await
+        }
+    }
+
+    /// A snapshot of the state a connection job needs
+    fn context(&self) -> ServerContext {
+        ServerContext {
+            routes: self.routes.clone(),
+            blacklisted_paths: self.blacklisted_paths.clone(),
+            theme: self.theme.clone(),
+            cache_policy: self.cache_policy.clone(),
+            ready: self.readiness.flag(),
+            stats: Arc::clone(&self.stats),
+            cpu_pool: self.cpu_pool.clone(),
+            clock: Arc::clone(&self.clock),
+            max_body_size: self.max_body_size,
+            multipart: self.multipart,
+            body_limits: self.body_limits.clone(),
+            max_request_line: self.max_request_line,
+            max_header_size: self.max_header_size,
+            max_headers: self.max_headers,
+            keep_alive_timeout: self.keep_alive_timeout,
+            header_timeout: self.header_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            max_requests_per_connection: self.max_requests_per_connection,
+            shutdown: self.shutdown.clone(),
+            geo_resolver: self.geo_resolver.clone(),
+            state: self.state.clone(),
+            middleware: middleware::before_order(&self.middleware),
+        }
+    }
+
+    /// Answers a connection with 503 Service Unavailable when the server is full
+    /// 
+    /// Runs on the accept loop, so it does not wait for a worker.
+    fn turn_away(&self, mut conn: ConnectionInfo, retry_after: Duration) {
+        self.stats.connections_rejected.fetch_add(1, Ordering::SeqCst);
+        let theme = self.theme.clone();
+        tokio::spawn(async move {
+            // An HTTPS client can only read the answer once the handshake is done
+            if let Ok(Err(e)) = tokio::time::timeout(TURN_AWAY_READ_TIMEOUT, conn.handshake()).await {
+                println!("Error turning away connection: {}", e);
+                return;
+            }
+            // Closing with the request unread would reset the connection before the client reads the answer
+            let head = conn.read_head_limited(DEFAULT_MAX_REQUEST_LINE, DEFAULT_MAX_HEADER_SIZE);
+            let head = match tokio::time::timeout(TURN_AWAY_READ_TIMEOUT, head).await {
+                Ok(Ok(Some(head))) => head,
+                _ => String::new(),
+            };
+            let headers = parse_head(&head).map(|(_, headers)| headers).unwrap_or_default();
+            let response = theme.localized_page(headers.get("accept-language"), 503, "Service Unavailable", "The server is busy, please try again shortly.")
+                .header("Retry-After", &retry_after.as_secs().max(1).to_string())
+                .header("Connection", "close");
+            let sent = tokio::time::timeout(DEFAULT_WRITE_TIMEOUT, async {
+                response.send(&mut conn).await?;
+                conn.flush().await
+            }).await;
+            if let Ok(Err(e)) = sent {
+                println!("Error turning away connection: {}", e);
+            }
+        });
+    }
+
+    /// Hands a connection to the thread pool
+    fn dispatch(&self, connection_info: ConnectionInfo) {
+        let context = self.context();
+
+        let id = ConnectionId(self.stats.connections_accepted.fetch_add(1, Ordering::SeqCst) + 1);
+        self.stats.connection_opened();
+        self.stats.connections_queued.fetch_add(1, Ordering::SeqCst);
+        self.thread_pool.execute_with_context(JobContext::new().with(id), move || {
+            context.stats.connections_queued.fetch_sub(1, Ordering::SeqCst);
+            let rt = Runtime::new().unwrap();
+            let shutdown = context.shutdown.clone();
+            let stats = Arc::clone(&context.stats);
+            let handled = utils::handle_connection(connection_info, context);
+            if let Err(e) = rt.block_on(utils::abort_on_force_close(&shutdown, &stats, handled)) {
+                println!("Error handling connection: {}", e);
+            }
+        });
+    }
+
+    /// Starts the webserver
+    /// 
+    /// Returns a [`ShutdownReport`] once the server has been shut down.
+    /// 
+    /// # Arguments
+    /// * `addr` - The address to start the server on
+    /// 
+    /// # Errors
+    /// Returns an error if an HTTPS server is started without a private key or certificate file,
+    /// or with ones that cannot be loaded
+    /// 
+    /// # Panics
+    /// Panics if the address is invalid
+    pub async fn start(&mut self, addr: &str, connection_type: ConnectionType, pk: Option<PathBuf>, sslc: Option<PathBuf>) -> Result<ShutdownReport, Box<dyn Error>> {
+        let started_at = self.clock.now();
+        let tls = match (&connection_type, pk, sslc) {
+            (ConnectionType::Http, _, _) => None,
+            (ConnectionType::Https, Some(pk), Some(sslc)) => Some((pk, sslc)),
+            (ConnectionType::Https, _, _) => {
+                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, "HTTPS needs a private key and a certificate file")));
+            },
+        };
+        if let Some(count) = self.workers.filter(|_| workers::worker_index().is_none()) {
+            let supervisor = Supervisor::current(count, self.shutdown_timeout + FORCE_CLOSE_GRACE)?;
+            return self.supervise(supervisor, started_at).await;
+        }
+        if let Some(core) = self.accept_loop_core {
+            crate::pin_current_thread(core);
+        }
+        self.preload_mounts();
+        self.start_readiness_gates();
+        self.refresh_sitemap();
+        for plugin in &self.plugins {
+            plugin.on_start();
+        }
+        let self_check = self.start_self_check();
+        let signal_handler = self.start_signal_handler();
+        self.connection_type = Some(connection_type);
+        let served = match tls {
+            None => self.start_http(addr).await,
+            Some((pk, sslc)) => self.start_https(addr, pk, sslc).await,
+        };
+        drop(self_check);
+        if let Some(signal_handler) = signal_handler {
+            signal_handler.abort();
+        }
+        served?;
+        // Connections waiting for a request close now, the rest once their response is sent
+        self.shutdown.shutdown();
+        if let (Some(audit_log), Some(actor)) = (&self.audit_log, self.shutdown.actor()) {
+            if let Err(e) = audit_log.record(&actor, "shutdown") {
+                println!("Error writing to the audit log: {}", e);
+            }
+        }
+        let active = self.stats.connections_active();
+        let served = self.stats.requests_served();
+        let deadline = tokio::time::Instant::now() + self.shutdown_timeout;
+        while self.stats.connections_active() > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        let still_active = self.stats.connections_active();
+        if still_active > 0 {
+            // Streams, tarpits and long polls would otherwise keep the workers, and the shutdown, waiting
+            self.shutdown.force_close();
+            let deadline = tokio::time::Instant::now() + FORCE_CLOSE_GRACE;
+            while self.stats.connections_active() > 0 && tokio::time::Instant::now() < deadline {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        }
+        let report = ShutdownReport {
+            uptime: self.clock.now().saturating_duration_since(started_at),
+            connections_accepted: self.stats.connections_accepted(),
+            requests_served: self.stats.requests_served(),
+            connections_drained: active.saturating_sub(still_active),
+            requests_completed_during_drain: self.stats.requests_served() - served,
+            connections_force_closed: self.stats.connections_force_closed(),
+        };
+        self.thread_pool.stop();
+        println!("{}", report);
+        for plugin in &self.plugins {
+            plugin.on_shutdown(&report);
+        }
+        Ok(report)
+    }
+
+    /// Runs the workers until the server is shut down
+    /// 
+    /// The report only has the uptime, since the supervisor serves no requests itself.
+    async fn supervise(&mut self, supervisor: Supervisor, started_at: std::time::Instant) -> Result<ShutdownReport, Box<dyn Error>> {
+        let signal_handler = self.start_signal_handler();
+        let shutdown = self.shutdown.clone();
+        let supervised = supervisor.run(&shutdown);
+        tokio::pin!(supervised);
+        let supervised = loop {
+            tokio::select! {
+                supervised = &mut supervised => break supervised,
+                msg = self.receive() => match msg {
+                    Some(Task::Shutdown) => self.shutdown.shutdown_by("task channel"),
+                    None => {},
+                    _ => println!("Received unknown message"),
+                },
+            }
+        };
+        if let Some(signal_handler) = signal_handler {
+            signal_handler.abort();
+        }
+        let supervised = supervised?;
+        self.thread_pool.stop();
+        println!("Supervisor ran for {:.1?}: {} workers started, {} restarted", self.clock.now().saturating_duration_since(started_at), supervised.started, supervised.restarted);
+        Ok(ShutdownReport {
+            uptime: self.clock.now().saturating_duration_since(started_at),
+            connections_accepted: 0,
+            requests_served: 0,
+            connections_drained: 0,
+            requests_completed_during_drain: 0,
+            connections_force_closed: 0,
+        })
+    }
+
+    /// Binds the address, sharing it with the other workers in a worker
+    async fn bind(&self, addr: &str) -> Result<TcpListener, std::io::Error> {
+        match self.workers.is_some() && workers::worker_index().is_some() {
+            true => workers::bind_shared(addr).await,
+            false => TcpListener::bind(addr).await,
+        }
+    }
+
+    async fn start_http(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
+        let listener = self.bind(addr).await?;
+        self.drop_privileges()?;
+        println!("Server started on {}...", addr);
+        self.accept_connections(listener, None).await
+    }
+
+    async fn start_https(&mut self, addr: &str, private_key_file: PathBuf, ssl_certificate_file: PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut acceptor_builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        acceptor_builder.set_private_key_file(private_key_file, SslFiletype::PEM)?;
+        acceptor_builder.set_certificate_chain_file(ssl_certificate_file)?;
+        let acceptor = acceptor_builder.build();
+        let listener = self.bind(addr).await?;
+        // The key and certificate are usually only readable by root, so they are loaded first
+        self.drop_privileges()?;
+        println!("Server started on {}...", addr);
+        self.accept_connections(listener, Some(acceptor)).await
+    }
+
+    /// Accepts connections until the server is shut down
+    /// 
+    /// With an acceptor the connections are HTTPS. Their TLS handshake runs on the thread pool
+    /// with the rest of the connection, so a slow client does not hold up the accept loop.
+    async fn accept_connections(&mut self, listener: TcpListener, acceptor: Option<SslAcceptor>) -> Result<(), Box<dyn Error>> {
+        let shutdown = self.shutdown.clone();
+        let limit = self.max_connections.map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+        loop {
+            tokio::select! {
+                conn = listener.accept() => match conn {
+                    Ok((stream, _)) => {
+                        let conn = match &acceptor {
+                            None => ConnectionInfo::new(stream),
+                            Some(acceptor) => match Ssl::new(acceptor.context()).and_then(|ssl| SslStream::new(ssl, stream)) {
+                                Ok(stream) => ConnectionInfo::new_ssl(stream),
+                                Err(e) => {
+                                    println!("Error setting up TLS for connection: {}", e);
+                                    continue;
+                                }
+                            },
+                        };
+                        let permit = match &limit {
+                            None => None,
+                            Some(limit) => match (Arc::clone(limit).try_acquire_owned(), self.saturated_retry_after) {
+                                (Ok(permit), _) => Some(permit),
+                                (Err(_), Some(retry_after)) => {
+                                    self.turn_away(conn, retry_after);
+                                    continue;
+                                },
+                                // Not accepting more until a connection closes is the backpressure
+                                (Err(_), None) => tokio::select! {
+                                    permit = Arc::clone(limit).acquire_owned() => permit.ok(),
+                                    _ = shutdown.wait() => {
+                                        println!("Shutting down server...");
+                                        return Ok(());
+                                    },
+                                },
+                            },
+                        };
+                        self.dispatch(conn.with_permit(permit));
+                    },
+                    Err(e) => {
+                        println!("Error accepting connection: {}", e);
+                    }
+                },
+                _ = shutdown.wait() => {
+                    println!("Shutting down server...");
+                    return Ok(());
+                },
+                msg = self.receive() => {
+                    match msg {
+                        Some(Task::Shutdown) => {
+                            self.shutdown.shutdown_by("task channel");
+                            println!("Shutting down server...");
+                            return Ok(());
+                        },
+                        None => {}
+                        _ => {
+                            println!("Received unknown message");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The id of a connection
+/// 
+/// Connections are numbered from 1 in the order they are accepted. Handlers can find
+/// the id of their connection through the job context:
+/// ```
+/// use simpleserve::{JobContext, ConnectionId};
+/// 
+/// let id = JobContext::with_current(|context| context.and_then(|c| c.get::<ConnectionId>().copied()));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub usize);
+
+/// Counters kept while the server is running
+/// 
+/// Shared between the accept loop and the connection jobs on the thread pool.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    connections_accepted: AtomicUsize,
+    connections_active: AtomicUsize,
+    requests_served: AtomicUsize,
+    tarpit_hits: AtomicUsize,
+    connections_rejected: AtomicUsize,
+    connections_force_closed: AtomicUsize,
+    connections_queued: AtomicUsize,
+    compressions_reduced: AtomicUsize,
+    compressions_skipped: AtomicUsize,
+}
+
+impl ServerStats {
+    pub fn connections_accepted(&self) -> usize {
+        self.connections_accepted.load(Ordering::SeqCst)
+    }
+
+    pub fn connections_active(&self) -> usize {
+        self.connections_active.load(Ordering::SeqCst)
+    }
+
+    pub fn requests_served(&self) -> usize {
+        self.requests_served.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests to tarpit routes
+    pub fn tarpit_hits(&self) -> usize {
+        self.tarpit_hits.load(Ordering::SeqCst)
+    }
+
+    /// The number of connections turned away because the server was full
+    /// 
+    /// See [`Webserver::reject_when_saturated`].
+    pub fn connections_rejected(&self) -> usize {
+        self.connections_rejected.load(Ordering::SeqCst)
+    }
+
+    /// The number of connections closed because they were still active after the shutdown timeout
+    pub fn connections_force_closed(&self) -> usize {
+        self.connections_force_closed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn connection_force_closed(&self) {
+        self.connections_force_closed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The number of accepted connections waiting for a worker of the thread pool
+    pub fn connections_queued(&self) -> usize {
+        self.connections_queued.load(Ordering::SeqCst)
+    }
+
+    /// The number of responses compressed at a lower level because the server was busy
+    /// 
+    /// See [`Compression::with_load_thresholds`](crate::compression::Compression::with_load_thresholds).
+    pub fn compressions_reduced(&self) -> usize {
+        self.compressions_reduced.load(Ordering::SeqCst)
+    }
+
+    /// The number of responses sent uncompressed because the server was busy
+    pub fn compressions_skipped(&self) -> usize {
+        self.compressions_skipped.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn compression_reduced(&self) {
+        self.compressions_reduced.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn compression_skipped(&self) {
+        self.compressions_skipped.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Counts a connection as active, until its [`ActiveConnection`] is dropped
+    pub(crate) fn connection_opened(&self) {
+        self.connections_active.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Marks a connection as active until it is dropped
+/// 
+/// Moves along with the connection if it is handed to another pool.
+pub(crate) struct ActiveConnection {
+    stats: Arc<ServerStats>,
+}
+
+impl ActiveConnection {
+    /// Tracks a connection already counted as active when it was accepted
+    pub(crate) fn new(stats: &Arc<ServerStats>) -> ActiveConnection {
+        ActiveConnection {
+            stats: Arc::clone(stats),
+        }
+    }
+
+    pub(crate) fn served(&self) {
+        self.stats.requests_served.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.connections_active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// How long a connection that is turned away has to send its request head
+const TURN_AWAY_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the number of active connections is checked while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long force-closed connections are given to let go of their workers
+const FORCE_CLOSE_GRACE: Duration = Duration::from_millis(100);
+
+/// Whether the server is ready, from the warm-up tasks and the self-checks
+/// 
+/// The two are tracked apart, so the self-checks recovering does not skip a pending warm-up,
+/// and the warm-up completing does not hide failing checks.
+#[derive(Debug)]
+pub(crate) struct Readiness {
+    // Whether every warm-up task has completed, and whether the self-checks are failing
+    state: Mutex<(bool, bool)>,
+    ready: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub(crate) fn new() -> Readiness {
+        Readiness {
+            state: Mutex::new((true, false)),
+            ready: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// The flag requests are checked against
+    pub(crate) fn flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.ready)
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_warmed_up(&self, warmed_up: bool) {
+        self.update(|state| state.0 = warmed_up);
+    }
+
+    pub(crate) fn set_checks_failing(&self, failing: bool) {
+        self.update(|state| state.1 = failing);
+    }
+
+    fn update<F: FnOnce(&mut (bool, bool))>(&self, change: F) {
+        let mut state = self.state.lock().unwrap();
+        change(&mut state);
+        let (warmed_up, checks_failing) = *state;
+        self.ready.store(warmed_up && !checks_failing, Ordering::SeqCst);
+    }
+}
+
+/// Shuts a running server down, from anywhere
+/// 
+/// Created by [`Webserver::shutdown_handle`]. Cloning is cheap, every clone shuts down the
+/// same server. The server stops accepting connections, closes idle ones, and waits for the
+/// requests being handled, up to its [shutdown timeout](Webserver::with_shutdown_timeout).
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    signal: Arc<ShutdownSignal>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownSignal {
+    triggered: AtomicBool,
+    notify: Notify,
+    actor: Mutex<Option<String>>,
+    // Set once the shutdown timeout has passed, to close the connections still active
+    force_closing: AtomicBool,
+    force_close: Notify,
+}
+
+impl ShutdownHandle {
+    fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Starts shutting the server down
+    /// 
+    /// Does nothing if the server is already shutting down.
+    pub fn shutdown(&self) {
+        self.shutdown_by("shutdown handle");
+    }
+
+    /// Starts shutting the server down, on behalf of an actor
+    /// 
+    /// The actor is recorded in the [audit log](Webserver::with_audit_log). Does nothing if the
+    /// server is already shutting down.
+    pub fn shutdown_by(&self, actor: &str) {
+        self.signal.actor.lock().unwrap().get_or_insert_with(|| String::from(actor));
+        self.signal.triggered.store(true, Ordering::SeqCst);
+        self.signal.notify.notify_waiters();
+    }
+
+    /// Who started shutting the server down, if it is shutting down
+    pub fn actor(&self) -> Option<String> {
+        self.signal.actor.lock().unwrap().clone()
+    }
+
+    /// Whether the server is shutting down
+    pub fn is_shutting_down(&self) -> bool {
+        self.signal.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the server starts shutting down
+    pub async fn wait(&self) {
+        loop {
+            // Created before the check, so a shutdown in between still wakes it
+            let notified = self.signal.notify.notified();
+            if self.is_shutting_down() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Closes the connections still active, once the shutdown timeout has passed
+    pub(crate) fn force_close(&self) {
+        self.signal.force_closing.store(true, Ordering::SeqCst);
+        self.signal.force_close.notify_waiters();
+    }
+
+    /// Whether the connections still active are being closed
+    pub fn is_force_closing(&self) -> bool {
+        self.signal.force_closing.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the connections still active are closed
+    pub async fn force_closed(&self) {
+        loop {
+            let notified = self.signal.force_close.notified();
+            if self.is_force_closing() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// A summary returned by [`Webserver::start`] when the server shuts down
+/// 
+/// Connections that finish their request within the shutdown timeout are counted as drained,
+/// the ones closed by the server because they were still being handled after it as force-closed.
+#[derive(Debug, Clone)]
+pub struct ShutdownReport {
+    pub uptime: Duration,
+    pub connections_accepted: usize,
+    pub requests_served: usize,
+    pub connections_drained: usize,
+    pub requests_completed_during_drain: usize,
+    pub connections_force_closed: usize,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Server ran for {:.1?}: {} connections accepted, {} requests served, {} connections drained ({} requests completed during drain), {} connections force-closed",
+            self.uptime,
+            self.connections_accepted,
+            self.requests_served,
+            self.connections_drained,
+            self.requests_completed_during_drain,
+            self.connections_force_closed
+        )
+    }
+}
+
+/// Internal handler struct
+/// 
+/// Cannot be created outside of the library
+#[derive(Clone)]
+pub struct Handler {
+    route: String,
+    method: Option<Method>,
+    handler: Callback,
+    health_check: bool,
+    pool_hint: PoolHint,
+    body_limits: Option<BodyLimits>,
+    accepted_types: Vec<String>,
+}
+
+impl Handler {
+    fn new(route: &str, handler: Callback) -> Handler {
+        Handler {
+            route: String::from(route),
+            method: None,
+            handler,
+            health_check: false,
+            pool_hint: PoolHint::Io,
+            body_limits: None,
+            accepted_types: Vec::new(),
+        }
+    }
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+    /// The method the handler is for, or `None` if it handles every method
+    pub fn method(&self) -> Option<&Method> {
+        self.method.as_ref()
+    }
+    /// The handler function, or `None` if the handler is async
+    pub fn handler(&self) -> Option<&HandlerFunction> {
+        match &self.handler {
+            Callback::Sync(handler) => Some(handler),
+            Callback::Async(_) => None,
+        }
+    }
+    /// The async handler function, or `None` if the handler is not async
+    pub fn async_handler(&self) -> Option<&AsyncHandlerFunction> {
+        match &self.handler {
+            Callback::Sync(_) => None,
+            Callback::Async(handler) => Some(handler),
+        }
+    }
+    /// Calls the handler, awaiting it if it is async
+    pub async fn call(&self, request: &RequestInfo<'_>) -> Box<dyn Sendable> {
+        match &self.handler {
+            Callback::Sync(handler) => handler(request),
+            Callback::Async(handler) => handler(request).await,
+        }
+    }
+    /// Whether the route is served before the server is ready
+    pub fn is_health_check(&self) -> bool {
+        self.health_check
+    }
+    /// The pool the handler should run on
+    pub fn pool_hint(&self) -> PoolHint {
+        self.pool_hint
+    }
+    /// The body limits of the route, if it has its own
+    pub fn body_limits(&self) -> Option<&BodyLimits> {
+        self.body_limits.as_ref()
+    }
+    /// The types of request body the route accepts, empty if it accepts any
+    pub fn accepted_types(&self) -> &[String] {
+        &self.accepted_types
+    }
+}
+
+/// Which thread pool a handler should run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolHint {
+    /// Blocking IO, such as file reads and database calls
+    Io,
+    /// CPU-bound work, such as rendering and compression
+    Cpu,
 }
 
+/// The state of the server that a connection job works with
+/// 
+/// A snapshot is taken for every connection.
+#[derive(Clone)]
+pub struct ServerContext {
+    pub routes: Vec<Handler>,
+    pub blacklisted_paths: Vec<path::PathBuf>,
+    pub theme: Theme,
+    pub cache_policy: CachePolicy,
+    /// Whether the server is ready, read for every request of a kept-alive connection
+    pub ready: Arc<AtomicBool>,
+    pub stats: Arc<ServerStats>,
+    pub cpu_pool: Option<Arc<ThreadPool>>,
+    pub clock: Arc<dyn Clock>,
+    pub max_body_size: usize,
+    pub multipart: MultipartOptions,
+    pub body_limits: BodyLimits,
+    pub max_request_line: usize,
+    pub max_header_size: usize,
+    pub max_headers: usize,
+    pub keep_alive_timeout: Duration,
+    pub header_timeout: Duration,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub max_requests_per_connection: usize,
+    pub shutdown: ShutdownHandle,
+    pub geo_resolver: Option<Arc<dyn GeoResolver>>,
+    pub state: AppState,
+    /// The middleware, in the order their `before` hooks run
+    pub middleware: Vec<Arc<dyn Middleware>>,
+}
+
+/// Shared application state, one value per type
+/// 
+/// Cloning is cheap, the values are shared.
+pub type AppState = Extensions;
+
 /// A page to be rendered
 /// 
 /// # Examples
@@ -399,11 +2526,23 @@ impl Page {
             content,
         }
     }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
 }
 
 impl Sendable for Page {
     fn render(&self) -> String {
-        format!("HTTP/1.1 {} OK\r\nContent-Length: {}\r\n\r\n{}", self.status, self.content.len(), self.content)
+        format!("{}\r\nContent-Length: {}\r\n\r\n{}", StatusCode::from(self.status).status_line(), self.content.len(), self.content)
+    }
+
+    fn to_response(&self) -> Option<Response> {
+        Some(Response::new(self.status).text(&self.content))
     }
 }
 
@@ -448,11 +2587,18 @@ impl Sendable for Page {
 ///    Box::new(Page::new(404, String::from("Not found")))
 /// }
 /// ```
+#[derive(Clone)]
 pub struct Bytes {
     status: u16,
     content: Vec<u8>,
     file_location: path::PathBuf,
     file_type: String,
+    modified: Option<SystemTime>,
+    etag: Option<ETag>,
+    // The strong ETag of the content, kept by files preloaded into a mount's cache
+    content_etag: Option<ETag>,
+    range: Option<(usize, usize)>,
+    cache_control: Option<(CacheControl, SystemTime)>,
 }
 
 impl Bytes {
@@ -461,63 +2607,479 @@ impl Bytes {
         let mut file = File::open(path)?;
         let mut content = Vec::new();
         file.read_to_end(&mut content)?;
+        let modified = file.metadata().and_then(|metadata| metadata.modified()).ok();
         let file_type = match canonical_path.extension() {
             Some(v) => v.to_str().unwrap_or(""),
             None => "",
         };
+        let etag = modified.map(|modified| ETag::for_metadata(content.len(), modified));
         Ok(Bytes {
             status,
             content,
             file_type: String::from(file_type),
             file_location: canonical_path,
+            modified,
+            etag,
+            content_etag: None,
+            range: None,
+            cache_control: None,
         })
     }
 
+    /// Computes the strong ETag of the content ahead of time, for a file that is sent many times
+    pub(crate) fn with_content_etag(mut self) -> Bytes {
+        self.content_etag = Some(ETag::for_content(&self.content));
+        self
+    }
+
+    /// Sets how the ETag of the file is made
+    ///
+    /// Files get weak ETags from their size and modification time by default.
+    pub fn with_etag_mode(mut self, mode: ETagMode) -> Bytes {
+        self.etag = match mode {
+            ETagMode::Weak => self.modified.map(|modified| ETag::for_metadata(self.content.len(), modified)),
+            ETagMode::Strong => Some(self.content_etag.clone().unwrap_or_else(|| ETag::for_content(&self.content))),
+            ETagMode::Disabled => None,
+        };
+        self
+    }
+
+    /// Adds `Cache-Control` and `Expires` headers
+    ///
+    /// # Arguments
+    /// * `control` - How the file may be cached
+    /// * `now` - The time the response is sent, which `Expires` is counted from
+    pub fn with_cache_control(mut self, control: CacheControl, now: SystemTime) -> Bytes {
+        self.cache_control = Some((control, now));
+        self
+    }
+
+    /// Answers the conditional and `Range` headers of a request
+    ///
+    /// The caching headers of the server's [`CachePolicy`] are added first. A request whose
+    /// `If-Match` or `If-Unmodified-Since` fails gets a 412 Precondition Failed, see
+    /// [`RequestInfo::preconditions_met`].
+    /// A `GET` or `HEAD` request gets a 304 Not Modified without a body if its `If-None-Match`
+    /// matches the ETag of the file, or if it has no `If-None-Match` and the file has not changed
+    /// since its `If-Modified-Since`. Otherwise the `Range` header is answered like with
+    /// [`Bytes::with_range`]. Only changes responses with a status of 200.
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::{Bytes, RequestInfo, Sendable};
+    ///
+    /// fn logo(request: &RequestInfo) -> Box<dyn Sendable> {
+    ///     let bytes = Bytes::new(200, "logo.png").expect("Error reading file");
+    ///     Box::new(bytes.for_request(request))
+    /// }
+    /// ```
+    pub fn for_request(mut self, request: &RequestInfo) -> Bytes {
+        self = self.with_etag_mode(request.cache_policy.etag_mode());
+        if let Some(control) = request.cache_policy.lookup(request.route) {
+            self = self.with_cache_control(control, request.clock.system_time());
+        }
+        if self.status != 200 {
+            return self;
+        }
+        if !request.preconditions_met(self.etag.as_ref(), self.modified) {
+            self.status = 412;
+            return self;
+        }
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return self;
+        }
+        let not_modified = match request.header("if-none-match") {
+            Some(header) => self.etag.as_ref().is_some_and(|etag| etag::if_none_match(header, etag)),
+            None => {
+                let since = request.header("if-modified-since").and_then(utils::parse_http_date);
+                match (since, self.modified) {
+                    (Some(since), Some(modified)) => whole_seconds(modified) <= whole_seconds(since),
+                    _ => false,
+                }
+            }
+        };
+        if not_modified {
+            self.status = 304;
+            return self;
+        }
+        self.with_range(request.header("range"))
+    }
+
+    /// Answers the `Range` header of a request with part of the file
+    ///
+    /// A range within the file makes this a 206 Partial Content response with only those bytes,
+    /// and a range past its end a 416 Range Not Satisfiable. Anything else sends the whole file.
+    /// Only changes responses with a status of 200.
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::{Bytes, RequestInfo, Sendable};
+    ///
+    /// fn video(request: &RequestInfo) -> Box<dyn Sendable> {
+    ///     let bytes = Bytes::new(200, "video.mp4").expect("Error reading file");
+    ///     Box::new(bytes.with_range(request.header("range")))
+    /// }
+    /// ```
+    pub fn with_range(mut self, range: Option<&str>) -> Bytes {
+        let range = match range {
+            Some(range) if self.status == 200 => range,
+            _ => return self,
+        };
+        match parse_range(range, self.content.len()) {
+            ByteRange::Full => {},
+            ByteRange::Partial(first, last) => {
+                self.status = 206;
+                self.range = Some((first, last));
+            },
+            ByteRange::Unsatisfiable => self.status = 416,
+        }
+        self
+    }
+
     pub fn file_location(&self) -> &path::PathBuf {
         &self.file_location
     }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// When the file was last modified, if the platform records it
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// The ETag of the file, if it has one
+    pub fn etag(&self) -> Option<&ETag> {
+        self.etag.as_ref()
+    }
+
+    /// The part of the file that is sent, all of it unless a range was asked for
+    pub fn body(&self) -> &[u8] {
+        match (self.status, self.range) {
+            (206, Some((first, last))) => &self.content[first..=last],
+            (304 | 412 | 416, _) => &[],
+            _ => &self.content,
+        }
+    }
+
+    /// The headers of the response, apart from its length
+    fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &self.etag {
+            headers.push(("ETag", etag.to_string()));
+        }
+        if let Some(modified) = self.modified {
+            headers.push(("Last-Modified", utils::format_http_date(modified)));
+        }
+        // A 304 has to repeat these, as they update the cached response
+        if let Some((control, now)) = &self.cache_control {
+            headers.push(("Cache-Control", control.header_value()));
+            headers.push(("Expires", control.expires(*now)));
+        }
+        if self.status == 304 {
+            return headers;
+        }
+        headers.push(("Content-Type", String::from(utils::get_mime_type(&self.file_type))));
+        headers.push(("Accept-Ranges", String::from("bytes")));
+        if let Some(range) = self.content_range() {
+            headers.push(("Content-Range", range));
+        }
+        headers
+    }
+
+    /// The `Content-Range` header of a partial response
+    pub fn content_range(&self) -> Option<String> {
+        match (self.status, self.range) {
+            (206, Some((first, last))) => Some(format!("bytes {}-{}/{}", first, last, self.content.len())),
+            (416, _) => Some(format!("bytes */{}", self.content.len())),
+            _ => None,
+        }
+    }
+
+    /// The MIME type of the file, based on its extension
+    pub fn mime_type(&self) -> &'static str {
+        utils::get_mime_type(&self.file_type)
+    }
 }
 
 #[async_trait]
 impl Sendable for Bytes {
     fn render(&self) -> String {
-        format!(
-            "HTTP/1.1 {} OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
-            self.status,
-            utils::get_mime_type(&self.file_type),
-            self.content.len()
-        )
+        let mut head = format!("{}\r\n", StatusCode::from(self.status).status_line());
+        for (name, value) in self.headers() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // A 304 has no body, so it has no length either
+        if self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body().len()));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    fn to_response(&self) -> Option<Response> {
+        let response = self.headers()
+            .into_iter()
+            .fold(Response::new(self.status), |response, (name, value)| response.header(name, &value));
+        Some(response.bytes(self.body().to_vec()))
     }
 
     async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
-        match conn.connection_type() {
-            ConnectionType::Http => {
-                conn.stream().write_all(self.render().as_bytes()).await?;
-                conn.stream().write_all(&self.content).await?;
-                return Ok(());
-            },
-            ConnectionType::Https => {
-                conn.ssl_stream().write_all(self.render().as_bytes()).await?;
-                conn.ssl_stream().write_all(&self.content).await?;
-                return Ok(());
-            }
-        }
+        conn.write_all(self.render().as_bytes()).await?;
+        conn.write_all(self.body()).await
     }
 }
 
+/// A time in whole seconds since 1970, the precision of HTTP dates
+fn whole_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
 pub struct RequestInfo<'a> {
     pub conn: &'a ConnectionInfo,
     pub route: &'a str,
+    pub params: &'a HashMap<String, String>,
+    pub query: &'a HashMap<String, String>,
     pub blacklisted_paths: &'a Vec<path::PathBuf>,
+    pub theme: &'a Theme,
+    pub cache_policy: &'a CachePolicy,
+    pub clock: &'a dyn Clock,
+    pub headers: &'a Headers,
+    pub method: &'a Method,
+    pub body: &'a [u8],
+    pub geo: Option<&'a GeoInfo>,
+    pub app_state: &'a AppState,
+    pub extensions: &'a Extensions,
+    pub stats: &'a ServerStats,
+    // For requests the server makes on behalf of this one
+    pub(crate) context: &'a ServerContext,
 }
 
 impl<'a> RequestInfo<'a> {
-    pub fn new(conn: &'a ConnectionInfo, route: &'a str, blacklisted_paths: &'a Vec<path::PathBuf>) -> RequestInfo<'a> {
+    pub fn new(conn: &'a ConnectionInfo, request: &'a Request, context: &'a ServerContext) -> RequestInfo<'a> {
         RequestInfo {
             conn,
-            route,
-            blacklisted_paths,
+            route: &request.route,
+            params: &request.params,
+            query: &request.query,
+            blacklisted_paths: &context.blacklisted_paths,
+            theme: &context.theme,
+            cache_policy: &context.cache_policy,
+            clock: context.clock.as_ref(),
+            headers: &request.headers,
+            method: &request.method,
+            body: &request.body,
+            geo: request.geo.as_ref(),
+            app_state: &context.state,
+            extensions: &request.extensions,
+            stats: &context.stats,
+            context,
+        }
+    }
+
+    /// A value of a type that a middleware attached to the request
+    pub fn extension<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.extensions.get()
+    }
+
+    /// The shared state of a type, if it was added with [`Webserver::with_state`]
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.app_state.get()
+    }
+
+    /// A Server-Sent Events channel, if it was created with [`Webserver::sse_channel`]
+    pub fn sse_channel(&self, name: &str) -> Option<sse::Channel> {
+        self.app_state.get::<sse::Channels>()?.get(name)
+    }
+
+    /// The URL of an asset under its hashed name, if it was mounted with [`Webserver::serve_assets`]
+    /// 
+    /// # Arguments
+    /// * `path` - The path of the file in the directory of assets, like `js/app.js`
+    pub fn asset(&self, path: &str) -> Option<String> {
+        self.app_state.get::<AssetManifest>()?.lookup(path)
+    }
+
+    /// The long polling queues, if they were created with [`Webserver::long_poll`]
+    pub fn long_poll(&self) -> Option<LongPoll> {
+        self.app_state.get::<LongPoll>().map(|long_poll| LongPoll::clone(&long_poll))
+    }
+
+    /// The event bus, if it was created with [`Webserver::events`]
+    pub fn events(&self) -> Option<EventBus> {
+        self.app_state.get::<EventBus>().map(|events| EventBus::clone(&events))
+    }
+
+    /// The body of the request
+    /// 
+    /// Empty if the request did not have a body.
+    pub fn body(&self) -> &[u8] {
+        self.body
+    }
+
+    /// Where the client is, if the server has a [`GeoResolver`] that knows
+    pub fn geo(&self) -> Option<&GeoInfo> {
+        self.geo
+    }
+
+    /// The body of the request as text
+    /// 
+    /// Returns an error if the body is not valid UTF-8.
+    pub fn body_string(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(self.body)
+    }
+
+    /// The method of the request
+    pub fn method(&self) -> &Method {
+        self.method
+    }
+
+    /// The headers of the request
+    /// 
+    /// # Examples
+    /// ```
+    /// use simpleserve::{
+    ///     Page,
+    ///     Sendable,
+    ///     RequestInfo
+    /// };
+    /// 
+    /// fn whoami(request: &RequestInfo) -> Box<dyn Sendable> {
+    ///     let user_agent = request.headers().get("User-Agent").unwrap_or("unknown");
+    ///     Box::new(Page::new(200, format!("You are using {}", user_agent)))
+    /// }
+    /// ```
+    pub fn headers(&self) -> &Headers {
+        self.headers
+    }
+
+    /// The value a route parameter captured
+    /// 
+    /// For the route `/users/:id`, `param("id")` is the part of the path after `/users/`.
+    /// A `*name` wildcard is captured the same way.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// The parameters of the query string
+    pub fn query(&self) -> &HashMap<String, String> {
+        self.query
+    }
+
+    /// The value of a query string parameter
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        self.query.get(key).map(String::as_str)
+    }
+
+    /// The fields of an `application/x-www-form-urlencoded` body, such as a submitted HTML form
+    ///
+    /// Returns `None` if the request has another content type or the body is not UTF-8.
+    /// Fields are decoded like the query string.
+    pub fn form(&self) -> Option<HashMap<String, String>> {
+        if !self.headers.has_mime_type(&["application/x-www-form-urlencoded"]) {
+            return None;
+        }
+        self.body_string().ok().map(parse_query)
+    }
+
+    /// The parts of a `multipart/form-data` body, such as a form with file uploads
+    ///
+    /// Returns `None` if the request has another content type or no boundary. The server parses
+    /// multipart bodies while reading them, so [`RequestInfo::body`] is empty and the parts can be
+    /// taken once, later calls return `None`. See the [`multipart`](crate::multipart) module.
+    pub fn multipart(&self) -> Option<Multipart<'_>> {
+        if let Some(streamed) = self.extensions.get::<multipart::Streamed>() {
+            return streamed.take();
+        }
+        let boundary = multipart::boundary(self.header("content-type")?)?;
+        Some(Multipart::new(self.body, boundary))
+    }
+
+    /// The value of a header, looked up without regard to case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// A page from the theme, in the language the client prefers
+    /// 
+    /// See [`Theme::localized_page`].
+    pub fn error_page(&self, status: u16, title: &str, message: &str) -> Response {
+        self.theme.localized_page(self.header("accept-language"), status, title, message)
+    }
+
+    /// Whether the `If-Match` and `If-Unmodified-Since` headers allow the request
+    ///
+    /// Handlers that change a resource, like `PUT` and `DELETE` routes, check these so a client
+    /// only overwrites the version it has seen. `If-Match` compares ETags strongly.
+    /// `If-Unmodified-Since` is only used without an `If-Match`, and only for a resource with
+    /// a modification time. Requests without either header always pass.
+    ///
+    /// # Arguments
+    /// * `etag` - The current ETag of the resource, `None` if it does not exist or has none
+    /// * `modified` - When the resource was last modified, if that is known
+    pub fn preconditions_met(&self, etag: Option<&ETag>, modified: Option<SystemTime>) -> bool {
+        if let Some(header) = self.header("if-match") {
+            return etag::if_match(header, etag);
+        }
+        let since = self.header("if-unmodified-since").and_then(utils::parse_http_date);
+        match (since, modified) {
+            (Some(since), Some(modified)) => whole_seconds(modified) <= whole_seconds(since),
+            _ => true,
+        }
+    }
+
+    /// A 412 Precondition Failed page if [`RequestInfo::preconditions_met`] fails
+    ///
+    /// # Examples
+    /// ```
+    /// use std::sync::Mutex;
+    /// use simpleserve::{Page, RequestInfo, Sendable, etag::ETag};
+    ///
+    /// static DOCUMENT: Mutex<String> = Mutex::new(String::new());
+    ///
+    /// fn save(request: &RequestInfo) -> Box<dyn Sendable> {
+    ///     let mut document = DOCUMENT.lock().unwrap();
+    ///     let etag = ETag::for_content(document.as_bytes());
+    ///     if let Some(failed) = request.check_preconditions(Some(&etag), None) {
+    ///         return failed;
+    ///     }
+    ///     *document = String::from(request.body_string().unwrap_or_default());
+    ///     Box::new(Page::new(200, String::from("Saved")))
+    /// }
+    /// ```
+    pub fn check_preconditions(&self, etag: Option<&ETag>, modified: Option<SystemTime>) -> Option<Box<dyn Sendable>> {
+        if self.preconditions_met(etag, modified) {
+            return None;
         }
+        Some(Box::new(self.error_page(412, "Precondition Failed", "The resource was changed since it was last fetched.")))
+    }
+
+    /// The cookies sent with the request
+    pub fn cookies(&self) -> Vec<Cookie> {
+        self.headers.get_all("cookie").flat_map(cookie::parse_cookies).collect()
+    }
+
+    /// The value of a cookie sent with the request
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies()
+            .into_iter()
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| String::from(cookie.value()))
+    }
+
+    /// The session of the request, if the [`Sessions`](crate::session::Sessions) middleware is used
+    pub fn session(&self) -> Option<Arc<Session>> {
+        self.extension()
+    }
+
+    /// The classified `User-Agent` header, if the request has one
+    pub fn user_agent(&self) -> Option<UserAgent> {
+        self.header("user-agent").map(UserAgent::parse)
     }
 }
 
@@ -535,9 +3097,67 @@ pub enum ConnectionType {
 
 #[derive(Debug)]
 pub struct ConnectionInfo {
+    // Holds a slot of the connection limit until the connection is dropped. Fields are dropped
+    // in order, so the slot is free before the socket closes and the client can connect again.
+    permit: Option<OwnedSemaphorePermit>,
     connection_type: ConnectionType,
     ssl_stream: Option<SslStream<TcpStream>>,
     stream: Option<TcpStream>,
+    buffer: Vec<u8>,
+    write_timeout: Option<Duration>,
+    // Set while answering a HEAD request, whose response has no body
+    omit_body: Option<BodyFilter>,
+    // When the connection was accepted, which the header timeout counts from
+    accepted: tokio::time::Instant,
+    // Fails reads and writes once the server force-closes its connections
+    shutdown: Option<ShutdownHandle>,
+}
+
+/// Lets the head of a response through and drops its body
+#[derive(Debug, Default)]
+struct BodyFilter {
+    // The last bytes written, to find a blank line split across writes
+    window: Vec<u8>,
+    head_ended: bool,
+}
+
+impl BodyFilter {
+    /// The part of the bytes that still belongs to the head
+    fn head<'a>(&mut self, bytes: &'a [u8]) -> &'a [u8] {
+        if self.head_ended {
+            return &[];
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.window.push(*byte);
+            if self.window.len() > 4 {
+                self.window.remove(0);
+            }
+            if self.window == b"\r\n\r\n" {
+                self.head_ended = true;
+                return &bytes[..=i];
+            }
+        }
+        bytes
+    }
+}
+
+/// Finds the blank line ending a request head, returning the position after the last header line
+/// 
+/// Lines have to end in `\r\n`. A bare `\r` or `\n` is an error, as soon as it is read.
+fn find_head_end(buffer: &[u8]) -> Result<Option<usize>, MalformedRequestError> {
+    for (i, byte) in buffer.iter().enumerate() {
+        match byte {
+            b'\n' if i == 0 || buffer[i - 1] != b'\r' => {
+                return Err(MalformedRequestError::new("Bare line feed in request head"));
+            },
+            b'\r' if buffer.get(i + 1).is_some_and(|next| *next != b'\n') => {
+                return Err(MalformedRequestError::new("Bare carriage return in request head"));
+            },
+            b'\n' if buffer[i + 1..].starts_with(b"\r\n") => return Ok(Some(i + 1)),
+            _ => {},
+        }
+    }
+    Ok(None)
 }
 
 impl ConnectionInfo {
@@ -546,6 +3166,12 @@ impl ConnectionInfo {
             connection_type: ConnectionType::Http,
             ssl_stream: None,
             stream: Some(stream),
+            buffer: Vec::new(),
+            write_timeout: None,
+            permit: None,
+            omit_body: None,
+            accepted: tokio::time::Instant::now(),
+            shutdown: None,
         }
     }
 
@@ -554,9 +3180,42 @@ impl ConnectionInfo {
             connection_type: ConnectionType::Https,
             ssl_stream: Some(stream),
             stream: None,
+            buffer: Vec::new(),
+            write_timeout: None,
+            permit: None,
+            omit_body: None,
+            accepted: tokio::time::Instant::now(),
+            shutdown: None,
+        }
+    }
+
+    /// When the connection was accepted
+    pub(crate) fn accepted(&self) -> tokio::time::Instant {
+        self.accepted
+    }
+
+    /// Runs the TLS handshake of an HTTPS connection, HTTP connections have none
+    pub(crate) async fn handshake(&mut self) -> Result<(), std::io::Error> {
+        match &mut self.ssl_stream {
+            Some(ssl_stream) => Pin::new(ssl_stream).accept().await.map_err(|e| {
+                e.into_io_error().unwrap_or_else(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+            }),
+            None => Ok(()),
         }
     }
 
+    /// Makes reads and writes fail with [`std::io::ErrorKind::ConnectionAborted`] once the server
+    /// closes the connections still active after its shutdown timeout
+    pub(crate) fn set_shutdown(&mut self, shutdown: ShutdownHandle) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// Keeps a slot of the connection limit for as long as the connection lives
+    pub(crate) fn with_permit(mut self, permit: Option<OwnedSemaphorePermit>) -> ConnectionInfo {
+        self.permit = permit;
+        self
+    }
+
     pub fn stream(&mut self) -> &mut TcpStream {
         match &mut self.stream {
             Some(v) => v,
@@ -574,4 +3233,183 @@ impl ConnectionInfo {
     pub fn connection_type(&self) -> &ConnectionType {
         &self.connection_type
     }
-}
\ No newline at end of file
+
+    /// The address of the client
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match (&self.stream, &self.ssl_stream) {
+            (Some(stream), _) => stream.peer_addr().ok(),
+            (_, Some(ssl_stream)) => ssl_stream.get_ref().peer_addr().ok(),
+            _ => None,
+        }
+    }
+
+    /// Reads from the connection, returning the number of bytes read
+    /// 
+    /// Bytes that were already read past the head of the request are returned first.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if !self.buffer.is_empty() {
+            let n = buf.len().min(self.buffer.len());
+            buf[..n].copy_from_slice(&self.buffer[..n]);
+            self.buffer.drain(..n);
+            return Ok(n);
+        }
+        let shutdown = self.shutdown.clone();
+        let read = async {
+            match self.connection_type {
+                ConnectionType::Http => self.stream().read(buf).await,
+                ConnectionType::Https => self.ssl_stream().read(buf).await,
+            }
+        };
+        until_force_closed(shutdown, read).await
+    }
+
+    /// Reads the head of a request (the request line and headers)
+    /// 
+    /// Returns `None` if the connection was closed before anything was sent.
+    /// Anything read past the blank line ending the head is kept for the next read.
+    pub async fn read_head(&mut self) -> Result<Option<String>, std::io::Error> {
+        self.read_head_limited(usize::MAX, usize::MAX).await
+    }
+
+    /// Reads the head of a request, failing once it is larger than the limits
+    /// 
+    /// The error has the kind [`std::io::ErrorKind::InvalidData`] and wraps a
+    /// [`RequestTooLargeError`], so a client cannot make the server buffer an endless head.
+    /// A line that does not end in `\r\n` fails the same way with a [`MalformedRequestError`].
+    /// 
+    /// # Arguments
+    /// * `max_request_line` - The longest request line, without its line break
+    /// * `max_head_size` - The largest head, request line and headers together
+    pub async fn read_head_limited(&mut self, max_request_line: usize, max_head_size: usize) -> Result<Option<String>, std::io::Error> {
+        let mut chunk = [0; 4096];
+        loop {
+            let line_end = self.buffer.iter().position(|byte| *byte == b'\n');
+            let line_length = line_end.unwrap_or(self.buffer.len());
+            if line_length > max_request_line + 1 {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, RequestTooLargeError::RequestLine));
+            }
+            let head_end = find_head_end(&self.buffer).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            match head_end {
+                Some(end) if end > max_head_size => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, RequestTooLargeError::Headers));
+                },
+                Some(end) => {
+                    let head: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                    return Ok(Some(String::from_utf8_lossy(&head[..end]).into_owned()));
+                },
+                None if self.buffer.len() > max_head_size => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, RequestTooLargeError::Headers));
+                },
+                None => {},
+            }
+            let shutdown = self.shutdown.clone();
+            let read = async {
+                match self.connection_type {
+                    ConnectionType::Http => self.stream().read(&mut chunk).await,
+                    ConnectionType::Https => self.ssl_stream().read(&mut chunk).await,
+                }
+            };
+            let n = until_force_closed(shutdown, read).await?;
+            if n == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed during request head"));
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Reads exactly `length` bytes of a request body
+    /// 
+    /// Anything left over from reading the head is used first.
+    pub async fn read_body(&mut self, length: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut body = vec![0; length];
+        let mut filled = 0;
+        while filled < length {
+            let n = self.read(&mut body[filled..]).await?;
+            if n == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed during request body"));
+            }
+            filled += n;
+        }
+        Ok(body)
+    }
+
+    /// Whether part of the next request was read already
+    pub fn has_buffered(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Sets how long a write or flush may take before it fails with [`std::io::ErrorKind::TimedOut`]
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+    }
+
+    /// Drops the body of the next responses written, keeping their head
+    /// 
+    /// Used to answer HEAD requests with the same headers as GET, including `Content-Length`.
+    pub(crate) fn set_omit_body(&mut self, omit_body: bool) {
+        self.omit_body = omit_body.then(BodyFilter::default);
+    }
+
+    /// Writes bytes to the connection, whether it is plain or TLS
+    pub async fn write_all(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        let bytes = match &mut self.omit_body {
+            Some(filter) => filter.head(bytes),
+            None => bytes,
+        };
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let write_timeout = self.write_timeout;
+        let shutdown = self.shutdown.clone();
+        let write = async {
+            match self.connection_type {
+                ConnectionType::Http => self.stream().write_all(bytes).await,
+                ConnectionType::Https => self.ssl_stream().write_all(bytes).await,
+            }
+        };
+        until_force_closed(shutdown, with_write_timeout(write_timeout, write)).await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), std::io::Error> {
+        let write_timeout = self.write_timeout;
+        let shutdown = self.shutdown.clone();
+        let flush = async {
+            match self.connection_type {
+                ConnectionType::Http => self.stream().flush().await,
+                ConnectionType::Https => self.ssl_stream().flush().await,
+            }
+        };
+        until_force_closed(shutdown, with_write_timeout(write_timeout, flush)).await
+    }
+}
+
+/// Fails an operation with [`std::io::ErrorKind::ConnectionAborted`] once the server force-closes its connections
+async fn until_force_closed<T, F>(shutdown: Option<ShutdownHandle>, operation: F) -> Result<T, std::io::Error>
+where
+    F: Future<Output = Result<T, std::io::Error>>,
+{
+    let shutdown = match shutdown {
+        Some(shutdown) => shutdown,
+        None => return operation.await,
+    };
+    tokio::select! {
+        result = operation => result,
+        _ = shutdown.force_closed() => Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Connection closed by the server shutting down")),
+    }
+}
+
+/// Fails a write with [`std::io::ErrorKind::TimedOut`] if it takes longer than the timeout
+async fn with_write_timeout<F>(write_timeout: Option<Duration>, write: F) -> Result<(), std::io::Error>
+where
+    F: Future<Output = Result<(), std::io::Error>>,
+{
+    match write_timeout {
+        Some(write_timeout) => tokio::time::timeout(write_timeout, write).await.unwrap_or_else(|_| {
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Write timed out"))
+        }),
+        None => write.await,
+    }
+}
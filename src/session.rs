@@ -0,0 +1,347 @@
+//! Sessions
+//!
+//! The [`Sessions`] middleware keeps a [`Session`] for every client, identified by a cookie,
+//! and stores its values in a [`SessionStore`]. Handlers get the session of a request with
+//! [`RequestInfo::session`](crate::RequestInfo::session). A session is only stored, and its cookie
+//! only set, once a value has been set on it. Handlers logging a user in should call
+//! [`Session::regenerate`], so an id planted on the client before the login is worthless after it.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     Page,
+//!     Sendable,
+//!     RequestInfo,
+//!     session::{MemoryStore, Sessions}
+//! };
+//!
+//! fn login(request: &RequestInfo) -> Box<dyn Sendable> {
+//!     let session = request.session().unwrap();
+//!     session.regenerate();
+//!     session.set("user", "ferris");
+//!     Box::new(Page::new(200, String::from("Logged in")))
+//! }
+//!
+//! fn profile(request: &RequestInfo) -> Box<dyn Sendable> {
+//!     match request.session().and_then(|session| session.get("user")) {
+//!         Some(user) => Box::new(Page::new(200, format!("Hello {}", user))),
+//!         None => Box::new(Page::new(401, String::from("Not logged in"))),
+//!     }
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_middleware(Sessions::new(MemoryStore::new()).with_expiry(Duration::from_secs(3600)));
+//! server.add_route("/login", login);
+//! server.add_route("/profile", profile);
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    cookie::{self, Cookie, SameSite},
+    middleware::Middleware,
+    request::Request,
+    server::{RequestInfo, Sendable}
+};
+
+/// The values of a session
+pub type SessionData = HashMap<String, String>;
+
+/// Where sessions are kept between requests
+pub trait SessionStore: Send + Sync {
+    /// The values of a session, or `None` if it does not exist or has expired
+    fn load(&self, id: &str) -> Option<SessionData>;
+
+    /// Stores the values of a session, to expire after `expiry`
+    fn save(&self, id: &str, data: SessionData, expiry: Duration);
+
+    fn remove(&self, id: &str);
+}
+
+/// A store that keeps sessions in memory
+///
+/// Sessions are lost when the server stops. Expired sessions are removed when they are next looked up,
+/// and whenever a session is saved.
+#[derive(Debug)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            sessions: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Sets the clock sessions expire by
+    ///
+    /// Useful in tests, together with [`MockClock`](crate::clock::MockClock).
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use simpleserve::{
+    ///     clock::MockClock,
+    ///     session::{MemoryStore, SessionData, SessionStore}
+    /// };
+    ///
+    /// let clock = MockClock::new();
+    /// let store = MemoryStore::new().with_clock(clock.clone());
+    /// store.save("abc", SessionData::new(), Duration::from_secs(60));
+    /// assert!(store.load("abc").is_some());
+    /// clock.advance(Duration::from_secs(60));
+    /// assert!(store.load("abc").is_none());
+    /// ```
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> MemoryStore {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The number of sessions, including expired ones not removed yet
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some((data, expires)) if *expires > self.clock.now() => Some(data.clone()),
+            Some(_) => {
+                sessions.remove(id);
+                None
+            },
+            None => None,
+        }
+    }
+
+    fn save(&self, id: &str, data: SessionData, expiry: Duration) {
+        let now = self.clock.now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.insert(String::from(id), (data, now + expiry));
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+/// The session of a request
+///
+/// Changes are saved to the store after the handler has run.
+#[derive(Debug)]
+pub struct Session {
+    state: Mutex<SessionState>,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    id: String,
+    // The id the session was stored under before it was regenerated
+    replaced: Option<String>,
+    data: SessionData,
+    new: bool,
+    modified: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    fn new(id: String, data: Option<SessionData>) -> Session {
+        Session {
+            state: Mutex::new(SessionState {
+                id,
+                replaced: None,
+                new: data.is_none(),
+                data: data.unwrap_or_default(),
+                modified: false,
+                destroyed: false,
+            }),
+        }
+    }
+
+    pub fn id(&self) -> String {
+        self.state.lock().unwrap().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.lock().unwrap().data.get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.data.insert(String::from(key), String::from(value));
+        state.modified = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        state.modified = true;
+        state.data.remove(key)
+    }
+
+    /// Removes the session from the store and the client, such as when logging out
+    pub fn destroy(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.data.clear();
+        state.destroyed = true;
+    }
+
+    /// Moves the session to a new id, keeping its values
+    ///
+    /// The old id stops working, so call this when the privileges of a session change, such as
+    /// when logging in, to keep a client from being handed a session id it did not get from us.
+    pub fn regenerate(&self) {
+        let mut state = self.state.lock().unwrap();
+        let old = std::mem::replace(&mut state.id, new_session_id());
+        if !state.new && state.replaced.is_none() {
+            state.replaced = Some(old);
+        }
+        state.modified = true;
+    }
+
+    /// Whether the session was created for this request
+    pub fn is_new(&self) -> bool {
+        self.state.lock().unwrap().new
+    }
+}
+
+/// Middleware that attaches a [`Session`] to every request
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    expiry: Duration,
+    secure: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Sessions {
+    /// Creates the middleware with a cookie named `session` and sessions that expire after a day
+    pub fn new<S: SessionStore + 'static>(store: S) -> Sessions {
+        Sessions {
+            store: Arc::new(store),
+            cookie_name: String::from("session"),
+            expiry: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_cookie_name(mut self, cookie_name: &str) -> Sessions {
+        self.cookie_name = String::from(cookie_name);
+        self
+    }
+
+    /// Sets how long a session is kept after it was last changed
+    pub fn with_expiry(mut self, expiry: Duration) -> Sessions {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Only sends the session cookie over HTTPS
+    pub fn secure(mut self) -> Sessions {
+        self.secure = true;
+        self
+    }
+
+    /// Sets the clock the `Expires` date of the session cookie is counted from
+    ///
+    /// Useful in tests, together with [`MockClock`](crate::clock::MockClock). The store keeps
+    /// its own clock, see [`MemoryStore::with_clock`].
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Sessions {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn store(&self) -> &Arc<dyn SessionStore> {
+        &self.store
+    }
+
+    fn cookie(&self, id: &str) -> Cookie {
+        let cookie = Cookie::new(&self.cookie_name, id)
+            .with_path("/")
+            .http_only()
+            .with_same_site(SameSite::Lax);
+        if self.secure {
+            cookie.secure()
+        } else {
+            cookie
+        }
+    }
+}
+
+impl Middleware for Sessions {
+    fn before(&self, request: &mut Request) -> Option<Box<dyn Sendable>> {
+        let existing = request.headers
+            .get_all("cookie")
+            .flat_map(cookie::parse_cookies)
+            .filter(|cookie| cookie.name() == self.cookie_name)
+            .find_map(|cookie| {
+                let data = self.store.load(cookie.value())?;
+                Some(Session::new(String::from(cookie.value()), Some(data)))
+            });
+        let session = existing.unwrap_or_else(|| Session::new(new_session_id(), None));
+        request.extensions.insert(session);
+        None
+    }
+
+    fn after(&self, request: &RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+        let session = match request.extension::<Session>() {
+            Some(session) => session,
+            None => return response,
+        };
+        let state = session.state.lock().unwrap();
+        if let Some(replaced) = &state.replaced {
+            self.store.remove(replaced);
+        }
+        let cookie = if state.destroyed {
+            self.store.remove(&state.id);
+            if state.new {
+                return response;
+            }
+            Cookie::removal(&self.cookie_name).with_path("/")
+        } else if state.modified {
+            self.store.save(&state.id, state.data.clone(), self.expiry);
+            self.cookie(&state.id)
+                .with_expires(self.clock.system_time() + self.expiry)
+                .with_max_age(self.expiry)
+        } else {
+            return response;
+        };
+        match response.to_response() {
+            Some(response) => Box::new(response.cookie(&cookie)),
+            None => {
+                println!("Could not set the session cookie on a streamed response");
+                response
+            }
+        }
+    }
+}
+
+/// A random session id of 128 bits, hex encoded
+fn new_session_id() -> String {
+    let mut bytes = [0; 16];
+    openssl::rand::rand_bytes(&mut bytes).expect("Could not generate a session id");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
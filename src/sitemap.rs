@@ -0,0 +1,153 @@
+//! Generation of `sitemap.xml`
+//!
+//! A [`Sitemap`] added with [`Webserver::with_sitemap`](crate::Webserver::with_sitemap) is served
+//! at `/sitemap.xml` and lists every route a crawler can fetch: routes that handle GET, are not
+//! patterns, health checks or the 404 handler, and are not excluded. It is regenerated from the
+//! registered routes every time the server starts.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     sitemap::{ChangeFrequency, Sitemap}
+//! };
+//!
+//! let sitemap = Sitemap::new("https://example.com")
+//!     .with_change_frequency(ChangeFrequency::Weekly)
+//!     .with_priority("/", 1.0)
+//!     .exclude("/admin");
+//! let server = Webserver::new(10, vec![]).with_sitemap(sitemap);
+//! ```
+
+use std::fmt;
+
+use crate::{
+    request::Method,
+    server::Handler
+};
+
+/// How often the content of a page is expected to change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeFrequency {
+    Always,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    Never,
+}
+
+impl fmt::Display for ChangeFrequency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frequency = match self {
+            ChangeFrequency::Always => "always",
+            ChangeFrequency::Hourly => "hourly",
+            ChangeFrequency::Daily => "daily",
+            ChangeFrequency::Weekly => "weekly",
+            ChangeFrequency::Monthly => "monthly",
+            ChangeFrequency::Yearly => "yearly",
+            ChangeFrequency::Never => "never",
+        };
+        write!(f, "{}", frequency)
+    }
+}
+
+/// The settings of a generated sitemap
+#[derive(Debug, Clone)]
+pub struct Sitemap {
+    base_url: String,
+    change_frequency: Option<ChangeFrequency>,
+    default_priority: Option<f32>,
+    priorities: Vec<(String, f32)>,
+    excluded: Vec<String>,
+}
+
+impl Sitemap {
+    /// Creates a sitemap for a site
+    ///
+    /// # Arguments
+    /// * `base_url` - The scheme and host the routes are served on, such as `https://example.com`
+    pub fn new(base_url: &str) -> Sitemap {
+        Sitemap {
+            base_url: String::from(base_url.trim_end_matches('/')),
+            change_frequency: None,
+            default_priority: None,
+            priorities: Vec::new(),
+            excluded: Vec::new(),
+        }
+    }
+
+    /// Sets the change frequency of every page
+    pub fn with_change_frequency(mut self, change_frequency: ChangeFrequency) -> Sitemap {
+        self.change_frequency = Some(change_frequency);
+        self
+    }
+
+    /// Sets the priority of pages without their own priority, between 0.0 and 1.0
+    pub fn with_default_priority(mut self, priority: f32) -> Sitemap {
+        self.default_priority = Some(priority.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Sets the priority of one route, between 0.0 and 1.0
+    pub fn with_priority(mut self, route: &str, priority: f32) -> Sitemap {
+        self.priorities.push((String::from(route), priority.clamp(0.0, 1.0)));
+        self
+    }
+
+    /// Leaves a route, and every route below it, out of the sitemap
+    pub fn exclude(mut self, route: &str) -> Sitemap {
+        self.excluded.push(String::from(route.trim_end_matches('/')));
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Whether a route is listed in the sitemap
+    pub fn includes(&self, handler: &Handler) -> bool {
+        let route = handler.route();
+        route.starts_with('/')
+            && route != "/sitemap.xml"
+            && !handler.is_health_check()
+            && handler.method().is_none_or(|method| *method == Method::Get)
+            && !route.split('/').any(|segment| segment.starts_with(':') || segment.starts_with('*'))
+            && !self.excluded.iter().any(|excluded| {
+                route == excluded || route.starts_with(&format!("{}/", excluded))
+            })
+    }
+
+    /// Renders the sitemap for a set of routes
+    pub fn render(&self, routes: &[Handler]) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+        for handler in routes.iter().filter(|handler| self.includes(handler)) {
+            let route = handler.route();
+            xml.push_str("  <url>\n");
+            xml.push_str(&format!("    <loc>{}</loc>\n", escape(&format!("{}{}", self.base_url, route))));
+            if let Some(change_frequency) = self.change_frequency {
+                xml.push_str(&format!("    <changefreq>{}</changefreq>\n", change_frequency));
+            }
+            let priority = self.priorities.iter()
+                .find(|(prioritized, _)| prioritized == route)
+                .map(|(_, priority)| *priority)
+                .or(self.default_priority);
+            if let Some(priority) = priority {
+                xml.push_str(&format!("    <priority>{:.1}</priority>\n", priority));
+            }
+            xml.push_str("  </url>\n");
+        }
+        xml.push_str("</urlset>\n");
+        xml
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,357 @@
+//! Server-Sent Events
+//!
+//! An [`EventStream`] holds its connection open and pushes every [`Event`] sent on a channel to the
+//! client as `text/event-stream`, which browsers read with `EventSource`. Comments are sent while
+//! there are no events, so proxies do not close the idle connection, and a `retry:` hint tells
+//! the browser how soon to reconnect if the stream ends.
+//!
+//! Every open stream keeps a worker of the thread pool busy, so the pool has to be sized for the
+//! expected number of listeners.
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     RequestInfo,
+//!     sse::{Event, EventStream}
+//! };
+//!
+//! fn clock(_: &RequestInfo) -> Box<dyn Sendable> {
+//!     let (sender, receiver) = tokio::sync::mpsc::channel(16);
+//!     std::thread::spawn(move || {
+//!         for tick in 0.. {
+//!             let event = Event::new(&tick.to_string()).with_event("tick");
+//!             if sender.blocking_send(event).is_err() {
+//!                 break;
+//!             }
+//!             std::thread::sleep(Duration::from_secs(1));
+//!         }
+//!     });
+//!     Box::new(EventStream::new(receiver).with_retry(Duration::from_secs(5)))
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_route("/clock", clock);
+//! ```
+//!
+//! A [`Channel`] sends each event to every client subscribed to it, and forgets clients once
+//! they disconnect:
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Page,
+//!     Sendable,
+//!     RequestInfo,
+//!     sse::Event
+//! };
+//!
+//! fn subscribe(request: &RequestInfo) -> Box<dyn Sendable> {
+//!     match request.sse_channel("news") {
+//!         Some(channel) => Box::new(channel.subscribe()),
+//!         None => Box::new(Page::new(404, String::from("No such channel"))),
+//!     }
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! let news = server.sse_channel("news");
+//! server.add_route("/news", subscribe);
+//! // From anywhere, for as long as the server runs
+//! news.publish(Event::new("Hello World!"));
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration
+};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::{
+    status::StatusCode,
+    server::{
+        ConnectionInfo,
+        Sendable
+    }
+};
+
+/// A comment is sent after this long without events by default, 15 seconds
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The number of events queued for each subscriber of a [`Channel`]
+///
+/// Events published while a slow client has this many waiting are not sent to it.
+pub const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// An event pushed to the client
+///
+/// # Examples
+/// ```
+/// use simpleserve::sse::Event;
+///
+/// let event = Event::new("first line\nsecond line").with_event("update").with_id("42");
+/// assert_eq!(event.to_string(), "event: update\nid: 42\ndata: first line\ndata: second line\n\n");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// Creates an event with data, which may span several lines
+    pub fn new(data: &str) -> Event {
+        Event {
+            data: String::from(data),
+            event: None,
+            id: None,
+            retry: None,
+        }
+    }
+
+    /// Sets the event type, which selects the listener in the browser
+    pub fn with_event(mut self, event: &str) -> Event {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// Sets the id the browser sends back in `Last-Event-ID` when it reconnects
+    pub fn with_id(mut self, id: &str) -> Event {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// Sets how long the browser waits before reconnecting
+    pub fn with_retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    pub fn event(&self) -> Option<&str> {
+        self.event.as_deref()
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+}
+
+impl fmt::Display for Event {
+    /// Formats the event as it is sent on the stream
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", event)?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line.strip_suffix('\r').unwrap_or(line))?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Line breaks would end a field early, and let the value inject fields of its own
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// A response that pushes events until the channel is closed or the client disconnects
+pub struct EventStream {
+    events: Mutex<Option<mpsc::Receiver<Event>>>,
+    keep_alive: Duration,
+    retry: Option<Duration>,
+}
+
+impl EventStream {
+    /// Creates a stream of the events sent on the channel
+    ///
+    /// The stream ends when every sender has been dropped. Events can only be streamed once.
+    pub fn new(events: mpsc::Receiver<Event>) -> EventStream {
+        EventStream {
+            events: Mutex::new(Some(events)),
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            retry: None,
+        }
+    }
+
+    /// Sets how long the stream can be idle before a comment is sent
+    ///
+    /// Defaults to [`DEFAULT_KEEP_ALIVE_INTERVAL`].
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> EventStream {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sends a `retry:` hint when the stream starts, telling the browser how soon to reconnect
+    pub fn with_retry(mut self, retry: Duration) -> EventStream {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+#[async_trait]
+impl Sendable for EventStream {
+    /// Renders the status line and headers
+    ///
+    /// There is no `Content-Length`, the stream ends when the connection is closed.
+    fn render(&self) -> String {
+        format!(
+            "{}\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
+            StatusCode::OK.status_line()
+        )
+    }
+
+    fn is_delimited(&self) -> bool {
+        false
+    }
+
+    async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
+        conn.write_all(self.render().as_bytes()).await?;
+        if let Some(retry) = self.retry {
+            conn.write_all(format!("retry: {}\n\n", retry.as_millis()).as_bytes()).await?;
+        }
+        conn.flush().await?;
+        let mut events = match self.events.lock().unwrap().take() {
+            Some(events) => events,
+            None => {
+                println!("The events of an event stream were already sent");
+                return Ok(());
+            }
+        };
+        loop {
+            let message = tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => event.to_string(),
+                    None => return Ok(()),
+                },
+                _ = tokio::time::sleep(self.keep_alive) => String::from(": keep-alive\n\n"),
+            };
+            // A client going away is the usual way for a stream to end
+            if conn.write_all(message.as_bytes()).await.is_err() || conn.flush().await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A named channel that sends events to every client subscribed to it
+///
+/// Created with [`Webserver::sse_channel`](crate::Webserver::sse_channel). Cloning is cheap,
+/// every clone publishes to the same subscribers. A client that disconnected is noticed the
+/// next time its stream writes, and removed when an event is published after that.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    inner: Arc<ChannelInner>,
+}
+
+#[derive(Debug)]
+struct ChannelInner {
+    name: String,
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+}
+
+impl Channel {
+    pub fn new(name: &str) -> Channel {
+        Channel {
+            inner: Arc::new(ChannelInner {
+                name: String::from(name),
+                subscribers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// A stream of the events published from now on, to be returned from a handler
+    pub fn subscribe(&self) -> EventStream {
+        EventStream::new(self.receiver())
+    }
+
+    /// The events published from now on, for helpers that do not stream them
+    pub(crate) fn receiver(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        self.inner.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends an event to every subscriber, returning how many it was queued for
+    ///
+    /// Subscribers that disconnected are removed.
+    pub fn publish(&self, event: Event) -> usize {
+        let mut subscribers = self.inner.subscribers.lock().unwrap();
+        let mut queued = 0;
+        subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => {
+                queued += 1;
+                true
+            },
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                println!("Subscriber of {} is too slow, dropped an event", self.inner.name);
+                true
+            },
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+        queued
+    }
+
+    /// The number of subscribers, without the ones whose stream has ended
+    pub fn subscribers(&self) -> usize {
+        let mut subscribers = self.inner.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+        subscribers.len()
+    }
+}
+
+/// The channels of a server, by name
+#[derive(Debug, Default)]
+pub(crate) struct Channels {
+    channels: RwLock<HashMap<String, Channel>>,
+}
+
+impl Channels {
+    pub(crate) fn len(&self) -> usize {
+        self.channels.read().unwrap().len()
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Channel> {
+        self.channels.read().unwrap().get(name).cloned()
+    }
+
+    pub(crate) fn get_or_create(&self, name: &str) -> Channel {
+        self.channels
+            .write()
+            .unwrap()
+            .entry(String::from(name))
+            .or_insert_with(|| Channel::new(name))
+            .clone()
+    }
+
+    /// Removes a channel if nobody is subscribed to it anymore
+    pub(crate) fn remove_unused(&self, name: &str) {
+        let mut channels = self.channels.write().unwrap();
+        if channels.get(name).is_some_and(|channel| channel.subscribers() == 0) {
+            channels.remove(name);
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! Serving a directory of static files
+//!
+//! [`Webserver::serve_directory`](crate::Webserver::serve_directory) maps a URL prefix to a
+//! directory, so every file under it is served without listing the files one by one. A route
+//! that names a directory is answered with the `index.html` in it, or, for a mount with listings
+//! turned on in its [`MountOptions`], with a page listing the directory when it has no index.
+//!
+//! Paths are resolved so they cannot leave the directory: `..` segments are removed from the
+//! route before it is routed, and the resolved path is canonicalized, so a symlink pointing
+//! outside the directory is refused as well. Files and directories in the blacklist of the
+//! server are refused with 403 Forbidden.
+//!
+//! ## Example
+//! ```no_run
+//! use simpleserve::Webserver;
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! // GET /static/css/app.css sends ./public/css/app.css
+//! server.serve_directory("/static", "./public").expect("Missing directory");
+//! ```
+//!
+//! A file-sharing server can list its directories:
+//! ```no_run
+//! use simpleserve::{Webserver, static_files::MountOptions};
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.serve_directory_with_options("/files", "./shared", MountOptions::new().with_listing(true))
+//!     .expect("Missing directory");
+//! ```
+//!
+//! A mount with [`MountOptions::with_preload`] is read into memory when the server starts, on
+//! its thread pool, so the first requests after a deploy do not wait for the disk. Preloaded
+//! files keep their strong ETag, and a file that changed since is read from disk again:
+//! ```no_run
+//! use simpleserve::{Webserver, static_files::MountOptions};
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! // Up to 64 MiB of the site is cached
+//! server.serve_directory_with_options("/", "./site", MountOptions::new().with_preload(64 * 1024 * 1024))
+//!     .expect("Missing directory");
+//! ```
+//!
+//! [`Webserver::serve_assets`](crate::Webserver::serve_assets) mounts a directory of assets under
+//! names with a hash of their content, like `app.3fa9c2e1.js` for `app.js`. A new version of a
+//! file gets a new name, so the hashed names are sent with `Cache-Control: immutable` and cached
+//! for a year. Pages link to the hashed names through the [`AssetManifest`]:
+//! ```no_run
+//! use simpleserve::{Webserver, Sendable, RequestInfo, Page};
+//!
+//! fn home(request: &RequestInfo) -> Box<dyn Sendable> {
+//!     let script = request.asset("js/app.js").unwrap_or_default();
+//!     Box::new(Page::new(200, format!("<script src=\"{}\"></script>", script)))
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.serve_assets("/assets", "./assets").expect("Missing directory");
+//! server.add_route("/", home);
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::Duration
+};
+
+use crate::{
+    cache_policy::CacheControl,
+    etag::ETag,
+    response::Response,
+    server::{
+        Bytes,
+        RequestInfo,
+        Sendable
+    },
+    utils,
+    ThreadPool
+};
+
+/// The file served for a route that names a directory
+pub const INDEX_FILE: &str = "index.html";
+
+/// How long files under a hashed name may be cached, a year
+pub const IMMUTABLE_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// The number of hex digits of the content hash in a hashed name
+const HASH_LENGTH: usize = 8;
+
+/// How a directory is served
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MountOptions {
+    listing: bool,
+    preload: usize,
+}
+
+impl MountOptions {
+    /// Serves files and index files only
+    pub fn new() -> MountOptions {
+        MountOptions::default()
+    }
+
+    /// Lists directories without an index file, instead of answering 404 Not Found
+    ///
+    /// Hidden files, whose name starts with a `.`, and blacklisted paths are left out.
+    pub fn with_listing(mut self, listing: bool) -> MountOptions {
+        self.listing = listing;
+        self
+    }
+
+    /// Reads the files of the directory into memory when the server starts
+    ///
+    /// Files are read in order of their path until `max_size` bytes are cached, skipping the ones
+    /// that do not fit, and their strong ETags are computed ahead of time. A cached file that
+    /// changes on disk afterwards is read from disk again. Hidden files are not preloaded.
+    ///
+    /// # Arguments
+    /// * `max_size` - The most bytes cached for the directory, 0 to preload nothing
+    pub fn with_preload(mut self, max_size: usize) -> MountOptions {
+        self.preload = max_size;
+        self
+    }
+
+    pub fn listing(&self) -> bool {
+        self.listing
+    }
+
+    pub fn preload(&self) -> usize {
+        self.preload
+    }
+}
+
+/// The files of a mount read into memory when the server starts
+///
+/// Files are keyed by their path relative to the directory, so they are still found once the
+/// directory is moved inside a chroot.
+#[derive(Default)]
+pub(crate) struct FileCache {
+    files: RwLock<HashMap<PathBuf, Bytes>>,
+}
+
+impl FileCache {
+    pub(crate) fn new() -> FileCache {
+        FileCache::default()
+    }
+
+    /// Reads the files of a directory on the threads of a pool, up to `max_size` bytes
+    ///
+    /// Returns how many files and bytes are cached.
+    pub(crate) fn preload(&self, pool: &ThreadPool, root: &Path, max_size: usize) -> Result<(usize, usize), io::Error> {
+        let mut found = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(relative) = pending.pop() {
+            for entry in fs::read_dir(root.join(&relative))? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if name.to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let relative = relative.join(name);
+                // Symlinks are followed like when they are served, as long as they stay inside
+                match resolve(root, &relative.to_string_lossy()) {
+                    Resolved::File(path) => found.push((relative, path)),
+                    Resolved::Directory(_) if entry.file_type()?.is_dir() => pending.push(relative),
+                    _ => {},
+                }
+            }
+        }
+        found.sort();
+
+        let mut size = 0;
+        let mut selected = Vec::new();
+        for (relative, path) in found {
+            let len = fs::metadata(&path)?.len() as usize;
+            if size + len <= max_size {
+                size += len;
+                selected.push((relative, path));
+            }
+        }
+        let files = Mutex::new(HashMap::new());
+        pool.scope(|scope| {
+            for (relative, path) in selected {
+                let files = &files;
+                scope.execute(move || match Bytes::new(200, &path) {
+                    Ok(bytes) => {
+                        files.lock().unwrap().insert(relative, bytes.with_content_etag());
+                    },
+                    Err(e) => println!("Error preloading {}: {}", path.display(), e),
+                });
+            }
+        });
+        let files = files.into_inner().unwrap();
+        let cached = (files.len(), files.values().map(|bytes| bytes.content().len()).sum());
+        self.files.write().unwrap().extend(files);
+        Ok(cached)
+    }
+
+    /// The cached file at a path inside the directory, unless it changed since it was read
+    fn get(&self, root: &Path, path: &Path) -> Option<Bytes> {
+        let relative = path.strip_prefix(root).ok()?;
+        let files = self.files.read().unwrap();
+        let bytes = files.get(relative)?;
+        let metadata = fs::metadata(path).ok()?;
+        if metadata.len() as usize != bytes.content().len() || metadata.modified().ok() != bytes.modified() {
+            return None;
+        }
+        Some(bytes.clone())
+    }
+}
+
+/// Where a path under a directory leads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved {
+    /// A file inside the directory, canonicalized
+    File(PathBuf),
+    /// A directory inside the directory, canonicalized
+    Directory(PathBuf),
+    /// Nothing exists at the path
+    NotFound,
+    /// The path leads outside the directory
+    Forbidden,
+}
+
+/// Resolves a relative path inside a directory
+///
+/// # Arguments
+/// * `root` - The directory, canonicalized
+/// * `relative` - The path inside the directory, with `/` separators
+pub fn resolve(root: &Path, relative: &str) -> Resolved {
+    let relative = Path::new(relative.trim_start_matches('/'));
+    // Routes are normalized before this, but a handler may pass any path
+    if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Resolved::Forbidden;
+    }
+    let path = match root.join(relative).canonicalize() {
+        Ok(path) => path,
+        Err(_) => return Resolved::NotFound,
+    };
+    if !path.starts_with(root) {
+        return Resolved::Forbidden;
+    }
+    if path.is_dir() {
+        Resolved::Directory(path)
+    } else {
+        Resolved::File(path)
+    }
+}
+
+/// The hashed names of the files in a directory of assets
+///
+/// Made when the directory is mounted with
+/// [`Webserver::serve_assets`](crate::Webserver::serve_assets), which adds it to the shared
+/// state of the server. Files changed afterwards keep their old name until the server restarts.
+///
+/// # Examples
+/// ```
+/// use simpleserve::static_files::AssetManifest;
+///
+/// let dir = std::env::temp_dir().join("simpleserve-manifest-doc");
+/// std::fs::create_dir_all(dir.join("css")).unwrap();
+/// std::fs::write(dir.join("css/site.css"), "body { margin: 0 }").unwrap();
+///
+/// let manifest = AssetManifest::scan("/assets", &dir).unwrap();
+/// let url = manifest.lookup("css/site.css").unwrap();
+/// assert!(url.starts_with("/assets/css/site.") && url.ends_with(".css"));
+/// assert_eq!(manifest.lookup("/css/site.css"), Some(url));
+/// assert_eq!(manifest.lookup("css/missing.css"), None);
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssetManifest {
+    prefix: String,
+    // The hashed path of each file, and the other way around, relative to the directory
+    hashed: BTreeMap<String, String>,
+    logical: HashMap<String, String>,
+}
+
+impl AssetManifest {
+    /// Hashes every file in a directory and its subdirectories
+    ///
+    /// Hidden files, whose name starts with a `.`, are left out.
+    ///
+    /// # Arguments
+    /// * `prefix` - The URL prefix the directory is served under, like `/assets`
+    /// * `dir` - The directory
+    pub fn scan<P: AsRef<Path>>(prefix: &str, dir: P) -> Result<AssetManifest, io::Error> {
+        let mut manifest = AssetManifest {
+            prefix: String::from(prefix.trim_end_matches('/')),
+            ..AssetManifest::default()
+        };
+        let mut pending = vec![PathBuf::new()];
+        while let Some(relative) = pending.pop() {
+            for entry in fs::read_dir(dir.as_ref().join(&relative))? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') {
+                    continue;
+                }
+                let path = relative.join(&name);
+                if entry.file_type()?.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let logical = path.components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let hashed = hashed_name(&logical, &fs::read(entry.path())?);
+                manifest.logical.insert(hashed.clone(), logical.clone());
+                manifest.hashed.insert(logical, hashed);
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The URL of a file under its hashed name
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file in the directory, like `js/app.js`
+    pub fn lookup(&self, path: &str) -> Option<String> {
+        let hashed = self.hashed.get(path.trim_start_matches('/'))?;
+        Some(format!("{}/{}", self.prefix, hashed))
+    }
+
+    /// The path of a file in the directory, from its hashed path
+    pub fn logical_path(&self, hashed: &str) -> Option<&str> {
+        self.logical.get(hashed.trim_start_matches('/')).map(String::as_str)
+    }
+
+    /// Iterates over the files as `(path, hashed path)` pairs, sorted by path
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.hashed.iter().map(|(logical, hashed)| (logical.as_str(), hashed.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashed.is_empty()
+    }
+}
+
+/// A path with a hash of the content put before the extension of the file name
+///
+/// # Examples
+/// ```
+/// use simpleserve::static_files::hashed_name;
+///
+/// let hashed = hashed_name("js/app.js", b"console.log(1)");
+/// assert!(hashed.starts_with("js/app.") && hashed.ends_with(".js"));
+/// assert_eq!(hashed.len(), "js/app..js".len() + 8);
+/// assert_ne!(hashed, hashed_name("js/app.js", b"console.log(2)"));
+/// assert!(hashed_name("LICENSE", b"MIT").starts_with("LICENSE."));
+/// ```
+pub fn hashed_name(path: &str, content: &[u8]) -> String {
+    let etag = ETag::for_content(content);
+    let hash = &etag.tag()[..HASH_LENGTH];
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), path),
+    };
+    // A leading dot is part of the name, not an extension
+    match name.rfind('.').filter(|&dot| dot > 0) {
+        Some(dot) => format!("{}{}.{}{}", dir, &name[..dot], hash, &name[dot..]),
+        None => format!("{}{}.{}", dir, name, hash),
+    }
+}
+
+/// Answers a request for a path inside a directory of assets
+///
+/// A hashed name is answered with its file, cached as immutable. Any other path is served like
+/// a file in a mounted directory, with the caching of the server's policy.
+pub(crate) fn serve_asset(request: &RequestInfo, root: &Path, manifest: &AssetManifest, relative: &str, options: MountOptions) -> Box<dyn Sendable> {
+    let logical = match manifest.logical_path(relative) {
+        Some(logical) => logical,
+        None => return serve(request, root, relative, options, None),
+    };
+    match resolve(root, logical) {
+        Resolved::File(path) if !is_blacklisted(request, &path) => {
+            send_file(request, &path, Some(CacheControl::Immutable(IMMUTABLE_MAX_AGE)))
+        },
+        _ => serve(request, root, relative, options, None),
+    }
+}
+
+/// Answers a request for a path inside a directory, from its cache when the file is in it
+pub(crate) fn serve(request: &RequestInfo, root: &Path, relative: &str, options: MountOptions, cache: Option<&FileCache>) -> Box<dyn Sendable> {
+    let path = match resolve(root, relative) {
+        Resolved::File(path) => path,
+        Resolved::Directory(dir) => {
+            // Relative links in the index would resolve against the parent without the slash
+            if !request.route.ends_with('/') {
+                let location = format!("{}/", request.route);
+                return Box::new(Response::new(301).header("Location", &location));
+            }
+            match resolve(&dir, INDEX_FILE) {
+                Resolved::File(path) => path,
+                Resolved::NotFound if options.listing && !is_blacklisted(request, &dir) => return list(request, root, &dir),
+                _ => return Box::new(request.error_page(404, "Not Found", "The requested page could not be found.")),
+            }
+        },
+        Resolved::NotFound => return Box::new(request.error_page(404, "Not Found", "The requested page could not be found.")),
+        Resolved::Forbidden => return Box::new(request.error_page(403, "Forbidden", "You do not have permission to access this page.")),
+    };
+    if is_blacklisted(request, &path) {
+        return Box::new(request.error_page(403, "Forbidden", "You do not have permission to access this page."));
+    }
+    if let Some(bytes) = cache.and_then(|cache| cache.get(root, &path)) {
+        return Box::new(bytes.for_request(request));
+    }
+    send_file(request, &path, None)
+}
+
+/// Sends a file, with caching headers from the server's policy unless it is given one
+fn send_file(request: &RequestInfo, path: &Path, control: Option<CacheControl>) -> Box<dyn Sendable> {
+    match Bytes::new(200, path) {
+        Ok(bytes) => {
+            let bytes = bytes.for_request(request);
+            match control {
+                Some(control) => Box::new(bytes.with_cache_control(control, request.clock.system_time())),
+                None => Box::new(bytes),
+            }
+        },
+        Err(e) => {
+            println!("Error reading file: {}", e);
+            Box::new(request.error_page(500, "Internal Server Error", "The file could not be read."))
+        }
+    }
+}
+
+/// Whether a path is blacklisted, or inside a blacklisted directory
+fn is_blacklisted(request: &RequestInfo, path: &Path) -> bool {
+    request.blacklisted_paths.iter().any(|blacklisted| {
+        let blacklisted = blacklisted.canonicalize().unwrap_or_else(|_| blacklisted.clone());
+        path.starts_with(blacklisted)
+    })
+}
+
+/// A page listing the files and directories in a directory
+fn list(request: &RequestInfo, root: &Path, dir: &Path) -> Box<dyn Sendable> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Error listing directory: {}", e);
+            return Box::new(request.error_page(500, "Internal Server Error", "The directory could not be read."));
+        }
+    };
+    let mut listed = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Entries that lead outside the mount, or are blacklisted, could not be opened anyway
+        let relative = dir.strip_prefix(root).unwrap_or(Path::new("")).join(&name);
+        let path = match resolve(root, &relative.to_string_lossy()) {
+            Resolved::File(path) | Resolved::Directory(path) if !is_blacklisted(request, &path) => path,
+            _ => continue,
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        listed.push((metadata.is_dir(), name, metadata));
+    }
+    // Directories first, then by name
+    listed.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let mut rows = String::new();
+    if dir != root {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (is_dir, name, metadata) in listed {
+        let suffix = if is_dir { "/" } else { "" };
+        let size = if is_dir { String::from("-") } else { metadata.len().to_string() };
+        let modified = metadata.modified().map(utils::format_http_date).unwrap_or_default();
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            urlencoding::encode(&name), suffix, escape(&name), suffix, size, modified
+        ));
+    }
+    let theme = &request.theme;
+    let (language, heading, message) = theme.translate(request.header("accept-language"), 200, "Index of", "");
+    let title = format!("{} {}", heading, escape(request.route));
+    let message = if message.is_empty() { String::new() } else { format!("<p>{}</p>\n", message) };
+    let content = format!(
+        "{}<table>\n<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n{}</table>",
+        message, rows
+    );
+    let page = Response::new(200)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .text(&theme.render(200, &title, &content));
+    Box::new(theme.with_language(page, 200, language))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,159 @@
+//! Streamed responses
+//!
+//! A [`StreamingResponse`] sends its body with `Transfer-Encoding: chunked`, one chunk at a time,
+//! so a handler can send a large or generated body without knowing its length or holding all of it
+//! in memory. The chunks come from an iterator, or from a channel that another task writes to.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     Sendable,
+//!     RequestInfo,
+//!     streaming::StreamingResponse
+//! };
+//!
+//! fn numbers(_: &RequestInfo) -> Box<dyn Sendable> {
+//!     let lines = (1..=100_000).map(|n| format!("{}\n", n).into_bytes());
+//!     Box::new(StreamingResponse::new(200, lines).header("Content-Type", "text/plain"))
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_route("/numbers", numbers);
+//! ```
+
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::{
+    request::Headers,
+    status::StatusCode,
+    server::{
+        ConnectionInfo,
+        Sendable
+    }
+};
+
+/// Where the chunks of a streamed body come from
+enum Source {
+    Iterator(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+    Channel(mpsc::Receiver<Vec<u8>>),
+}
+
+/// A response whose body is sent in chunks as they are produced
+///
+/// The body can only be sent once. Middleware cannot change it, since it is never buffered.
+pub struct StreamingResponse {
+    status: u16,
+    headers: Headers,
+    source: Mutex<Option<Source>>,
+}
+
+impl StreamingResponse {
+    /// Creates a response that sends every item of the iterator as a chunk
+    ///
+    /// The iterator runs on the connection's thread while the response is sent.
+    pub fn new<I>(status: u16, chunks: I) -> StreamingResponse
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        StreamingResponse::with_source(status, Source::Iterator(Box::new(chunks.into_iter())))
+    }
+
+    /// Creates a response that sends every message of the channel as a chunk
+    ///
+    /// The body ends when every sender has been dropped.
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::streaming::StreamingResponse;
+    ///
+    /// let (sender, receiver) = tokio::sync::mpsc::channel(16);
+    /// std::thread::spawn(move || {
+    ///     for line in ["one\n", "two\n"] {
+    ///         sender.blocking_send(line.as_bytes().to_vec()).unwrap();
+    ///     }
+    /// });
+    /// let response = StreamingResponse::from_receiver(200, receiver);
+    /// ```
+    pub fn from_receiver(status: u16, chunks: mpsc::Receiver<Vec<u8>>) -> StreamingResponse {
+        StreamingResponse::with_source(status, Source::Channel(chunks))
+    }
+
+    fn with_source(status: u16, source: Source) -> StreamingResponse {
+        StreamingResponse {
+            status,
+            headers: Headers::new(),
+            source: Mutex::new(Some(source)),
+        }
+    }
+
+    /// Adds a header, keeping any existing headers with the same name
+    ///
+    /// `Content-Length` and `Transfer-Encoding` are ignored, since the response sets its own framing.
+    /// Like [`Response::header`](crate::Response::header), headers with a line break are ignored.
+    pub fn header(mut self, name: &str, value: &str) -> StreamingResponse {
+        let framing = ["content-length", "transfer-encoding"].iter().any(|framing| name.eq_ignore_ascii_case(framing));
+        if framing || [name, value].iter().any(|part| part.contains(['\r', '\n'])) {
+            println!("Ignoring header {} on a streamed response", name.escape_debug());
+            return self;
+        }
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+}
+
+#[async_trait]
+impl Sendable for StreamingResponse {
+    /// Renders the status line and headers
+    fn render(&self) -> String {
+        let mut head = format!("{}\r\n", StatusCode::from(self.status).status_line());
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+        head
+    }
+
+    async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
+        conn.write_all(self.render().as_bytes()).await?;
+        let source = self.source.lock().unwrap().take();
+        match source {
+            Some(Source::Iterator(chunks)) => {
+                for chunk in chunks {
+                    write_chunk(conn, &chunk).await?;
+                }
+            },
+            Some(Source::Channel(mut chunks)) => {
+                while let Some(chunk) = chunks.recv().await {
+                    write_chunk(conn, &chunk).await?;
+                }
+            },
+            None => println!("The body of a streamed response was already sent"),
+        }
+        conn.write_all(b"0\r\n\r\n").await
+    }
+}
+
+/// Writes a chunk and flushes it, so the client gets it as soon as it is produced
+async fn write_chunk(conn: &mut ConnectionInfo, chunk: &[u8]) -> Result<(), std::io::Error> {
+    // An empty chunk would end the body
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    conn.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+    conn.write_all(chunk).await?;
+    conn.write_all(b"\r\n").await?;
+    conn.flush().await
+}
@@ -0,0 +1,130 @@
+//! Tarpits for scanners
+//!
+//! A tarpit route answers very slowly, a few bytes at a time, to waste the time of scanners
+//! probing for paths like `/wp-login.php`. Every tarpitted connection is capped in how long it is
+//! held and how many bytes it is sent, so a tarpit cannot tie up the server indefinitely.
+//! Hits are logged and counted in [`ServerStats::tarpit_hits`](crate::ServerStats::tarpit_hits).
+//!
+//! ## Example
+//! ```
+//! use std::time::Duration;
+//! use simpleserve::{
+//!     Webserver,
+//!     tarpit::Tarpit
+//! };
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_tarpit("/wp-login.php");
+//! server.add_tarpit_with("/.env", Tarpit::new().with_max_duration(Duration::from_secs(10)));
+//! ```
+
+use std::{
+    sync::Arc,
+    time::Duration
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::OwnedSemaphorePermit,
+    time::Instant
+};
+
+use crate::{
+    server::{
+        ConnectionInfo,
+        Sendable
+    },
+    status::StatusCode
+};
+
+/// A response that drips bytes to the client
+#[derive(Debug, Clone)]
+pub struct Tarpit {
+    interval: Duration,
+    max_duration: Duration,
+    max_bytes: usize,
+    // A slot of the server's tarpit limit, held until the response is dropped
+    permit: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+impl Tarpit {
+    /// Creates a tarpit that sends a byte every second, for up to a minute
+    pub fn new() -> Tarpit {
+        Tarpit {
+            interval: Duration::from_secs(1),
+            max_duration: Duration::from_secs(60),
+            max_bytes: 1024,
+            permit: None,
+        }
+    }
+
+    /// Sets the time between bytes
+    pub fn with_interval(mut self, interval: Duration) -> Tarpit {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the longest a connection is held
+    pub fn with_max_duration(mut self, max_duration: Duration) -> Tarpit {
+        self.max_duration = max_duration;
+        self
+    }
+
+    /// Sets the most bytes of body sent to a connection
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Tarpit {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Holds a slot of the tarpit limit for as long as the response lives
+    pub(crate) fn holding(mut self, permit: OwnedSemaphorePermit) -> Tarpit {
+        self.permit = Some(Arc::new(permit));
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn max_duration(&self) -> Duration {
+        self.max_duration
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+impl Default for Tarpit {
+    fn default() -> Tarpit {
+        Tarpit::new()
+    }
+}
+
+#[async_trait]
+impl Sendable for Tarpit {
+    fn render(&self) -> String {
+        format!("{}\r\nContent-Type: text/html\r\n\r\n", StatusCode::OK.status_line())
+    }
+
+    fn is_delimited(&self) -> bool {
+        false
+    }
+
+    /// Sends the head, then one byte per interval until a cap is reached or the client gives up
+    async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), std::io::Error> {
+        let deadline = Instant::now() + self.max_duration;
+        conn.write_all(self.render().as_bytes()).await?;
+        for _ in 0..self.max_bytes {
+            if Instant::now() + self.interval > deadline {
+                break;
+            }
+            tokio::time::sleep(self.interval).await;
+            // A client hanging up is the expected way for a tarpit to end
+            if conn.write_all(b" ").await.is_err() || conn.flush().await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
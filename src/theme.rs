@@ -0,0 +1,195 @@
+//! Theming for the pages the server generates itself
+//!
+//! Built-in pages (404, 403, 500, ...) are rendered through a [`Theme`], so
+//! a single layout template is enough to make them match the rest of a site.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     theme::Theme
+//! };
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.set_theme(Theme::new(String::from(
+//!     "<html><head><title>{{title}}</title></head><body><h1>{{status}}</h1>{{content}}</body></html>"
+//! )));
+//! ```
+//!
+//! Pages can be translated, and the language is picked from the `Accept-Language` header of the
+//! request. The messages passed in by the server are the default language, English unless set
+//! with [`Theme::with_default_language`]. A translated page is sent with `Content-Language` and
+//! `Vary: Accept-Language`, so caches keep a copy per language:
+//! ```
+//! use simpleserve::theme::Theme;
+//!
+//! let theme = Theme::default()
+//!     .with_translation("fr", 404, "Page introuvable", "La page demandée est introuvable.")
+//!     .with_translation("de", 404, "Nicht gefunden", "Die Seite wurde nicht gefunden.");
+//! let page = theme.localized_page(Some("de-DE, en;q=0.5"), 404, "Not Found", "The page could not be found.");
+//! assert_eq!(page.headers().get("content-language"), Some("de"));
+//! assert_eq!(page.headers().get("vary"), Some("Accept-Language"));
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path
+};
+
+use crate::{
+    request,
+    response::Response,
+    server::Page
+};
+
+const DEFAULT_LAYOUT: &str = "<!DOCTYPE html>\n\
+<html>\n\
+<head><meta charset=\"utf-8\"><title>{{status}} {{title}}</title></head>\n\
+<body>\n\
+<h1>{{status}} {{title}}</h1>\n\
+{{content}}\n\
+</body>\n\
+</html>\n";
+
+/// A layout template applied to built-in pages
+///
+/// The layout may contain the placeholders `{{status}}`, `{{title}}` and
+/// `{{content}}`, which are replaced when a page is rendered.
+#[derive(Clone, Debug)]
+pub struct Theme {
+    layout: String,
+    // Language, then status, to title and message
+    translations: HashMap<String, HashMap<u16, (String, String)>>,
+    default_language: String,
+}
+
+impl Theme {
+    /// Creates a theme from a layout template
+    pub fn new(layout: String) -> Theme {
+        Theme {
+            layout,
+            translations: HashMap::new(),
+            default_language: String::from("en"),
+        }
+    }
+
+    /// Reads a layout template from a file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Theme, std::io::Error> {
+        Ok(Theme::new(fs::read_to_string(path)?))
+    }
+
+    pub fn layout(&self) -> &str {
+        &self.layout
+    }
+
+    /// Renders the layout with the given values
+    ///
+    /// # Arguments
+    /// * `status` - The status code of the page
+    /// * `title` - The title of the page
+    /// * `content` - The HTML content of the page
+    pub fn render(&self, status: u16, title: &str, content: &str) -> String {
+        self.layout
+            .replace("{{status}}", &status.to_string())
+            .replace("{{title}}", title)
+            .replace("{{content}}", content)
+    }
+
+    /// Renders the layout as a page with a short message as its content
+    pub fn page(&self, status: u16, title: &str, message: &str) -> Page {
+        let content = format!("<p>{}</p>", message);
+        Page::new(status, self.render(status, title, &content))
+    }
+
+    /// Sets the language of the messages the server passes in, sent as `Content-Language` when
+    /// a page with translations falls back to them
+    pub fn with_default_language(mut self, language: &str) -> Theme {
+        self.default_language = String::from(language);
+        self
+    }
+
+    pub fn default_language(&self) -> &str {
+        &self.default_language
+    }
+
+    /// Translates the page for a status into a language
+    ///
+    /// The directory listing is the page for 200: its title takes the place of `Index of`, and
+    /// its message is shown above the list.
+    ///
+    /// # Arguments
+    /// * `language` - The language tag, like `fr` or `pt-BR`
+    /// * `status` - The status code of the page
+    /// * `title` - The translated title
+    /// * `message` - The translated message, which may contain HTML
+    pub fn with_translation(mut self, language: &str, status: u16, title: &str, message: &str) -> Theme {
+        self.translations
+            .entry(language.to_ascii_lowercase())
+            .or_default()
+            .insert(status, (String::from(title), String::from(message)));
+        self
+    }
+
+    /// The languages a page for a status is translated into
+    pub fn languages(&self, status: u16) -> Vec<&str> {
+        let mut languages: Vec<&str> = self.translations
+            .iter()
+            .filter(|(_, pages)| pages.contains_key(&status))
+            .map(|(language, _)| language.as_str())
+            .collect();
+        languages.sort_unstable();
+        languages
+    }
+
+    /// Creates a page in the language the client prefers
+    ///
+    /// Falls back to `title` and `message` if the page is not translated into any language
+    /// the client accepts.
+    ///
+    /// # Arguments
+    /// * `accept_language` - The `Accept-Language` header of the request
+    /// * `status` - The status code of the page
+    /// * `title` - The title in the default language
+    /// * `message` - The message in the default language
+    pub fn localized_page(&self, accept_language: Option<&str>, status: u16, title: &str, message: &str) -> Response {
+        let (language, title, message) = self.translate(accept_language, status, title, message);
+        let page = self.page(status, title, message);
+        let response = Response::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .text(page.content());
+        self.with_language(response, status, language)
+    }
+
+    /// The title and message for a status in the language the client prefers, and that language
+    ///
+    /// The language is `None` if the default title and message are used.
+    pub(crate) fn translate<'a>(&'a self, accept_language: Option<&str>, status: u16, title: &'a str, message: &'a str) -> (Option<&'a str>, &'a str, &'a str) {
+        let languages = self.languages(status);
+        let translated = accept_language
+            .and_then(|header| request::negotiate_language(header, &languages))
+            .and_then(|language| Some((language, self.translations.get(language)?.get(&status)?)));
+        match translated {
+            Some((language, (title, message))) => (Some(language), title, message),
+            None => (None, title, message),
+        }
+    }
+
+    /// Adds the headers of a page for a status in a language, picked by [`Theme::translate`]
+    ///
+    /// Pages without translations are the same for every client, and are left as they are.
+    pub(crate) fn with_language(&self, response: Response, status: u16, language: Option<&str>) -> Response {
+        if self.languages(status).is_empty() {
+            return response;
+        }
+        response
+            .header("Content-Language", language.unwrap_or(&self.default_language))
+            .header("Vary", "Accept-Language")
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::new(String::from(DEFAULT_LAYOUT))
+    }
+}
@@ -0,0 +1,142 @@
+//! Response body transformation
+//!
+//! A [`BodyTransform`] rewrites the bodies of responses after the handler made them, for things
+//! like adding an analytics snippet to HTML pages or rewriting links in proxied pages. It runs as
+//! a [`Middleware`] when wrapped in a [`Transform`], which decides which bodies it sees:
+//! - Only buffered bodies are transformed. Streamed responses, which
+//!   [`Sendable::to_response`] cannot represent, are sent as they are.
+//! - Bodies over the size cap, [`DEFAULT_MAX_SIZE`] unless set with [`Transform::with_max_size`],
+//!   are sent as they are, so a large download is not copied to be looked at.
+//! - Bodies that are already encoded (with a `Content-Encoding`), partial (with a
+//!   `Content-Range`), or empty are sent as they are.
+//!
+//! Transforms run in [`Phase::PostHandler`], and
+//! [`Compression`](crate::compression::Compression) runs in [`Phase::PreSend`], so transforms
+//! always see the body before it is compressed, whatever order they were added in. Between
+//! themselves, transforms run in the reverse order they were added, like every `after` hook.
+//!
+//! A transformed response loses its `Content-Length`, which is added back for the new body, and a
+//! strong `ETag` is made weak, because it was made for the bytes of the old body.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     transform::Transform
+//! };
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.add_middleware(Transform::new(|_: &simpleserve::RequestInfo, body: &[u8]| {
+//!     let page = std::str::from_utf8(body).ok()?;
+//!     let end = page.rfind("</body>")?;
+//!     Some(format!("{}<script src=\"/analytics.js\"></script>{}", &page[..end], &page[end..]).into_bytes())
+//! }).for_types(&["text/html"]));
+//! ```
+
+use crate::{
+    middleware::{Middleware, Phase},
+    response::Response,
+    server::{
+        RequestInfo,
+        Sendable
+    }
+};
+
+/// The largest body a [`Transform`] looks at by default, 1 MiB
+pub const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+
+/// Rewrites a response body
+///
+/// Implemented for closures taking the request and the body.
+pub trait BodyTransform: Send + Sync {
+    /// Whether the response should be transformed, every response by default
+    ///
+    /// Called before [`BodyTransform::transform`], with the headers of the response.
+    fn applies(&self, _request: &RequestInfo, _response: &Response) -> bool {
+        true
+    }
+
+    /// The new body, or `None` to keep the body as it is
+    fn transform(&self, request: &RequestInfo, body: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl<F> BodyTransform for F
+where
+    F: Fn(&RequestInfo, &[u8]) -> Option<Vec<u8>> + Send + Sync
+{
+    fn transform(&self, request: &RequestInfo, body: &[u8]) -> Option<Vec<u8>> {
+        self(request, body)
+    }
+}
+
+/// Middleware that applies a [`BodyTransform`] to buffered responses
+pub struct Transform<T> {
+    transform: T,
+    max_size: usize,
+    types: Vec<String>,
+}
+
+impl<T: BodyTransform> Transform<T> {
+    pub fn new(transform: T) -> Transform<T> {
+        Transform {
+            transform,
+            max_size: DEFAULT_MAX_SIZE,
+            types: Vec::new(),
+        }
+    }
+
+    /// Sets the largest body that is transformed, in bytes
+    pub fn with_max_size(mut self, max_size: usize) -> Transform<T> {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Only transforms responses of these content types
+    ///
+    /// A type ending in `/` matches every subtype. Responses without a `Content-Type` are left
+    /// alone once types are set.
+    pub fn for_types(mut self, types: &[&str]) -> Transform<T> {
+        self.types = types.iter().map(|mime_type| mime_type.to_ascii_lowercase()).collect();
+        self
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn types(&self) -> &[String] {
+        &self.types
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.transform
+    }
+}
+
+impl<T: BodyTransform> Middleware for Transform<T> {
+    fn after_phase(&self) -> Phase {
+        Phase::PostHandler
+    }
+
+    fn after(&self, request: &RequestInfo, response: Box<dyn Sendable>) -> Box<dyn Sendable> {
+        let original = match response.to_response() {
+            Some(original) => original,
+            None => return response,
+        };
+        let headers = original.headers();
+        if original.body().is_empty()
+            || original.body().len() > self.max_size
+            || headers.contains("content-encoding")
+            || headers.contains("content-range")
+            || (!self.types.is_empty() && !headers.has_mime_type(&self.types))
+            || !self.transform.applies(request, &original)
+        {
+            return response;
+        }
+        let body = match self.transform.transform(request, original.body()) {
+            Some(body) => body,
+            None => return response,
+        };
+        Box::new(original.with_new_body(body))
+    }
+}
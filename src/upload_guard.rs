@@ -0,0 +1,298 @@
+//! Checking the type of uploaded files
+//!
+//! The `Content-Type` of a multipart [`Part`] is whatever the client claims. An [`UploadGuard`]
+//! looks at the first bytes of the content instead, and refuses uploads that are not what they
+//! claim to be, or that a browser would run scripts from when they are served back, like HTML or
+//! SVG. An HTML page uploaded as `image/png` to a directory mounted with
+//! [`Webserver::serve_directory`](crate::Webserver::serve_directory) would otherwise be served as
+//! `text/html`, from the origin of the site, to anyone following a link to it.
+//!
+//! Refused uploads can be moved to a quarantine directory, to be looked at later, instead of being
+//! dropped.
+//!
+//! ## Example
+//! ```no_run
+//! use simpleserve::{
+//!     Page,
+//!     Sendable,
+//!     RequestInfo,
+//!     upload_guard::UploadGuard
+//! };
+//!
+//! fn upload(request: &RequestInfo) -> Box<dyn Sendable> {
+//!     let guard = UploadGuard::new()
+//!         .with_allowed_types(&["image/"])
+//!         .with_quarantine("./quarantine");
+//!     let parts = match request.multipart() {
+//!         Some(parts) => parts,
+//!         None => return Box::new(Page::new(415, String::from("Expected a multipart form"))),
+//!     };
+//!     for part in parts.flatten() {
+//!         if part.filename().is_none() {
+//!             continue;
+//!         }
+//!         match guard.screen(part) {
+//!             Ok(part) => println!("Accepted {:?}", part.filename()),
+//!             Err(e) => return Box::new(Page::new(415, e.to_string())),
+//!         }
+//!     }
+//!     Box::new(Page::new(200, String::from("Uploaded")))
+//! }
+//! ```
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering}
+};
+
+use crate::{
+    errors::{UploadRejectedError, UploadRejection},
+    multipart::Part
+};
+
+/// How many bytes are read to find the type of an upload
+pub const SNIFF_LENGTH: usize = 512;
+
+/// Types a browser may run scripts from when they are served from the site
+pub const DANGEROUS_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/javascript",
+    "application/javascript",
+    // Browsers run script elements in the XHTML namespace of any XML document
+    "application/xml",
+    "text/xml",
+];
+
+/// Extensions served as one of the [`DANGEROUS_TYPES`]
+const DANGEROUS_EXTENSIONS: &[&str] = &["html", "htm", "xhtml", "xht", "svg", "js", "mjs", "xml", "xsl"];
+
+/// Signatures at the start of binary formats
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+];
+
+/// Tags that make a browser treat text as HTML, whatever the rest of it is
+const HTML_TAGS: &[&str] = &["<!doctype html", "<html", "<head", "<body", "<script", "<iframe"];
+
+/// Finds the type of content from its first bytes
+///
+/// Returns `None` for content without a known signature, such as plain text.
+///
+/// # Examples
+/// ```
+/// use simpleserve::upload_guard::sniff;
+///
+/// assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+/// assert_eq!(sniff(b"\n  <!DOCTYPE html><title>Hi</title>"), Some("text/html"));
+/// assert_eq!(sniff(b"Hello World!"), None);
+/// ```
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    let bytes = &bytes[..bytes.len().min(SNIFF_LENGTH)];
+    for (signature, mime_type) in SIGNATURES {
+        if bytes.starts_with(signature) {
+            return Some(mime_type);
+        }
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    let text = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    let start = text.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(text.len());
+    let text = String::from_utf8_lossy(&text[start..]).to_ascii_lowercase();
+    if HTML_TAGS.iter().any(|tag| text.starts_with(tag)) {
+        Some("text/html")
+    } else if text.starts_with("<svg") || (text.starts_with("<?xml") && text.contains("<svg")) {
+        Some("image/svg+xml")
+    } else if text.starts_with("<?xml") {
+        Some("application/xml")
+    } else {
+        None
+    }
+}
+
+/// Whether a type is one of the [`DANGEROUS_TYPES`]
+pub fn is_dangerous(mime_type: &str) -> bool {
+    let mime_type = essence(mime_type);
+    DANGEROUS_TYPES.iter().any(|dangerous| mime_type == *dangerous)
+}
+
+/// Checks uploaded files against their declared type
+///
+/// By default an upload is refused if its content looks like a different type than it declared,
+/// or if it is of a dangerous type, by its content, its declared type or the extension of its
+/// file name. Uploads with no type, or declared as `application/octet-stream`, only need to not
+/// be dangerous.
+#[derive(Debug, Clone, Default)]
+pub struct UploadGuard {
+    allowed_types: Vec<String>,
+    allow_dangerous: bool,
+    quarantine: Option<PathBuf>,
+}
+
+impl UploadGuard {
+    /// Creates a guard that refuses mismatched and dangerous uploads
+    pub fn new() -> UploadGuard {
+        UploadGuard::default()
+    }
+
+    /// Accepts only these types, by their declared and detected types
+    ///
+    /// A type ending in `/`, like `image/`, allows every type starting with it.
+    pub fn with_allowed_types(mut self, types: &[&str]) -> UploadGuard {
+        self.allowed_types = types.iter().map(|mime_type| mime_type.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Accepts dangerous types, for uploads that are never served back from the site
+    pub fn allow_dangerous(mut self, allow: bool) -> UploadGuard {
+        self.allow_dangerous = allow;
+        self
+    }
+
+    /// Moves refused uploads to a directory, instead of dropping them
+    ///
+    /// The directory is created when the first upload is quarantined.
+    pub fn with_quarantine<P: AsRef<Path>>(mut self, dir: P) -> UploadGuard {
+        self.quarantine = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn quarantine(&self) -> Option<&Path> {
+        self.quarantine.as_deref()
+    }
+
+    /// Checks a part, without quarantining it
+    ///
+    /// # Errors
+    /// If the part is refused, or its content could not be read.
+    pub fn check(&self, part: &Part) -> Result<(), UploadRejectedError> {
+        let head = match head(part) {
+            Ok(head) => head,
+            Err(e) => {
+                println!("Error reading upload: {}", e);
+                return Err(UploadRejectedError::new(UploadRejection::Mismatch, part.content_type(), None));
+            }
+        };
+        let declared = part.content_type().map(essence).filter(|declared| declared != "application/octet-stream");
+        let detected = sniff(&head);
+        let reject = |reason| Err(UploadRejectedError::new(reason, part.content_type(), detected));
+
+        if let (Some(declared), Some(detected)) = (&declared, detected) {
+            if !matches(declared, detected) {
+                return reject(UploadRejection::Mismatch);
+            }
+        }
+        if !self.allow_dangerous {
+            let extension = part.filename()
+                .and_then(|filename| Path::new(filename).extension())
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extension.to_ascii_lowercase());
+            let dangerous_extension = extension.is_some_and(|extension| DANGEROUS_EXTENSIONS.contains(&extension.as_str()));
+            if dangerous_extension || declared.as_deref().is_some_and(is_dangerous) || detected.is_some_and(is_dangerous) {
+                return reject(UploadRejection::Dangerous);
+            }
+        }
+        if !self.allowed_types.is_empty() {
+            let mime_type = detected.map(String::from).or(declared);
+            if !mime_type.is_some_and(|mime_type| self.allows(&mime_type)) {
+                return reject(UploadRejection::NotAllowed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a part, quarantining it if it is refused
+    ///
+    /// # Errors
+    /// If the part is refused. The error has the path of the quarantined file, if there is one.
+    pub fn screen(&self, part: Part) -> Result<Part, UploadRejectedError> {
+        let error = match self.check(&part) {
+            Ok(()) => return Ok(part),
+            Err(error) => error,
+        };
+        let dir = match &self.quarantine {
+            Some(dir) => dir,
+            None => return Err(error),
+        };
+        match quarantine(dir, part) {
+            Ok(path) => Err(error.with_quarantined(path)),
+            Err(e) => {
+                println!("Error quarantining upload: {}", e);
+                Err(error)
+            }
+        }
+    }
+
+    fn allows(&self, mime_type: &str) -> bool {
+        self.allowed_types.iter().any(|allowed| match allowed.ends_with('/') {
+            true => mime_type.starts_with(allowed.as_str()),
+            false => same_type(mime_type, allowed),
+        })
+    }
+}
+
+/// The first bytes of a part, wherever it is kept
+fn head(part: &Part) -> io::Result<Vec<u8>> {
+    if let Some(data) = part.data() {
+        return Ok(data[..data.len().min(SNIFF_LENGTH)].to_vec());
+    }
+    let mut head = Vec::with_capacity(SNIFF_LENGTH);
+    if let Some(file) = part.file() {
+        File::open(file.path())?.take(SNIFF_LENGTH as u64).read_to_end(&mut head)?;
+    }
+    Ok(head)
+}
+
+/// Moves a part to the quarantine directory, under a name of its own
+fn quarantine(dir: &Path, part: Part) -> io::Result<PathBuf> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    fs::create_dir_all(dir)?;
+    // The name sent by the client is not used, it could be anything
+    let path = dir.join(format!(
+        "upload-{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    match part.data() {
+        Some(data) => fs::write(&path, data)?,
+        None => if let Some(file) = part.into_file() {
+            file.persist(&path)?;
+        },
+    }
+    Ok(path)
+}
+
+/// A type without its parameters, in lowercase
+fn essence(mime_type: &str) -> String {
+    mime_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// Whether a declared type fits the detected one
+fn matches(declared: &str, detected: &str) -> bool {
+    // Office documents, jars and epubs are zip archives too
+    same_type(declared, detected) || (detected == "application/zip" && !is_dangerous(declared))
+}
+
+/// Whether two types name the same format, allowing for common aliases
+fn same_type(a: &str, b: &str) -> bool {
+    let canonical = |mime_type: &str| match mime_type {
+        "image/jpg" | "image/pjpeg" => "image/jpeg",
+        "application/x-gzip" => "application/gzip",
+        "application/x-zip-compressed" => "application/zip",
+        "text/xml" => "application/xml",
+        "application/x-javascript" => "application/javascript",
+        other => other,
+    }.to_string();
+    canonical(a) == canonical(b)
+}
@@ -0,0 +1,116 @@
+//! Classification of `User-Agent` headers
+//!
+//! The classification is a set of simple heuristics over well-known product tokens.
+//! It is good enough for analytics and throttling rules, but it can be spoofed trivially
+//! and should not be used for anything security-sensitive. A
+//! [`BotThrottle`](crate::bot_throttle::BotThrottle) can verify crawlers by reverse DNS instead.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::user_agent::UserAgent;
+//!
+//! let ua = UserAgent::parse("Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1");
+//! assert_eq!(ua.product(), Some("Safari"));
+//! assert_eq!(ua.version(), Some("17.0"));
+//! assert!(ua.is_mobile());
+//! assert!(!ua.is_bot());
+//! ```
+
+/// Product tokens of browsers, checked in order since most browsers also claim to be the ones before them
+const BROWSERS: [(&str, &str); 6] = [
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Firefox/", "Firefox"),
+    ("Chrome/", "Chrome"),
+    ("CriOS/", "Chrome"),
+    ("Version/", "Safari"),
+];
+
+/// Words that identify crawlers and other automated clients
+const BOT_MARKERS: [&str; 6] = ["bot", "crawler", "spider", "slurp", "fetcher", "scraper"];
+
+/// A classified `User-Agent` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent {
+    raw: String,
+    product: Option<String>,
+    version: Option<String>,
+    bot: bool,
+    mobile: bool,
+}
+
+impl UserAgent {
+    /// Classifies a `User-Agent` header value
+    ///
+    /// # Examples
+    /// ```
+    /// use simpleserve::user_agent::UserAgent;
+    ///
+    /// let ua = UserAgent::parse("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+    /// assert!(ua.is_bot());
+    ///
+    /// let ua = UserAgent::parse("curl/8.4.0");
+    /// assert_eq!(ua.product(), Some("curl"));
+    /// assert_eq!(ua.version(), Some("8.4.0"));
+    /// ```
+    pub fn parse(raw: &str) -> UserAgent {
+        let lowercase = raw.to_ascii_lowercase();
+        let bot = BOT_MARKERS.iter().any(|marker| lowercase.contains(marker));
+        let mobile = ["mobile", "android", "iphone", "ipad"].iter().any(|marker| lowercase.contains(marker));
+
+        let browser = BROWSERS.iter().find_map(|(token, name)| {
+            let start = raw.find(token)? + token.len();
+            Some((*name, version_at(&raw[start..])))
+        });
+        let (product, version) = match browser {
+            Some((name, version)) => (Some(String::from(name)), version),
+            // Not a browser, so the first product token is usually the client, such as `curl/8.4.0`
+            None => match raw.split_whitespace().next().and_then(|token| token.split_once('/')) {
+                Some((name, version)) if name != "Mozilla" => (Some(String::from(name)), Some(String::from(version))),
+                _ => (None, None),
+            },
+        };
+
+        UserAgent {
+            raw: String::from(raw),
+            product,
+            version,
+            bot,
+            mobile,
+        }
+    }
+
+    /// The header value the classification was made from
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    /// The name of the browser or client, such as `Firefox` or `curl`
+    pub fn product(&self) -> Option<&str> {
+        self.product.as_deref()
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Whether the client identifies as a crawler or other automated client
+    pub fn is_bot(&self) -> bool {
+        self.bot
+    }
+
+    /// Whether the client is a phone or tablet
+    pub fn is_mobile(&self) -> bool {
+        self.mobile
+    }
+
+    pub fn is_desktop(&self) -> bool {
+        !self.mobile && !self.bot
+    }
+}
+
+/// The version at the start of `rest`, up to the next space
+fn version_at(rest: &str) -> Option<String> {
+    let version = rest.split_whitespace().next()?;
+    Some(String::from(version))
+}
@@ -4,12 +4,33 @@
 
 
 use std::{
-    path, 
     error::Error,
-    fs
+    collections::HashMap,
+    fs,
+    future::Future,
+    ops::Range,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH}
 };
 
-use crate::errors;
+use crate::errors::{
+    self,
+    MalformedRequestError,
+    RequestTooLargeError,
+    UnsupportedMediaTypeError
+};
+use crate::request::{
+    self,
+    Extensions,
+    Method,
+    Request
+};
+use crate::middleware;
+use crate::multipart;
+use crate::pool;
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::theme::Theme;
 use crate::server::{
     Sendable,
     Page,
@@ -17,15 +38,14 @@ use crate::server::{
     Handler,
     RequestInfo,
     ConnectionInfo,
-    ConnectionType
+    ServerContext,
+    ServerStats,
+    ShutdownHandle,
+    ActiveConnection,
+    PoolHint
 };
 
-use regex::Regex;
-use tokio::io::{
-    BufReader,
-    AsyncBufReadExt,
-    AsyncWriteExt,
-};
+use tokio::runtime::Runtime;
 
 pub fn get_mime_type(extension: &str) -> &'static str {
     match extension {
@@ -33,6 +53,7 @@ pub fn get_mime_type(extension: &str) -> &'static str {
         "css" => "text/css",
         "js" => "application/javascript",
         "json" => "application/json",
+        "xml" => "application/xml",
         "png" => "image/png",
         "jpg" | "jpeg" => "image/jpeg",
         "gif" => "image/gif",
@@ -41,69 +62,559 @@ pub fn get_mime_type(extension: &str) -> &'static str {
     }
 }
 
-pub async fn handle_connection(conn: ConnectionInfo, routes: Vec<Handler>, blacklisted_paths: Vec<path::PathBuf>) -> Result<(), Box<dyn Error>> {
-    match conn.connection_type() {
-        ConnectionType::Http => {
-            handle_http_connection(conn, routes, blacklisted_paths).await?;
-        },
-        ConnectionType::Https => {
-            handle_https_connection(conn, routes, blacklisted_paths).await?;
-        }
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats a time as an HTTP date, like in `Last-Modified` headers
+/// 
+/// Times before 1970 are formatted as the start of 1970.
+/// 
+/// # Examples
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use simpleserve::utils::format_http_date;
+/// 
+/// let time = UNIX_EPOCH + Duration::from_secs(784111777);
+/// assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+/// ```
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses an HTTP date in the format of [`format_http_date`]
+/// 
+/// The obsolete formats that clients may still send are not supported.
+/// 
+/// # Examples
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use simpleserve::utils::parse_http_date;
+/// 
+/// let time = UNIX_EPOCH + Duration::from_secs(784111777);
+/// assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+/// assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+/// ```
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    if parts.len() != 6 || !parts[0].ends_with(',') || parts[5] != "GMT" {
+        return None;
     }
-    Ok(())
+    let day: u64 = parts[1].parse().ok()?;
+    let month = MONTHS.iter().position(|month| *month == parts[2])? as u64 + 1;
+    let year: u64 = parts[3].parse().ok()?;
+    let time: Vec<u64> = parts[4].split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    if year < 1970 || !(1..=31).contains(&day) || time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
 }
 
-async fn handle_http_connection(mut conn: ConnectionInfo, routes: Vec<Handler>, blacklisted_paths: Vec<path::PathBuf>) -> Result<(), Box<dyn Error>> {
-    let buf_reader = BufReader::new(conn.stream());
-    let request_line = match buf_reader.lines().next_line().await? {
-        Some(line) => line,
-        None => {
-            println!("No request line found");
-            return Err(Box::new(errors::OptionUnwrapError {}));
+/// The date of a number of days since 1970-01-01, in the proleptic Gregorian calendar
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Counted in eras of 400 years from 0000-03-01, so leap days fall at the end of a year
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The number of days since 1970-01-01 of a date, the inverse of [`civil_from_days`]
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Splits a comma-separated header value into its items
+/// 
+/// Commas inside quoted strings do not split, and empty items are skipped.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::split_list;
+/// 
+/// assert_eq!(split_list("gzip, , br"), vec!["gzip", "br"]);
+/// assert_eq!(split_list("\"a,b\", W/\"c\""), vec!["\"a,b\"", "W/\"c\""]);
+/// ```
+pub fn split_list(value: &str) -> Vec<&str> {
+    split_outside_quotes(value, b',')
+}
+
+/// Parses a list with quality values, like `Accept-Encoding` or `Accept-Language`
+/// 
+/// Names are lowercased, and items without a `q` parameter have a quality of 1. Items are kept
+/// in the order of the header, including the ones with a quality of 0, which mean "not this".
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::parse_quality_list;
+/// 
+/// assert_eq!(
+///     parse_quality_list("gzip;q=0.5, BR, identity;q=0"),
+///     vec![(String::from("gzip"), 0.5), (String::from("br"), 1.0), (String::from("identity"), 0.0)]
+/// );
+/// ```
+pub fn parse_quality_list(value: &str) -> Vec<(String, f32)> {
+    let mut items = Vec::new();
+    for item in split_list(value) {
+        let mut parts = split_outside_quotes(item, b';').into_iter();
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        if name.is_empty() {
+            continue;
         }
+        let quality = parts
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map(|(_, value)| value.trim().parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0))
+            .unwrap_or(1.0);
+        items.push((name, quality));
+    }
+    items
+}
+
+/// Parses a list of directives, like `Cache-Control`
+/// 
+/// Names are lowercased and quoted values are unquoted.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::parse_directives;
+/// 
+/// let directives = parse_directives("No-Cache, max-age=60, private=\"Set-Cookie, Authorization\"");
+/// assert_eq!(directives[0], (String::from("no-cache"), None));
+/// assert_eq!(directives[1], (String::from("max-age"), Some(String::from("60"))));
+/// assert_eq!(directives[2].1.as_deref(), Some("Set-Cookie, Authorization"));
+/// ```
+pub fn parse_directives(value: &str) -> Vec<(String, Option<String>)> {
+    split_list(value)
+        .into_iter()
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(unquote(value.trim()))),
+            None => (directive.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// Parses a `Forwarded` header into one map per proxy, the client first
+/// 
+/// Parameter names are lowercased and quoted values are unquoted.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::parse_forwarded;
+/// 
+/// let hops = parse_forwarded("for=\"[2001:db8::1]:4711\";proto=https, For=192.0.2.43");
+/// assert_eq!(hops.len(), 2);
+/// assert_eq!(hops[0]["for"], "[2001:db8::1]:4711");
+/// assert_eq!(hops[0]["proto"], "https");
+/// assert_eq!(hops[1]["for"], "192.0.2.43");
+/// ```
+pub fn parse_forwarded(value: &str) -> Vec<HashMap<String, String>> {
+    split_list(value)
+        .into_iter()
+        .map(|element| {
+            split_outside_quotes(element, b';')
+                .into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (name.trim().to_ascii_lowercase(), unquote(value.trim())))
+                .collect()
+        })
+        .collect()
+}
+
+/// Removes the quotes and escapes of a quoted string, or returns the value as it is
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return String::from(value),
     };
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
 
-    let route = match request_line.split_whitespace().nth(1) {
-        Some(route) => route,
-        None => {
-            println!("No route found");
-            return Err(Box::new(errors::OptionUnwrapError {}));
+/// Splits on a delimiter outside of quoted strings, trimming the parts and skipping empty ones
+fn split_outside_quotes(value: &str, delimiter: u8) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, byte) in value.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if quoted => escaped = true,
+            b'"' => quoted = !quoted,
+            byte if byte == delimiter && !quoted => {
+                parts.push(value[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
         }
-    };
-    // URL decode
-    let route = &*urlencoding::decode(route)?.into_owned();
-    // Remove /../
-    let route = &*Regex::new(r"/\.\./")?.replace_all(route, "/").into_owned();
-    // Regex replace to remove query string
-    let route = &*Regex::new(r"\?[^ ]+")?.replace(route, "").into_owned();
+    }
+    parts.push(value[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
 
-    let request_info = RequestInfo::new(&conn, route, &blacklisted_paths);
+/// A value in a structured field, see [`parse_sf_item`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BareItem {
+    Integer(i64),
+    Decimal(f64),
+    String(String),
+    Token(String),
+    ByteSequence(Vec<u8>),
+    Boolean(bool),
+}
+
+/// The parameters of a structured field item, in order
+pub type Parameters = Vec<(String, BareItem)>;
 
-    let mut response: Box<dyn Sendable> = Box::new(Page::new(404, String::from("Not found")));
-    for handler in &routes {
-        if handler.route() == route {
-            response = (handler.handler())(&request_info);
-            break;
-        } else if handler.route() == "404" {
-            response = (handler.handler())(&request_info);
+/// A structured field item with its parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub value: BareItem,
+    pub params: Parameters,
+}
+
+/// A member of a structured field list or dictionary
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListMember {
+    Item(Item),
+    InnerList(Vec<Item>, Parameters),
+}
+
+/// Parses a structured field item (RFC 8941)
+/// 
+/// Returns `None` if the value is not a valid item.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::{parse_sf_item, BareItem};
+/// 
+/// let item = parse_sf_item("\"text\"; lang=en; final").unwrap();
+/// assert_eq!(item.value, BareItem::String(String::from("text")));
+/// assert_eq!(item.params[0], (String::from("lang"), BareItem::Token(String::from("en"))));
+/// assert_eq!(item.params[1], (String::from("final"), BareItem::Boolean(true)));
+/// assert!(parse_sf_item("1, 2").is_none());
+/// ```
+pub fn parse_sf_item(value: &str) -> Option<Item> {
+    let mut parser = SfParser::new(value);
+    let item = parser.item()?;
+    parser.finish(item)
+}
+
+/// Parses a structured field list (RFC 8941)
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::{parse_sf_list, BareItem, ListMember};
+/// 
+/// let list = parse_sf_list("1, (\"a\" \"b\");q=?0, :aGk=:").unwrap();
+/// assert_eq!(list.len(), 3);
+/// assert!(matches!(&list[1], ListMember::InnerList(items, _) if items.len() == 2));
+/// assert!(matches!(&list[2], ListMember::Item(item) if item.value == BareItem::ByteSequence(b"hi".to_vec())));
+/// ```
+pub fn parse_sf_list(value: &str) -> Option<Vec<ListMember>> {
+    let mut parser = SfParser::new(value);
+    let mut members = Vec::new();
+    while !parser.at_end() {
+        members.push(parser.member()?);
+        if !parser.next_in_list() {
+            return None;
         }
     }
+    parser.finish(members)
+}
 
-    response.send(&mut conn).await?;
-    conn.stream().flush().await?;
-    Ok(())
+/// Parses a structured field dictionary (RFC 8941)
+/// 
+/// A key without a value is `true`. A repeated key keeps its first position and its last value.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::{parse_sf_dictionary, BareItem, ListMember};
+/// 
+/// let dictionary = parse_sf_dictionary("u=1, i, a=4.5").unwrap();
+/// assert_eq!(dictionary[1].0, "i");
+/// assert!(matches!(&dictionary[1].1, ListMember::Item(item) if item.value == BareItem::Boolean(true)));
+/// assert!(matches!(&dictionary[2].1, ListMember::Item(item) if item.value == BareItem::Decimal(4.5)));
+/// ```
+pub fn parse_sf_dictionary(value: &str) -> Option<Vec<(String, ListMember)>> {
+    let mut parser = SfParser::new(value);
+    let mut members: Vec<(String, ListMember)> = Vec::new();
+    while !parser.at_end() {
+        let key = parser.key()?;
+        let member = if parser.eat(b'=') {
+            parser.member()?
+        } else {
+            ListMember::Item(Item {
+                value: BareItem::Boolean(true),
+                params: parser.params()?,
+            })
+        };
+        match members.iter_mut().find(|(existing, _)| *existing == key) {
+            Some(existing) => existing.1 = member,
+            None => members.push((key, member)),
+        }
+        if !parser.next_in_list() {
+            return None;
+        }
+    }
+    parser.finish(members)
 }
 
-async fn handle_https_connection(mut conn: ConnectionInfo, routes: Vec<Handler>, blacklisted_paths: Vec<path::PathBuf>) -> Result<(), Box<dyn Error>> {
-    let buf_reader = BufReader::new(conn.ssl_stream());
-    let request_line = match buf_reader.lines().next_line().await? {
-        Some(line) => line,
-        None => {
-            println!("No request line found");
-            return Err(Box::new(errors::OptionUnwrapError {}));
+/// A parser for structured fields, following the algorithms of RFC 8941 section 4.2
+struct SfParser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SfParser<'a> {
+    fn new(value: &'a str) -> SfParser<'a> {
+        SfParser {
+            input: value.trim_matches(' ').as_bytes(),
+            position: 0,
         }
-    };
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.input.len()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.eat(b' ') {}
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.position += 1;
+        }
+    }
+
+    /// Moves past the comma between members, failing on a trailing comma
+    fn next_in_list(&mut self) -> bool {
+        self.skip_whitespace();
+        if self.at_end() {
+            return true;
+        }
+        if !self.eat(b',') {
+            return false;
+        }
+        self.skip_whitespace();
+        !self.at_end()
+    }
+
+    fn finish<T>(&self, parsed: T) -> Option<T> {
+        self.at_end().then_some(parsed)
+    }
+
+    fn member(&mut self) -> Option<ListMember> {
+        if !self.eat(b'(') {
+            return self.item().map(ListMember::Item);
+        }
+        let mut items = Vec::new();
+        loop {
+            self.skip_spaces();
+            if self.eat(b')') {
+                return Some(ListMember::InnerList(items, self.params()?));
+            }
+            items.push(self.item()?);
+            if !matches!(self.peek(), Some(b' ' | b')')) {
+                return None;
+            }
+        }
+    }
+
+    fn item(&mut self) -> Option<Item> {
+        let value = self.bare_item()?;
+        Some(Item {
+            value,
+            params: self.params()?,
+        })
+    }
+
+    fn params(&mut self) -> Option<Parameters> {
+        let mut params: Parameters = Vec::new();
+        while self.eat(b';') {
+            self.skip_spaces();
+            let key = self.key()?;
+            let value = if self.eat(b'=') { self.bare_item()? } else { BareItem::Boolean(true) };
+            match params.iter_mut().find(|(existing, _)| *existing == key) {
+                Some(existing) => existing.1 = value,
+                None => params.push((key, value)),
+            }
+        }
+        Some(params)
+    }
+
+    fn key(&mut self) -> Option<String> {
+        let start = self.position;
+        if !matches!(self.peek(), Some(b'a'..=b'z' | b'*')) {
+            return None;
+        }
+        while matches!(self.peek(), Some(b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-' | b'.' | b'*')) {
+            self.position += 1;
+        }
+        Some(String::from_utf8_lossy(&self.input[start..self.position]).into_owned())
+    }
 
+    fn bare_item(&mut self) -> Option<BareItem> {
+        match self.peek()? {
+            b'-' | b'0'..=b'9' => self.number(),
+            b'"' => self.string(),
+            b'*' | b'A'..=b'Z' | b'a'..=b'z' => Some(self.token()),
+            b':' => self.byte_sequence(),
+            b'?' => self.boolean(),
+            _ => None,
+        }
+    }
+
+    fn number(&mut self) -> Option<BareItem> {
+        let start = self.position;
+        self.eat(b'-');
+        let digits_start = self.position;
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.position += 1;
+        }
+        let integer_digits = self.position - digits_start;
+        if integer_digits == 0 {
+            return None;
+        }
+        if !self.eat(b'.') {
+            if integer_digits > 15 {
+                return None;
+            }
+            let number = std::str::from_utf8(&self.input[start..self.position]).ok()?;
+            return number.parse().ok().map(BareItem::Integer);
+        }
+        let fraction_start = self.position;
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.position += 1;
+        }
+        let fraction_digits = self.position - fraction_start;
+        if integer_digits > 12 || fraction_digits == 0 || fraction_digits > 3 {
+            return None;
+        }
+        let number = std::str::from_utf8(&self.input[start..self.position]).ok()?;
+        number.parse().ok().map(BareItem::Decimal)
+    }
+
+    fn string(&mut self) -> Option<BareItem> {
+        self.eat(b'"');
+        let mut string = String::new();
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.position += 1;
+                    return Some(BareItem::String(string));
+                },
+                b'\\' => {
+                    self.position += 1;
+                    match self.peek()? {
+                        escaped @ (b'"' | b'\\') => string.push(escaped as char),
+                        _ => return None,
+                    }
+                },
+                byte @ 0x20..=0x7e => string.push(byte as char),
+                _ => return None,
+            }
+            self.position += 1;
+        }
+    }
+
+    fn token(&mut self) -> BareItem {
+        let start = self.position;
+        self.position += 1;
+        while let Some(byte) = self.peek() {
+            let tchar = byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~:/".contains(&byte);
+            if !tchar {
+                break;
+            }
+            self.position += 1;
+        }
+        BareItem::Token(String::from_utf8_lossy(&self.input[start..self.position]).into_owned())
+    }
+
+    fn byte_sequence(&mut self) -> Option<BareItem> {
+        self.eat(b':');
+        let start = self.position;
+        while matches!(self.peek(), Some(byte) if byte.is_ascii_alphanumeric() || b"+/=".contains(&byte)) {
+            self.position += 1;
+        }
+        let encoded = std::str::from_utf8(&self.input[start..self.position]).ok()?;
+        if !self.eat(b':') {
+            return None;
+        }
+        openssl::base64::decode_block(encoded).ok().map(BareItem::ByteSequence)
+    }
+
+    fn boolean(&mut self) -> Option<BareItem> {
+        self.eat(b'?');
+        let value = match self.peek()? {
+            b'1' => true,
+            b'0' => false,
+            _ => return None,
+        };
+        self.position += 1;
+        Some(BareItem::Boolean(value))
+    }
+}
+
+/// Extracts the route from a request line
+/// 
+/// The query string is removed, then the route is URL decoded and normalized with [`normalize_path`].
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::parse_route;
+/// 
+/// assert_eq!(parse_route("GET /index.html?page=2 HTTP/1.1").unwrap(), "/index.html");
+/// assert_eq!(parse_route("GET /%2e%2e/Cargo.lock HTTP/1.1").unwrap(), "/Cargo.lock");
+/// assert!(parse_route("GET").is_err());
+/// ```
+pub fn parse_route(request_line: &str) -> Result<String, Box<dyn Error>> {
     let route = match request_line.split_whitespace().nth(1) {
         Some(route) => route,
         None => {
@@ -111,49 +622,512 @@ async fn handle_https_connection(mut conn: ConnectionInfo, routes: Vec<Handler>,
             return Err(Box::new(errors::OptionUnwrapError {}));
         }
     };
+    // Remove the query string before decoding, so an encoded `?` stays part of the path
+    let route = match route.split_once('?') {
+        Some((path, _)) => path,
+        None => route,
+    };
+    let route = urlencoding::decode(route)?;
+    Ok(normalize_path(&route))
+}
+
+/// Normalizes a decoded path
+/// 
+/// Empty and `.` segments are removed and `..` segments remove the segment before them.
+/// The result always starts with `/` and can never climb above it. A trailing slash is kept.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::normalize_path;
+/// 
+/// assert_eq!(normalize_path("/static/./css//../app.js"), "/static/app.js");
+/// assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+/// assert_eq!(normalize_path("/docs/"), "/docs/");
+/// ```
+pub fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop();
+            },
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = String::from("/") + &segments.join("/");
+    if !segments.is_empty() && path.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Matches a route against a route pattern
+/// 
+/// A pattern segment starting with `:` matches any one non-empty segment, and a last segment
+/// starting with `*` matches the rest of the route. Returns the captured values by name,
+/// or `None` if the route does not match.
+/// 
+/// # Examples
+/// ```
+/// use simpleserve::utils::match_route;
+/// 
+/// let params = match_route("/users/:id", "/users/42").unwrap();
+/// assert_eq!(params["id"], "42");
+/// let params = match_route("/static/*path", "/static/css/app.css").unwrap();
+/// assert_eq!(params["path"], "css/app.css");
+/// assert!(match_route("/users/:id", "/users/42/posts").is_none());
+/// assert!(match_route("/about", "/about").unwrap().is_empty());
+/// ```
+pub fn match_route(pattern: &str, route: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    if !is_pattern(pattern) {
+        return (pattern == route).then_some(params);
+    }
+    let mut pattern_segments = pattern.split('/');
+    let mut route_segments = route.split('/');
+    loop {
+        match (pattern_segments.next(), route_segments.next()) {
+            (Some(segment), Some(first)) if segment.starts_with('*') => {
+                let rest: Vec<&str> = std::iter::once(first).chain(route_segments).collect();
+                params.insert(String::from(&segment[1..]), rest.join("/"));
+                return Some(params);
+            },
+            (Some(segment), Some(value)) if segment.starts_with(':') && !value.is_empty() => {
+                params.insert(String::from(&segment[1..]), String::from(value));
+            },
+            (Some(segment), Some(value)) if segment == value => {},
+            (None, None) => return Some(params),
+            _ => return None,
+        }
+    }
+}
 
-    let route = &*urlencoding::decode(route)?.into_owned();
+fn is_pattern(route: &str) -> bool {
+    route.split('/').any(|segment| segment.starts_with(':') || segment.starts_with('*'))
+}
 
-    let route = &*Regex::new(r"/\.\./")?.replace_all(route, "/").into_owned();
-    // Regex replace to remove query string
-    let route = &*Regex::new(r"\?[^ ]+")?.replace(route, "").into_owned();
+/// How specific a route pattern is, lower is more specific
+/// 
+/// Exact routes come first, then patterns without a wildcard, then patterns with fewer parameters.
+fn specificity(route: &str) -> (bool, bool, usize) {
+    (
+        is_pattern(route),
+        route.split('/').any(|segment| segment.starts_with('*')),
+        route.split('/').filter(|segment| segment.starts_with(':')).count(),
+    )
+}
 
-    let request_info = RequestInfo::new(&conn, route, &blacklisted_paths);
+/// Finds the handler for a route and method
+/// 
+/// Returns the most specific handler whose route matches and that is registered for this method
+/// (or for any method), or the 404 handler if there is none. See [`match_route`].
+/// 
+/// A handler for GET also answers HEAD, unless the route has a handler for HEAD of its own.
+pub fn find_handler<'a>(routes: &'a [Handler], route: &str, method: &Method) -> Option<&'a Handler> {
+    routes.iter()
+        .filter(|handler| handler.route() != "404" && handler.method().is_none_or(|m| serves(m, method)))
+        .filter(|handler| match_route(handler.route(), route).is_some())
+        .min_by_key(|handler| (specificity(handler.route()), handler.method().is_some_and(|m| m != method)))
+        .or_else(|| routes.iter().find(|handler| handler.route() == "404"))
+}
 
-    let mut response: Box<dyn Sendable> = Box::new(Page::new(404, String::from("Not found")));
-    for handler in &routes {
-        if handler.route() == route {
-            response = (handler.handler())(&request_info);
-            break;
-        } else if handler.route() == "404" {
-            response = (handler.handler())(&request_info);
+/// Whether a handler registered for a method answers a request with another
+fn serves(registered: &Method, method: &Method) -> bool {
+    registered == method || (*registered == Method::Get && *method == Method::Head)
+}
+
+/// The methods a route has handlers for
+/// 
+/// Empty if the route does not exist. A route with a handler for any method returns every method,
+/// and a route with a handler for GET returns HEAD as well.
+pub fn allowed_methods(routes: &[Handler], route: &str) -> Vec<Method> {
+    let mut methods = Vec::new();
+    for handler in routes.iter().filter(|handler| handler.route() != "404" && match_route(handler.route(), route).is_some()) {
+        let method = match handler.method() {
+            Some(method) => method,
+            None => return Method::ALL.to_vec(),
+        };
+        let implied = match method {
+            Method::Get => Some(Method::Head),
+            _ => None,
+        };
+        for method in std::iter::once(method.clone()).chain(implied) {
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
         }
     }
+    methods
+}
 
-    response.send(&mut conn).await?;
-    conn.stream().flush().await?;
+/// Handles a single connection
+/// 
+/// Requests are read and routed on the calling thread. If a handler is hinted as CPU-bound
+/// and the server has a CPU pool, the rest of that request is handed off to the pool, and the
+/// connection is closed once it has been answered.
+/// 
+/// HTTP/1.1 connections are kept open for further requests until the client asks to close them,
+/// they are idle for longer than the keep-alive timeout, or they reach the maximum number of requests.
+/// 
+/// A client that takes longer than the header timeout to send its first request head, or longer
+/// than the read timeout to send a body, is answered with 408 Request Timeout and disconnected.
+/// 
+/// # Arguments
+/// * `conn` - The connection to handle
+/// * `context` - The state of the server
+pub async fn handle_connection(mut conn: ConnectionInfo, context: ServerContext) -> Result<(), Box<dyn Error>> {
+    let mut active = ActiveConnection::new(&context.stats);
+    let mut served = 0;
+    conn.set_write_timeout(Some(context.write_timeout));
+    conn.set_shutdown(context.shutdown.clone());
+    // The handshake and the first head share one deadline
+    let deadline = conn.accepted() + context.header_timeout;
+    match tokio::time::timeout_at(deadline, conn.handshake()).await {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => return Err(Box::new(e)),
+        Err(_) => return Ok(()),
+    }
+    loop {
+        let deadline = if served == 0 { deadline } else { tokio::time::Instant::now() + context.keep_alive_timeout };
+        // A connection waiting for a request is idle, so it is closed when the server shuts down
+        let head = tokio::select! {
+            head = tokio::time::timeout_at(deadline, conn.read_head_limited(context.max_request_line, context.max_header_size)) => match head {
+                Ok(Ok(head)) => head,
+                Ok(Err(e)) if e.get_ref().is_some_and(|inner| inner.is::<MalformedRequestError>()) => {
+                    return reject(conn, context.theme.page(400, "Bad Request", "The request could not be understood."), e).await;
+                },
+                Ok(Err(e)) => match e.get_ref().and_then(|inner| inner.downcast_ref::<RequestTooLargeError>()) {
+                    Some(too_large) => return reject(conn, too_large_page(&context.theme, None, *too_large), *too_large).await,
+                    None => return Err(Box::new(e)),
+                },
+                // A client that stalls in the middle of a request, or never sends one, is told why
+                Err(_) if served == 0 || conn.has_buffered() => {
+                    return time_out(conn, context.theme.page(408, "Request Timeout", "The request took too long to arrive.")).await;
+                },
+                Err(_) => return Ok(()),
+            },
+            _ = context.shutdown.wait() => return Ok(()),
+        };
+        let head = match head {
+            Some(head) => head,
+            // The client closed a kept-alive connection
+            None if served > 0 => return Ok(()),
+            None => {
+                println!("No request line found");
+                return Err(Box::new(errors::OptionUnwrapError {}));
+            }
+        };
+        served += 1;
+        let keep_alive = served < context.max_requests_per_connection && !context.shutdown.is_shutting_down();
+        match handle_request(conn, &context, head, active, keep_alive).await? {
+            Some((reused, still_active)) => {
+                conn = reused;
+                active = still_active;
+            },
+            None => return Ok(()),
+        }
+    }
+}
 
-    Ok(())
+/// Runs a connection job, dropping it along with its connection if the server force-closes connections
+/// 
+/// Dropping the job ends whatever it waits for, such as the next event of a stream. The connection
+/// is counted in [`ServerStats::connections_force_closed`].
+pub(crate) async fn abort_on_force_close<F>(shutdown: &ShutdownHandle, stats: &ServerStats, job: F) -> Result<(), Box<dyn Error>>
+where
+    F: Future<Output = Result<(), Box<dyn Error>>>,
+{
+    tokio::select! {
+        // Checked first, so a job failing because its connection was closed is still counted
+        biased;
+        _ = shutdown.force_closed() => {
+            stats.connection_force_closed();
+            Ok(())
+        },
+        handled = job => handled,
+    }
 }
 
-pub fn base_file_handler(request: &RequestInfo) -> Box<dyn Sendable> {
-    // This handles files based on route
-    match request.conn.connection_type() {
-        ConnectionType::Http => {
-            handle_http_file(request)
+/// Handles one request on a connection
+/// 
+/// Returns the connection if it can be used for another request.
+async fn handle_request(
+    mut conn: ConnectionInfo,
+    context: &ServerContext,
+    head: String,
+    active: ActiveConnection,
+    keep_alive: bool
+) -> Result<Option<(ConnectionInfo, ActiveConnection)>, Box<dyn Error>> {
+    let theme = &context.theme;
+    let (request_line, headers) = match request::parse_head(&head) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return reject(conn, theme.page(400, "Bad Request", "The request could not be understood."), e).await;
+        }
+    };
+    let language = headers.get("accept-language");
+    let method = match request::parse_method(request_line) {
+        Ok(method) => method,
+        Err(e) => {
+            return reject(conn, theme.localized_page(language, 501, "Not Implemented", "The request method is not supported."), e).await;
+        }
+    };
+    let route = match parse_route(request_line) {
+        Ok(route) => route,
+        Err(_) => {
+            let e = MalformedRequestError::new("Missing route");
+            return reject(conn, theme.localized_page(language, 400, "Bad Request", "The request could not be understood."), e).await;
+        }
+    };
+    let length = match headers.content_length() {
+        Ok(length) => length.unwrap_or(0),
+        Err(e) => {
+            return reject(conn, theme.localized_page(language, 400, "Bad Request", "The request could not be understood."), e).await;
+        }
+    };
+    if headers.contains("transfer-encoding") {
+        let e = MalformedRequestError::new("Transfer-Encoding is not supported");
+        return reject(conn, theme.localized_page(language, 501, "Not Implemented", "Chunked request bodies are not supported."), e).await;
+    }
+    if headers.len() > context.max_headers {
+        let e = RequestTooLargeError::Headers;
+        return reject(conn, too_large_page(theme, language, e), e).await;
+    }
+    // Multipart bodies are parsed as they arrive, so they are not held in memory
+    let boundary = headers.get("content-type").and_then(multipart::boundary).map(String::from);
+    let fallback = if boundary.is_some() { context.multipart.max_size() } else { context.max_body_size };
+    // The body is read before middleware runs, so the checks are those of the route in the request line
+    let handler = find_handler(&context.routes, &route, &method);
+    let accepted = handler.map(Handler::accepted_types).unwrap_or_default();
+    // A request without a body has no type to refuse
+    if length > 0 && !accepted.is_empty() && !headers.has_mime_type(accepted) {
+        let e = UnsupportedMediaTypeError::new(headers.get("content-type"));
+        return reject(conn, unsupported_type_page(theme, language, &method, accepted), e).await;
+    }
+    let limits = handler.and_then(Handler::body_limits).unwrap_or(&context.body_limits);
+    let max_body_size = match limits.max_size(headers.get("content-type"), fallback) {
+        Ok(max_body_size) => max_body_size,
+        Err(_) if length == 0 => fallback,
+        Err(e) => {
+            let listed: Vec<&str> = limits.types().map(|(mime_type, _)| mime_type).collect();
+            return reject(conn, unsupported_type_page(theme, language, &method, &listed), e).await;
+        }
+    };
+    if length > max_body_size {
+        let e = RequestTooLargeError::Body;
+        return reject(conn, too_large_page(theme, language, e), e).await;
+    }
+    let read = async {
+        match &boundary {
+            Some(boundary) => multipart::read(&mut conn, length, boundary, context.multipart).await.map(|streamed| (Vec::new(), Some(streamed))),
+            None => conn.read_body(length).await.map(|body| (body, None)),
+        }
+    };
+    let (body, streamed) = match tokio::time::timeout(context.read_timeout, read).await {
+        Ok(body) => body?,
+        Err(_) => {
+            time_out(conn, theme.localized_page(language, 408, "Request Timeout", "The request took too long to arrive.")).await?;
+            return Ok(None);
+        }
+    };
+    let keep_alive = keep_alive && request::wants_keep_alive(request_line, &headers);
+    let peer_addr = conn.peer_addr();
+    let geo = match (&context.geo_resolver, peer_addr) {
+        (Some(resolver), Some(addr)) => resolver.resolve(addr.ip()),
+        _ => None,
+    };
+    let query = request::query_string(request_line)
+        .map(request::parse_query)
+        .unwrap_or_default();
+    let mut request = Request {
+        method,
+        route,
+        params: HashMap::new(),
+        query,
+        headers,
+        body,
+        geo,
+        peer_addr,
+        extensions: Extensions::new(),
+    };
+    if let Some(streamed) = streamed {
+        request.extensions.insert(streamed);
+    }
+
+    let pre_routing = context.middleware.iter()
+        .take_while(|middleware| middleware::runs_before_routing(middleware.as_ref()))
+        .count();
+    let outcome = match intercept(context, &mut request, 0..pre_routing) {
+        Some(outcome) => outcome,
+        None => {
+            let handler = find_handler(&context.routes, &request.route, &request.method).cloned();
+            if let Some(params) = handler.as_ref().and_then(|handler| match_route(handler.route(), &request.route)) {
+                request.params = params;
+            }
+            intercept(context, &mut request, pre_routing..context.middleware.len()).unwrap_or(Outcome::Handler(handler))
+        }
+    };
+
+    match (&outcome, &context.cpu_pool) {
+        (Outcome::Handler(Some(cpu_handler)), Some(cpu_pool)) if cpu_handler.pool_hint() == PoolHint::Cpu => {
+            let cpu_pool = Arc::clone(cpu_pool);
+            let context = context.clone();
+            cpu_pool.execute(move || {
+                let rt = Runtime::new().unwrap();
+                let responded = async {
+                    respond(&mut conn, &context, &request, outcome, &active).await.map(|_| ())
+                };
+                if let Err(e) = rt.block_on(abort_on_force_close(&context.shutdown, &context.stats, responded)) {
+                    println!("Error handling connection: {}", e);
+                }
+            });
+            Ok(None)
         },
-        ConnectionType::Https => {
-            handle_https_file(request)
+        _ => {
+            let delimited = respond(&mut conn, context, &request, outcome, &active).await?;
+            Ok((keep_alive && delimited).then_some((conn, active)))
+        },
+    }
+}
+
+/// What a request is answered with
+enum Outcome {
+    /// The handler the request was routed to
+    Handler(Option<Handler>),
+    /// A response from a middleware, with the number of middleware that ran
+    Intercepted(Box<dyn Sendable>, usize),
+}
+
+/// Runs the `before` hook of the middleware in a range, until one of them responds
+fn intercept(context: &ServerContext, request: &mut Request, range: Range<usize>) -> Option<Outcome> {
+    for i in range {
+        if let Some(response) = context.middleware[i].before(request) {
+            return Some(Outcome::Intercepted(response, i + 1));
         }
     }
+    None
 }
 
-fn handle_http_file(request: &RequestInfo) -> Box<dyn Sendable> {
-    Box::new(Bytes::new(200, &request.route[1..]).unwrap())
+/// Answers a request that did not arrive in time, and closes the connection
+async fn time_out(mut conn: ConnectionInfo, page: impl Sendable) -> Result<(), Box<dyn Error>> {
+    println!("Request timed out");
+    page.send(&mut conn).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// The page for a request larger than the limits
+fn too_large_page(theme: &Theme, language: Option<&str>, error: RequestTooLargeError) -> Response {
+    let message = match error {
+        RequestTooLargeError::RequestLine => "The request line is too long.",
+        RequestTooLargeError::Headers => "The request headers are too large.",
+        RequestTooLargeError::Body => "The request body is too large.",
+    };
+    theme.localized_page(language, error.status(), StatusCode::from(error.status()).reason_phrase(), message)
+}
+
+/// The page for a request body of a type the route does not accept
+/// 
+/// Lists the accepted types in `Accept-Patch` for PATCH requests, and in `Accept-Post` otherwise.
+fn unsupported_type_page<S: AsRef<str>>(theme: &Theme, language: Option<&str>, method: &Method, accepted: &[S]) -> Response {
+    let page = theme.localized_page(language, 415, "Unsupported Media Type", "The request body has a type this route does not accept.");
+    if accepted.is_empty() {
+        return page;
+    }
+    // A type ending in `/` accepts every subtype, which is written with a `*`
+    let accepted: Vec<String> = accepted.iter()
+        .map(|mime_type| match mime_type.as_ref() {
+            mime_type if mime_type.ends_with('/') => format!("{}*", mime_type),
+            mime_type => String::from(mime_type),
+        })
+        .collect();
+    let name = if *method == Method::Patch { "Accept-Patch" } else { "Accept-Post" };
+    page.header(name, &accepted.join(", "))
 }
 
-fn handle_https_file(request: &RequestInfo) -> Box<dyn Sendable> {
-    Box::new(Bytes::new(200, &request.route).unwrap())
+/// Sends an error page for a request that could not be parsed
+async fn reject<T, E: Error + 'static>(mut conn: ConnectionInfo, page: impl Sendable, error: E) -> Result<T, Box<dyn Error>> {
+    println!("{}", error);
+    page.send(&mut conn).await?;
+    conn.flush().await?;
+    Err(Box::new(error))
+}
+
+/// Answers a request, returning whether the response marked where it ends
+async fn respond(
+    conn: &mut ConnectionInfo,
+    context: &ServerContext,
+    request: &Request,
+    outcome: Outcome,
+    active: &ActiveConnection
+) -> Result<bool, Box<dyn Error>> {
+    let request_info = RequestInfo::new(conn, request, context);
+
+    let (handler, ran) = match outcome {
+        Outcome::Handler(handler) => (handler, context.middleware.len()),
+        Outcome::Intercepted(response, ran) => {
+            let response = wrap(context, &request_info, response, ran);
+            return send(conn, request, response, active).await;
+        }
+    };
+    // Transactions the handler drops are rolled back before the response goes out
+    let handled = async {
+        match handler {
+            Some(handler) if !context.ready.load(Ordering::SeqCst) && !handler.is_health_check() => {
+                Box::new(request_info.error_page(503, "Service Unavailable", "The server is starting up, please try again shortly."))
+            },
+            Some(handler) if handler.route() == "404" => {
+                let allowed = allowed_methods(&context.routes, &request.route);
+                if allowed.is_empty() {
+                    handler.call(&request_info).await
+                } else {
+                    let allowed: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+                    let allowed = allowed.join(", ");
+                    let message = format!("Allowed methods: {}", allowed);
+                    Box::new(request_info.error_page(405, "Method Not Allowed", &message)
+                        .header("Allow", &allowed))
+                }
+            },
+            Some(handler) => handler.call(&request_info).await,
+            None => Box::new(request_info.error_page(404, "Not Found", "The requested page could not be found.")),
+        }
+    };
+    let response: Box<dyn Sendable> = pool::rolling_back(handled).await;
+    let response = wrap(context, &request_info, response, ran);
+    send(conn, request, response, active).await
+}
+
+/// Passes a response back through the `after` hook of the middleware that ran
+/// 
+/// The hooks run in reverse order within their phase. See [`middleware::after_order`].
+fn wrap(context: &ServerContext, request: &RequestInfo, response: Box<dyn Sendable>, ran: usize) -> Box<dyn Sendable> {
+    middleware::after_order(&context.middleware[..ran])
+        .into_iter()
+        .fold(response, |response, middleware| middleware.after(request, response))
+}
+
+/// Sends a response, leaving out its body if the request was HEAD
+async fn send(conn: &mut ConnectionInfo, request: &Request, response: Box<dyn Sendable>, active: &ActiveConnection) -> Result<bool, Box<dyn Error>> {
+    let delimited = response.is_delimited();
+    conn.set_omit_body(request.method == Method::Head);
+    let sent = response.send(conn).await;
+    conn.set_omit_body(false);
+    sent?;
+    conn.flush().await?;
+    active.served();
+    Ok(delimited)
+}
+
+pub fn base_file_handler(request: &RequestInfo) -> Box<dyn Sendable> {
+    // This handles files based on route, relative to the working directory over HTTP and HTTPS alike
+    match Bytes::new(200, &request.route[1..]) {
+        Ok(bytes) => Box::new(bytes.for_request(request)),
+        Err(e) => {
+            println!("Error reading file: {}", e);
+            Box::new(request.error_page(500, "Internal Server Error", "The file could not be read."))
+        }
+    }
 }
 
 pub fn base_not_found_handler(request: &RequestInfo) -> Box<dyn Sendable> {
@@ -161,13 +1135,14 @@ pub fn base_not_found_handler(request: &RequestInfo) -> Box<dyn Sendable> {
     if let Ok(bytes) = Bytes::new(200, &request.route[1..]) {
         for path in request.blacklisted_paths {
             if path == bytes.file_location() {
-                return Box::new(Page::new(403, String::from("Forbidden")));
+                return Box::new(request.error_page(403, "Forbidden", "You do not have permission to access this page."));
             }
         }
         println!("Sending file: {}", bytes.file_location().to_str().unwrap());
-        Box::new(bytes)
-    } else {
-        let content = fs::read_to_string("404.html").unwrap();
+        Box::new(bytes.for_request(request))
+    } else if let Ok(content) = fs::read_to_string("404.html") {
         Box::new(Page::new(404, content))
+    } else {
+        Box::new(request.error_page(404, "Not Found", "The requested page could not be found."))
     }
 }
\ No newline at end of file
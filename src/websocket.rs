@@ -0,0 +1,352 @@
+//! WebSockets
+//!
+//! [`Webserver::on_websocket`](crate::Webserver::on_websocket) adds a route that upgrades GET
+//! requests to a WebSocket. The handler gets a [`WebSocket`] to receive and send text and binary
+//! [`Message`]s until either side closes it. Pings are answered automatically.
+//!
+//! Every open socket keeps a worker of the thread pool busy, so the pool has to be sized for the
+//! expected number of sockets.
+//!
+//! ## Example
+//! ```
+//! use simpleserve::{
+//!     Webserver,
+//!     websocket::{Message, WebSocket, WebSocketFuture}
+//! };
+//!
+//! fn echo<'a>(mut socket: WebSocket<'a>) -> WebSocketFuture<'a> {
+//!     Box::pin(async move {
+//!         while let Ok(Some(message)) = socket.receive().await {
+//!             if socket.send(message).await.is_err() {
+//!                 break;
+//!             }
+//!         }
+//!     })
+//! }
+//!
+//! let mut server = Webserver::new(10, vec![]);
+//! server.on_websocket("/echo", echo);
+//! ```
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc
+};
+
+use async_trait::async_trait;
+use openssl::{base64, sha::sha1};
+
+use crate::{
+    request::Headers,
+    response::Response,
+    status::StatusCode,
+    server::{
+        ConnectionInfo,
+        RequestInfo,
+        Sendable,
+        DEFAULT_MAX_BODY_SIZE
+    },
+    utils
+};
+
+/// The future returned by a WebSocket handler
+pub type WebSocketFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// A WebSocket handler
+///
+/// Runs for as long as the socket should stay open.
+pub type WebSocketHandler = Arc<dyn for<'a> Fn(WebSocket<'a>) -> WebSocketFuture<'a> + Send + Sync>;
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`, as defined in RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Close codes defined in RFC 6455
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+}
+
+/// A message received from or sent to the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`
+///
+/// # Examples
+/// ```
+/// use simpleserve::websocket::accept_key;
+///
+/// // The example from RFC 6455
+/// assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+/// ```
+pub fn accept_key(key: &str) -> String {
+    base64::encode_block(&sha1(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes()))
+}
+
+/// Whether a header value contains a token, such as `Connection: keep-alive, Upgrade`
+fn has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .flat_map(utils::split_list)
+        .any(|value| value.eq_ignore_ascii_case(token))
+}
+
+/// Answers a request to a WebSocket route, with the handshake or an error
+pub(crate) fn upgrade(request: &RequestInfo, handler: &WebSocketHandler) -> Box<dyn Sendable> {
+    let headers = request.headers();
+    if !has_token(headers, "upgrade", "websocket") || !has_token(headers, "connection", "upgrade") {
+        return Box::new(Response::new(426)
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .text("This route only accepts WebSocket connections"));
+    }
+    if headers.get("sec-websocket-version") != Some("13") {
+        return Box::new(Response::new(426)
+            .header("Sec-WebSocket-Version", "13")
+            .text("Unsupported WebSocket version"));
+    }
+    let key = match headers.get("sec-websocket-key") {
+        Some(key) if base64::decode_block(key.trim()).is_ok_and(|key| key.len() == 16) => key,
+        _ => return Box::new(Response::new(400).text("Missing or invalid Sec-WebSocket-Key")),
+    };
+    Box::new(Upgrade {
+        accept: accept_key(key),
+        handler: Arc::clone(handler),
+        route: String::from(request.route),
+        params: request.params.clone(),
+        query: request.query.clone(),
+    })
+}
+
+/// The handshake response, which hands the connection to the handler once it is sent
+struct Upgrade {
+    accept: String,
+    handler: WebSocketHandler,
+    route: String,
+    params: HashMap<String, String>,
+    query: HashMap<String, String>,
+}
+
+#[async_trait]
+impl Sendable for Upgrade {
+    fn render(&self) -> String {
+        format!(
+            "{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            StatusCode::SWITCHING_PROTOCOLS.status_line(),
+            self.accept
+        )
+    }
+
+    fn is_delimited(&self) -> bool {
+        false
+    }
+
+    async fn send(&self, conn: &mut ConnectionInfo) -> Result<(), io::Error> {
+        conn.write_all(self.render().as_bytes()).await?;
+        conn.flush().await?;
+        let socket = WebSocket {
+            conn,
+            route: self.route.clone(),
+            params: self.params.clone(),
+            query: self.query.clone(),
+            max_message_size: DEFAULT_MAX_BODY_SIZE,
+            closed: false,
+        };
+        (self.handler)(socket).await;
+        Ok(())
+    }
+}
+
+/// An open WebSocket connection
+pub struct WebSocket<'a> {
+    conn: &'a mut ConnectionInfo,
+    route: String,
+    params: HashMap<String, String>,
+    query: HashMap<String, String>,
+    max_message_size: usize,
+    closed: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    /// The route of the upgrade request
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// The value of a route parameter of the upgrade request
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// The value of a query string parameter of the upgrade request
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        self.query.get(key).map(String::as_str)
+    }
+
+    /// Sets the largest message accepted, in bytes
+    ///
+    /// Defaults to [`DEFAULT_MAX_BODY_SIZE`]. Larger messages close the socket.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Waits for the next message
+    ///
+    /// Returns `None` once the socket is closed. Pings are answered while waiting. Protocol errors
+    /// close the socket and are returned as [`io::ErrorKind::InvalidData`].
+    pub async fn receive(&mut self) -> Result<Option<Message>, io::Error> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        while !self.closed {
+            let frame = match self.read_frame().await {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.closed = true;
+                    return Ok(None);
+                },
+                Err(e) => return Err(e),
+            };
+            match (frame.opcode, &mut message) {
+                (0x8, _) => {
+                    // Echo the code back, as the closing handshake expects
+                    let code = frame.payload.get(..2).map(|code| u16::from_be_bytes([code[0], code[1]]));
+                    self.close(code.unwrap_or(close_code::NORMAL), "").await?;
+                    return Ok(None);
+                },
+                (0x9, _) => self.write_frame(0xA, &frame.payload).await?,
+                (0xA, _) => {},
+                (0x1 | 0x2, None) => message = Some((frame.opcode, frame.payload)),
+                (0x0, Some((_, data))) => {
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(close_code::MESSAGE_TOO_BIG, "Message too big").await);
+                    }
+                    data.extend_from_slice(&frame.payload);
+                },
+                _ => return Err(self.fail(close_code::PROTOCOL_ERROR, "Unexpected frame").await),
+            }
+            if frame.fin && frame.opcode < 0x8 {
+                return match message.take() {
+                    Some((0x1, data)) => match String::from_utf8(data) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => Err(self.fail(close_code::INVALID_DATA, "Text message is not UTF-8").await),
+                    },
+                    Some((_, data)) => Ok(Some(Message::Binary(data))),
+                    None => Err(self.fail(close_code::PROTOCOL_ERROR, "Unexpected frame").await),
+                };
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), io::Error> {
+        match message {
+            Message::Text(text) => self.write_frame(0x1, text.as_bytes()).await,
+            Message::Binary(data) => self.write_frame(0x2, &data).await,
+        }
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> Result<(), io::Error> {
+        self.write_frame(0x1, text.as_bytes()).await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        self.write_frame(0x2, data).await
+    }
+
+    /// Sends a close frame, after which nothing more is sent or received
+    ///
+    /// # Arguments
+    /// * `code` - The reason for closing, see [`close_code`]
+    /// * `reason` - A short description, at most 123 bytes
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), io::Error> {
+        if self.closed {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        let sent = self.write_frame(0x8, &payload).await;
+        self.closed = true;
+        sent
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Closes the socket because of a protocol error, returning the error to report
+    async fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        let _ = self.close(code, reason).await;
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, io::Error> {
+        let head = self.conn.read_body(2).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(close_code::PROTOCOL_ERROR, "Reserved bits are set").await);
+        }
+        // Clients must mask every frame
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(close_code::PROTOCOL_ERROR, "Frame is not masked").await);
+        }
+        let length = match head[1] & 0x7F {
+            126 => u16::from_be_bytes(self.conn.read_body(2).await?.try_into().unwrap()) as u64,
+            127 => u64::from_be_bytes(self.conn.read_body(8).await?.try_into().unwrap()),
+            length => length as u64,
+        };
+        let control = opcode >= 0x8;
+        if control && (length > 125 || !fin) {
+            return Err(self.fail(close_code::PROTOCOL_ERROR, "Invalid control frame").await);
+        }
+        if length > self.max_message_size as u64 {
+            return Err(self.fail(close_code::MESSAGE_TOO_BIG, "Message too big").await);
+        }
+        let mask = self.conn.read_body(4).await?;
+        let mut payload = self.conn.read_body(length as usize).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), io::Error> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "The WebSocket is closed"));
+        }
+        // Server frames are sent unmasked and unfragmented
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            },
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.conn.write_all(&frame).await?;
+        self.conn.flush().await
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
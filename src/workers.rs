@@ -0,0 +1,215 @@
+//! Running the server in several processes
+//!
+//! The thread pool shares one process, so a handler that crashes, leaks or corrupts memory takes
+//! every connection with it. With [`Webserver::with_workers`](crate::Webserver::with_workers),
+//! [`Webserver::start`](crate::Webserver::start) instead becomes a supervisor that starts a number
+//! of worker processes and restarts the ones that exit.
+//!
+//! Routes are closures registered in code, which cannot be sent to another process, so a worker
+//! runs the same executable with the same arguments as the supervisor, with [`WORKER_ENV`] set to
+//! its index. The application builds the same server there, and `start` serves requests in the
+//! worker. Workers bind the address with `SO_REUSEPORT`, so the kernel spreads connections over
+//! them, and each has its own thread pool, limits and state. Anything set up before `start`, like
+//! connection pools, is set up in every worker, and [`worker_index`] tells which one it is.
+//!
+//! A worker that exits is restarted after [`RESTART_DELAY`], so one that crashes on start does not
+//! spin. The supervisor and the workers shut down on `SIGTERM` and Ctrl-C: the supervisor passes
+//! `SIGTERM` on to the workers, waits for them to drain like a single server does, and kills the
+//! ones still running after that. On Linux, workers also get `SIGTERM` if the supervisor dies.
+//!
+//! Workers are only supported on Unix, where `SO_REUSEPORT` and signals exist.
+//!
+//! ## Example
+//! ```no_run
+//! use simpleserve::{Webserver, ConnectionType, workers};
+//!
+//! # async fn run() {
+//! let mut server = Webserver::new(10, vec![]).with_workers(4);
+//! if let Some(index) = workers::worker_index() {
+//!     println!("Worker {} starting", index);
+//! }
+//! server.start("0.0.0.0:7878", ConnectionType::Http, None, None).await.unwrap();
+//! # }
+//! ```
+
+use std::{
+    ffi::OsString,
+    io,
+    path::PathBuf,
+    process::ExitStatus,
+    time::Duration
+};
+
+use tokio::{
+    net::{TcpListener, TcpSocket},
+    process::Command,
+    sync::mpsc
+};
+
+use crate::server::ShutdownHandle;
+
+/// The environment variable that tells a process it is a worker, set to its index from 0
+pub const WORKER_ENV: &str = "SIMPLESERVE_WORKER";
+
+/// How long the supervisor waits before restarting a worker that exited
+pub const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The index of this worker, or `None` if this process is not a worker
+pub fn worker_index() -> Option<usize> {
+    std::env::var(WORKER_ENV).ok()?.parse().ok()
+}
+
+/// Binds a listener that other processes can bind the same address with
+pub(crate) async fn bind_shared(addr: &str) -> io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr).await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no address", addr)))?;
+    let socket = match addr {
+        std::net::SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        std::net::SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// What the supervisor did while it ran
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Supervised {
+    pub(crate) started: usize,
+    pub(crate) restarted: usize,
+}
+
+/// Starts workers and keeps them running until the server shuts down
+#[derive(Debug, Clone)]
+pub(crate) struct Supervisor {
+    program: PathBuf,
+    args: Vec<OsString>,
+    workers: usize,
+    grace: Duration,
+}
+
+impl Supervisor {
+    /// Supervises workers running the current executable with the current arguments
+    ///
+    /// # Arguments
+    /// * `workers` - How many workers run at once
+    /// * `grace` - How long the workers get to drain before they are killed
+    pub(crate) fn current(workers: usize, grace: Duration) -> io::Result<Supervisor> {
+        if cfg!(not(unix)) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Workers are only supported on Unix"));
+        }
+        Ok(Supervisor::new(std::env::current_exe()?, std::env::args_os().skip(1).collect(), workers, grace))
+    }
+
+    pub(crate) fn new(program: PathBuf, args: Vec<OsString>, workers: usize, grace: Duration) -> Supervisor {
+        Supervisor {
+            program,
+            args,
+            workers,
+            grace,
+        }
+    }
+
+    /// Starts a worker, which reports its exit on the channel
+    fn spawn(&self, index: usize, exits: &mpsc::UnboundedSender<(usize, io::Result<ExitStatus>)>) -> io::Result<u32> {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args).env(WORKER_ENV, index.to_string());
+        #[cfg(target_os = "linux")]
+        // SAFETY: prctl is async-signal-safe, and nothing else runs between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                // A worker left without its supervisor would hold the port forever. The signal
+                // follows the thread that started the worker, a runtime thread that lives as long as
+                // the supervisor.
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn()?;
+        let pid = child.id().ok_or_else(|| io::Error::other("The worker exited before it started"))?;
+        let exits = exits.clone();
+        tokio::spawn(async move {
+            let _ = exits.send((index, child.wait().await));
+        });
+        Ok(pid)
+    }
+
+    /// Runs the workers until the shutdown handle is triggered, then stops them
+    pub(crate) async fn run(&self, shutdown: &ShutdownHandle) -> io::Result<Supervised> {
+        let (exits, mut exited) = mpsc::unbounded_channel();
+        let mut running = Vec::new();
+        for index in 0..self.workers {
+            running.push(Some(self.spawn(index, &exits)?));
+        }
+        let mut supervised = Supervised { started: self.workers, restarted: 0 };
+        println!("Supervising {} workers", self.workers);
+        loop {
+            let (index, status) = tokio::select! {
+                exit = exited.recv() => exit.expect("The supervisor keeps a sender"),
+                _ = shutdown.wait() => break,
+            };
+            running[index] = None;
+            match status {
+                Ok(status) => println!("Worker {} exited with {}, restarting it", index, status),
+                Err(e) => println!("Error waiting for worker {}: {}, restarting it", index, e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RESTART_DELAY) => {},
+                _ = shutdown.wait() => break,
+            }
+            match self.spawn(index, &exits) {
+                Ok(pid) => {
+                    running[index] = Some(pid);
+                    supervised.restarted += 1;
+                },
+                // Tried again once the delay has passed, through the exit this sends
+                Err(e) => {
+                    println!("Error restarting worker {}: {}", index, e);
+                    let _ = exits.send((index, Err(e)));
+                },
+            }
+        }
+
+        println!("Stopping {} workers...", running.iter().flatten().count());
+        for pid in running.iter().flatten() {
+            terminate(*pid);
+        }
+        let deadline = tokio::time::Instant::now() + self.grace;
+        while running.iter().any(Option::is_some) {
+            match tokio::time::timeout_at(deadline, exited.recv()).await {
+                Ok(Some((index, _))) => running[index] = None,
+                Ok(None) | Err(_) => break,
+            }
+        }
+        for (index, pid) in running.iter().enumerate().filter_map(|(index, pid)| Some((index, (*pid)?))) {
+            println!("Worker {} did not stop in time, killing it", index);
+            kill(pid);
+        }
+        Ok(supervised)
+    }
+}
+
+/// Asks a worker that has not been waited for yet to shut down
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    // SAFETY: a plain system call, and the pid is still the worker's since it was not waited for
+    unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+}
+
+/// Kills a worker that has not been waited for yet
+#[cfg(unix)]
+fn kill(pid: u32) {
+    // SAFETY: as above
+    unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn terminate(_pid: u32) {}
+
+#[cfg(not(unix))]
+fn kill(_pid: u32) {}